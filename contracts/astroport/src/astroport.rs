@@ -1,80 +1,129 @@
+use std::collections::HashMap;
+
 use andromeda_std::{
     ado_contract::ADOContract,
     amp::{
-        messages::{AMPMsg, AMPPkt},
+        messages::{AMPCtx, AMPMsg, AMPPkt},
         AndrAddr, Recipient,
     },
     common::{context::ExecuteContext, denom::Asset},
     error::ContractError,
 };
 use astroport::{
-    asset::AssetInfo,
+    asset::{AssetInfo, PairInfo},
+    factory::{PairsResponse, QueryMsg as AstroFactoryQueryMsg},
     router::{
-        Cw20HookMsg as AstroCw20HookMsg, ExecuteMsg as AstroExecuteMsg, QueryMsg as AstroQueryMsg,
+        ConfigResponse as AstroRouterConfigResponse, Cw20HookMsg as AstroCw20HookMsg,
+        ExecuteMsg as AstroExecuteMsg, QueryMsg as AstroQueryMsg,
         SwapOperation as AstroSwapOperation,
     },
 };
 use cosmwasm_std::{
-    attr, coin, ensure, to_json_binary, wasm_execute, Coin, Decimal, Deps, DepsMut, Env, Reply,
-    Response, StdError, SubMsg, Uint128, WasmMsg,
+    attr, coin, ensure, to_json_binary, wasm_execute, Addr, BankMsg, Decimal, Deps, DepsMut, Env,
+    Event, Order, Reply, Response, StdError, StdResult, Storage, SubMsg, Uint128, WasmMsg,
 };
-use cw20::{BalanceResponse, Cw20ExecuteMsg, Cw20QueryMsg};
+use cw20::{BalanceResponse, Cw20ExecuteMsg, Cw20QueryMsg, Expiration};
+use cw_storage_plus::Bound;
 
-use crate::state::{ForwardReplyState, FORWARD_REPLY_STATE, PREV_BALANCE, SWAP_ROUTER};
+use crate::oracle::apply_oracle_guard;
+use crate::state::{
+    asset_key, asset_volume, load_max_hops, load_referral_share, load_slippage_policy,
+    load_swap_router, next_swap_id, pause_key, sender_volume, Consolidation, ForwardReplyState,
+    ACCRUED_FEES, ALLOWED_ASSETS, ASSET_VOLUMES, CONSOLIDATIONS, CW20_CODE_IDS, DENIED_ASSETS,
+    FEE_CONFIG, FORWARD_REPLY_STATE, PAUSES, PREV_BALANCE, REFERRAL_EARNINGS, ROUTES,
+    SENDER_VOLUMES, SWAP_ROUTER, TREASURY_CONFIG, VOLUME_LIMITS,
+};
 
-use andromeda_swap_and_forward::astroport::{SimulateSwapOperationResponse, SwapOperation};
+use andromeda_swap_and_forward::{
+    astroport::{
+        FindRouteResponse, PauseScope, PriceImpactResponse, SimulateSwapOperationResponse,
+        SwapOperation,
+    },
+    common::{
+        ensure_price_impact, find_paths, paused_error, price_impact, price_impact_probe,
+        recipient_shares, referral_amount, FeeSide, WeightedRecipient,
+    },
+};
 
-pub const ASTROPORT_MSG_SWAP_ID: u64 = 1;
 pub const ASTROPORT_MSG_FORWARD_ID: u64 = 2;
+/// Swap submessages reply with `ASTROPORT_MSG_SWAP_ID_OFFSET + swap_id`
+pub const ASTROPORT_MSG_SWAP_ID_OFFSET: u64 = 1_000;
+
+/// Longest path tried by route discovery, further capped by the configured max hops
+pub const MAX_ROUTE_HOPS: usize = 3;
+/// Candidate paths simulated by route discovery
+pub const MAX_ROUTE_CANDIDATES: usize = 10;
+const PAIRS_PAGE_LIMIT: u32 = 30;
+
+/// A swap through the astroport router along with what its reply does with the output
+pub(crate) struct AstroSwap {
+    pub from_asset: Asset,
+    /// The amount swapped, without the input fee
+    pub from_amount: Uint128,
+    /// The input fee held back until the swap succeeds
+    pub input_fee: Uint128,
+    pub to_asset: Asset,
+    /// The operations picked by `resolve_astro_operations`
+    pub operations: Vec<SwapOperation>,
+    /// The receivers the swapped token goes to
+    pub recipients: Vec<WeightedRecipient>,
+    pub refund_addr: AndrAddr,
+    pub max_spread: Option<Decimal>,
+    pub minimum_receive: Option<Uint128>,
+    pub refund_on_failure: bool,
+    pub exact_out_amount: Option<Uint128>,
+    /// The output fee an exact-output swap asks for on top of `exact_out_amount`
+    pub output_fee: Uint128,
+    pub referrer: Option<Addr>,
+    pub batch_leg: Option<u32>,
+    pub consolidation_id: Option<u64>,
+}
 
-#[allow(clippy::too_many_arguments)]
 pub(crate) fn execute_swap_astroport_msg(
     ctx: ExecuteContext,
-    from_asset: Asset,
-    from_amount: Uint128,
-    to_asset: Asset,
-    recipient: Recipient,  // receiver where the swapped token goes to
-    refund_addr: AndrAddr, // refund address
-    max_spread: Option<Decimal>,
-    minimum_receive: Option<Uint128>,
-    operations: Option<Vec<SwapOperation>>,
+    swap: AstroSwap,
 ) -> Result<SubMsg, ContractError> {
+    let AstroSwap {
+        from_asset,
+        from_amount,
+        input_fee,
+        to_asset,
+        operations,
+        recipients,
+        refund_addr,
+        max_spread,
+        minimum_receive,
+        refund_on_failure,
+        exact_out_amount,
+        output_fee,
+        referrer,
+        batch_leg,
+        consolidation_id,
+    } = swap;
     let ExecuteContext { deps, env, .. } = ctx;
 
     // Prepare offer and ask asset
     ensure!(from_asset != to_asset, ContractError::DuplicateTokens {});
+    ensure_asset_allowed(&deps.as_ref(), &from_asset)?;
+    ensure_asset_allowed(&deps.as_ref(), &to_asset)?;
+    let max_spread = resolve_max_spread(&deps.as_ref(), &from_asset, &to_asset, max_spread)?;
     let from_denom = match from_asset.clone() {
         Asset::NativeToken(denom) => denom,
         Asset::Cw20Token(andr_addr) => andr_addr.get_raw_address(&deps.as_ref())?.to_string(),
     };
 
     // Prepare swap operations
-    let operations: Vec<AstroSwapOperation> = operations
-        .unwrap_or(vec![SwapOperation {
-            offer_asset_info: from_asset.clone(),
-            ask_asset_info: to_asset.clone(),
-        }])
-        .iter()
-        .map(|oper| {
-            let astro_operation = AstroSwapOperation::AstroSwap {
-                offer_asset_info: generate_asset_info_from_asset(
-                    &deps.as_ref(),
-                    oper.offer_asset_info.clone(),
-                )?,
-                ask_asset_info: generate_asset_info_from_asset(
-                    &deps.as_ref(),
-                    oper.ask_asset_info.clone(),
-                )?,
-            };
-            Ok(astro_operation)
-        })
-        .collect::<Result<Vec<AstroSwapOperation>, ContractError>>()?;
-    ensure!(
-        FORWARD_REPLY_STATE
-            .may_load(deps.as_ref().storage)?
-            .is_none(),
-        ContractError::Unauthorized {}
-    );
+    ensure_not_paused(&deps.as_ref(), &from_asset, &to_asset, &operations)?;
+    let minimum_receive = apply_oracle_guard(
+        &deps.as_ref(),
+        &env,
+        &from_asset,
+        from_amount,
+        &to_asset,
+        &operations,
+        minimum_receive,
+    )?;
+    let operations = generate_astro_swap_operations(&deps.as_ref(), &operations)?;
 
     let amp_ctx = if let Some(pkt) = ctx.amp_ctx.clone() {
         Some(pkt.ctx)
@@ -82,22 +131,30 @@ pub(crate) fn execute_swap_astroport_msg(
         None
     };
 
+    let swap_id = next_swap_id(deps.storage)?;
     let prev_balance = query_balance(&deps.as_ref(), &env, &to_asset)?;
     FORWARD_REPLY_STATE.save(
         deps.storage,
+        swap_id,
         &ForwardReplyState {
-            recipient,
+            recipients,
             refund_addr,
             amp_ctx,
             from_asset: from_asset.clone(),
+            from_amount,
             to_asset: to_asset.clone(),
+            refund_on_failure,
+            exact_out_amount,
+            input_fee,
+            output_fee,
+            referrer,
+            batch_leg,
+            consolidation_id,
         },
     )?;
-    PREV_BALANCE.save(deps.storage, &prev_balance)?;
+    PREV_BALANCE.save(deps.storage, swap_id, &prev_balance)?;
 
-    let swap_router = SWAP_ROUTER
-        .load(deps.storage)?
-        .get_raw_address(&deps.as_ref())?;
+    let swap_router = load_swap_router(&deps.as_ref())?;
     // Build swap msg
     let msg = match from_asset {
         Asset::NativeToken(_) => {
@@ -135,7 +192,544 @@ pub(crate) fn execute_swap_astroport_msg(
         }
     };
 
-    Ok(SubMsg::reply_always(msg, ASTROPORT_MSG_SWAP_ID))
+    Ok(SubMsg::reply_always(
+        msg,
+        ASTROPORT_MSG_SWAP_ID_OFFSET + swap_id,
+    ))
+}
+
+/// Sizes the input needed to receive exactly `to_amount` of `to_asset` and builds the swap for it,
+/// along with the refund of the unused input.
+/// The fee is paid on top: added to the input, or asked from the swap along with `to_amount`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn execute_swap_exact_out_astroport_msg(
+    ctx: ExecuteContext,
+    from_asset: Asset,
+    from_amount: Uint128,
+    to_asset: Asset,
+    to_amount: Uint128,
+    recipient: Recipient,
+    refund_addr: AndrAddr,
+    max_spread: Option<Decimal>,
+    max_price_impact: Option<Decimal>,
+    operations: Vec<SwapOperation>,
+    refund_on_failure: bool,
+) -> Result<Response, ContractError> {
+    ensure!(from_asset != to_asset, ContractError::DuplicateTokens {});
+    let deps = ctx.deps.as_ref();
+    ensure_asset_allowed(&deps, &from_asset)?;
+    ensure_asset_allowed(&deps, &to_asset)?;
+    let astro_operations = generate_astro_swap_operations(&deps, &operations)?;
+
+    let fee_config = FEE_CONFIG.may_load(deps.storage)?;
+    let output_fee = match &fee_config {
+        Some(fee_config) if fee_config.fee_side == FeeSide::Output => {
+            fee_config.fee_amount(to_amount)
+        }
+        _ => Uint128::zero(),
+    };
+    let ask_amount = to_amount.checked_add(output_fee)?;
+    let swap_router = load_swap_router(&deps)?;
+    let res: SimulateSwapOperationResponse = deps.querier.query_wasm_smart(
+        swap_router,
+        &AstroQueryMsg::SimulateReverseSwapOperations {
+            ask_amount,
+            operations: astro_operations,
+        },
+    )?;
+    let offer_amount = res.amount;
+    let input_fee = match &fee_config {
+        Some(fee_config) if fee_config.fee_side == FeeSide::Input => {
+            fee_config.fee_amount(offer_amount)
+        }
+        _ => Uint128::zero(),
+    };
+    let required_amount = offer_amount.checked_add(input_fee)?;
+    ensure!(
+        required_amount <= from_amount,
+        ContractError::InvalidFunds {
+            msg: format!(
+                "Insufficient input: {} required to receive {} {}, {} provided",
+                required_amount, to_amount, to_asset, from_amount
+            ),
+        }
+    );
+    let price_impact =
+        check_astro_price_impact(&deps, offer_amount, &operations, max_price_impact)?;
+
+    let unused_amount = from_amount - required_amount;
+    let refund_msg = if unused_amount.is_zero() {
+        None
+    } else {
+        Some(generate_refund_msg(
+            &deps,
+            &ctx.env,
+            &from_asset,
+            unused_amount,
+            &refund_addr,
+            ctx.amp_ctx.clone().map(|pkt| pkt.ctx),
+        )?)
+    };
+
+    let swap_msg = execute_swap_astroport_msg(
+        ctx,
+        AstroSwap {
+            from_asset: from_asset.clone(),
+            from_amount: offer_amount,
+            input_fee,
+            to_asset: to_asset.clone(),
+            operations,
+            recipients: vec![WeightedRecipient::new(recipient.clone(), Uint128::one())],
+            refund_addr: refund_addr.clone(),
+            max_spread,
+            minimum_receive: Some(ask_amount),
+            refund_on_failure,
+            exact_out_amount: Some(to_amount),
+            output_fee,
+            referrer: None,
+            batch_leg: None,
+            consolidation_id: None,
+        },
+    )?;
+    Ok(Response::default()
+        .add_submessage(swap_msg)
+        .add_submessages(refund_msg)
+        .add_attributes(vec![
+            attr("from_asset", from_asset.to_string()),
+            attr("from_amount", offer_amount),
+            attr("fee_amount", input_fee.max(output_fee)),
+            attr("refund_amount", unused_amount),
+            attr("refund_addr", refund_addr),
+            attr("to_asset", to_asset.to_string()),
+            attr("to_amount", to_amount),
+            attr("recipient", recipient.get_addr()),
+        ])
+        .add_attributes(price_impact.map(|impact| attr("price_impact", impact.to_string()))))
+}
+
+/// Picks the operations of a swap: the given ones first, then the registered route,
+/// then a direct swap from `from_asset` to `to_asset`.
+/// Routes are only discovered through the `FindRoute` query.
+pub(crate) fn resolve_astro_operations(
+    deps: &Deps,
+    from_asset: &Asset,
+    to_asset: &Asset,
+    operations: Option<Vec<SwapOperation>>,
+) -> Result<Vec<SwapOperation>, ContractError> {
+    ensure!(from_asset != to_asset, ContractError::DuplicateTokens {});
+    let route_key = (asset_key(deps, from_asset)?, asset_key(deps, to_asset)?);
+    let operations = match operations {
+        Some(operations) => operations,
+        None => ROUTES
+            .may_load(deps.storage, route_key)?
+            .unwrap_or(vec![SwapOperation {
+                offer_asset_info: from_asset.clone(),
+                ask_asset_info: to_asset.clone(),
+            }]),
+    };
+    validate_astro_operations(deps, from_asset, to_asset, &operations)?;
+    Ok(operations)
+}
+
+/// Ensures `operations` is a path of at most the configured max hops leading from `from_asset`
+/// to `to_asset`, where every hop offers the asset asked by the previous one.
+pub(crate) fn validate_astro_operations(
+    deps: &Deps,
+    from_asset: &Asset,
+    to_asset: &Asset,
+    operations: &[SwapOperation],
+) -> Result<(), ContractError> {
+    ensure!(
+        !operations.is_empty(),
+        ContractError::Std(StdError::generic_err("Swap operations cannot be empty"))
+    );
+    let max_hops = load_max_hops(deps.storage)?;
+    ensure!(
+        operations.len() <= max_hops as usize,
+        ContractError::Std(StdError::generic_err(format!(
+            "Swap operations take {} hops, at most {} are allowed",
+            operations.len(),
+            max_hops
+        )))
+    );
+
+    let mut expected_offer = from_asset;
+    let mut expected_offer_key = asset_key(deps, from_asset)?;
+    for (hop, operation) in operations.iter().enumerate() {
+        ensure!(
+            asset_key(deps, &operation.offer_asset_info)? == expected_offer_key,
+            ContractError::Std(StdError::generic_err(format!(
+                "Swap operation {} offers {} instead of {}",
+                hop, operation.offer_asset_info, expected_offer
+            )))
+        );
+        let ask_key = asset_key(deps, &operation.ask_asset_info)?;
+        ensure!(
+            ask_key != expected_offer_key,
+            ContractError::Std(StdError::generic_err(format!(
+                "Swap operation {} asks for the offered asset {}",
+                hop, operation.offer_asset_info
+            )))
+        );
+        expected_offer = &operation.ask_asset_info;
+        expected_offer_key = ask_key;
+    }
+    ensure!(
+        expected_offer_key == asset_key(deps, to_asset)?,
+        ContractError::Std(StdError::generic_err(format!(
+            "Swap operations end with {} instead of {}",
+            expected_offer, to_asset
+        )))
+    );
+    Ok(())
+}
+
+/// Converts the operations picked by `resolve_astro_operations` into astroport swap operations.
+pub(crate) fn generate_astro_swap_operations(
+    deps: &Deps,
+    operations: &[SwapOperation],
+) -> Result<Vec<AstroSwapOperation>, ContractError> {
+    operations
+        .iter()
+        .map(|oper| {
+            let astro_operation = AstroSwapOperation::AstroSwap {
+                offer_asset_info: generate_asset_info_from_asset(
+                    deps,
+                    oper.offer_asset_info.clone(),
+                )?,
+                ask_asset_info: generate_asset_info_from_asset(deps, oper.ask_asset_info.clone())?,
+            };
+            Ok(astro_operation)
+        })
+        .collect::<Result<Vec<AstroSwapOperation>, ContractError>>()
+}
+
+/// Fails when `asset` is on the deny list, or the allow list holds assets but not this one
+pub(crate) fn ensure_asset_allowed(deps: &Deps, asset: &Asset) -> Result<(), ContractError> {
+    let key = asset_key(deps, asset)?;
+    ensure!(
+        !DENIED_ASSETS.has(deps.storage, &key),
+        ContractError::Std(StdError::generic_err(format!(
+            "{} is on the deny list",
+            asset
+        )))
+    );
+    let allow_all = ALLOWED_ASSETS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .next()
+        .is_none();
+    ensure!(
+        allow_all || ALLOWED_ASSETS.has(deps.storage, &key),
+        ContractError::Std(StdError::generic_err(format!(
+            "{} is not on the allow list",
+            asset
+        )))
+    );
+    Ok(())
+}
+
+/// Fails when CW20 code ids are pinned and `cw20_addr` is not an instance of one of them
+pub(crate) fn ensure_cw20_code_id(deps: &Deps, cw20_addr: &Addr) -> Result<(), ContractError> {
+    let accept_all = CW20_CODE_IDS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .next()
+        .is_none();
+    if accept_all {
+        return Ok(());
+    }
+    let code_id = deps.querier.query_wasm_contract_info(cw20_addr)?.code_id;
+    ensure!(
+        CW20_CODE_IDS.has(deps.storage, code_id),
+        ContractError::Std(StdError::generic_err(format!(
+            "CW20 {} has code id {}, which is not pinned",
+            cw20_addr, code_id
+        )))
+    );
+    Ok(())
+}
+
+/// Enforces the volume limit of `asset` on a swap of `amount` offered by `sender`
+/// and records the amount in the current bucket of the rolling window.
+pub(crate) fn record_swap_volume(
+    deps: DepsMut,
+    env: &Env,
+    sender: &Addr,
+    asset: &Asset,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    let key = asset_key(&deps.as_ref(), asset)?;
+    let Some(volume_limit) = VOLUME_LIMITS.may_load(deps.storage, &key)? else {
+        return Ok(());
+    };
+    if let Some(max_swap_amount) = volume_limit.max_swap_amount {
+        ensure!(
+            amount <= max_swap_amount,
+            ContractError::Std(StdError::generic_err(format!(
+                "Swap of {} {} exceeds the max swap amount of {}",
+                amount, asset, max_swap_amount
+            )))
+        );
+    }
+
+    let now = env.block.time.seconds();
+    let since = volume_limit.window_start(now);
+    let bucket = volume_limit.bucket_start(now);
+    if let Some(max_asset_volume) = volume_limit.max_asset_volume {
+        let expired = ASSET_VOLUMES
+            .prefix(&key)
+            .keys(
+                deps.storage,
+                None,
+                Some(Bound::exclusive(since)),
+                Order::Ascending,
+            )
+            .collect::<StdResult<Vec<u64>>>()?;
+        for start in expired {
+            ASSET_VOLUMES.remove(deps.storage, (&key, start));
+        }
+        let volume = asset_volume(deps.storage, &key, since)?.checked_add(amount)?;
+        ensure!(
+            volume <= max_asset_volume,
+            ContractError::Std(StdError::generic_err(format!(
+                "Swaps of {} would exceed the volume cap of {} within {} seconds",
+                asset, max_asset_volume, volume_limit.window_seconds
+            )))
+        );
+        ASSET_VOLUMES.update(
+            deps.storage,
+            (&key, bucket),
+            |volume| -> StdResult<Uint128> { Ok(volume.unwrap_or_default().checked_add(amount)?) },
+        )?;
+    }
+    if let Some(max_sender_volume) = volume_limit.max_sender_volume {
+        let expired = SENDER_VOLUMES
+            .prefix((sender, &key))
+            .keys(
+                deps.storage,
+                None,
+                Some(Bound::exclusive(since)),
+                Order::Ascending,
+            )
+            .collect::<StdResult<Vec<u64>>>()?;
+        for start in expired {
+            SENDER_VOLUMES.remove(deps.storage, (sender, &key, start));
+        }
+        let volume = sender_volume(deps.storage, sender, &key, since)?.checked_add(amount)?;
+        ensure!(
+            volume <= max_sender_volume,
+            ContractError::Std(StdError::generic_err(format!(
+                "Swaps of {} by {} would exceed the volume cap of {} within {} seconds",
+                asset, sender, max_sender_volume, volume_limit.window_seconds
+            )))
+        );
+        SENDER_VOLUMES.update(
+            deps.storage,
+            (sender, &key, bucket),
+            |volume| -> StdResult<Uint128> { Ok(volume.unwrap_or_default().checked_add(amount)?) },
+        )?;
+    }
+    Ok(())
+}
+
+/// Fails when a swap from `from_asset` to `to_asset` through `operations` falls within a paused scope
+pub(crate) fn ensure_not_paused(
+    deps: &Deps,
+    from_asset: &Asset,
+    to_asset: &Asset,
+    operations: &[SwapOperation],
+) -> Result<(), ContractError> {
+    let assets = std::iter::once(from_asset.clone())
+        .chain(operations.iter().map(|oper| oper.ask_asset_info.clone()));
+    let scopes = [
+        PauseScope::Global,
+        PauseScope::Pair {
+            from_asset: from_asset.clone(),
+            to_asset: to_asset.clone(),
+        },
+    ]
+    .into_iter()
+    .chain(assets.map(|asset| PauseScope::Asset { asset }));
+    for scope in scopes {
+        if let Some(pause) = PAUSES.may_load(deps.storage, &pause_key(deps, &scope)?)? {
+            return Err(paused_error(&pause.scope, &pause.paused_by));
+        }
+    }
+    Ok(())
+}
+
+/// Derives `minimum_receive` from a simulation of the whole path when a tolerance is given,
+/// since `max_spread` only bounds each hop
+pub(crate) fn resolve_minimum_receive(
+    deps: &Deps,
+    from_amount: Uint128,
+    operations: &[SwapOperation],
+    minimum_receive: Option<Uint128>,
+    minimum_receive_tolerance: Option<Decimal>,
+) -> Result<Option<Uint128>, ContractError> {
+    let Some(tolerance) = minimum_receive_tolerance else {
+        return Ok(minimum_receive);
+    };
+    ensure!(
+        minimum_receive.is_none(),
+        ContractError::Std(StdError::generic_err(
+            "Only one of minimum_receive and minimum_receive_tolerance can be set"
+        ))
+    );
+    ensure!(
+        tolerance < Decimal::one(),
+        ContractError::Std(StdError::generic_err(
+            "Minimum receive tolerance must be below 100%"
+        ))
+    );
+
+    let simulated_amount =
+        query_simulate_astro_swap_operation(*deps, from_amount, operations)?.amount;
+    Ok(Some(simulated_amount * (Decimal::one() - tolerance)))
+}
+
+/// Rejects the swap when its price impact exceeds `max_price_impact`, returning the price impact
+pub(crate) fn check_astro_price_impact(
+    deps: &Deps,
+    from_amount: Uint128,
+    operations: &[SwapOperation],
+    max_price_impact: Option<Decimal>,
+) -> Result<Option<Decimal>, ContractError> {
+    let Some(max_price_impact) = max_price_impact else {
+        return Ok(None);
+    };
+    let price_impact = simulate_astro_price_impact(deps, from_amount, operations)?.price_impact;
+    ensure_price_impact(price_impact, max_price_impact)?;
+    Ok(Some(price_impact))
+}
+
+/// Compares the simulated output of `amount` against a small probe through the same operations
+pub(crate) fn simulate_astro_price_impact(
+    deps: &Deps,
+    amount: Uint128,
+    operations: &[SwapOperation],
+) -> Result<PriceImpactResponse, ContractError> {
+    let return_amount = query_simulate_astro_swap_operation(*deps, amount, operations)?.amount;
+    let probe_amount = price_impact_probe(amount);
+    let probe_return_amount =
+        query_simulate_astro_swap_operation(*deps, probe_amount, operations)?.amount;
+    Ok(PriceImpactResponse {
+        return_amount,
+        price_impact: price_impact(amount, return_amount, probe_amount, probe_return_amount)?,
+    })
+}
+
+/// Walks the factory pairs for paths of up to `MAX_ROUTE_HOPS` hops from `from_asset` to `to_asset`
+/// and picks the one with the highest simulated output for `amount`.
+pub(crate) fn find_astro_route(
+    deps: &Deps,
+    from_asset: &Asset,
+    to_asset: &Asset,
+    amount: Uint128,
+) -> Result<FindRouteResponse, ContractError> {
+    ensure!(from_asset != to_asset, ContractError::DuplicateTokens {});
+    let from = generate_asset_info_from_asset(deps, from_asset.clone())?;
+    let to = generate_asset_info_from_asset(deps, to_asset.clone())?;
+
+    let swap_router = SWAP_ROUTER.load(deps.storage)?.get_raw_address(deps)?;
+    let config: AstroRouterConfigResponse = deps
+        .querier
+        .query_wasm_smart(swap_router.clone(), &AstroQueryMsg::Config {})?;
+
+    // The router picks the pair of each hop, so assets are only linked once
+    let mut graph: HashMap<AssetInfo, Vec<AssetInfo>> = HashMap::new();
+    for pair in query_astro_pairs(deps, &config.astroport_factory)? {
+        for offer in pair.asset_infos.iter() {
+            let neighbours = graph.entry(offer.clone()).or_default();
+            for ask in pair.asset_infos.iter() {
+                if ask != offer && !neighbours.contains(ask) {
+                    neighbours.push(ask.clone());
+                }
+            }
+        }
+    }
+
+    let mut best: Option<(Vec<AstroSwapOperation>, Uint128)> = None;
+    let max_hops = MAX_ROUTE_HOPS.min(load_max_hops(deps.storage)? as usize);
+    let paths = find_paths(
+        &graph,
+        |asset_info| asset_info,
+        &from,
+        &to,
+        max_hops,
+        MAX_ROUTE_CANDIDATES,
+    );
+    for path in paths {
+        let offers = std::iter::once(&from).chain(path.iter());
+        let operations: Vec<AstroSwapOperation> = offers
+            .zip(path.iter())
+            .map(|(offer, ask)| AstroSwapOperation::AstroSwap {
+                offer_asset_info: offer.clone(),
+                ask_asset_info: ask.clone(),
+            })
+            .collect();
+        // Paths through pools that cannot take the amount fail to simulate and are skipped
+        let simulation: StdResult<SimulateSwapOperationResponse> = deps.querier.query_wasm_smart(
+            swap_router.clone(),
+            &AstroQueryMsg::SimulateSwapOperations {
+                offer_amount: amount,
+                operations: operations.clone(),
+            },
+        );
+        let Ok(simulation) = simulation else {
+            continue;
+        };
+        let is_better = match &best {
+            Some((_, best_amount)) => simulation.amount > *best_amount,
+            None => true,
+        };
+        if is_better {
+            best = Some((operations, simulation.amount));
+        }
+    }
+
+    let (operations, amount) = best.ok_or_else(|| {
+        ContractError::Std(StdError::generic_err(format!(
+            "No route found from {} to {}",
+            from_asset, to_asset
+        )))
+    })?;
+    let operations = operations
+        .into_iter()
+        .map(|operation| match operation {
+            AstroSwapOperation::AstroSwap {
+                offer_asset_info,
+                ask_asset_info,
+            } => Ok(SwapOperation {
+                offer_asset_info: generate_asset_from_asset_info(offer_asset_info),
+                ask_asset_info: generate_asset_from_asset_info(ask_asset_info),
+            }),
+            AstroSwapOperation::NativeSwap { .. } => Err(ContractError::Std(
+                StdError::generic_err("Native swaps are not supported"),
+            )),
+        })
+        .collect::<Result<Vec<SwapOperation>, ContractError>>()?;
+    Ok(FindRouteResponse { operations, amount })
+}
+
+/// Lists every pair registered in the astroport factory
+fn query_astro_pairs(deps: &Deps, factory: &str) -> Result<Vec<PairInfo>, ContractError> {
+    let mut pairs = vec![];
+    let mut start_after = None;
+    loop {
+        let res: PairsResponse = deps.querier.query_wasm_smart(
+            factory,
+            &AstroFactoryQueryMsg::Pairs {
+                start_after,
+                limit: Some(PAIRS_PAGE_LIMIT),
+            },
+        )?;
+        let page_len = res.pairs.len();
+        start_after = res.pairs.last().map(|pair| pair.asset_infos.clone());
+        pairs.extend(res.pairs);
+        if page_len < PAIRS_PAGE_LIMIT as usize {
+            return Ok(pairs);
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -157,80 +751,510 @@ pub fn generate_asset_info_from_asset(
     }
 }
 
+pub fn generate_asset_from_asset_info(asset_info: AssetInfo) -> Asset {
+    match asset_info {
+        AssetInfo::Token { contract_addr } => {
+            Asset::Cw20Token(AndrAddr::from_string(contract_addr))
+        }
+        AssetInfo::NativeToken { denom } => Asset::NativeToken(denom),
+    }
+}
+
 pub fn handle_astroport_swap_reply(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: Env,
     msg: Reply,
+    swap_id: u64,
     state: ForwardReplyState,
 ) -> Result<Response, ContractError> {
-    let balance = query_balance(&deps.as_ref(), &env, &state.to_asset)?;
-    let prev_balance = PREV_BALANCE.load(deps.storage)?;
-    let return_amount = balance.checked_sub(prev_balance)?;
-    PREV_BALANCE.remove(deps.storage);
+    let return_amount = take_swap_output(deps.branch(), &env, &state.to_asset, swap_id)?;
 
     if return_amount.is_zero() {
         return Err(ContractError::Std(StdError::generic_err(format!(
-            "Incomplete data in Osmosis swap response: {:?}",
+            "Incomplete data in Astroport swap response: {:?}",
             msg
         ))));
     }
 
     let mut resp = Response::default();
 
-    let transfer_msg = match &state.to_asset {
+    // Exact-output swaps forward the requested amount and hand any surplus back
+    let return_amount = match state.exact_out_amount {
+        Some(exact_out_amount) => {
+            // The output fee is asked for on top of the exact amount
+            let asked_amount = exact_out_amount.checked_add(state.output_fee)?;
+            ensure!(
+                return_amount >= asked_amount,
+                ContractError::Std(StdError::generic_err(format!(
+                    "Astroport swap returned {} {}, expected {}",
+                    return_amount, state.to_asset, asked_amount
+                )))
+            );
+            let surplus = return_amount - asked_amount;
+            if !surplus.is_zero() {
+                let refund_msg = generate_refund_msg(
+                    &deps.as_ref(),
+                    &env,
+                    &state.to_asset,
+                    surplus,
+                    &state.refund_addr,
+                    state.amp_ctx.clone(),
+                )?;
+                resp = resp
+                    .add_submessage(refund_msg)
+                    .add_attribute("surplus_refunded", surplus);
+            }
+            exact_out_amount
+        }
+        None => return_amount,
+    };
+
+    let (return_amount, output_fee) = match state.exact_out_amount {
+        Some(_) => (return_amount, state.output_fee),
+        None => deduct_fee(deps.storage, FeeSide::Output, return_amount)?,
+    };
+    // Input fees are held back until the swap succeeds
+    let (fee_asset, fee_amount) = if state.input_fee.is_zero() {
+        (&state.to_asset, output_fee)
+    } else {
+        (&state.from_asset, state.input_fee)
+    };
+    if !fee_amount.is_zero() {
+        let fee_resp = pay_fee(
+            deps.branch(),
+            &env,
+            fee_asset,
+            fee_amount,
+            state.referrer.as_ref(),
+        )?;
+        resp = resp
+            .add_submessages(fee_resp.messages)
+            .add_attributes(fee_resp.attributes);
+    }
+
+    let (transfer_msgs, forward_addrs) = generate_forward_msgs(
+        &deps.as_ref(),
+        &env,
+        &state.to_asset,
+        return_amount,
+        &state.recipients,
+        state.amp_ctx,
+    )?;
+    let kernel_address = ADOContract::default().get_kernel_address(deps.storage)?;
+    resp = resp.add_submessages(transfer_msgs).add_attributes(vec![
+        attr("action", "swap_and_forward"),
+        attr("dex", "astroport"),
+        attr("swap_id", swap_id.to_string()),
+        attr("to_denom", state.to_asset.to_string()),
+        attr("to_amount", return_amount),
+        attr("kernel_address", kernel_address),
+    ]);
+    if let Some(batch_leg) = state.batch_leg {
+        resp = resp.add_attribute("batch_leg", batch_leg.to_string());
+    }
+    resp = resp.add_attributes(
+        forward_addrs
+            .into_iter()
+            .map(|addr| attr("recipient", addr)),
+    );
+    Ok(resp)
+}
+
+/// Books the output of one consolidation swap and forwards the combined amount after the last one.
+pub fn handle_astroport_consolidation_reply(
+    mut deps: DepsMut,
+    env: Env,
+    swap_id: u64,
+    consolidation_id: u64,
+    state: ForwardReplyState,
+) -> Result<Response, ContractError> {
+    let swap_output = take_swap_output(deps.branch(), &env, &state.to_asset, swap_id)?;
+
+    let mut consolidation = CONSOLIDATIONS.load(deps.storage, consolidation_id)?;
+    // The output of the earlier swaps is still held by the contract
+    let return_amount = swap_output.checked_sub(consolidation.received_amount)?;
+    consolidation.received_amount = consolidation.received_amount.checked_add(return_amount)?;
+    consolidation.total_amount = consolidation.total_amount.checked_add(return_amount)?;
+    consolidation.pending_swaps -= 1;
+
+    let resp = Response::default().add_attributes(vec![
+        attr("action", "consolidate_swap"),
+        attr("dex", "astroport"),
+        attr("swap_id", swap_id.to_string()),
+        attr("consolidation_id", consolidation_id.to_string()),
+        attr("from_asset", state.from_asset.to_string()),
+        attr("to_amount", return_amount),
+    ]);
+    if consolidation.pending_swaps > 0 {
+        CONSOLIDATIONS.save(deps.storage, consolidation_id, &consolidation)?;
+        return Ok(resp);
+    }
+
+    CONSOLIDATIONS.remove(deps.storage, consolidation_id);
+    let forward_resp = forward_consolidation(deps, &env, consolidation_id, consolidation)?;
+    Ok(resp
+        .add_submessages(forward_resp.messages)
+        .add_attributes(forward_resp.attributes))
+}
+
+/// Forwards the combined amount of a consolidation to its recipients.
+pub(crate) fn forward_consolidation(
+    mut deps: DepsMut,
+    env: &Env,
+    consolidation_id: u64,
+    consolidation: Consolidation,
+) -> Result<Response, ContractError> {
+    let Consolidation {
+        recipients,
+        amp_ctx,
+        to_asset,
+        minimum_receive,
+        total_amount,
+        charge_fee,
+        ..
+    } = consolidation;
+    ensure!(
+        !total_amount.is_zero(),
+        ContractError::Std(StdError::generic_err("Nothing to forward"))
+    );
+
+    let mut resp = Response::default();
+    // Consolidations pay the fee on the combined amount
+    let fee_amount = match FEE_CONFIG.may_load(deps.storage)? {
+        Some(fee_config) if charge_fee => fee_config.fee_amount(total_amount),
+        _ => Uint128::zero(),
+    };
+    if !fee_amount.is_zero() {
+        let fee_msg = collect_fee(deps.branch(), env, &to_asset, fee_amount)?;
+        resp = resp.add_submessages(fee_msg).add_attributes(vec![
+            attr("fee_asset", to_asset.to_string()),
+            attr("fee_amount", fee_amount),
+        ]);
+    }
+    let total_amount = total_amount.checked_sub(fee_amount)?;
+    if let Some(minimum_receive) = minimum_receive {
+        ensure!(
+            total_amount >= minimum_receive,
+            ContractError::Std(StdError::generic_err(format!(
+                "Consolidated {} {}, expected at least {}",
+                total_amount, to_asset, minimum_receive
+            )))
+        );
+    }
+
+    let (transfer_msgs, forward_addrs) = generate_forward_msgs(
+        &deps.as_ref(),
+        env,
+        &to_asset,
+        total_amount,
+        &recipients,
+        amp_ctx,
+    )?;
+    Ok(resp
+        .add_submessages(transfer_msgs)
+        .add_attributes(vec![
+            attr("action", "consolidate_and_forward"),
+            attr("dex", "astroport"),
+            attr("consolidation_id", consolidation_id.to_string()),
+            attr("to_denom", to_asset.to_string()),
+            attr("to_amount", total_amount),
+        ])
+        .add_attributes(
+            forward_addrs
+                .into_iter()
+                .map(|addr| attr("recipient", addr)),
+        ))
+}
+
+/// Splits `amount` of `to_asset` between the recipients by weight.
+/// Native tokens go out in a single AMP packet, CW20 tokens with one transfer per recipient.
+/// Returns the messages along with the addresses that received a share.
+pub(crate) fn generate_forward_msgs(
+    deps: &Deps,
+    env: &Env,
+    to_asset: &Asset,
+    amount: Uint128,
+    recipients: &[WeightedRecipient],
+    amp_ctx: Option<AMPCtx>,
+) -> Result<(Vec<SubMsg>, Vec<String>), ContractError> {
+    let recipient_shares = recipient_shares(amount, recipients)?;
+
+    let transfer_msgs = match to_asset {
         Asset::NativeToken(denom) => {
-            let funds = vec![Coin {
-                denom: denom.to_string(),
-                amount: return_amount,
-            }];
-
-            let mut pkt = if let Some(amp_ctx) = state.amp_ctx.clone() {
-                AMPPkt::new(amp_ctx.get_origin(), amp_ctx.get_previous_sender(), vec![])
-            } else {
-                AMPPkt::new(
-                    env.contract.address.clone(),
-                    env.contract.address.clone(),
-                    vec![],
-                )
-            };
+            let mut pkt = new_amp_pkt(env, amp_ctx);
 
-            let Recipient { address, msg, .. } = &state.recipient;
-            let msg = AMPMsg::new(
-                address.clone(),
-                msg.clone().unwrap_or_default(),
-                Some(funds.clone()),
-            );
+            // A single packet carries one message per recipient
+            for (Recipient { address, msg, .. }, share) in &recipient_shares {
+                let msg = AMPMsg::new(
+                    address.clone(),
+                    msg.clone().unwrap_or_default(),
+                    Some(vec![coin(share.u128(), denom)]),
+                );
+                pkt = pkt.add_message(msg);
+            }
 
-            pkt = pkt.add_message(msg);
+            let funds = vec![coin(amount.u128(), denom)];
             let kernel_address = ADOContract::default().get_kernel_address(deps.storage)?;
-            pkt.to_sub_msg(kernel_address, Some(funds), ASTROPORT_MSG_FORWARD_ID)?
+            vec![pkt.to_sub_msg(kernel_address, Some(funds), ASTROPORT_MSG_FORWARD_ID)?]
+        }
+        Asset::Cw20Token(andr_addr) => {
+            let cw20_addr = andr_addr.get_raw_address(deps)?;
+            recipient_shares
+                .iter()
+                .map(|(Recipient { address, msg, .. }, share)| {
+                    let recipient_addr = address.get_raw_address(deps)?.to_string();
+                    // Hand the tokens to the recipient contract along with its message when one is set
+                    let transfer_msg = match msg {
+                        Some(msg) => Cw20ExecuteMsg::Send {
+                            contract: recipient_addr,
+                            amount: *share,
+                            msg: msg.clone(),
+                        },
+                        None => Cw20ExecuteMsg::Transfer {
+                            recipient: recipient_addr,
+                            amount: *share,
+                        },
+                    };
+                    let wasm_msg = wasm_execute(cw20_addr.clone(), &transfer_msg, vec![])?;
+                    Ok(SubMsg::reply_always(wasm_msg, ASTROPORT_MSG_FORWARD_ID))
+                })
+                .collect::<Result<Vec<SubMsg>, ContractError>>()?
+        }
+    };
+
+    let forward_addrs = recipient_shares
+        .iter()
+        .map(|(recipient, _)| recipient.get_addr())
+        .collect();
+    Ok((transfer_msgs, forward_addrs))
+}
+
+pub fn handle_astroport_swap_failure(
+    deps: DepsMut,
+    env: Env,
+    error: String,
+    swap_id: u64,
+    state: ForwardReplyState,
+) -> Result<Response, ContractError> {
+    PREV_BALANCE.remove(deps.storage, swap_id);
+
+    // The held back input fee goes back along with the offered amount
+    let refund_amount = state.from_amount.checked_add(state.input_fee)?;
+    let refund_msg = generate_refund_msg(
+        &deps.as_ref(),
+        &env,
+        &state.from_asset,
+        refund_amount,
+        &state.refund_addr,
+        state.amp_ctx,
+    )?;
+
+    let mut event = Event::new("swap_failed_refunded").add_attributes(vec![
+        attr("dex", "astroport"),
+        attr("swap_id", swap_id.to_string()),
+        attr("from_asset", state.from_asset.to_string()),
+        attr("refund_amount", refund_amount),
+        attr("refund_addr", state.refund_addr),
+        attr("error", error),
+    ]);
+    if let Some(batch_leg) = state.batch_leg {
+        event = event.add_attribute("batch_leg", batch_leg.to_string());
+    }
+
+    Ok(Response::default()
+        .add_submessage(refund_msg)
+        .add_event(event))
+}
+
+/// Refunds a swap that arrived after its deadline instead of executing it
+pub(crate) fn refund_expired_swap(
+    deps: &Deps,
+    env: &Env,
+    deadline: &Expiration,
+    asset: &Asset,
+    amount: Uint128,
+    refund_addr: &AndrAddr,
+    amp_ctx: Option<AMPCtx>,
+) -> Result<Response, ContractError> {
+    let refund_msg = generate_refund_msg(deps, env, asset, amount, refund_addr, amp_ctx)?;
+
+    Ok(Response::default()
+        .add_submessage(refund_msg)
+        .add_attributes(vec![
+            attr("action", "refund-expired-swap"),
+            attr("deadline", deadline.to_string()),
+            attr("from_asset", asset.to_string()),
+            attr("refund_amount", amount),
+            attr("refund_addr", refund_addr.to_string()),
+        ]))
+}
+
+/// Applies the slippage policy of the pair to the requested max spread
+pub(crate) fn resolve_max_spread(
+    deps: &Deps,
+    from_asset: &Asset,
+    to_asset: &Asset,
+    max_spread: Option<Decimal>,
+) -> Result<Option<Decimal>, ContractError> {
+    let pair_key = (asset_key(deps, from_asset)?, asset_key(deps, to_asset)?);
+    match load_slippage_policy(deps.storage, pair_key)? {
+        Some(slippage_policy) => Ok(Some(slippage_policy.resolve(max_spread)?)),
+        None => Ok(max_spread),
+    }
+}
+
+/// Splits the fee off `amount` when the fee config takes it on `side`.
+/// Returns the amount left along with the fee.
+pub(crate) fn deduct_fee(
+    storage: &dyn Storage,
+    side: FeeSide,
+    amount: Uint128,
+) -> Result<(Uint128, Uint128), ContractError> {
+    let fee = match FEE_CONFIG.may_load(storage)? {
+        Some(fee_config) if fee_config.fee_side == side => fee_config.fee_amount(amount),
+        _ => Uint128::zero(),
+    };
+    Ok((amount.checked_sub(fee)?, fee))
+}
+
+/// Pays the fee taken on a swap, handing the referrer their share of it
+pub(crate) fn pay_fee(
+    mut deps: DepsMut,
+    env: &Env,
+    asset: &Asset,
+    fee_amount: Uint128,
+    referrer: Option<&Addr>,
+) -> Result<Response, ContractError> {
+    let mut resp = Response::default().add_attributes(vec![
+        attr("fee_asset", asset.to_string()),
+        attr("fee_amount", fee_amount),
+    ]);
+    let mut protocol_amount = fee_amount;
+    if let Some(referrer) = referrer {
+        let share_bps = load_referral_share(deps.storage, referrer)?;
+        let referral_amount = referral_amount(fee_amount, share_bps);
+        if !referral_amount.is_zero() {
+            let key = asset_key(&deps.as_ref(), asset)?;
+            REFERRAL_EARNINGS.update(
+                deps.storage,
+                (referrer, &key),
+                |earned| -> StdResult<Uint128> {
+                    Ok(earned.unwrap_or_default().checked_add(referral_amount)?)
+                },
+            )?;
+            let referral_msg = generate_refund_msg(
+                &deps.as_ref(),
+                env,
+                asset,
+                referral_amount,
+                &AndrAddr::from_string(referrer),
+                None,
+            )?;
+            resp = resp.add_submessage(referral_msg).add_attributes(vec![
+                attr("referrer", referrer),
+                attr("referral_amount", referral_amount),
+            ]);
+            protocol_amount = protocol_amount.checked_sub(referral_amount)?;
+        }
+    }
+    if !protocol_amount.is_zero() {
+        resp = resp.add_submessages(collect_fee(deps.branch(), env, asset, protocol_amount)?);
+    }
+    Ok(resp)
+}
+
+/// Collects `amount` of `asset` as protocol fee. Fees accrue in the contract while a treasury
+/// is set, otherwise they are paid to the fee recipient right away.
+pub(crate) fn collect_fee(
+    deps: DepsMut,
+    env: &Env,
+    asset: &Asset,
+    amount: Uint128,
+) -> Result<Option<SubMsg>, ContractError> {
+    if TREASURY_CONFIG.may_load(deps.storage)?.is_some() {
+        let key = asset_key(&deps.as_ref(), asset)?;
+        ACCRUED_FEES.update(deps.storage, &key, |accrued| -> StdResult<Uint128> {
+            Ok(accrued.unwrap_or_default().checked_add(amount)?)
+        })?;
+        return Ok(None);
+    }
+    let fee_recipient = FEE_CONFIG.load(deps.storage)?.fee_recipient;
+    let fee_msg = generate_refund_msg(&deps.as_ref(), env, asset, amount, &fee_recipient, None)?;
+    Ok(Some(fee_msg))
+}
+
+/// The packet forwards and refunds are relayed in, continuing the AMP context of the swap
+/// when it arrived through one
+pub(crate) fn new_amp_pkt(env: &Env, amp_ctx: Option<AMPCtx>) -> AMPPkt {
+    match amp_ctx {
+        Some(amp_ctx) => AMPPkt::new(amp_ctx.get_origin(), amp_ctx.get_previous_sender(), vec![]),
+        None => AMPPkt::new(
+            env.contract.address.clone(),
+            env.contract.address.clone(),
+            vec![],
+        ),
+    }
+}
+
+/// Sends `amount` of `asset` back to `refund_addr`.
+/// Native refunds are routed through the kernel when the swap was triggered by an AMP packet.
+pub(crate) fn generate_refund_msg(
+    deps: &Deps,
+    env: &Env,
+    asset: &Asset,
+    amount: Uint128,
+    refund_addr: &AndrAddr,
+    amp_ctx: Option<AMPCtx>,
+) -> Result<SubMsg, ContractError> {
+    let msg = match asset {
+        Asset::NativeToken(denom) => {
+            let funds = vec![coin(amount.u128(), denom)];
+            match amp_ctx {
+                Some(amp_ctx) => {
+                    let pkt = new_amp_pkt(env, Some(amp_ctx)).add_message(AMPMsg::new(
+                        refund_addr.clone(),
+                        Default::default(),
+                        Some(funds.clone()),
+                    ));
+                    let kernel_address = ADOContract::default().get_kernel_address(deps.storage)?;
+                    pkt.to_sub_msg(kernel_address, Some(funds), ASTROPORT_MSG_FORWARD_ID)?
+                }
+                None => SubMsg::new(BankMsg::Send {
+                    to_address: refund_addr.get_raw_address(deps)?.to_string(),
+                    amount: funds,
+                }),
+            }
         }
         Asset::Cw20Token(andr_addr) => {
-            let Recipient { address, .. } = &state.recipient;
             let transfer_msg = Cw20ExecuteMsg::Transfer {
-                recipient: address.get_raw_address(&deps.as_ref())?.to_string(),
-                amount: return_amount,
+                recipient: refund_addr.get_raw_address(deps)?.to_string(),
+                amount,
             };
-            let wasm_msg = wasm_execute(
-                andr_addr.get_raw_address(&deps.as_ref())?,
+            SubMsg::new(wasm_execute(
+                andr_addr.get_raw_address(deps)?,
                 &transfer_msg,
                 vec![],
-            )?;
-            SubMsg::new(wasm_msg)
+            )?)
         }
     };
-    let kernel_address = ADOContract::default().get_kernel_address(deps.storage)?;
-    resp = resp.add_submessage(transfer_msg).add_attributes(vec![
-        attr("action", "swap_and_forward"),
-        attr("dex", "astroport"),
-        attr("to_denom", state.to_asset.to_string()),
-        attr("to_amount", return_amount),
-        attr("recipient", state.recipient.get_addr()),
-        attr("kernel_address", kernel_address),
-    ]);
-    Ok(resp)
+    Ok(msg)
+}
+
+/// The balance of `asset` gained since swap `swap_id` was queued, clearing its recorded balance.
+/// Accrued fees are left out, so fees booked by earlier replies do not count as output.
+pub(crate) fn take_swap_output(
+    deps: DepsMut,
+    env: &Env,
+    asset: &Asset,
+    swap_id: u64,
+) -> Result<Uint128, ContractError> {
+    let balance = query_balance(&deps.as_ref(), env, asset)?;
+    let prev_balance = PREV_BALANCE.load(deps.storage, swap_id)?;
+    PREV_BALANCE.remove(deps.storage, swap_id);
+    Ok(balance.checked_sub(prev_balance)?)
 }
 
+/// Balance of `asset` held by the contract, without the accrued fees
 pub(crate) fn query_balance(
     deps: &Deps,
     env: &Env,
@@ -253,27 +1277,19 @@ pub(crate) fn query_balance(
                 .amount
         }
     };
-    Ok(balance)
+    // Accrued fees are held for the treasury and left out of swap accounting
+    let accrued = ACCRUED_FEES
+        .may_load(deps.storage, &asset_key(deps, asset)?)?
+        .unwrap_or_default();
+    Ok(balance.checked_sub(accrued)?)
 }
 
 pub fn query_simulate_astro_swap_operation(
     deps: Deps,
     offer_amount: Uint128,
-    operations: Vec<SwapOperation>,
+    operations: &[SwapOperation],
 ) -> Result<SimulateSwapOperationResponse, ContractError> {
-    let operations: Vec<AstroSwapOperation> = operations
-        .iter()
-        .map(|oper| {
-            let astro_operation = AstroSwapOperation::AstroSwap {
-                offer_asset_info: generate_asset_info_from_asset(
-                    &deps,
-                    oper.offer_asset_info.clone(),
-                )?,
-                ask_asset_info: generate_asset_info_from_asset(&deps, oper.ask_asset_info.clone())?,
-            };
-            Ok(astro_operation)
-        })
-        .collect::<Result<Vec<AstroSwapOperation>, ContractError>>()?;
+    let operations = generate_astro_swap_operations(&deps, operations)?;
     let query_msg = AstroQueryMsg::SimulateSwapOperations {
         offer_amount,
         operations,
@@ -8,28 +8,74 @@ use andromeda_std::{
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    attr, ensure, from_json, Binary, Decimal, Deps, DepsMut, Env, MessageInfo, Reply, Response,
-    StdError, Uint128,
+    attr, ensure, from_json, wasm_execute, Addr, Binary, Coin, Decimal, Deps, DepsMut, Empty, Env,
+    MessageInfo, Order, Reply, Response, StdError, StdResult, SubMsg, Timestamp, Uint128,
 };
 use cw2::set_contract_version;
-use cw20::Cw20ReceiveMsg;
+use cw20::{Cw20ExecuteMsg, Cw20ReceiveMsg, Expiration};
+use cw_storage_plus::Bound;
 use cw_utils::one_coin;
 
 use crate::{
     astroport::{
-        execute_swap_astroport_msg, handle_astroport_swap_reply,
-        query_simulate_astro_swap_operation, ASTROPORT_MSG_FORWARD_ID, ASTROPORT_MSG_SWAP_ID,
+        check_astro_price_impact, deduct_fee, ensure_cw20_code_id, execute_swap_astroport_msg,
+        execute_swap_exact_out_astroport_msg, find_astro_route, forward_consolidation,
+        handle_astroport_consolidation_reply, handle_astroport_swap_failure,
+        handle_astroport_swap_reply, query_simulate_astro_swap_operation, record_swap_volume,
+        refund_expired_swap, resolve_astro_operations, resolve_minimum_receive,
+        simulate_astro_price_impact, validate_astro_operations, AstroSwap,
+        ASTROPORT_MSG_FORWARD_ID, ASTROPORT_MSG_SWAP_ID_OFFSET,
+    },
+    state::{
+        asset_from_key, asset_key, asset_volume, load_max_hops, load_referral_share,
+        load_slippage_policy, load_swap_router_timelock, next_swap_id, pause_key, sender_volume,
+        Consolidation, ForwardReplyState, ACCRUED_FEES, ALLOWED_ASSETS, CONSOLIDATIONS,
+        CW20_CODE_IDS, DEFAULT_REFERRAL_SHARE, DENIED_ASSETS, FEE_CONFIG, FORWARD_REPLY_STATE,
+        LEGACY_SWAP_STATE_KEYS, MAX_HOPS, ORACLE_GUARD, ORACLE_PAIRS, PAIR_SLIPPAGE_POLICIES,
+        PAUSES, REFERRAL_EARNINGS, REFERRAL_SHARES, ROLES, ROUTES, SLIPPAGE_POLICY, SWAP_ROUTER,
+        SWAP_ROUTER_PIN, SWAP_ROUTER_PROPOSAL, SWAP_ROUTER_TIMELOCK, TREASURY_CONFIG,
+        VOLUME_LIMITS,
     },
-    state::{ForwardReplyState, FORWARD_REPLY_STATE, SWAP_ROUTER},
 };
 
-use andromeda_swap_and_forward::astroport::{
-    Cw20HookMsg, ExecuteMsg, InstantiateMsg, QueryMsg, SimulateSwapOperationResponse, SwapOperation,
+use andromeda_swap_and_forward::{
+    astroport::{
+        AccruedFee, AccruedFeesResponse, AssetListResponse, Cw20CodeIdsResponse, Cw20HookMsg,
+        Cw20Input, ExecuteMsg, FeeConfigResponse, InstantiateMsg, MaxHopsResponse, OracleGuard,
+        OracleGuardResponse, OraclePair, OraclePairResponse, Pause, PauseScope,
+        PauseStatusResponse, QueryMsg, ReferralEarning, ReferralEarningsResponse,
+        ReferralShareResponse, RouteInfo, RoutesResponse, SimulateSwapOperationResponse,
+        SlippagePolicyResponse, SwapLeg, SwapOperation, TreasuryConfig, TreasuryConfigResponse,
+        VolumeCapacityResponse,
+    },
+    common::{
+        ensure_funds_match, ensure_not_expired, expired_error, get_weighted_recipients,
+        resolve_referrer, validate_referral_share, validate_swap_router_timelock, AssetList,
+        CodePin, FeeConfig, FeeSide, Role, RolesResponse, SlippagePolicy, SwapRouterProposal,
+        SwapRouterResponse, VolumeLimit, WeightedRecipient,
+    },
 };
 
 const CONTRACT_NAME: &str = "crates.io:swap-and-forward-astroport";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// Swap actions checked against ADO permissions, named after their messages
+pub const SWAP_AND_FORWARD_ACTION: &str = "SwapAndForward";
+pub const SWAP_EXACT_OUT_AND_FORWARD_ACTION: &str = "SwapExactOutAndForward";
+pub const BATCH_SWAP_AND_FORWARD_ACTION: &str = "BatchSwapAndForward";
+pub const CONSOLIDATE_AND_FORWARD_ACTION: &str = "ConsolidateAndForward";
+pub const RECEIVE_ACTION: &str = "Receive";
+const SWAP_ACTIONS: [&str; 5] = [
+    SWAP_AND_FORWARD_ACTION,
+    SWAP_EXACT_OUT_AND_FORWARD_ACTION,
+    BATCH_SWAP_AND_FORWARD_ACTION,
+    CONSOLIDATE_AND_FORWARD_ACTION,
+    RECEIVE_ACTION,
+];
+
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
@@ -57,6 +103,15 @@ pub fn instantiate(
         .unwrap_or(AndrAddr::from_string("/lib/astroport/router"));
     swap_router.get_raw_address(&deps.as_ref())?;
     SWAP_ROUTER.save(deps.storage, &swap_router)?;
+    if let Some(timelock_seconds) = msg.swap_router_timelock {
+        validate_swap_router_timelock(timelock_seconds)?;
+        SWAP_ROUTER_TIMELOCK.save(deps.storage, &timelock_seconds)?;
+    }
+    if msg.permissioned_swaps.unwrap_or(false) {
+        for action in SWAP_ACTIONS {
+            ADOContract::default().permission_action(action, deps.storage)?;
+        }
+    }
 
     Ok(inst_resp
         .add_attribute("method", "instantiate")
@@ -80,108 +135,363 @@ pub fn execute(
     }
 }
 
-pub fn handle_execute(ctx: ExecuteContext, msg: ExecuteMsg) -> Result<Response, ContractError> {
+pub fn handle_execute(mut ctx: ExecuteContext, msg: ExecuteMsg) -> Result<Response, ContractError> {
+    if let Some((action, identifier)) = swap_permission(&ctx, &msg) {
+        ADOContract::default().is_permissioned(
+            ctx.deps.branch(),
+            ctx.env.clone(),
+            action,
+            identifier,
+        )?;
+    }
+
     match msg {
         ExecuteMsg::Receive(msg) => handle_receive_cw20(ctx, msg),
         ExecuteMsg::SwapAndForward {
             to_asset,
             recipient,
+            recipients,
             max_spread,
             minimum_receive,
+            minimum_receive_tolerance,
+            max_price_impact,
             operations,
+            referrer,
+            refund_on_failure,
+            deadline,
         } => execute_swap_and_forward(
             ctx,
             to_asset,
             recipient,
+            recipients,
             max_spread,
             minimum_receive,
+            minimum_receive_tolerance,
+            max_price_impact,
+            operations,
+            referrer,
+            refund_on_failure.unwrap_or(false),
+            deadline,
+        ),
+        ExecuteMsg::SwapExactOutAndForward {
+            to_asset,
+            to_amount,
+            recipient,
+            refund_addr,
+            max_spread,
+            max_price_impact,
             operations,
+            refund_on_failure,
+            deadline,
+        } => execute_swap_exact_out_and_forward(
+            ctx,
+            to_asset,
+            to_amount,
+            recipient,
+            refund_addr,
+            max_spread,
+            max_price_impact,
+            operations,
+            refund_on_failure.unwrap_or(false),
+            deadline,
+        ),
+        ExecuteMsg::BatchSwapAndForward { legs } => execute_batch_swap_and_forward(ctx, legs),
+        ExecuteMsg::ConsolidateAndForward {
+            to_asset,
+            recipient,
+            cw20_inputs,
+            max_spread,
+            minimum_receive,
+            deadline,
+        } => execute_consolidate_and_forward(
+            ctx,
+            to_asset,
+            recipient,
+            cw20_inputs,
+            max_spread,
+            minimum_receive,
+            deadline,
         ),
+        ExecuteMsg::ConvertFees {
+            assets,
+            max_spread,
+            minimum_receive,
+        } => execute_convert_fees(ctx, assets, max_spread, minimum_receive),
         ExecuteMsg::UpdateSwapRouter { swap_router } => {
-            execute_update_swap_router(ctx, swap_router)
+            execute_propose_swap_router(ctx, swap_router, None)
+        }
+        ExecuteMsg::ProposeSwapRouter {
+            swap_router,
+            code_pin,
+        } => execute_propose_swap_router(ctx, swap_router, code_pin),
+        ExecuteMsg::AcceptSwapRouter {} => execute_accept_swap_router(ctx),
+        ExecuteMsg::CancelSwapRouter {} => execute_cancel_swap_router(ctx),
+        ExecuteMsg::SetSwapRouterTimelock { timelock_seconds } => {
+            execute_set_swap_router_timelock(ctx, timelock_seconds)
+        }
+        ExecuteMsg::Pause { scope } => execute_pause(ctx, scope),
+        ExecuteMsg::Unpause { scope } => execute_unpause(ctx, scope),
+        ExecuteMsg::GrantRole { role, address } => execute_grant_role(ctx, role, address),
+        ExecuteMsg::RevokeRole { role, address } => execute_revoke_role(ctx, role, address),
+        ExecuteMsg::SetFeeConfig { fee_config } => execute_set_fee_config(ctx, fee_config),
+        ExecuteMsg::SetTreasuryConfig { treasury_config } => {
+            execute_set_treasury_config(ctx, treasury_config)
+        }
+        ExecuteMsg::SetSlippagePolicy { slippage_policy } => {
+            execute_set_slippage_policy(ctx, slippage_policy)
+        }
+        ExecuteMsg::SetPairSlippagePolicy {
+            from_asset,
+            to_asset,
+            slippage_policy,
+        } => execute_set_pair_slippage_policy(ctx, from_asset, to_asset, slippage_policy),
+        ExecuteMsg::UpdateAssetList { list, add, remove } => {
+            execute_update_asset_list(ctx, list, add, remove)
+        }
+        ExecuteMsg::UpdateCw20CodeIds { add, remove } => {
+            execute_update_cw20_code_ids(ctx, add, remove)
+        }
+        ExecuteMsg::SetVolumeLimit {
+            asset,
+            volume_limit,
+        } => execute_set_volume_limit(ctx, asset, volume_limit),
+        ExecuteMsg::SetOracleGuard { oracle_guard } => execute_set_oracle_guard(ctx, oracle_guard),
+        ExecuteMsg::SetOraclePair { asset, oracle_pair } => {
+            execute_set_oracle_pair(ctx, asset, oracle_pair)
         }
+        ExecuteMsg::SetReferralShare {
+            referrer,
+            share_bps,
+        } => execute_set_referral_share(ctx, referrer, share_bps),
+        ExecuteMsg::UpdateDefaultReferralShare { share_bps } => {
+            execute_update_default_referral_share(ctx, share_bps)
+        }
+        ExecuteMsg::UpdateMaxHops { max_hops } => execute_update_max_hops(ctx, max_hops),
+        ExecuteMsg::SetRoute {
+            from_asset,
+            to_asset,
+            operations,
+        } => execute_set_route(ctx, from_asset, to_asset, operations),
+        ExecuteMsg::RemoveRoute {
+            from_asset,
+            to_asset,
+        } => execute_remove_route(ctx, from_asset, to_asset),
         _ => ADOContract::default().execute(ctx, msg),
     }
 }
 
 fn handle_receive_cw20(
-    ctx: ExecuteContext,
+    mut ctx: ExecuteContext,
     cw20_msg: Cw20ReceiveMsg,
 ) -> Result<Response, ContractError> {
-    let ExecuteContext { ref info, .. } = ctx;
+    ensure_cw20_code_id(&ctx.deps.as_ref(), &ctx.info.sender)?;
 
     let amount = cw20_msg.amount;
     let sender = cw20_msg.sender;
-    let from_addr = AndrAddr::from_string(info.sender.clone());
+    let from_addr = AndrAddr::from_string(ctx.info.sender.clone());
     let from_asset = Asset::Cw20Token(from_addr);
+    let sender_addr = ctx.deps.api.addr_validate(&sender)?;
+    let hook_msg: Cw20HookMsg = from_json(&cw20_msg.msg)?;
+    let deadline = match &hook_msg {
+        Cw20HookMsg::SwapAndForward { deadline, .. }
+        | Cw20HookMsg::SwapExactOutAndForward { deadline, .. } => deadline,
+    };
+    ensure_not_expired(deadline.as_ref(), &ctx.env.block)?;
+    record_swap_volume(
+        ctx.deps.branch(),
+        &ctx.env,
+        &sender_addr,
+        &from_asset,
+        amount,
+    )?;
 
-    match from_json(&cw20_msg.msg)? {
+    match hook_msg {
         Cw20HookMsg::SwapAndForward {
             to_asset,
             recipient,
+            recipients,
             max_spread,
             minimum_receive,
+            minimum_receive_tolerance,
+            max_price_impact,
+            operations,
+            referrer,
+            refund_on_failure,
+            deadline: _,
+        } => {
+            let recipients = get_weighted_recipients(
+                recipient,
+                recipients,
+                AndrAddr::from_string(sender.clone()),
+            )?;
+            swap_and_forward_cw20(
+                ctx,
+                from_asset,
+                Uint128::new(amount.u128()),
+                to_asset,
+                recipients,
+                AndrAddr::from_string(sender),
+                max_spread,
+                minimum_receive,
+                minimum_receive_tolerance,
+                max_price_impact,
+                operations,
+                referrer,
+                refund_on_failure.unwrap_or(false),
+            )
+        }
+        Cw20HookMsg::SwapExactOutAndForward {
+            to_asset,
+            to_amount,
+            recipient,
+            refund_addr,
+            max_spread,
+            max_price_impact,
             operations,
+            refund_on_failure,
+            deadline: _,
         } => {
             let recipient = match recipient {
                 None => Recipient::new(sender.clone(), None),
                 Some(recipient) => recipient,
             };
-            swap_and_forward_cw20(
+            swap_exact_out_and_forward(
                 ctx,
                 from_asset,
                 Uint128::new(amount.u128()),
                 to_asset,
+                to_amount,
                 recipient,
-                AndrAddr::from_string(sender),
+                refund_addr.unwrap_or(AndrAddr::from_string(sender)),
                 max_spread,
-                minimum_receive,
+                max_price_impact,
                 operations,
+                refund_on_failure.unwrap_or(false),
             )
         }
     }
 }
 
+/// The permissioned action a swap message falls under and the address it is checked against
+fn swap_permission(ctx: &ExecuteContext, msg: &ExecuteMsg) -> Option<(&'static str, String)> {
+    let action = match msg {
+        // Tokens sent through a hook are checked against the CW20 sender, not the token
+        ExecuteMsg::Receive(cw20_msg) => return Some((RECEIVE_ACTION, cw20_msg.sender.clone())),
+        ExecuteMsg::SwapAndForward { .. } => SWAP_AND_FORWARD_ACTION,
+        ExecuteMsg::SwapExactOutAndForward { .. } => SWAP_EXACT_OUT_AND_FORWARD_ACTION,
+        ExecuteMsg::BatchSwapAndForward { .. } => BATCH_SWAP_AND_FORWARD_ACTION,
+        ExecuteMsg::ConsolidateAndForward { .. } => CONSOLIDATE_AND_FORWARD_ACTION,
+        _ => return None,
+    };
+    Some((action, swap_origin(ctx).to_string()))
+}
+
+/// The address a swap is made by, packets relayed through the kernel count as sent by their origin
+/// The kernel vouches for the origin, which may be an address on another chain.
+fn swap_origin(ctx: &ExecuteContext) -> Addr {
+    match &ctx.amp_ctx {
+        Some(pkt) => Addr::unchecked(pkt.ctx.get_origin()),
+        None => ctx.info.sender.clone(),
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn execute_swap_and_forward(
-    ctx: ExecuteContext,
+    mut ctx: ExecuteContext,
     to_asset: Asset,
     recipient: Option<Recipient>,
+    recipients: Option<Vec<WeightedRecipient>>,
     max_spread: Option<Decimal>,
     minimum_receive: Option<Uint128>,
+    minimum_receive_tolerance: Option<Decimal>,
+    max_price_impact: Option<Decimal>,
     operations: Option<Vec<SwapOperation>>,
+    referrer: Option<AndrAddr>,
+    refund_on_failure: bool,
+    deadline: Option<Expiration>,
 ) -> Result<Response, ContractError> {
     let fund = one_coin(&ctx.info).map_err(|_| ContractError::InvalidAsset {
         asset: "Invalid or missing coin".to_string(),
     })?;
 
     let from_asset = Asset::NativeToken(fund.denom);
-    let sender = AndrAddr::from_string(&ctx.info.sender);
-    let recipient = match recipient {
-        None => Recipient::new(sender.clone(), None),
-        Some(recipient) => recipient,
-    };
+    let origin = swap_origin(&ctx);
+    if let Some(deadline) = deadline.filter(|deadline| deadline.is_expired(&ctx.env.block)) {
+        // Funds relayed through AMP are refunded, direct callers get the error
+        ensure!(ctx.amp_ctx.is_some(), expired_error(&deadline));
+        return refund_expired_swap(
+            &ctx.deps.as_ref(),
+            &ctx.env,
+            &deadline,
+            &from_asset,
+            fund.amount,
+            &AndrAddr::from_string(&origin),
+            ctx.amp_ctx.map(|pkt| pkt.ctx),
+        );
+    }
+    record_swap_volume(
+        ctx.deps.branch(),
+        &ctx.env,
+        &origin,
+        &from_asset,
+        fund.amount,
+    )?;
+    let sender = AndrAddr::from_string(&origin);
+    let recipients = get_weighted_recipients(recipient, recipients, sender.clone())?;
+    let referrer = resolve_referrer(&ctx.deps.as_ref(), referrer, origin.as_str())?;
+    let (from_amount, input_fee) = deduct_fee(ctx.deps.storage, FeeSide::Input, fund.amount)?;
+    let operations =
+        resolve_astro_operations(&ctx.deps.as_ref(), &from_asset, &to_asset, operations)?;
+    let price_impact = check_astro_price_impact(
+        &ctx.deps.as_ref(),
+        from_amount,
+        &operations,
+        max_price_impact,
+    )?;
+    let minimum_receive = resolve_minimum_receive(
+        &ctx.deps.as_ref(),
+        from_amount,
+        &operations,
+        minimum_receive,
+        minimum_receive_tolerance,
+    )?;
 
     let swap_msg = execute_swap_astroport_msg(
         ctx,
-        from_asset.clone(),
-        fund.amount,
-        to_asset.clone(),
-        recipient.clone(),
-        sender,
-        max_spread,
-        minimum_receive,
-        operations,
+        AstroSwap {
+            from_asset: from_asset.clone(),
+            from_amount,
+            input_fee,
+            to_asset: to_asset.clone(),
+            operations,
+            recipients: recipients.clone(),
+            refund_addr: sender,
+            max_spread,
+            minimum_receive,
+            refund_on_failure,
+            exact_out_amount: None,
+            output_fee: Uint128::zero(),
+            referrer,
+            batch_leg: None,
+            consolidation_id: None,
+        },
     )?;
 
     Ok(Response::default()
         .add_submessage(swap_msg)
         .add_attributes(vec![
             attr("from_asset", from_asset.to_string()),
-            attr("from_amount", fund.amount),
+            attr("from_amount", from_amount),
+            attr("fee_amount", input_fee),
             attr("to_asset", to_asset.to_string()),
-            attr("recipient", recipient.get_addr()),
-        ]))
+        ])
+        .add_attributes(minimum_receive.map(|amount| attr("minimum_receive", amount)))
+        .add_attributes(price_impact.map(|impact| attr("price_impact", impact.to_string())))
+        .add_attributes(
+            recipients
+                .iter()
+                .map(|r| attr("recipient", r.recipient.get_addr())),
+        ))
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -190,22 +500,53 @@ fn swap_and_forward_cw20(
     from_asset: Asset,
     from_amount: Uint128,
     to_asset: Asset,
-    recipient: Recipient,
+    recipients: Vec<WeightedRecipient>,
     refund_addr: AndrAddr,
     max_spread: Option<Decimal>,
     minimum_receive: Option<Uint128>,
+    minimum_receive_tolerance: Option<Decimal>,
+    max_price_impact: Option<Decimal>,
     operations: Option<Vec<SwapOperation>>,
+    referrer: Option<AndrAddr>,
+    refund_on_failure: bool,
 ) -> Result<Response, ContractError> {
-    let swap_msg = execute_swap_astroport_msg(
-        ctx,
-        from_asset.clone(),
+    // Hook swaps refund the CW20 sender, who made the swap
+    let referrer = resolve_referrer(&ctx.deps.as_ref(), referrer, refund_addr.as_str())?;
+    let (from_amount, input_fee) = deduct_fee(ctx.deps.storage, FeeSide::Input, from_amount)?;
+    let operations =
+        resolve_astro_operations(&ctx.deps.as_ref(), &from_asset, &to_asset, operations)?;
+    let price_impact = check_astro_price_impact(
+        &ctx.deps.as_ref(),
         from_amount,
-        to_asset.clone(),
-        recipient.clone(),
-        refund_addr,
-        max_spread,
+        &operations,
+        max_price_impact,
+    )?;
+    let minimum_receive = resolve_minimum_receive(
+        &ctx.deps.as_ref(),
+        from_amount,
+        &operations,
         minimum_receive,
-        operations,
+        minimum_receive_tolerance,
+    )?;
+    let swap_msg = execute_swap_astroport_msg(
+        ctx,
+        AstroSwap {
+            from_asset: from_asset.clone(),
+            from_amount,
+            input_fee,
+            to_asset: to_asset.clone(),
+            operations,
+            recipients: recipients.clone(),
+            refund_addr,
+            max_spread,
+            minimum_receive,
+            refund_on_failure,
+            exact_out_amount: None,
+            output_fee: Uint128::zero(),
+            referrer,
+            batch_leg: None,
+            consolidation_id: None,
+        },
     )?;
 
     Ok(Response::default()
@@ -213,75 +554,1320 @@ fn swap_and_forward_cw20(
         .add_attributes(vec![
             attr("from_asset", from_asset.to_string()),
             attr("from_amount", from_amount),
+            attr("fee_amount", input_fee),
             attr("to_asset", to_asset.to_string()),
-            attr("recipient", recipient.get_addr()),
-        ]))
+        ])
+        .add_attributes(minimum_receive.map(|amount| attr("minimum_receive", amount)))
+        .add_attributes(price_impact.map(|impact| attr("price_impact", impact.to_string())))
+        .add_attributes(
+            recipients
+                .iter()
+                .map(|r| attr("recipient", r.recipient.get_addr())),
+        ))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_swap_exact_out_and_forward(
+    mut ctx: ExecuteContext,
+    to_asset: Asset,
+    to_amount: Uint128,
+    recipient: Option<Recipient>,
+    refund_addr: Option<AndrAddr>,
+    max_spread: Option<Decimal>,
+    max_price_impact: Option<Decimal>,
+    operations: Option<Vec<SwapOperation>>,
+    refund_on_failure: bool,
+    deadline: Option<Expiration>,
+) -> Result<Response, ContractError> {
+    ensure_not_expired(deadline.as_ref(), &ctx.env.block)?;
+    let fund = one_coin(&ctx.info).map_err(|_| ContractError::InvalidAsset {
+        asset: "Invalid or missing coin".to_string(),
+    })?;
+
+    let from_asset = Asset::NativeToken(fund.denom);
+    let origin = swap_origin(&ctx);
+    record_swap_volume(
+        ctx.deps.branch(),
+        &ctx.env,
+        &origin,
+        &from_asset,
+        fund.amount,
+    )?;
+    let sender = AndrAddr::from_string(&origin);
+    let recipient = match recipient {
+        None => Recipient::new(sender.clone(), None),
+        Some(recipient) => recipient,
+    };
+
+    swap_exact_out_and_forward(
+        ctx,
+        from_asset,
+        fund.amount,
+        to_asset,
+        to_amount,
+        recipient,
+        refund_addr.unwrap_or(sender),
+        max_spread,
+        max_price_impact,
+        operations,
+        refund_on_failure,
+    )
 }
 
-fn execute_update_swap_router(
+#[allow(clippy::too_many_arguments)]
+fn swap_exact_out_and_forward(
     ctx: ExecuteContext,
-    swap_router: AndrAddr,
+    from_asset: Asset,
+    from_amount: Uint128,
+    to_asset: Asset,
+    to_amount: Uint128,
+    recipient: Recipient,
+    refund_addr: AndrAddr,
+    max_spread: Option<Decimal>,
+    max_price_impact: Option<Decimal>,
+    operations: Option<Vec<SwapOperation>>,
+    refund_on_failure: bool,
+) -> Result<Response, ContractError> {
+    let operations =
+        resolve_astro_operations(&ctx.deps.as_ref(), &from_asset, &to_asset, operations)?;
+    execute_swap_exact_out_astroport_msg(
+        ctx,
+        from_asset,
+        from_amount,
+        to_asset,
+        to_amount,
+        recipient,
+        refund_addr,
+        max_spread,
+        max_price_impact,
+        operations,
+        refund_on_failure,
+    )
+}
+
+fn execute_batch_swap_and_forward(
+    mut ctx: ExecuteContext,
+    legs: Vec<SwapLeg>,
 ) -> Result<Response, ContractError> {
-    let sender = ctx.info.sender;
     ensure!(
-        ADOContract::default().is_owner_or_operator(ctx.deps.storage, sender.as_ref())?,
-        ContractError::Unauthorized {}
+        !legs.is_empty(),
+        ContractError::Std(StdError::generic_err("Batch must contain at least one leg"))
+    );
+    let offered: Vec<Coin> = legs.iter().map(|leg| leg.from_coin.clone()).collect();
+    ensure_funds_match(&ctx.info.funds, &offered)?;
+    // Swap replies measure the output by balance difference, which an offered denom would skew
+    ensure!(
+        legs.iter().all(|leg| !offered
+            .iter()
+            .any(|coin| leg.to_asset == Asset::NativeToken(coin.denom.clone()))),
+        ContractError::Std(StdError::generic_err(
+            "A leg cannot swap into a denom offered by the batch"
+        ))
     );
-    let ExecuteContext { deps, .. } = ctx;
 
-    swap_router.get_raw_address(&deps.as_ref())?;
-    let previous_swap_router = SWAP_ROUTER.load(deps.storage)?;
+    let origin = swap_origin(&ctx);
+    let sender = AndrAddr::from_string(&origin);
+    let mut resp = Response::default().add_attributes(vec![
+        attr("action", "batch_swap_and_forward"),
+        attr("legs", legs.len().to_string()),
+    ]);
+    for (batch_leg, leg) in legs.into_iter().enumerate() {
+        ensure_not_expired(leg.deadline.as_ref(), &ctx.env.block)?;
+        let recipients = get_weighted_recipients(leg.recipient, None, sender.clone())?;
+        let from_asset = Asset::NativeToken(leg.from_coin.denom);
+        record_swap_volume(
+            ctx.deps.branch(),
+            &ctx.env,
+            &origin,
+            &from_asset,
+            leg.from_coin.amount,
+        )?;
+        let leg_ctx = ExecuteContext {
+            deps: ctx.deps.branch(),
+            info: ctx.info.clone(),
+            env: ctx.env.clone(),
+            amp_ctx: ctx.amp_ctx.clone(),
+        };
+        let (from_amount, input_fee) =
+            deduct_fee(leg_ctx.deps.storage, FeeSide::Input, leg.from_coin.amount)?;
+        let operations = resolve_astro_operations(
+            &leg_ctx.deps.as_ref(),
+            &from_asset,
+            &leg.to_asset,
+            leg.operations,
+        )?;
+        check_astro_price_impact(
+            &leg_ctx.deps.as_ref(),
+            from_amount,
+            &operations,
+            leg.max_price_impact,
+        )?;
+        // Failed legs are refunded by default so the remaining legs still go through
+        let swap_msg = execute_swap_astroport_msg(
+            leg_ctx,
+            AstroSwap {
+                from_asset,
+                from_amount,
+                input_fee,
+                to_asset: leg.to_asset,
+                operations,
+                recipients,
+                refund_addr: sender.clone(),
+                max_spread: leg.max_spread,
+                minimum_receive: leg.minimum_receive,
+                refund_on_failure: leg.refund_on_failure.unwrap_or(true),
+                exact_out_amount: None,
+                output_fee: Uint128::zero(),
+                referrer: None,
+                batch_leg: Some(batch_leg as u32),
+                consolidation_id: None,
+            },
+        )?;
+        resp = resp.add_submessage(swap_msg);
+    }
+    Ok(resp)
+}
 
-    SWAP_ROUTER.save(deps.storage, &swap_router)?;
-    Ok(Response::new().add_attributes(vec![
-        attr("action", "update-swap-router"),
-        attr("previous_swap_router", previous_swap_router),
-        attr("swap_router", swap_router),
-    ]))
+fn execute_consolidate_and_forward(
+    mut ctx: ExecuteContext,
+    to_asset: Asset,
+    recipient: Option<Recipient>,
+    cw20_inputs: Option<Vec<Cw20Input>>,
+    max_spread: Option<Decimal>,
+    minimum_receive: Option<Uint128>,
+    deadline: Option<Expiration>,
+) -> Result<Response, ContractError> {
+    ensure_not_expired(deadline.as_ref(), &ctx.env.block)?;
+    let origin = swap_origin(&ctx);
+    let sender = AndrAddr::from_string(&origin);
+    let recipients = get_weighted_recipients(recipient, None, sender.clone())?;
+
+    let mut inputs: Vec<(Asset, Uint128)> = ctx
+        .info
+        .funds
+        .iter()
+        .filter(|fund| !fund.amount.is_zero())
+        .map(|fund| (Asset::NativeToken(fund.denom.clone()), fund.amount))
+        .collect();
+    let mut pull_msgs = vec![];
+    for Cw20Input { token, amount } in cw20_inputs.unwrap_or_default() {
+        ensure!(
+            !amount.is_zero(),
+            ContractError::InvalidFunds {
+                msg: format!("Zero amount given for {}", token),
+            }
+        );
+        let transfer_from_msg = Cw20ExecuteMsg::TransferFrom {
+            owner: ctx.info.sender.to_string(),
+            recipient: ctx.env.contract.address.to_string(),
+            amount,
+        };
+        pull_msgs.push(SubMsg::new(wasm_execute(
+            token.get_raw_address(&ctx.deps.as_ref())?,
+            &transfer_from_msg,
+            vec![],
+        )?));
+        inputs.push((Asset::Cw20Token(token), amount));
+    }
+    ensure!(
+        !inputs.is_empty(),
+        ContractError::InvalidFunds {
+            msg: "No inputs to consolidate".to_string(),
+        }
+    );
+
+    let mut consolidation = Consolidation {
+        recipients,
+        amp_ctx: ctx.amp_ctx.clone().map(|pkt| pkt.ctx),
+        to_asset: to_asset.clone(),
+        minimum_receive,
+        pending_swaps: 0,
+        total_amount: Uint128::zero(),
+        received_amount: Uint128::zero(),
+        charge_fee: true,
+    };
+    let mut swap_inputs = vec![];
+    for (from_asset, from_amount) in inputs {
+        // Inputs already in the asked asset are forwarded as they are
+        if from_asset == to_asset {
+            consolidation.total_amount = consolidation.total_amount.checked_add(from_amount)?;
+            // Pulled CW20 tokens arrive after the swaps record their previous balance
+            if let Asset::Cw20Token(_) = from_asset {
+                consolidation.received_amount =
+                    consolidation.received_amount.checked_add(from_amount)?;
+            }
+            continue;
+        }
+        record_swap_volume(
+            ctx.deps.branch(),
+            &ctx.env,
+            &origin,
+            &from_asset,
+            from_amount,
+        )?;
+        swap_inputs.push((from_asset, from_amount));
+    }
+
+    let consolidate_resp = consolidate(ctx, consolidation, swap_inputs, sender, max_spread)?;
+    Ok(Response::default()
+        .add_submessages(pull_msgs)
+        .add_submessages(consolidate_resp.messages)
+        .add_attributes(consolidate_resp.attributes))
 }
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
-    match msg {
-        QueryMsg::SimulateSwapOperation {
-            offer_amount,
-            operations,
-        } => encode_binary(&query_simulate_swap_operation(
-            deps,
-            offer_amount,
-            operations,
-        )?),
+
+/// Swaps every input into the asked asset of `consolidation`,
+/// the combined amount is forwarded once the last swap replies.
+fn consolidate(
+    mut ctx: ExecuteContext,
+    mut consolidation: Consolidation,
+    inputs: Vec<(Asset, Uint128)>,
+    refund_addr: AndrAddr,
+    max_spread: Option<Decimal>,
+) -> Result<Response, ContractError> {
+    let consolidation_id = next_swap_id(ctx.deps.storage)?;
+    let to_asset = consolidation.to_asset.clone();
+    let mut swap_msgs = vec![];
+    for (from_asset, from_amount) in inputs {
+        let leg_ctx = ExecuteContext {
+            deps: ctx.deps.branch(),
+            info: ctx.info.clone(),
+            env: ctx.env.clone(),
+            amp_ctx: ctx.amp_ctx.clone(),
+        };
+        let operations =
+            resolve_astro_operations(&leg_ctx.deps.as_ref(), &from_asset, &to_asset, None)?;
+        let swap_msg = execute_swap_astroport_msg(
+            leg_ctx,
+            AstroSwap {
+                from_asset,
+                from_amount,
+                // The fee is taken once from the consolidated amount
+                input_fee: Uint128::zero(),
+                to_asset: to_asset.clone(),
+                operations,
+                recipients: consolidation.recipients.clone(),
+                refund_addr: refund_addr.clone(),
+                max_spread,
+                minimum_receive: None,
+                refund_on_failure: false,
+                exact_out_amount: None,
+                output_fee: Uint128::zero(),
+                referrer: None,
+                batch_leg: None,
+                consolidation_id: Some(consolidation_id),
+            },
+        )?;
+        swap_msgs.push(swap_msg);
+        consolidation.pending_swaps += 1;
+    }
+
+    let resp = Response::default()
+        .add_submessages(swap_msgs)
+        .add_attributes(vec![
+            attr("action", "consolidate"),
+            attr("consolidation_id", consolidation_id.to_string()),
+            attr("to_asset", to_asset.to_string()),
+            attr("pending_swaps", consolidation.pending_swaps.to_string()),
+        ]);
+    if consolidation.pending_swaps > 0 {
+        CONSOLIDATIONS.save(ctx.deps.storage, consolidation_id, &consolidation)?;
+        return Ok(resp);
     }
+
+    // Nothing to swap, every input is already in the asked asset
+    let forward_resp = forward_consolidation(ctx.deps, &ctx.env, consolidation_id, consolidation)?;
+    Ok(resp
+        .add_submessages(forward_resp.messages)
+        .add_attributes(forward_resp.attributes))
 }
 
-fn query_simulate_swap_operation(
-    deps: Deps,
-    offer_amount: Uint128,
-    swap_operation: Vec<SwapOperation>,
-) -> Result<SimulateSwapOperationResponse, ContractError> {
-    query_simulate_astro_swap_operation(deps, offer_amount, swap_operation)
+fn execute_convert_fees(
+    ctx: ExecuteContext,
+    assets: Option<Vec<Asset>>,
+    max_spread: Option<Decimal>,
+    minimum_receive: Option<Uint128>,
+) -> Result<Response, ContractError> {
+    ensure_has_role(ctx.deps.as_ref(), &ctx.info.sender, Role::Keeper)?;
+    let treasury_config = TREASURY_CONFIG
+        .may_load(ctx.deps.storage)?
+        .ok_or_else(|| ContractError::Std(StdError::generic_err("No treasury is set")))?;
+
+    let keys = match assets {
+        Some(assets) => assets
+            .iter()
+            .map(|asset| asset_key(&ctx.deps.as_ref(), asset))
+            .collect::<Result<Vec<String>, ContractError>>()?,
+        None => ACCRUED_FEES
+            .keys(ctx.deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<String>>>()?,
+    };
+
+    let treasury_key = asset_key(&ctx.deps.as_ref(), &treasury_config.asset)?;
+    let mut consolidation = Consolidation {
+        recipients: vec![WeightedRecipient::new(
+            Recipient::new(treasury_config.recipient.clone(), None),
+            Uint128::one(),
+        )],
+        amp_ctx: None,
+        to_asset: treasury_config.asset,
+        minimum_receive,
+        pending_swaps: 0,
+        total_amount: Uint128::zero(),
+        received_amount: Uint128::zero(),
+        charge_fee: false,
+    };
+    let mut inputs = vec![];
+    for key in keys {
+        let Some(amount) = ACCRUED_FEES.may_load(ctx.deps.storage, &key)? else {
+            continue;
+        };
+        // Converted fees are released before the swaps record their previous balance
+        ACCRUED_FEES.remove(ctx.deps.storage, &key);
+        if key == treasury_key {
+            consolidation.total_amount = consolidation.total_amount.checked_add(amount)?;
+        } else {
+            inputs.push((asset_from_key(&key)?, amount));
+        }
+    }
+    ensure!(
+        !inputs.is_empty() || !consolidation.total_amount.is_zero(),
+        ContractError::Std(StdError::generic_err("No accrued fees to convert"))
+    );
+
+    let consolidate_resp = consolidate(
+        ctx,
+        consolidation,
+        inputs,
+        treasury_config.recipient,
+        max_spread,
+    )?;
+    Ok(Response::default()
+        .add_attribute("action", "convert_fees")
+        .add_submessages(consolidate_resp.messages)
+        .add_attributes(consolidate_resp.attributes))
 }
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn migrate(deps: DepsMut, _env: Env, _msg: MigrateMsg) -> Result<Response, ContractError> {
-    ADOContract::default().migrate(deps, CONTRACT_NAME, CONTRACT_VERSION)
+fn execute_propose_swap_router(
+    ctx: ExecuteContext,
+    swap_router: AndrAddr,
+    code_pin: Option<CodePin>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, env, .. } = ctx;
+
+    let swap_router_addr = swap_router.get_raw_address(&deps.as_ref())?;
+    if let Some(code_pin) = &code_pin {
+        code_pin.verify(&deps.as_ref(), &swap_router_addr)?;
+    }
+    let timelock_seconds = load_swap_router_timelock(deps.storage)?;
+    // `plus_seconds` panics on overflow
+    let executable_at = timelock_seconds
+        .checked_mul(1_000_000_000)
+        .and_then(|nanos| env.block.time.nanos().checked_add(nanos))
+        .map(Timestamp::from_nanos)
+        .ok_or_else(|| {
+            ContractError::Std(StdError::generic_err(format!(
+                "A swap router timelock of {} seconds is out of range",
+                timelock_seconds
+            )))
+        })?;
+
+    // A new proposal replaces the pending one and restarts the timelock
+    SWAP_ROUTER_PROPOSAL.save(
+        deps.storage,
+        &SwapRouterProposal {
+            swap_router: swap_router.clone(),
+            code_pin: code_pin.clone(),
+            proposed_by: sender,
+            executable_at,
+        },
+    )?;
+    Ok(Response::new()
+        .add_attributes(vec![
+            attr("action", "propose-swap-router"),
+            attr("swap_router", swap_router),
+            attr("executable_at", executable_at.to_string()),
+        ])
+        .add_attributes(code_pin.map(|code_pin| attr("code_pin", code_pin.to_string()))))
 }
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
-    match msg.id {
-        ASTROPORT_MSG_SWAP_ID => {
-            let state: ForwardReplyState = FORWARD_REPLY_STATE.load(deps.storage)?;
-            FORWARD_REPLY_STATE.remove(deps.storage);
+fn execute_accept_swap_router(ctx: ExecuteContext) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, env, .. } = ctx;
 
-            if msg.result.is_err() {
-                Err(ContractError::Std(StdError::generic_err(format!(
-                    "Astroport swap failed with error: {:?}",
-                    msg.result.unwrap_err()
-                ))))
-            } else {
-                handle_astroport_swap_reply(deps, env, msg, state)
-            }
+    let Some(proposal) = SWAP_ROUTER_PROPOSAL.may_load(deps.storage)? else {
+        return Err(ContractError::Std(StdError::generic_err(
+            "No swap router is proposed",
+        )));
+    };
+    ensure!(
+        env.block.time >= proposal.executable_at,
+        ContractError::Std(StdError::generic_err(format!(
+            "The proposed swap router can be accepted from {}",
+            proposal.executable_at
+        )))
+    );
+    // The router may have been migrated to other code since it was proposed
+    let swap_router_addr = proposal.swap_router.get_raw_address(&deps.as_ref())?;
+    if let Some(code_pin) = &proposal.code_pin {
+        code_pin.verify(&deps.as_ref(), &swap_router_addr)?;
+    }
+    let previous_swap_router = SWAP_ROUTER.load(deps.storage)?;
+
+    SWAP_ROUTER.save(deps.storage, &proposal.swap_router)?;
+    match &proposal.code_pin {
+        Some(code_pin) => SWAP_ROUTER_PIN.save(deps.storage, code_pin)?,
+        None => SWAP_ROUTER_PIN.remove(deps.storage),
+    }
+    SWAP_ROUTER_PROPOSAL.remove(deps.storage);
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "accept-swap-router"),
+        attr("previous_swap_router", previous_swap_router),
+        attr("swap_router", proposal.swap_router),
+    ]))
+}
+
+fn execute_cancel_swap_router(ctx: ExecuteContext) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let Some(proposal) = SWAP_ROUTER_PROPOSAL.may_load(deps.storage)? else {
+        return Err(ContractError::Std(StdError::generic_err(
+            "No swap router is proposed",
+        )));
+    };
+    SWAP_ROUTER_PROPOSAL.remove(deps.storage);
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "cancel-swap-router"),
+        attr("swap_router", proposal.swap_router),
+    ]))
+}
+
+fn execute_set_swap_router_timelock(
+    ctx: ExecuteContext,
+    timelock_seconds: u64,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    // Lowering the timelock would let a single key skip it
+    let current_timelock = load_swap_router_timelock(deps.storage)?;
+    ensure!(
+        timelock_seconds >= current_timelock,
+        ContractError::Std(StdError::generic_err(format!(
+            "The swap router timelock can only be raised, it is {} seconds",
+            current_timelock
+        )))
+    );
+    validate_swap_router_timelock(timelock_seconds)?;
+
+    SWAP_ROUTER_TIMELOCK.save(deps.storage, &timelock_seconds)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-swap-router-timelock"),
+        attr("timelock_seconds", timelock_seconds.to_string()),
+    ]))
+}
+
+fn execute_pause(ctx: ExecuteContext, scope: PauseScope) -> Result<Response, ContractError> {
+    let ExecuteContext {
+        deps, info, env, ..
+    } = ctx;
+    ensure_has_role(deps.as_ref(), &info.sender, Role::Pauser)?;
+
+    let key = pause_key(&deps.as_ref(), &scope)?;
+    ensure!(
+        !PAUSES.has(deps.storage, &key),
+        ContractError::Std(StdError::generic_err(format!(
+            "{} are already paused",
+            scope
+        )))
+    );
+    PAUSES.save(
+        deps.storage,
+        &key,
+        &Pause {
+            scope: scope.clone(),
+            paused_by: info.sender.clone(),
+            paused_at: env.block.time,
+        },
+    )?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "pause"),
+        attr("scope", scope.to_string()),
+        attr("paused_by", info.sender),
+    ]))
+}
+
+fn execute_unpause(ctx: ExecuteContext, scope: PauseScope) -> Result<Response, ContractError> {
+    let ExecuteContext { deps, info, .. } = ctx;
+    ensure_has_role(deps.as_ref(), &info.sender, Role::Pauser)?;
+
+    let key = pause_key(&deps.as_ref(), &scope)?;
+    ensure!(
+        PAUSES.has(deps.storage, &key),
+        ContractError::Std(StdError::generic_err(format!("{} are not paused", scope)))
+    );
+    PAUSES.remove(deps.storage, &key);
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "unpause"),
+        attr("scope", scope.to_string()),
+        attr("unpaused_by", info.sender),
+    ]))
+}
+
+/// Owners and operators hold every role
+fn ensure_has_role(deps: Deps, sender: &Addr, role: Role) -> Result<(), ContractError> {
+    ensure!(
+        ROLES.has(deps.storage, (role.as_str(), sender))
+            || ADOContract::default().is_owner_or_operator(deps.storage, sender.as_str())?,
+        ContractError::Unauthorized {}
+    );
+    Ok(())
+}
+
+fn execute_grant_role(
+    ctx: ExecuteContext,
+    role: Role,
+    address: AndrAddr,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure!(
+        ADOContract::default().is_owner_or_operator(ctx.deps.storage, sender.as_ref())?,
+        ContractError::Unauthorized {}
+    );
+    let ExecuteContext { deps, .. } = ctx;
+
+    let address = address.get_raw_address(&deps.as_ref())?;
+    ROLES.save(deps.storage, (role.as_str(), &address), &Empty {})?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "grant-role"),
+        attr("role", role.to_string()),
+        attr("address", address),
+    ]))
+}
+
+fn execute_revoke_role(
+    ctx: ExecuteContext,
+    role: Role,
+    address: AndrAddr,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure!(
+        ADOContract::default().is_owner_or_operator(ctx.deps.storage, sender.as_ref())?,
+        ContractError::Unauthorized {}
+    );
+    let ExecuteContext { deps, .. } = ctx;
+
+    let address = address.get_raw_address(&deps.as_ref())?;
+    ensure!(
+        ROLES.has(deps.storage, (role.as_str(), &address)),
+        ContractError::Std(StdError::generic_err(format!(
+            "{} does not hold the {} role",
+            address, role
+        )))
+    );
+    ROLES.remove(deps.storage, (role.as_str(), &address));
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "revoke-role"),
+        attr("role", role.to_string()),
+        attr("address", address),
+    ]))
+}
+
+fn execute_set_fee_config(
+    ctx: ExecuteContext,
+    fee_config: Option<FeeConfig>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::FeeAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let Some(fee_config) = fee_config else {
+        FEE_CONFIG.remove(deps.storage);
+        return Ok(Response::new().add_attribute("action", "remove-fee-config"));
+    };
+    fee_config.validate(&deps.as_ref())?;
+
+    FEE_CONFIG.save(deps.storage, &fee_config)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-fee-config"),
+        attr("fee_bps", fee_config.fee_bps.to_string()),
+        attr("fee_recipient", fee_config.fee_recipient),
+    ]))
+}
+
+fn execute_set_treasury_config(
+    ctx: ExecuteContext,
+    treasury_config: Option<TreasuryConfig>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::FeeAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    // Fees accrued so far stay in the contract and can still be converted once a treasury is set
+    let Some(treasury_config) = treasury_config else {
+        TREASURY_CONFIG.remove(deps.storage);
+        return Ok(Response::new().add_attribute("action", "remove-treasury-config"));
+    };
+    asset_key(&deps.as_ref(), &treasury_config.asset)?;
+    treasury_config.recipient.get_raw_address(&deps.as_ref())?;
+
+    TREASURY_CONFIG.save(deps.storage, &treasury_config)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-treasury-config"),
+        attr("treasury_asset", treasury_config.asset.to_string()),
+        attr("treasury_recipient", treasury_config.recipient),
+    ]))
+}
+
+fn execute_set_slippage_policy(
+    ctx: ExecuteContext,
+    slippage_policy: Option<SlippagePolicy>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let Some(slippage_policy) = slippage_policy else {
+        SLIPPAGE_POLICY.remove(deps.storage);
+        return Ok(Response::new().add_attribute("action", "remove-slippage-policy"));
+    };
+    slippage_policy.validate()?;
+
+    SLIPPAGE_POLICY.save(deps.storage, &slippage_policy)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-slippage-policy"),
+        attr(
+            "default_slippage",
+            slippage_policy.default_slippage.to_string(),
+        ),
+        attr("max_slippage", slippage_policy.max_slippage.to_string()),
+    ]))
+}
+
+fn execute_set_pair_slippage_policy(
+    ctx: ExecuteContext,
+    from_asset: Asset,
+    to_asset: Asset,
+    slippage_policy: Option<SlippagePolicy>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let pair_key = (
+        asset_key(&deps.as_ref(), &from_asset)?,
+        asset_key(&deps.as_ref(), &to_asset)?,
+    );
+    let action = match slippage_policy {
+        Some(slippage_policy) => {
+            slippage_policy.validate()?;
+            PAIR_SLIPPAGE_POLICIES.save(deps.storage, pair_key, &slippage_policy)?;
+            "set-pair-slippage-policy"
+        }
+        None => {
+            PAIR_SLIPPAGE_POLICIES.remove(deps.storage, pair_key);
+            "remove-pair-slippage-policy"
+        }
+    };
+    Ok(Response::new().add_attributes(vec![
+        attr("action", action),
+        attr("from_asset", from_asset.to_string()),
+        attr("to_asset", to_asset.to_string()),
+    ]))
+}
+
+fn execute_set_oracle_guard(
+    ctx: ExecuteContext,
+    oracle_guard: Option<OracleGuard>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let Some(oracle_guard) = oracle_guard else {
+        ORACLE_GUARD.remove(deps.storage);
+        return Ok(Response::new().add_attribute("action", "remove-oracle-guard"));
+    };
+    oracle_guard.validate()?;
+
+    ORACLE_GUARD.save(deps.storage, &oracle_guard)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-oracle-guard"),
+        attr("max_deviation", oracle_guard.max_deviation.to_string()),
+    ]))
+}
+
+fn execute_set_oracle_pair(
+    ctx: ExecuteContext,
+    asset: Asset,
+    oracle_pair: Option<OraclePair>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let key = asset_key(&deps.as_ref(), &asset)?;
+    let Some(oracle_pair) = oracle_pair else {
+        ORACLE_PAIRS.remove(deps.storage, &key);
+        return Ok(Response::new().add_attributes(vec![
+            attr("action", "remove-oracle-pair"),
+            attr("asset", asset.to_string()),
+        ]));
+    };
+    oracle_pair.validate()?;
+
+    ORACLE_PAIRS.save(deps.storage, &key, &oracle_pair)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-oracle-pair"),
+        attr("asset", asset.to_string()),
+        attr("currency_pair", oracle_pair.currency_pair.to_string()),
+    ]))
+}
+
+fn execute_update_asset_list(
+    ctx: ExecuteContext,
+    list: AssetList,
+    add: Vec<Asset>,
+    remove: Vec<Asset>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let asset_list = match list {
+        AssetList::Allow => ALLOWED_ASSETS,
+        AssetList::Deny => DENIED_ASSETS,
+    };
+    for asset in add.iter() {
+        let key = asset_key(&deps.as_ref(), asset)?;
+        asset_list.save(deps.storage, &key, &Empty {})?;
+    }
+    for asset in remove.iter() {
+        let key = asset_key(&deps.as_ref(), asset)?;
+        asset_list.remove(deps.storage, &key);
+    }
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "update-asset-list"),
+        attr("list", list.to_string()),
+        attr("added", add.len().to_string()),
+        attr("removed", remove.len().to_string()),
+    ]))
+}
+
+fn execute_update_cw20_code_ids(
+    ctx: ExecuteContext,
+    add: Vec<u64>,
+    remove: Vec<u64>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    for code_id in add.iter() {
+        CW20_CODE_IDS.save(deps.storage, *code_id, &Empty {})?;
+    }
+    for code_id in remove.iter() {
+        CW20_CODE_IDS.remove(deps.storage, *code_id);
+    }
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "update-cw20-code-ids"),
+        attr("added", add.len().to_string()),
+        attr("removed", remove.len().to_string()),
+    ]))
+}
+
+fn execute_set_volume_limit(
+    ctx: ExecuteContext,
+    asset: Asset,
+    volume_limit: Option<VolumeLimit>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let key = asset_key(&deps.as_ref(), &asset)?;
+    let Some(volume_limit) = volume_limit else {
+        VOLUME_LIMITS.remove(deps.storage, &key);
+        return Ok(Response::new().add_attributes(vec![
+            attr("action", "remove-volume-limit"),
+            attr("asset", asset.to_string()),
+        ]));
+    };
+    volume_limit.validate()?;
+
+    VOLUME_LIMITS.save(deps.storage, &key, &volume_limit)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-volume-limit"),
+        attr("asset", asset.to_string()),
+        attr("window_seconds", volume_limit.window_seconds.to_string()),
+    ]))
+}
+
+fn execute_set_referral_share(
+    ctx: ExecuteContext,
+    referrer: AndrAddr,
+    share_bps: Option<u16>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::FeeAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let referrer = referrer.get_raw_address(&deps.as_ref())?;
+    let Some(share_bps) = share_bps else {
+        REFERRAL_SHARES.remove(deps.storage, &referrer);
+        return Ok(Response::new().add_attributes(vec![
+            attr("action", "remove-referral-share"),
+            attr("referrer", referrer),
+        ]));
+    };
+    validate_referral_share(share_bps)?;
+
+    REFERRAL_SHARES.save(deps.storage, &referrer, &share_bps)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-referral-share"),
+        attr("referrer", referrer),
+        attr("share_bps", share_bps.to_string()),
+    ]))
+}
+
+fn execute_update_default_referral_share(
+    ctx: ExecuteContext,
+    share_bps: u16,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::FeeAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    validate_referral_share(share_bps)?;
+
+    DEFAULT_REFERRAL_SHARE.save(deps.storage, &share_bps)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "update-default-referral-share"),
+        attr("share_bps", share_bps.to_string()),
+    ]))
+}
+
+fn execute_update_max_hops(ctx: ExecuteContext, max_hops: u32) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    ensure!(
+        max_hops > 0,
+        ContractError::Std(StdError::generic_err("Max hops must be at least 1"))
+    );
+    let previous_max_hops = load_max_hops(deps.storage)?;
+
+    MAX_HOPS.save(deps.storage, &max_hops)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "update-max-hops"),
+        attr("previous_max_hops", previous_max_hops.to_string()),
+        attr("max_hops", max_hops.to_string()),
+    ]))
+}
+
+fn execute_set_route(
+    ctx: ExecuteContext,
+    from_asset: Asset,
+    to_asset: Asset,
+    operations: Vec<SwapOperation>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    ensure!(from_asset != to_asset, ContractError::DuplicateTokens {});
+    validate_astro_operations(&deps.as_ref(), &from_asset, &to_asset, &operations)?;
+
+    let route_key = (
+        asset_key(&deps.as_ref(), &from_asset)?,
+        asset_key(&deps.as_ref(), &to_asset)?,
+    );
+    ROUTES.save(deps.storage, route_key, &operations)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-route"),
+        attr("from_asset", from_asset.to_string()),
+        attr("to_asset", to_asset.to_string()),
+        attr("hops", operations.len().to_string()),
+    ]))
+}
+
+fn execute_remove_route(
+    ctx: ExecuteContext,
+    from_asset: Asset,
+    to_asset: Asset,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let route_key = (
+        asset_key(&deps.as_ref(), &from_asset)?,
+        asset_key(&deps.as_ref(), &to_asset)?,
+    );
+    ensure!(
+        ROUTES.has(deps.storage, route_key.clone()),
+        ContractError::Std(StdError::generic_err(format!(
+            "No route registered from {} to {}",
+            from_asset, to_asset
+        )))
+    );
+    ROUTES.remove(deps.storage, route_key);
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "remove-route"),
+        attr("from_asset", from_asset.to_string()),
+        attr("to_asset", to_asset.to_string()),
+    ]))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
+    match msg {
+        QueryMsg::SimulateSwapOperation {
+            offer_amount,
+            operations,
+        } => encode_binary(&query_simulate_swap_operation(
+            deps,
+            offer_amount,
+            operations,
+        )?),
+        QueryMsg::SimulatePriceImpact {
+            from_asset,
+            to_asset,
+            amount,
+            operations,
+        } => {
+            let operations = resolve_astro_operations(&deps, &from_asset, &to_asset, operations)?;
+            encode_binary(&simulate_astro_price_impact(&deps, amount, &operations)?)
+        }
+        QueryMsg::PauseStatus { start_after, limit } => {
+            encode_binary(&query_pause_status(deps, start_after, limit)?)
         }
+        QueryMsg::Roles {
+            role,
+            start_after,
+            limit,
+        } => encode_binary(&query_roles(deps, role, start_after, limit)?),
+        QueryMsg::SwapRouter {} => encode_binary(&SwapRouterResponse {
+            swap_router: SWAP_ROUTER.load(deps.storage)?,
+            code_pin: SWAP_ROUTER_PIN.may_load(deps.storage)?,
+            timelock_seconds: load_swap_router_timelock(deps.storage)?,
+            proposal: SWAP_ROUTER_PROPOSAL.may_load(deps.storage)?,
+        }),
+        QueryMsg::FeeConfig {} => encode_binary(&FeeConfigResponse {
+            fee_config: FEE_CONFIG.may_load(deps.storage)?,
+        }),
+        QueryMsg::TreasuryConfig {} => encode_binary(&TreasuryConfigResponse {
+            treasury_config: TREASURY_CONFIG.may_load(deps.storage)?,
+        }),
+        QueryMsg::AccruedFees { start_after, limit } => {
+            encode_binary(&query_accrued_fees(deps, start_after, limit)?)
+        }
+        QueryMsg::SlippagePolicy {} => encode_binary(&SlippagePolicyResponse {
+            slippage_policy: SLIPPAGE_POLICY.may_load(deps.storage)?,
+        }),
+        QueryMsg::PairSlippagePolicy {
+            from_asset,
+            to_asset,
+        } => encode_binary(&SlippagePolicyResponse {
+            slippage_policy: load_slippage_policy(
+                deps.storage,
+                (asset_key(&deps, &from_asset)?, asset_key(&deps, &to_asset)?),
+            )?,
+        }),
+        QueryMsg::OracleGuard {} => encode_binary(&OracleGuardResponse {
+            oracle_guard: ORACLE_GUARD.may_load(deps.storage)?,
+        }),
+        QueryMsg::OraclePair { asset } => encode_binary(&OraclePairResponse {
+            oracle_pair: ORACLE_PAIRS.may_load(deps.storage, &asset_key(&deps, &asset)?)?,
+        }),
+        QueryMsg::AssetList {
+            list,
+            start_after,
+            limit,
+        } => encode_binary(&query_asset_list(deps, list, start_after, limit)?),
+        QueryMsg::Cw20CodeIds { start_after, limit } => {
+            encode_binary(&query_cw20_code_ids(deps, start_after, limit)?)
+        }
+        QueryMsg::VolumeCapacity { address, asset } => {
+            encode_binary(&query_volume_capacity(deps, env, address, asset)?)
+        }
+        QueryMsg::ReferralShare { referrer } => encode_binary(&ReferralShareResponse {
+            share_bps: load_referral_share(deps.storage, &referrer.get_raw_address(&deps)?)?,
+        }),
+        QueryMsg::ReferralEarnings {
+            referrer,
+            start_after,
+            limit,
+        } => encode_binary(&query_referral_earnings(
+            deps,
+            referrer,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::MaxHops {} => encode_binary(&MaxHopsResponse {
+            max_hops: load_max_hops(deps.storage)?,
+        }),
+        QueryMsg::Routes { start_after, limit } => {
+            encode_binary(&query_routes(deps, start_after, limit)?)
+        }
+        QueryMsg::FindRoute {
+            from_asset,
+            to_asset,
+            amount,
+        } => encode_binary(&find_astro_route(&deps, &from_asset, &to_asset, amount)?),
+    }
+}
+
+fn query_simulate_swap_operation(
+    deps: Deps,
+    offer_amount: Uint128,
+    swap_operation: Vec<SwapOperation>,
+) -> Result<SimulateSwapOperationResponse, ContractError> {
+    query_simulate_astro_swap_operation(deps, offer_amount, &swap_operation)
+}
+
+fn query_routes(
+    deps: Deps,
+    start_after: Option<(Asset, Asset)>,
+    limit: Option<u32>,
+) -> Result<RoutesResponse, ContractError> {
+    let start = start_after
+        .map(|(from_asset, to_asset)| -> Result<_, ContractError> {
+            Ok((asset_key(&deps, &from_asset)?, asset_key(&deps, &to_asset)?))
+        })
+        .transpose()?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let routes = ROUTES
+        .range(
+            deps.storage,
+            start.map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| {
+            // Registered routes always start with the offered asset and end with the asked one
+            let (_, operations) = item?;
+            Ok(RouteInfo {
+                from_asset: operations[0].offer_asset_info.clone(),
+                to_asset: operations[operations.len() - 1].ask_asset_info.clone(),
+                operations,
+            })
+        })
+        .collect::<Result<Vec<RouteInfo>, ContractError>>()?;
+    Ok(RoutesResponse { routes })
+}
+
+fn query_pause_status(
+    deps: Deps,
+    start_after: Option<PauseScope>,
+    limit: Option<u32>,
+) -> Result<PauseStatusResponse, ContractError> {
+    let start = start_after
+        .map(|scope| pause_key(&deps, &scope))
+        .transpose()?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let pauses = PAUSES
+        .range(
+            deps.storage,
+            start.as_deref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| item.map(|(_, pause)| pause))
+        .collect::<StdResult<Vec<Pause>>>()?;
+    Ok(PauseStatusResponse { pauses })
+}
+
+fn query_roles(
+    deps: Deps,
+    role: Role,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> Result<RolesResponse, ContractError> {
+    let start = start_after
+        .map(|address| deps.api.addr_validate(&address))
+        .transpose()?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let addresses = ROLES
+        .prefix(role.as_str())
+        .keys(
+            deps.storage,
+            start.as_ref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .collect::<StdResult<Vec<Addr>>>()?;
+    Ok(RolesResponse { role, addresses })
+}
+
+fn query_asset_list(
+    deps: Deps,
+    list: AssetList,
+    start_after: Option<Asset>,
+    limit: Option<u32>,
+) -> Result<AssetListResponse, ContractError> {
+    let start = start_after
+        .map(|asset| asset_key(&deps, &asset))
+        .transpose()?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let asset_list = match list {
+        AssetList::Allow => ALLOWED_ASSETS,
+        AssetList::Deny => DENIED_ASSETS,
+    };
+    let assets = asset_list
+        .keys(
+            deps.storage,
+            start.as_deref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|key| asset_from_key(&key?))
+        .collect::<Result<Vec<Asset>, ContractError>>()?;
+    Ok(AssetListResponse { assets })
+}
+
+fn query_cw20_code_ids(
+    deps: Deps,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Cw20CodeIdsResponse, ContractError> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let code_ids = CW20_CODE_IDS
+        .keys(
+            deps.storage,
+            start_after.map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .collect::<StdResult<Vec<u64>>>()?;
+    Ok(Cw20CodeIdsResponse { code_ids })
+}
+
+fn query_volume_capacity(
+    deps: Deps,
+    env: Env,
+    address: AndrAddr,
+    asset: Asset,
+) -> Result<VolumeCapacityResponse, ContractError> {
+    let key = asset_key(&deps, &asset)?;
+    let address = address.get_raw_address(&deps)?;
+    let Some(volume_limit) = VOLUME_LIMITS.may_load(deps.storage, &key)? else {
+        return Ok(VolumeCapacityResponse {
+            volume_limit: None,
+            sender_remaining: None,
+            asset_remaining: None,
+        });
+    };
+
+    let since = volume_limit.window_start(env.block.time.seconds());
+    let sender_remaining = volume_limit
+        .max_sender_volume
+        .map(|max_volume| -> StdResult<Uint128> {
+            Ok(max_volume.saturating_sub(sender_volume(deps.storage, &address, &key, since)?))
+        })
+        .transpose()?;
+    let asset_remaining = volume_limit
+        .max_asset_volume
+        .map(|max_volume| -> StdResult<Uint128> {
+            Ok(max_volume.saturating_sub(asset_volume(deps.storage, &key, since)?))
+        })
+        .transpose()?;
+    Ok(VolumeCapacityResponse {
+        volume_limit: Some(volume_limit),
+        sender_remaining,
+        asset_remaining,
+    })
+}
+
+fn query_accrued_fees(
+    deps: Deps,
+    start_after: Option<Asset>,
+    limit: Option<u32>,
+) -> Result<AccruedFeesResponse, ContractError> {
+    let start = start_after
+        .map(|asset| asset_key(&deps, &asset))
+        .transpose()?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let fees = ACCRUED_FEES
+        .range(
+            deps.storage,
+            start.as_deref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| {
+            let (key, amount) = item?;
+            Ok(AccruedFee {
+                asset: asset_from_key(&key)?,
+                amount,
+            })
+        })
+        .collect::<Result<Vec<AccruedFee>, ContractError>>()?;
+    Ok(AccruedFeesResponse { fees })
+}
+
+fn query_referral_earnings(
+    deps: Deps,
+    referrer: AndrAddr,
+    start_after: Option<Asset>,
+    limit: Option<u32>,
+) -> Result<ReferralEarningsResponse, ContractError> {
+    let referrer = referrer.get_raw_address(&deps)?;
+    let start = start_after
+        .map(|asset| asset_key(&deps, &asset))
+        .transpose()?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let earnings = REFERRAL_EARNINGS
+        .prefix(&referrer)
+        .range(
+            deps.storage,
+            start.as_deref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| {
+            let (key, amount) = item?;
+            Ok(ReferralEarning {
+                asset: asset_from_key(&key)?,
+                amount,
+            })
+        })
+        .collect::<Result<Vec<ReferralEarning>, ContractError>>()?;
+    Ok(ReferralEarningsResponse { earnings })
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(deps: DepsMut, _env: Env, _msg: MigrateMsg) -> Result<Response, ContractError> {
+    // Swap state lives in maps keyed by swap id now, the old single items are left over at most
+    for key in LEGACY_SWAP_STATE_KEYS {
+        deps.storage.remove(key);
+    }
+    ADOContract::default().migrate(deps, CONTRACT_NAME, CONTRACT_VERSION)
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    match msg.id {
         ASTROPORT_MSG_FORWARD_ID => {
             if msg.result.is_err() {
                 return Err(ContractError::Std(StdError::generic_err(format!(
@@ -292,6 +1878,26 @@ pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractEr
             Ok(Response::default()
                 .add_attributes(vec![attr("action", "message_forwarded_success")]))
         }
+        id if id >= ASTROPORT_MSG_SWAP_ID_OFFSET => {
+            let swap_id = id - ASTROPORT_MSG_SWAP_ID_OFFSET;
+            let state: ForwardReplyState = FORWARD_REPLY_STATE.load(deps.storage, swap_id)?;
+            FORWARD_REPLY_STATE.remove(deps.storage, swap_id);
+
+            if msg.result.is_err() {
+                let error = msg.result.unwrap_err();
+                if state.refund_on_failure {
+                    return handle_astroport_swap_failure(deps, env, error, swap_id, state);
+                }
+                Err(ContractError::Std(StdError::generic_err(format!(
+                    "Astroport swap failed with error: {:?}",
+                    error
+                ))))
+            } else if let Some(consolidation_id) = state.consolidation_id {
+                handle_astroport_consolidation_reply(deps, env, swap_id, consolidation_id, state)
+            } else {
+                handle_astroport_swap_reply(deps, env, msg, swap_id, state)
+            }
+        }
         _ => Err(ContractError::Std(StdError::GenericErr {
             msg: "Invalid Reply ID".to_string(),
         })),
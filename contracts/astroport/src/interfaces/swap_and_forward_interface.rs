@@ -2,13 +2,18 @@ use std::str::FromStr;
 
 use crate::contract::{execute, instantiate, migrate, query};
 use andromeda_std::common::denom::Asset;
-use andromeda_std::{ado_base::MigrateMsg, amp::Recipient};
-use andromeda_swap_and_forward::astroport::{
-    Cw20HookMsg, ExecuteMsg, InstantiateMsg, QueryMsg, SwapOperation,
+use andromeda_std::{
+    ado_base::MigrateMsg,
+    amp::{AndrAddr, Recipient},
+};
+use andromeda_swap_and_forward::{
+    astroport::{Cw20HookMsg, ExecuteMsg, InstantiateMsg, QueryMsg, SwapOperation},
+    common::WeightedRecipient,
 };
 use cosmrs::cosmwasm::MsgExecuteContract;
 use cosmrs::AccountId;
 use cosmwasm_std::{to_json_binary, Decimal, Uint128};
+use cw20::Expiration;
 use cw_orch::{interface, prelude::*};
 use cw_orch_daemon::{Daemon, DaemonBase, TxSender, Wallet};
 
@@ -36,16 +41,28 @@ impl SwapAndForwardContract<DaemonBase<Wallet>> {
         from_amount: Uint128,
         to_asset: Asset,
         recipient: Option<Recipient>,
+        recipients: Option<Vec<WeightedRecipient>>,
         max_spread: Option<Decimal>,
         minimum_receive: Option<Uint128>,
+        minimum_receive_tolerance: Option<Decimal>,
+        max_price_impact: Option<Decimal>,
         operations: Option<Vec<SwapOperation>>,
+        referrer: Option<AndrAddr>,
+        refund_on_failure: Option<bool>,
+        deadline: Option<Expiration>,
     ) {
         let hook_msg = Cw20HookMsg::SwapAndForward {
             to_asset,
             recipient,
+            recipients,
             max_spread,
             minimum_receive,
+            minimum_receive_tolerance,
+            max_price_impact,
             operations,
+            referrer,
+            refund_on_failure,
+            deadline,
         };
         let cw_20_transfer_msg = cw20::Cw20ExecuteMsg::Send {
             contract: self.addr_str().unwrap(),
@@ -1,5 +1,6 @@
 pub mod astroport;
 pub mod contract;
+pub mod oracle;
 pub mod state;
 
 #[cfg(test)]
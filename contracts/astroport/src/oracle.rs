@@ -0,0 +1,199 @@
+use std::cmp::Ordering;
+
+use andromeda_std::{common::denom::Asset, error::ContractError};
+use cosmwasm_std::{ensure, Binary, Decimal, Deps, Env, QueryRequest, StdError, Uint128, Uint64};
+use prost::Message;
+use serde::Deserialize;
+
+use crate::{
+    astroport::query_simulate_astro_swap_operation,
+    state::{asset_key, ORACLE_GUARD, ORACLE_PAIRS},
+};
+
+use andromeda_swap_and_forward::astroport::{
+    CurrencyPair, OracleGuardAction, OraclePair, SwapOperation,
+};
+
+/// Stargate path of the Slinky oracle price query
+pub const SLINKY_GET_PRICE_PATH: &str = "/slinky.oracle.v1.Query/GetPrice";
+
+/// `slinky.oracle.v1.GetPriceRequest`
+#[derive(Clone, PartialEq, Message)]
+struct GetPriceRequest {
+    #[prost(message, optional, tag = "1")]
+    currency_pair: Option<ProtoCurrencyPair>,
+}
+
+/// `slinky.types.v1.CurrencyPair`
+#[derive(Clone, PartialEq, Message)]
+struct ProtoCurrencyPair {
+    #[prost(string, tag = "1")]
+    base: String,
+    #[prost(string, tag = "2")]
+    quote: String,
+}
+
+/// Whitelisted stargate queries answer in JSON, so only the request is protobuf encoded
+#[derive(Deserialize)]
+struct GetPriceResponse {
+    price: Option<QuotePrice>,
+    decimals: Uint64,
+}
+
+#[derive(Deserialize)]
+struct QuotePrice {
+    price: Uint128,
+    block_height: Option<Uint64>,
+}
+
+/// Checks a swap of `from_amount` of `from_asset` through `operations` against the oracle
+/// prices of both assets. Depending on the oracle guard action, a swap falling short of the
+/// oracle floor is rejected, or `minimum_receive` is raised to the floor.
+/// Swaps are left alone while the guard is off or either asset has no oracle pair.
+pub(crate) fn apply_oracle_guard(
+    deps: &Deps,
+    env: &Env,
+    from_asset: &Asset,
+    from_amount: Uint128,
+    to_asset: &Asset,
+    operations: &[SwapOperation],
+    minimum_receive: Option<Uint128>,
+) -> Result<Option<Uint128>, ContractError> {
+    let Some(oracle_guard) = ORACLE_GUARD.may_load(deps.storage)? else {
+        return Ok(minimum_receive);
+    };
+    let (Some(from_pair), Some(to_pair)) = (
+        ORACLE_PAIRS.may_load(deps.storage, &asset_key(deps, from_asset)?)?,
+        ORACLE_PAIRS.may_load(deps.storage, &asset_key(deps, to_asset)?)?,
+    ) else {
+        return Ok(minimum_receive);
+    };
+
+    let oracle_amount = oracle_output(
+        deps,
+        env,
+        oracle_guard.max_price_age,
+        from_amount,
+        &from_pair,
+        &to_pair,
+    )?;
+    let oracle_floor = oracle_amount * (Decimal::one() - oracle_guard.max_deviation);
+    match oracle_guard.action {
+        OracleGuardAction::Reject => {
+            let simulated_amount =
+                query_simulate_astro_swap_operation(*deps, from_amount, operations)?.amount;
+            ensure!(
+                simulated_amount >= oracle_floor,
+                ContractError::Std(StdError::generic_err(format!(
+                    "Swap returns {} {}, below the oracle floor of {}",
+                    simulated_amount, to_asset, oracle_floor
+                )))
+            );
+            Ok(minimum_receive)
+        }
+        OracleGuardAction::RaiseMinimumReceive => {
+            Ok(Some(minimum_receive.unwrap_or_default().max(oracle_floor)))
+        }
+    }
+}
+
+/// The amount of the `to_pair` asset worth `from_amount` of the `from_pair` asset at oracle prices
+fn oracle_output(
+    deps: &Deps,
+    env: &Env,
+    max_price_age: Option<u64>,
+    from_amount: Uint128,
+    from_pair: &OraclePair,
+    to_pair: &OraclePair,
+) -> Result<Uint128, ContractError> {
+    ensure!(
+        from_pair.currency_pair.quote == to_pair.currency_pair.quote,
+        ContractError::Std(StdError::generic_err(format!(
+            "Oracle pairs {} and {} are quoted in different currencies",
+            from_pair.currency_pair, to_pair.currency_pair
+        )))
+    );
+    let from_price = query_oracle_price(deps, env, max_price_age, &from_pair.currency_pair)?;
+    let to_price = query_oracle_price(deps, env, max_price_age, &to_pair.currency_pair)?;
+
+    let amount = from_amount
+        .checked_multiply_ratio(from_price.atomics(), to_price.atomics())
+        .map_err(|_| {
+            ContractError::Std(StdError::generic_err(format!(
+                "Oracle output of {} {} overflows",
+                from_amount, from_pair.currency_pair
+            )))
+        })?;
+    // Oracle prices are per whole token, raw amounts carry each asset's decimals
+    let amount = match to_pair.decimals.cmp(&from_pair.decimals) {
+        Ordering::Greater => {
+            amount.checked_mul(decimals_scale(to_pair.decimals - from_pair.decimals)?)?
+        }
+        Ordering::Less => amount
+            .checked_div(decimals_scale(from_pair.decimals - to_pair.decimals)?)
+            .map_err(StdError::from)?,
+        Ordering::Equal => amount,
+    };
+    Ok(amount)
+}
+
+/// `10^decimals` as a `Uint128`
+fn decimals_scale(decimals: u8) -> Result<Uint128, ContractError> {
+    10u128
+        .checked_pow(decimals.into())
+        .map(Uint128::new)
+        .ok_or_else(|| {
+            ContractError::Std(StdError::generic_err(format!(
+                "Decimals difference of {} is out of range",
+                decimals
+            )))
+        })
+}
+
+/// Queries the Slinky oracle for the price of `currency_pair`, rejecting prices last updated
+/// more than `max_price_age` blocks ago
+pub fn query_oracle_price(
+    deps: &Deps,
+    env: &Env,
+    max_price_age: Option<u64>,
+    currency_pair: &CurrencyPair,
+) -> Result<Decimal, ContractError> {
+    let res: GetPriceResponse = deps.querier.query(&QueryRequest::Stargate {
+        path: SLINKY_GET_PRICE_PATH.to_string(),
+        data: encode_get_price_request(currency_pair),
+    })?;
+    let Some(quote_price) = res.price.filter(|quote_price| !quote_price.price.is_zero()) else {
+        return Err(ContractError::Std(StdError::generic_err(format!(
+            "Oracle has no price for {}",
+            currency_pair
+        ))));
+    };
+    if let Some(max_price_age) = max_price_age {
+        let updated_at = quote_price.block_height.unwrap_or_default().u64();
+        ensure!(
+            env.block.height.saturating_sub(updated_at) <= max_price_age,
+            ContractError::Std(StdError::generic_err(format!(
+                "Oracle price of {} was last updated at height {}",
+                currency_pair, updated_at
+            )))
+        );
+    }
+    Decimal::from_atomics(quote_price.price, res.decimals.u64() as u32).map_err(|_| {
+        ContractError::Std(StdError::generic_err(format!(
+            "Oracle price of {} is out of range",
+            currency_pair
+        )))
+    })
+}
+
+/// Protobuf encodes `GetPriceRequest { currency_pair: CurrencyPair { base, quote } }`
+pub(crate) fn encode_get_price_request(currency_pair: &CurrencyPair) -> Binary {
+    GetPriceRequest {
+        currency_pair: Some(ProtoCurrencyPair {
+            base: currency_pair.base.clone(),
+            quote: currency_pair.quote.clone(),
+        }),
+    }
+    .encode_to_vec()
+    .into()
+}
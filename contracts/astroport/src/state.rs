@@ -1,28 +1,269 @@
 use andromeda_std::{
-    amp::{messages::AMPCtx, AndrAddr, Recipient},
+    amp::{messages::AMPCtx, AndrAddr},
     common::denom::Asset,
+    error::ContractError,
 };
-use cosmwasm_std::Uint128;
-use cw_storage_plus::Item;
+use andromeda_swap_and_forward::{
+    astroport::{OracleGuard, OraclePair, Pause, PauseScope, SwapOperation, TreasuryConfig},
+    common::{
+        CodePin, FeeConfig, SlippagePolicy, SwapRouterProposal, VolumeLimit, WeightedRecipient,
+        DEFAULT_SWAP_ROUTER_TIMELOCK,
+    },
+};
+use cosmwasm_std::{Addr, Deps, Empty, Order, StdError, StdResult, Storage, Uint128};
+use cw_storage_plus::{Bound, Item, Map};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
 pub struct ForwardReplyState {
-    /// Forward Recipient
-    pub recipient: Recipient,
+    /// Forward recipients, the swapped token is split between them by weight
+    pub recipients: Vec<WeightedRecipient>,
     /// Refund Address
     pub refund_addr: AndrAddr,
     /// Amp ctx to be used for ibc communication
     pub amp_ctx: Option<AMPCtx>,
     /// Offered asset to the astroport
     pub from_asset: Asset,
+    /// Offered amount to the astroport
+    pub from_amount: Uint128,
     /// Asked asset returning from the astroport
     pub to_asset: Asset,
+    /// Whether the offered asset is refunded when the swap fails
+    pub refund_on_failure: bool,
+    /// Exact amount of the asked asset to forward for exact-output swaps, any surplus is refunded
+    pub exact_out_amount: Option<Uint128>,
+    /// Input side fee held back from the offered amount, paid out once the swap succeeds
+    pub input_fee: Uint128,
+    /// Output side fee asked from an exact-output swap on top of `exact_out_amount`
+    pub output_fee: Uint128,
+    /// Referrer paid a share of the protocol fee
+    pub referrer: Option<Addr>,
+    /// Index of the leg within a `BatchSwapAndForward`
+    pub batch_leg: Option<u32>,
+    /// Consolidation the swap belongs to, its output is forwarded together with the other inputs
+    pub consolidation_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Consolidation {
+    /// Forward recipients of the combined amount
+    pub recipients: Vec<WeightedRecipient>,
+    /// Amp ctx to be used for ibc communication
+    pub amp_ctx: Option<AMPCtx>,
+    /// Asked asset every input is swapped into
+    pub to_asset: Asset,
+    /// Minimum combined amount to forward
+    pub minimum_receive: Option<Uint128>,
+    /// Number of swaps still waiting for their reply
+    pub pending_swaps: u32,
+    /// Combined amount collected so far
+    pub total_amount: Uint128,
+    /// Amount received after the swaps were queued, which the remaining swap replies must discount
+    pub received_amount: Uint128,
+    /// Whether the protocol fee is taken from the combined amount
+    pub charge_fee: bool,
 }
 
-pub const FORWARD_REPLY_STATE: Item<ForwardReplyState> = Item::new("forward_reply_state");
+/// Pending swaps keyed by swap id
+pub const FORWARD_REPLY_STATE: Map<u64, ForwardReplyState> = Map::new("forward_reply_states");
+
+/// Pending consolidations keyed by consolidation id
+pub const CONSOLIDATIONS: Map<u64, Consolidation> = Map::new("consolidations");
+
+/// Registered routes keyed by the offered and asked asset keys
+pub const ROUTES: Map<(String, String), Vec<SwapOperation>> = Map::new("routes");
+
+/// Protocol fee, no fee is taken while unset
+pub const FEE_CONFIG: Item<FeeConfig> = Item::new("fee_config");
+
+/// Treasury fees accrue for, fees are paid straight to the fee recipient while unset
+pub const TREASURY_CONFIG: Item<TreasuryConfig> = Item::new("treasury_config");
+
+/// Fees accrued in the contract and not converted yet, keyed by asset key
+pub const ACCRUED_FEES: Map<&str, Uint128> = Map::new("accrued_fees");
+
+/// Slippage policy of every pair without its own, slippage is left to the caller while unset
+pub const SLIPPAGE_POLICY: Item<SlippagePolicy> = Item::new("slippage_policy");
+
+/// Slippage policy overrides keyed by the offered and asked asset keys
+pub const PAIR_SLIPPAGE_POLICIES: Map<(String, String), SlippagePolicy> =
+    Map::new("pair_slippage_policies");
+
+/// Active pauses keyed by `pause_key`
+pub const PAUSES: Map<&str, Pause> = Map::new("pauses");
+
+/// Granted roles keyed by role and address
+pub const ROLES: Map<(&str, &Addr), Empty> = Map::new("roles");
+
+/// Guard checking swaps against oracle prices, off while unset
+pub const ORACLE_GUARD: Item<OracleGuard> = Item::new("oracle_guard");
+
+/// Oracle currency pairs keyed by asset key
+pub const ORACLE_PAIRS: Map<&str, OraclePair> = Map::new("oracle_pairs");
+
+/// Assets that may be offered or asked for, keyed by asset key. Any asset may while it is empty
+pub const ALLOWED_ASSETS: Map<&str, Empty> = Map::new("allowed_assets");
+
+/// Assets that may not be offered or asked for, keyed by asset key
+pub const DENIED_ASSETS: Map<&str, Empty> = Map::new("denied_assets");
+
+/// Code ids CW20 hooks are accepted from, any CW20 is accepted while it is empty
+pub const CW20_CODE_IDS: Map<u64, Empty> = Map::new("cw20_code_ids");
+
+/// Volume limits keyed by the asset key of the offered asset
+pub const VOLUME_LIMITS: Map<&str, VolumeLimit> = Map::new("volume_limits");
+
+/// Offered volume keyed by asset key and the block time its bucket starts at
+pub const ASSET_VOLUMES: Map<(&str, u64), Uint128> = Map::new("asset_volumes");
+
+/// Offered volume keyed by sender, asset key and the block time its bucket starts at
+pub const SENDER_VOLUMES: Map<(&Addr, &str, u64), Uint128> = Map::new("sender_volumes");
+
+/// Share of the protocol fee paid to each referrer, in basis points of the fee
+pub const REFERRAL_SHARES: Map<&Addr, u16> = Map::new("referral_shares");
+
+/// Share of the protocol fee paid to referrers without their own share, none until configured
+pub const DEFAULT_REFERRAL_SHARE: Item<u16> = Item::new("default_referral_share");
+
+/// Cumulative referral earnings keyed by referrer and asset key
+pub const REFERRAL_EARNINGS: Map<(&Addr, &str), Uint128> = Map::new("referral_earnings");
+
+/// Most hops a swap path may take, `DEFAULT_MAX_HOPS` until configured
+pub const MAX_HOPS: Item<u32> = Item::new("max_hops");
+
+pub const DEFAULT_MAX_HOPS: u32 = 4;
+
+/// The last swap id handed out, swap ids increase monotonically
+pub const SWAP_ID: Item<u64> = Item::new("swap_id");
 
 pub const SWAP_ROUTER: Item<AndrAddr> = Item::new("swap_router");
 
-pub const PREV_BALANCE: Item<Uint128> = Item::new("prev_balance");
+/// Code the swap router must run, checked on every swap
+pub const SWAP_ROUTER_PIN: Item<CodePin> = Item::new("swap_router_pin");
+
+/// The swap router waiting for its timelock to pass
+pub const SWAP_ROUTER_PROPOSAL: Item<SwapRouterProposal> = Item::new("swap_router_proposal");
+
+/// Seconds a proposed swap router waits, `DEFAULT_SWAP_ROUTER_TIMELOCK` until configured
+pub const SWAP_ROUTER_TIMELOCK: Item<u64> = Item::new("swap_router_timelock");
+
+/// Balance of the asked asset before the swap, keyed by swap id
+pub const PREV_BALANCE: Map<u64, Uint128> = Map::new("prev_balances");
+
+/// Keys of the swap state items used before swaps were keyed by swap id, cleared on migration
+pub const LEGACY_SWAP_STATE_KEYS: [&[u8]; 2] = [b"forward_reply_state", b"prev_balance"];
+
+pub fn next_swap_id(storage: &mut dyn Storage) -> StdResult<u64> {
+    let swap_id = SWAP_ID.may_load(storage)?.unwrap_or_default() + 1;
+    SWAP_ID.save(storage, &swap_id)?;
+    Ok(swap_id)
+}
+
+/// Storage key of an asset, CW20 addresses are resolved so that aliases share a key
+pub fn asset_key(deps: &Deps, asset: &Asset) -> Result<String, ContractError> {
+    match asset {
+        Asset::NativeToken(denom) => Ok(format!("native:{}", denom)),
+        Asset::Cw20Token(andr_addr) => Ok(format!("cw20:{}", andr_addr.get_raw_address(deps)?)),
+    }
+}
+
+/// Reverses `asset_key`
+pub fn asset_from_key(key: &str) -> Result<Asset, ContractError> {
+    match key.split_once(':') {
+        Some(("native", denom)) => Ok(Asset::NativeToken(denom.to_string())),
+        Some(("cw20", addr)) => Ok(Asset::Cw20Token(AndrAddr::from_string(addr))),
+        _ => Err(ContractError::Std(StdError::generic_err(format!(
+            "Invalid asset key {}",
+            key
+        )))),
+    }
+}
+
+/// Storage key of a pause scope
+pub fn pause_key(deps: &Deps, scope: &PauseScope) -> Result<String, ContractError> {
+    match scope {
+        PauseScope::Global => Ok("global".to_string()),
+        PauseScope::Asset { asset } => Ok(format!("asset:{}", asset_key(deps, asset)?)),
+        PauseScope::Pair {
+            from_asset,
+            to_asset,
+        } => Ok(format!(
+            "pair:{}|{}",
+            asset_key(deps, from_asset)?,
+            asset_key(deps, to_asset)?
+        )),
+    }
+}
+
+pub fn load_max_hops(storage: &dyn Storage) -> StdResult<u32> {
+    Ok(MAX_HOPS.may_load(storage)?.unwrap_or(DEFAULT_MAX_HOPS))
+}
+
+/// The raw swap router address, checked against its code pin when one is set
+pub fn load_swap_router(deps: &Deps) -> Result<Addr, ContractError> {
+    let swap_router = SWAP_ROUTER.load(deps.storage)?.get_raw_address(deps)?;
+    if let Some(code_pin) = SWAP_ROUTER_PIN.may_load(deps.storage)? {
+        code_pin.verify(deps, &swap_router)?;
+    }
+    Ok(swap_router)
+}
+
+pub fn load_swap_router_timelock(storage: &dyn Storage) -> StdResult<u64> {
+    Ok(SWAP_ROUTER_TIMELOCK
+        .may_load(storage)?
+        .unwrap_or(DEFAULT_SWAP_ROUTER_TIMELOCK))
+}
+
+pub fn load_referral_share(storage: &dyn Storage, referrer: &Addr) -> StdResult<u16> {
+    match REFERRAL_SHARES.may_load(storage, referrer)? {
+        Some(share_bps) => Ok(share_bps),
+        None => Ok(DEFAULT_REFERRAL_SHARE
+            .may_load(storage)?
+            .unwrap_or_default()),
+    }
+}
+
+/// The slippage policy of a pair, its override first and then the contract wide policy
+pub fn load_slippage_policy(
+    storage: &dyn Storage,
+    pair_key: (String, String),
+) -> StdResult<Option<SlippagePolicy>> {
+    match PAIR_SLIPPAGE_POLICIES.may_load(storage, pair_key)? {
+        Some(slippage_policy) => Ok(Some(slippage_policy)),
+        None => SLIPPAGE_POLICY.may_load(storage),
+    }
+}
+
+/// Volume offered of `asset_key` in the buckets starting at or after `since`
+pub fn asset_volume(storage: &dyn Storage, asset_key: &str, since: u64) -> StdResult<Uint128> {
+    ASSET_VOLUMES
+        .prefix(asset_key)
+        .range(
+            storage,
+            Some(Bound::inclusive(since)),
+            None,
+            Order::Ascending,
+        )
+        .map(|item| item.map(|(_, amount)| amount))
+        .sum()
+}
+
+/// Volume `sender` offered of `asset_key` in the buckets starting at or after `since`
+pub fn sender_volume(
+    storage: &dyn Storage,
+    sender: &Addr,
+    asset_key: &str,
+    since: u64,
+) -> StdResult<Uint128> {
+    SENDER_VOLUMES
+        .prefix((sender, asset_key))
+        .range(
+            storage,
+            Some(Bound::inclusive(since)),
+            None,
+            Order::Ascending,
+        )
+        .map(|item| item.map(|(_, amount)| amount))
+        .sum()
+}
@@ -50,6 +50,8 @@ mod test {
             kernel_address: kernel_address.to_string(),
             owner: None,
             swap_router: None,
+            swap_router_timelock: None,
+            permissioned_swaps: None,
         };
         let swap_and_forward_component = AppComponent::new(
             swap_and_forward_component_name,
@@ -149,6 +151,12 @@ mod test {
                 None,
                 None,
                 None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
                 &[coin(100, denom)],
             )
             .unwrap();
@@ -220,6 +228,12 @@ mod test {
             None,
             None,
             None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
         );
 
         // ==================================================================================== //
@@ -286,7 +300,13 @@ mod test {
                 None,
                 None,
                 None,
+                None,
+                None,
+                None,
                 Some(recipient),
+                None,
+                None,
+                None,
                 &[coin(100000000, astro_denom)],
             )
             .unwrap();
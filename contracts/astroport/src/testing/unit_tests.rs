@@ -0,0 +1,1669 @@
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, marker::PhantomData};
+
+    use andromeda_std::{
+        ado_base::permissioning::Permission,
+        ado_contract::ADOContract,
+        amp::{messages::AMPPkt, AndrAddr, Recipient},
+        common::{context::ExecuteContext, denom::Asset},
+        error::ContractError,
+    };
+    use andromeda_swap_and_forward::{
+        astroport::{
+            AssetListResponse, CurrencyPair, Cw20CodeIdsResponse, Cw20HookMsg, ExecuteMsg,
+            InstantiateMsg, OracleGuard, OracleGuardAction, OraclePair, PauseScope,
+            PauseStatusResponse, QueryMsg, RouteInfo, RoutesResponse,
+            SimulateSwapOperationResponse, SwapOperation, TreasuryConfig,
+        },
+        common::{
+            expired_error, AssetList, CodePin, FeeConfig, FeeSide, Role, SwapRouterResponse,
+            VolumeLimit, WeightedRecipient, DEFAULT_SWAP_ROUTER_TIMELOCK, MAX_SWAP_ROUTER_TIMELOCK,
+        },
+    };
+    use astroport::router::{ExecuteMsg as AstroExecuteMsg, SwapOperation as AstroSwapOperation};
+    use cosmwasm_std::{
+        coin, from_json,
+        testing::{
+            mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
+            MOCK_CONTRACT_ADDR,
+        },
+        to_json_binary, wasm_execute, Addr, BankMsg, Binary, Coin, ContractInfoResponse,
+        ContractResult, CosmosMsg, Decimal, DepsMut, Empty, OwnedDeps, Querier, QuerierResult,
+        QueryRequest, Reply, Response, StdError, SubMsg, SubMsgResponse, SubMsgResult, SystemError,
+        SystemResult, Timestamp, Uint128, WasmMsg, WasmQuery,
+    };
+    use cw20::{BalanceResponse, Cw20ExecuteMsg, Cw20ReceiveMsg, Expiration};
+    use serde_json::json;
+
+    use crate::{
+        astroport::{
+            generate_forward_msgs, handle_astroport_consolidation_reply, record_swap_volume,
+            take_swap_output, ASTROPORT_MSG_FORWARD_ID,
+        },
+        contract::{execute, handle_execute, instantiate, query, reply, SWAP_AND_FORWARD_ACTION},
+        oracle::{
+            apply_oracle_guard, encode_get_price_request, query_oracle_price, SLINKY_GET_PRICE_PATH,
+        },
+        state::{
+            asset_key, asset_volume, load_swap_router, sender_volume, Consolidation,
+            ForwardReplyState, ACCRUED_FEES, ASSET_VOLUMES, CONSOLIDATIONS, ORACLE_GUARD,
+            ORACLE_PAIRS, PREV_BALANCE, SENDER_VOLUMES, SWAP_ROUTER, VOLUME_LIMITS,
+        },
+    };
+
+    const OWNER: &str = "owner";
+    const KERNEL: &str = "kernel";
+
+    fn usdc() -> Asset {
+        Asset::NativeToken("uusdc".to_string())
+    }
+
+    fn setup() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        setup_with(false)
+    }
+
+    fn setup_with(permissioned_swaps: bool) -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies();
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            InstantiateMsg {
+                kernel_address: KERNEL.to_string(),
+                owner: None,
+                swap_router: Some(AndrAddr::from_string("router")),
+                swap_router_timelock: None,
+                permissioned_swaps: Some(permissioned_swaps),
+            },
+        )
+        .unwrap();
+        deps
+    }
+
+    /// Executes `msg` as relayed by the kernel in a packet sent by `origin`
+    fn amp_execute(
+        deps: DepsMut,
+        origin: &str,
+        funds: &[Coin],
+        msg: ExecuteMsg,
+    ) -> Result<Response, ContractError> {
+        let mut ctx = ExecuteContext::new(deps, mock_info(KERNEL, funds), mock_env());
+        ctx.amp_ctx = Some(AMPPkt::new(origin, origin, vec![]));
+        handle_execute(ctx, msg)
+    }
+
+    fn swap_to_usdc(refund_on_failure: bool) -> ExecuteMsg {
+        ExecuteMsg::SwapAndForward {
+            to_asset: usdc(),
+            recipient: None,
+            recipients: None,
+            max_spread: None,
+            minimum_receive: None,
+            minimum_receive_tolerance: None,
+            max_price_impact: None,
+            operations: Some(vec![SwapOperation {
+                offer_asset_info: Asset::NativeToken("untrn".to_string()),
+                ask_asset_info: usdc(),
+            }]),
+            referrer: None,
+            refund_on_failure: Some(refund_on_failure),
+            deadline: None,
+        }
+    }
+
+    /// Replies to the swap submessage `index` of `res` with an error
+    fn fail_swap(deps: DepsMut, res: &Response, index: usize) -> Result<Response, ContractError> {
+        reply(
+            deps,
+            mock_env(),
+            Reply {
+                id: res.messages[index].id,
+                result: SubMsgResult::Err("pool drained".to_string()),
+            },
+        )
+    }
+
+    /// Replies to the swap submessage `index` of `res` as succeeded
+    fn complete_swap(
+        deps: DepsMut,
+        res: &Response,
+        index: usize,
+    ) -> Result<Response, ContractError> {
+        reply(
+            deps,
+            mock_env(),
+            Reply {
+                id: res.messages[index].id,
+                result: SubMsgResult::Ok(SubMsgResponse {
+                    events: vec![],
+                    data: None,
+                }),
+            },
+        )
+    }
+
+    fn attr_value(res: &Response, key: &str) -> String {
+        res.attributes
+            .iter()
+            .find(|attr| attr.key == key)
+            .map(|attr| attr.value.clone())
+            .unwrap()
+    }
+
+    fn event_attr(res: &Response, key: &str) -> String {
+        res.events
+            .iter()
+            .flat_map(|event| event.attributes.iter())
+            .find(|attr| attr.key == key)
+            .map(|attr| attr.value.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_concurrent_swaps() {
+        let mut deps = setup();
+        let info = mock_info("alice", &[coin(100, "untrn")]);
+        let alice = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        let info = mock_info("bob", &[coin(50, "untrn")]);
+        let bob = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        assert_ne!(alice.messages[0].id, bob.messages[0].id);
+
+        // Replies come back in any order, each forwarding the output of its own swap
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(40, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &bob, 0).unwrap();
+        assert_eq!(attr_value(&res, "to_amount"), "40");
+        assert_eq!(attr_value(&res, "recipient"), "bob");
+
+        // Bob's output has left by the time Alice's swap replies
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(80, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &alice, 0).unwrap();
+        assert_eq!(attr_value(&res, "to_amount"), "80");
+        assert_eq!(attr_value(&res, "recipient"), "alice");
+
+        // A swap replies once
+        assert!(complete_swap(deps.as_mut(), &bob, 0).is_err());
+    }
+
+    #[test]
+    fn test_failed_swap_refunds_sender() {
+        let mut deps = setup();
+        let funds = vec![coin(100, "untrn")];
+        let info = mock_info("alice", &funds);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(true)).unwrap();
+
+        let res = fail_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(event_attr(&res, "refund_addr"), "alice");
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: funds,
+            })]
+        );
+    }
+
+    #[test]
+    fn test_failed_amp_swap_refunds_origin() {
+        let mut deps = setup();
+        let funds = vec![coin(100, "untrn")];
+        let res = amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(true)).unwrap();
+
+        // The refund is relayed back through the kernel to the origin of the packet
+        let res = fail_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(event_attr(&res, "refund_addr"), "alice");
+        assert!(matches!(
+            &res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, funds: sent, .. })
+                if contract_addr == KERNEL && *sent == funds
+        ));
+    }
+
+    #[test]
+    fn test_expired_amp_swap_refunds_origin() {
+        let mut deps = setup();
+        let funds = vec![coin(100, "untrn")];
+        let deadline = Expiration::AtHeight(1);
+        let mut msg = swap_to_usdc(false);
+        if let ExecuteMsg::SwapAndForward {
+            deadline: expiry, ..
+        } = &mut msg
+        {
+            *expiry = Some(deadline);
+        }
+
+        let info = mock_info("alice", &funds);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err(),
+            expired_error(&deadline)
+        );
+
+        // Packets are refunded to their origin rather than the kernel relaying them
+        let res = amp_execute(deps.as_mut(), "alice", &funds, msg).unwrap();
+        assert_eq!(attr_value(&res, "action"), "refund-expired-swap");
+        assert_eq!(attr_value(&res, "refund_addr"), "alice");
+        assert!(matches!(
+            &res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, funds: sent, .. })
+                if contract_addr == KERNEL && *sent == funds
+        ));
+    }
+
+    #[test]
+    fn test_failed_swap_without_refund_reverts() {
+        let mut deps = setup();
+        let funds = [coin(100, "untrn")];
+        let res = amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(false)).unwrap();
+        assert!(fail_swap(deps.as_mut(), &res, 0).is_err());
+    }
+
+    #[test]
+    fn test_sender_volume_follows_swap_origin() {
+        let mut deps = setup();
+        let msg = ExecuteMsg::SetVolumeLimit {
+            asset: Asset::NativeToken("untrn".to_string()),
+            volume_limit: Some(VolumeLimit {
+                max_swap_amount: None,
+                max_sender_volume: Some(Uint128::new(100)),
+                max_asset_volume: None,
+                window_seconds: 3_600,
+            }),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        let untrn_key =
+            asset_key(&deps.as_ref(), &Asset::NativeToken("untrn".to_string())).unwrap();
+        let funds = [coin(60, "untrn")];
+
+        // Packets count against their origins rather than the kernel relaying them
+        assert!(amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(false)).is_ok());
+        assert!(amp_execute(deps.as_mut(), "bob", &funds, swap_to_usdc(false)).is_ok());
+        let volume = |deps: &OwnedDeps<_, _, _>, sender: &str| {
+            sender_volume(
+                deps.as_ref().storage,
+                &Addr::unchecked(sender),
+                &untrn_key,
+                0,
+            )
+            .unwrap()
+        };
+        assert_eq!(volume(&deps, "alice"), Uint128::new(60));
+        assert_eq!(volume(&deps, "bob"), Uint128::new(60));
+        assert!(volume(&deps, KERNEL).is_zero());
+
+        // Direct swaps share the cap of the same address
+        let info = mock_info("alice", &funds);
+        assert!(execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).is_err());
+        assert!(amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(false)).is_err());
+        let info = mock_info("carol", &funds);
+        assert!(execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).is_ok());
+    }
+
+    #[test]
+    fn test_swaps_open_by_default() {
+        let mut deps = setup();
+        let funds = [coin(100, "untrn")];
+        assert!(amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(false)).is_ok());
+        let info = mock_info("alice", &funds);
+        assert!(execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).is_ok());
+    }
+
+    #[test]
+    fn test_permissioned_swaps_check_origin() {
+        let mut deps = setup_with(true);
+        ADOContract::set_permission(
+            deps.as_mut().storage,
+            SWAP_AND_FORWARD_ACTION,
+            "alice",
+            Permission::Whitelisted(None),
+        )
+        .unwrap();
+        let funds = [coin(100, "untrn")];
+
+        // Packets are checked against their origin rather than the kernel relaying them
+        assert!(amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(false)).is_ok());
+        assert_eq!(
+            amp_execute(deps.as_mut(), "mallory", &funds, swap_to_usdc(false)).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+
+        // Direct calls are checked against their sender
+        let info = mock_info("alice", &funds);
+        assert!(execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).is_ok());
+        for sender in ["mallory", KERNEL] {
+            let info = mock_info(sender, &funds);
+            assert_eq!(
+                execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap_err(),
+                ContractError::Unauthorized {}
+            );
+        }
+    }
+
+    fn usdc_route() -> Vec<SwapOperation> {
+        vec![
+            SwapOperation {
+                offer_asset_info: Asset::NativeToken("untrn".to_string()),
+                ask_asset_info: atom(),
+            },
+            SwapOperation {
+                offer_asset_info: atom(),
+                ask_asset_info: usdc(),
+            },
+        ]
+    }
+
+    /// The assets asked by each operation of the swap submessage `index` of `res`
+    fn swapped_assets(res: &Response, index: usize) -> Vec<String> {
+        let CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) = &res.messages[index].msg else {
+            panic!("Not a swap router message");
+        };
+        let AstroExecuteMsg::ExecuteSwapOperations { operations, .. } = from_json(msg).unwrap()
+        else {
+            panic!("Not a swap");
+        };
+        operations
+            .iter()
+            .map(|operation| match operation {
+                AstroSwapOperation::AstroSwap { ask_asset_info, .. } => ask_asset_info.to_string(),
+                AstroSwapOperation::NativeSwap { ask_denom, .. } => ask_denom.clone(),
+            })
+            .collect()
+    }
+
+    fn query_routes(deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>) -> Vec<RouteInfo> {
+        let msg = QueryMsg::Routes {
+            start_after: None,
+            limit: None,
+        };
+        let res: RoutesResponse =
+            from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        res.routes
+    }
+
+    #[test]
+    fn test_route_registry() {
+        let mut deps = setup();
+        let set_route = ExecuteMsg::SetRoute {
+            from_asset: Asset::NativeToken("untrn".to_string()),
+            to_asset: usdc(),
+            operations: usdc_route(),
+        };
+        let remove_route = ExecuteMsg::RemoveRoute {
+            from_asset: Asset::NativeToken("untrn".to_string()),
+            to_asset: usdc(),
+        };
+
+        // Only router admins manage routes
+        let info = mock_info("alice", &[]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, set_route.clone()).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+        let info = mock_info(OWNER, &[]);
+        execute(deps.as_mut(), mock_env(), info, set_route).unwrap();
+        assert_eq!(
+            query_routes(&deps),
+            vec![RouteInfo {
+                from_asset: Asset::NativeToken("untrn".to_string()),
+                to_asset: usdc(),
+                operations: usdc_route(),
+            }]
+        );
+
+        // Swaps without operations of their own take the registered route
+        let mut unrouted = swap_to_usdc(false);
+        if let ExecuteMsg::SwapAndForward { operations, .. } = &mut unrouted {
+            *operations = None;
+        }
+        let info = mock_info("alice", &[coin(100, "untrn")]);
+        let res = execute(deps.as_mut(), mock_env(), info, unrouted.clone()).unwrap();
+        assert_eq!(swapped_assets(&res, 0), vec!["uatom", "uusdc"]);
+        let info = mock_info("alice", &[coin(100, "untrn")]);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        assert_eq!(swapped_assets(&res, 0), vec!["uusdc"]);
+
+        let info = mock_info("alice", &[]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, remove_route.clone()).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+        let info = mock_info(OWNER, &[]);
+        execute(deps.as_mut(), mock_env(), info, remove_route.clone()).unwrap();
+        assert!(query_routes(&deps).is_empty());
+        let info = mock_info(OWNER, &[]);
+        assert!(execute(deps.as_mut(), mock_env(), info, remove_route).is_err());
+
+        // Without a registered route the swap goes straight to the asked asset
+        let info = mock_info("alice", &[coin(100, "untrn")]);
+        let res = execute(deps.as_mut(), mock_env(), info, unrouted).unwrap();
+        assert_eq!(swapped_assets(&res, 0), vec!["uusdc"]);
+    }
+
+    fn operation(offer_denom: &str, ask_denom: &str) -> SwapOperation {
+        SwapOperation {
+            offer_asset_info: Asset::NativeToken(offer_denom.to_string()),
+            ask_asset_info: Asset::NativeToken(ask_denom.to_string()),
+        }
+    }
+
+    /// Swaps 100 untrn into uusdc through `operations`
+    fn swap_through(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+        operations: Vec<SwapOperation>,
+    ) -> Result<Response, ContractError> {
+        let mut msg = swap_to_usdc(false);
+        if let ExecuteMsg::SwapAndForward {
+            operations: swap_operations,
+            ..
+        } = &mut msg
+        {
+            *swap_operations = Some(operations);
+        }
+        let info = mock_info("alice", &[coin(100, "untrn")]);
+        execute(deps.as_mut(), mock_env(), info, msg)
+    }
+
+    #[test]
+    fn test_swap_operation_hops() {
+        let mut deps = setup();
+        let info = mock_info(OWNER, &[]);
+        let msg = ExecuteMsg::UpdateMaxHops { max_hops: 2 };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        assert_eq!(
+            swap_through(&mut deps, vec![]).unwrap_err(),
+            ContractError::Std(StdError::generic_err("Swap operations cannot be empty"))
+        );
+        assert_eq!(
+            swap_through(&mut deps, vec![operation("uatom", "uusdc")]).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Swap operation 0 offers native:uatom instead of native:untrn"
+            ))
+        );
+        let gapped = vec![operation("untrn", "uatom"), operation("uosmo", "uusdc")];
+        assert_eq!(
+            swap_through(&mut deps, gapped).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Swap operation 1 offers native:uosmo instead of native:uatom"
+            ))
+        );
+        let into_itself = vec![operation("untrn", "untrn"), operation("untrn", "uusdc")];
+        assert_eq!(
+            swap_through(&mut deps, into_itself).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Swap operation 0 asks for the offered asset native:untrn"
+            ))
+        );
+        assert_eq!(
+            swap_through(&mut deps, vec![operation("untrn", "uatom")]).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Swap operations end with native:uatom instead of native:uusdc"
+            ))
+        );
+        let three_hops = vec![
+            operation("untrn", "uatom"),
+            operation("uatom", "uosmo"),
+            operation("uosmo", "uusdc"),
+        ];
+        assert_eq!(
+            swap_through(&mut deps, three_hops).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Swap operations take 3 hops, at most 2 are allowed"
+            ))
+        );
+        let res = swap_through(&mut deps, usdc_route()).unwrap();
+        assert_eq!(swapped_assets(&res, 0), vec!["uatom", "uusdc"]);
+
+        // Registered routes are held to the same rules
+        let info = mock_info(OWNER, &[]);
+        let msg = ExecuteMsg::SetRoute {
+            from_asset: Asset::NativeToken("untrn".to_string()),
+            to_asset: usdc(),
+            operations: vec![operation("untrn", "uatom")],
+        };
+        assert!(execute(deps.as_mut(), mock_env(), info, msg).is_err());
+    }
+
+    /// Charges a 1% fee on `fee_side`, paid to the treasury
+    fn set_fee(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, fee_side: FeeSide) {
+        let msg = ExecuteMsg::SetFeeConfig {
+            fee_config: Some(FeeConfig {
+                fee_bps: 100,
+                fee_recipient: AndrAddr::from_string("treasury"),
+                fee_side,
+            }),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+    }
+
+    fn bank_send(to_address: &str, amount: Coin) -> SubMsg {
+        SubMsg::new(BankMsg::Send {
+            to_address: to_address.to_string(),
+            amount: vec![amount],
+        })
+    }
+
+    /// The funds the last message of `res` forwards through the kernel
+    fn forwarded_funds(res: &Response) -> Vec<Coin> {
+        let CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr,
+            funds,
+            ..
+        }) = &res.messages.last().unwrap().msg
+        else {
+            panic!("Not a forward");
+        };
+        assert_eq!(contract_addr, KERNEL);
+        funds.clone()
+    }
+
+    /// The funds the swap submessage `index` of `res` offers to the swap router
+    fn swap_funds(res: &Response, index: usize) -> Vec<Coin> {
+        let CosmosMsg::Wasm(WasmMsg::Execute { funds, .. }) = &res.messages[index].msg else {
+            panic!("Not a swap router message");
+        };
+        funds.clone()
+    }
+
+    #[test]
+    fn test_input_fee_paid_on_swap() {
+        let mut deps = setup();
+        set_fee(&mut deps, FeeSide::Input);
+        let info = mock_info("alice", &[coin(1000, "untrn")]);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        assert_eq!(attr_value(&res, "from_amount"), "990");
+        assert_eq!(attr_value(&res, "fee_amount"), "10");
+        assert_eq!(swap_funds(&res, 0), vec![coin(990, "untrn")]);
+
+        // The fee held back is paid once the swap succeeds, the whole output is forwarded
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(500, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(attr_value(&res, "fee_asset"), "native:untrn");
+        assert_eq!(attr_value(&res, "fee_amount"), "10");
+        assert_eq!(res.messages[0], bank_send("treasury", coin(10, "untrn")));
+        assert_eq!(forwarded_funds(&res), vec![coin(500, "uusdc")]);
+        assert_eq!(attr_value(&res, "recipient"), "alice");
+    }
+
+    #[test]
+    fn test_output_fee_paid_on_swap() {
+        let mut deps = setup();
+        set_fee(&mut deps, FeeSide::Output);
+        let info = mock_info("alice", &[coin(1000, "untrn")]);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        assert_eq!(attr_value(&res, "from_amount"), "1000");
+        assert_eq!(attr_value(&res, "fee_amount"), "0");
+        assert_eq!(swap_funds(&res, 0), vec![coin(1000, "untrn")]);
+
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(500, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(attr_value(&res, "fee_asset"), "native:uusdc");
+        assert_eq!(attr_value(&res, "fee_amount"), "5");
+        assert_eq!(res.messages[0], bank_send("treasury", coin(5, "uusdc")));
+        assert_eq!(forwarded_funds(&res), vec![coin(495, "uusdc")]);
+    }
+
+    #[test]
+    fn test_fees_accrue_for_treasury() {
+        let mut deps = setup();
+        set_fee(&mut deps, FeeSide::Output);
+        let msg = ExecuteMsg::SetTreasuryConfig {
+            treasury_config: Some(TreasuryConfig {
+                asset: Asset::NativeToken("untrn".to_string()),
+                recipient: AndrAddr::from_string("treasury"),
+            }),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        let usdc_key = asset_key(&deps.as_ref(), &usdc()).unwrap();
+
+        let info = mock_info("alice", &[coin(1000, "untrn")]);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(500, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(attr_value(&res, "fee_amount"), "5");
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(forwarded_funds(&res), vec![coin(495, "uusdc")]);
+        assert_eq!(
+            ACCRUED_FEES.load(deps.as_ref().storage, &usdc_key).unwrap(),
+            Uint128::new(5)
+        );
+
+        // The fees held in the contract do not count as output of later swaps
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(5, "uusdc")]);
+        let info = mock_info("bob", &[coin(1000, "untrn")]);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(305, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(forwarded_funds(&res), vec![coin(297, "uusdc")]);
+        assert_eq!(
+            ACCRUED_FEES.load(deps.as_ref().storage, &usdc_key).unwrap(),
+            Uint128::new(8)
+        );
+    }
+
+    fn convert_fees() -> ExecuteMsg {
+        ExecuteMsg::ConvertFees {
+            assets: None,
+            max_spread: None,
+            minimum_receive: None,
+        }
+    }
+
+    #[test]
+    fn test_convert_fees_restricted_to_keepers() {
+        let mut deps = setup();
+        set_fee(&mut deps, FeeSide::Output);
+        let msg = ExecuteMsg::SetTreasuryConfig {
+            treasury_config: Some(TreasuryConfig {
+                asset: usdc(),
+                recipient: AndrAddr::from_string("treasury"),
+            }),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        let usdc_key = asset_key(&deps.as_ref(), &usdc()).unwrap();
+        ACCRUED_FEES
+            .save(deps.as_mut().storage, &usdc_key, &Uint128::new(10))
+            .unwrap();
+
+        let info = mock_info("bot", &[]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, convert_fees()).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+
+        // Granted keepers convert fees next to the owner
+        let msg = ExecuteMsg::GrantRole {
+            role: Role::Keeper,
+            address: AndrAddr::from_string("bot"),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        for sender in ["bot", OWNER] {
+            ACCRUED_FEES
+                .save(deps.as_mut().storage, &usdc_key, &Uint128::new(10))
+                .unwrap();
+            let info = mock_info(sender, &[]);
+            assert!(execute(deps.as_mut(), mock_env(), info, convert_fees()).is_ok());
+        }
+
+        // Other roles do not convert fees
+        let msg = ExecuteMsg::GrantRole {
+            role: Role::FeeAdmin,
+            address: AndrAddr::from_string("admin"),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        let info = mock_info("admin", &[]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, convert_fees()).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+    }
+
+    fn set_pause(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+        sender: &str,
+        scope: PauseScope,
+        paused: bool,
+    ) -> Result<Response, ContractError> {
+        let msg = if paused {
+            ExecuteMsg::Pause { scope }
+        } else {
+            ExecuteMsg::Unpause { scope }
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(sender, &[]), msg)
+    }
+
+    fn paused_by_bot(scope: &str) -> ContractError {
+        ContractError::Std(StdError::generic_err(format!(
+            "Paused: {} are paused by bot",
+            scope
+        )))
+    }
+
+    #[test]
+    fn test_pause_scopes() {
+        let mut deps = setup();
+        let direct = vec![operation("untrn", "uusdc")];
+        let through_atom = usdc_route();
+
+        // Only pausers pause
+        assert_eq!(
+            set_pause(&mut deps, "bot", PauseScope::Global, true).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+        let msg = ExecuteMsg::GrantRole {
+            role: Role::Pauser,
+            address: AndrAddr::from_string("bot"),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+
+        // A global pause stops every swap
+        set_pause(&mut deps, "bot", PauseScope::Global, true).unwrap();
+        assert!(set_pause(&mut deps, "bot", PauseScope::Global, true).is_err());
+        let msg = QueryMsg::PauseStatus {
+            start_after: None,
+            limit: None,
+        };
+        let res: PauseStatusResponse =
+            from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.pauses.len(), 1);
+        assert_eq!(res.pauses[0].paused_by, Addr::unchecked("bot"));
+        for operations in [direct.clone(), through_atom.clone()] {
+            assert_eq!(
+                swap_through(&mut deps, operations).unwrap_err(),
+                paused_by_bot("all swaps")
+            );
+        }
+        set_pause(&mut deps, "bot", PauseScope::Global, false).unwrap();
+        assert!(set_pause(&mut deps, "bot", PauseScope::Global, false).is_err());
+        assert!(swap_through(&mut deps, direct.clone()).is_ok());
+
+        // An asset pause stops swaps offering, asking for or passing through the asset
+        let atom_scope = PauseScope::Asset { asset: atom() };
+        set_pause(&mut deps, "bot", atom_scope.clone(), true).unwrap();
+        assert_eq!(
+            swap_through(&mut deps, through_atom.clone()).unwrap_err(),
+            paused_by_bot("swaps through native:uatom")
+        );
+        assert!(swap_through(&mut deps, direct.clone()).is_ok());
+        set_pause(&mut deps, "bot", atom_scope, false).unwrap();
+        let usdc_scope = PauseScope::Asset { asset: usdc() };
+        set_pause(&mut deps, "bot", usdc_scope.clone(), true).unwrap();
+        assert_eq!(
+            swap_through(&mut deps, direct.clone()).unwrap_err(),
+            paused_by_bot("swaps through native:uusdc")
+        );
+        set_pause(&mut deps, "bot", usdc_scope, false).unwrap();
+
+        // A pair pause only stops swaps in its direction
+        let pair = PauseScope::Pair {
+            from_asset: Asset::NativeToken("untrn".to_string()),
+            to_asset: usdc(),
+        };
+        set_pause(&mut deps, "bot", pair.clone(), true).unwrap();
+        for operations in [direct.clone(), through_atom] {
+            assert_eq!(
+                swap_through(&mut deps, operations).unwrap_err(),
+                paused_by_bot("swaps from native:untrn to native:uusdc")
+            );
+        }
+        let mut reverse = swap_to_usdc(false);
+        if let ExecuteMsg::SwapAndForward {
+            to_asset,
+            operations,
+            ..
+        } = &mut reverse
+        {
+            *to_asset = Asset::NativeToken("untrn".to_string());
+            *operations = Some(vec![operation("uusdc", "untrn")]);
+        }
+        let info = mock_info("alice", &[coin(100, "uusdc")]);
+        assert!(execute(deps.as_mut(), mock_env(), info, reverse).is_ok());
+        set_pause(&mut deps, "bot", pair, false).unwrap();
+        assert!(swap_through(&mut deps, direct).is_ok());
+    }
+
+    fn set_router_code(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, code_id: u64) {
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::ContractInfo { .. } => SystemResult::Ok(ContractResult::Ok(
+                to_json_binary(&ContractInfoResponse::new(code_id, "creator")).unwrap(),
+            )),
+            _ => panic!("Unexpected query"),
+        });
+    }
+
+    fn execute_at(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+        seconds: u64,
+        msg: ExecuteMsg,
+    ) -> Result<Response, ContractError> {
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(seconds);
+        execute(deps.as_mut(), env, mock_info(OWNER, &[]), msg)
+    }
+
+    #[test]
+    fn test_swap_router_timelock() {
+        let mut deps = setup();
+        set_router_code(&mut deps, 7);
+        let propose = ExecuteMsg::ProposeSwapRouter {
+            swap_router: AndrAddr::from_string("router2"),
+            code_pin: Some(CodePin::CodeId(7)),
+        };
+        let info = mock_info("alice", &[]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, propose.clone()).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+        execute_at(&mut deps, 0, propose).unwrap();
+
+        // The proposal waits out the timelock, swaps keep using the current router
+        let timelock = DEFAULT_SWAP_ROUTER_TIMELOCK;
+        assert!(execute_at(&mut deps, timelock - 1, ExecuteMsg::AcceptSwapRouter {}).is_err());
+        assert_eq!(
+            load_swap_router(&deps.as_ref()).unwrap(),
+            Addr::unchecked("router")
+        );
+        execute_at(&mut deps, timelock, ExecuteMsg::AcceptSwapRouter {}).unwrap();
+        assert_eq!(
+            load_swap_router(&deps.as_ref()).unwrap(),
+            Addr::unchecked("router2")
+        );
+        let res = swap_usdc(&mut deps).unwrap();
+        assert!(matches!(
+            &res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, .. }) if contract_addr == "router2"
+        ));
+
+        // Swaps stop once the router is migrated off the pinned code
+        set_router_code(&mut deps, 8);
+        let err = ContractError::Std(StdError::generic_err(
+            "Swap router router2 runs code id 8, which does not match the pinned code id 7",
+        ));
+        assert_eq!(load_swap_router(&deps.as_ref()).unwrap_err(), err);
+        assert_eq!(swap_usdc(&mut deps).unwrap_err(), err);
+
+        // The timelock can only be raised, up to its maximum
+        let set_timelock =
+            |timelock_seconds| ExecuteMsg::SetSwapRouterTimelock { timelock_seconds };
+        assert!(execute_at(&mut deps, 0, set_timelock(timelock - 1)).is_err());
+        assert_eq!(
+            execute_at(&mut deps, 0, set_timelock(MAX_SWAP_ROUTER_TIMELOCK + 1)).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Swap router timelock cannot exceed 2592000 seconds"
+            ))
+        );
+        execute_at(&mut deps, 0, set_timelock(MAX_SWAP_ROUTER_TIMELOCK)).unwrap();
+        let res: SwapRouterResponse =
+            from_json(query(deps.as_ref(), mock_env(), QueryMsg::SwapRouter {}).unwrap()).unwrap();
+        assert_eq!(res.timelock_seconds, MAX_SWAP_ROUTER_TIMELOCK);
+        assert_eq!(res.code_pin, Some(CodePin::CodeId(7)));
+        assert_eq!(res.proposal, None);
+
+        let msg = InstantiateMsg {
+            kernel_address: KERNEL.to_string(),
+            owner: None,
+            swap_router: None,
+            swap_router_timelock: Some(MAX_SWAP_ROUTER_TIMELOCK + 1),
+            permissioned_swaps: None,
+        };
+        let mut deps = mock_dependencies();
+        assert!(instantiate(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).is_err());
+    }
+
+    fn update_list(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+        list: AssetList,
+        add: &[&str],
+        remove: &[&str],
+    ) -> Result<Response, ContractError> {
+        let native = |denom: &&str| Asset::NativeToken(denom.to_string());
+        let msg = ExecuteMsg::UpdateAssetList {
+            list,
+            add: add.iter().map(native).collect(),
+            remove: remove.iter().map(native).collect(),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg)
+    }
+
+    fn swap_usdc(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+    ) -> Result<Response, ContractError> {
+        swap_through(deps, vec![operation("untrn", "uusdc")])
+    }
+
+    #[test]
+    fn test_asset_lists() {
+        let mut deps = setup();
+        let msg = ExecuteMsg::UpdateAssetList {
+            list: AssetList::Deny,
+            add: vec![usdc()],
+            remove: vec![],
+        };
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+
+        // Denied assets can neither be offered nor asked for
+        update_list(&mut deps, AssetList::Deny, &["uusdc"], &[]).unwrap();
+        assert_eq!(
+            swap_usdc(&mut deps).unwrap_err(),
+            ContractError::Std(StdError::generic_err("native:uusdc is on the deny list"))
+        );
+        update_list(&mut deps, AssetList::Deny, &["untrn"], &["uusdc"]).unwrap();
+        assert_eq!(
+            swap_usdc(&mut deps).unwrap_err(),
+            ContractError::Std(StdError::generic_err("native:untrn is on the deny list"))
+        );
+        update_list(&mut deps, AssetList::Deny, &[], &["untrn"]).unwrap();
+        assert!(swap_usdc(&mut deps).is_ok());
+
+        // Once an asset is allowed, every other asset is refused
+        update_list(&mut deps, AssetList::Allow, &["untrn"], &[]).unwrap();
+        assert_eq!(
+            swap_usdc(&mut deps).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "native:uusdc is not on the allow list"
+            ))
+        );
+        update_list(&mut deps, AssetList::Allow, &["uusdc"], &[]).unwrap();
+        assert!(swap_usdc(&mut deps).is_ok());
+        let msg = QueryMsg::AssetList {
+            list: AssetList::Allow,
+            start_after: None,
+            limit: None,
+        };
+        let res: AssetListResponse =
+            from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(
+            res.assets,
+            vec![Asset::NativeToken("untrn".to_string()), usdc()]
+        );
+
+        // The deny list wins over the allow list
+        update_list(&mut deps, AssetList::Deny, &["uusdc"], &[]).unwrap();
+        assert_eq!(
+            swap_usdc(&mut deps).unwrap_err(),
+            ContractError::Std(StdError::generic_err("native:uusdc is on the deny list"))
+        );
+    }
+
+    /// Receives 100 of the CW20 at `cw20`, an instance of `code_id`, to swap into uusdc
+    fn receive_cw20(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+        cw20: &str,
+        code_id: u64,
+    ) -> Result<Response, ContractError> {
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::ContractInfo { .. } => SystemResult::Ok(ContractResult::Ok(
+                to_json_binary(&ContractInfoResponse::new(code_id, "creator")).unwrap(),
+            )),
+            _ => panic!("Unexpected query"),
+        });
+        let hook = Cw20HookMsg::SwapAndForward {
+            to_asset: usdc(),
+            recipient: None,
+            recipients: None,
+            max_spread: None,
+            minimum_receive: None,
+            minimum_receive_tolerance: None,
+            max_price_impact: None,
+            operations: Some(vec![SwapOperation {
+                offer_asset_info: Asset::Cw20Token(AndrAddr::from_string(cw20)),
+                ask_asset_info: usdc(),
+            }]),
+            referrer: None,
+            refund_on_failure: None,
+            deadline: None,
+        };
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "alice".to_string(),
+            amount: Uint128::new(100),
+            msg: to_json_binary(&hook).unwrap(),
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(cw20, &[]), msg)
+    }
+
+    fn update_code_ids(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+        sender: &str,
+        add: Vec<u64>,
+        remove: Vec<u64>,
+    ) -> Result<Response, ContractError> {
+        let msg = ExecuteMsg::UpdateCw20CodeIds { add, remove };
+        execute(deps.as_mut(), mock_env(), mock_info(sender, &[]), msg)
+    }
+
+    #[test]
+    fn test_cw20_code_pins() {
+        let mut deps = setup();
+        // Any CW20 is accepted while no code id is pinned
+        assert!(receive_cw20(&mut deps, "cw20", 7).is_ok());
+
+        assert_eq!(
+            update_code_ids(&mut deps, "alice", vec![5], vec![]).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+        update_code_ids(&mut deps, OWNER, vec![5, 6], vec![]).unwrap();
+        assert_eq!(
+            receive_cw20(&mut deps, "cw20", 7).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "CW20 cw20 has code id 7, which is not pinned"
+            ))
+        );
+        assert!(receive_cw20(&mut deps, "cw20", 5).is_ok());
+        update_code_ids(&mut deps, OWNER, vec![], vec![5]).unwrap();
+        let msg = QueryMsg::Cw20CodeIds {
+            start_after: None,
+            limit: None,
+        };
+        let res: Cw20CodeIdsResponse =
+            from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.code_ids, vec![6]);
+        assert!(receive_cw20(&mut deps, "cw20", 5).is_err());
+
+        // Unpinning every code id accepts any CW20 again
+        update_code_ids(&mut deps, OWNER, vec![], vec![6]).unwrap();
+        assert!(receive_cw20(&mut deps, "cw20", 7).is_ok());
+    }
+
+    #[test]
+    fn test_batch_legs_into_same_asset() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+
+        // Both legs are queued before either swap runs
+        PREV_BALANCE
+            .save(deps.as_mut().storage, 1, &Uint128::zero())
+            .unwrap();
+        PREV_BALANCE
+            .save(deps.as_mut().storage, 2, &Uint128::zero())
+            .unwrap();
+
+        // The first leg returns 100, its reply forwards 99 and accrues a fee of 1
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(100, "uusdc")]);
+        let output = take_swap_output(deps.as_mut(), &env, &usdc(), 1).unwrap();
+        assert_eq!(output, Uint128::new(100));
+        ACCRUED_FEES
+            .save(deps.as_mut().storage, "native:uusdc", &Uint128::one())
+            .unwrap();
+
+        // The second leg only gets its own output, not the fee left by the first one
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(201, "uusdc")]);
+        let output = take_swap_output(deps.as_mut(), &env, &usdc(), 2).unwrap();
+        assert_eq!(output, Uint128::new(200));
+
+        for swap_id in [1, 2] {
+            assert!(!PREV_BALANCE.has(deps.as_ref().storage, swap_id));
+        }
+    }
+
+    #[test]
+    fn test_swap_output_below_recorded_balance() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        PREV_BALANCE
+            .save(deps.as_mut().storage, 1, &Uint128::new(50))
+            .unwrap();
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(40, "uusdc")]);
+        assert!(take_swap_output(deps.as_mut(), &env, &usdc(), 1).is_err());
+    }
+
+    fn cw20_transfer(msg: &Cw20ExecuteMsg) -> SubMsg {
+        SubMsg::reply_always(
+            wasm_execute("cw20", msg, vec![]).unwrap(),
+            ASTROPORT_MSG_FORWARD_ID,
+        )
+    }
+
+    #[test]
+    fn test_forward_cw20_to_weighted_recipients() {
+        let deps = mock_dependencies();
+        let hook = to_json_binary(&"hook").unwrap();
+        let recipients = vec![
+            WeightedRecipient::new(Recipient::from_string("alice"), Uint128::new(1)),
+            WeightedRecipient::new(Recipient::new("vault", Some(hook.clone())), Uint128::new(2)),
+            WeightedRecipient::new(Recipient::from_string("carol"), Uint128::new(1)),
+        ];
+
+        let (msgs, forward_addrs) = generate_forward_msgs(
+            &deps.as_ref(),
+            &mock_env(),
+            &Asset::Cw20Token(AndrAddr::from_string("cw20")),
+            Uint128::new(101),
+            &recipients,
+            None,
+        )
+        .unwrap();
+
+        // The rounding remainder goes to the first recipient, the recipient with a message
+        // gets its share through Send
+        assert_eq!(
+            msgs,
+            vec![
+                cw20_transfer(&Cw20ExecuteMsg::Transfer {
+                    recipient: "alice".to_string(),
+                    amount: Uint128::new(26),
+                }),
+                cw20_transfer(&Cw20ExecuteMsg::Send {
+                    contract: "vault".to_string(),
+                    amount: Uint128::new(50),
+                    msg: hook,
+                }),
+                cw20_transfer(&Cw20ExecuteMsg::Transfer {
+                    recipient: "carol".to_string(),
+                    amount: Uint128::new(25),
+                }),
+            ]
+        );
+        assert_eq!(forward_addrs, vec!["alice", "vault", "carol"]);
+    }
+
+    #[test]
+    fn test_forward_cw20_skips_empty_shares() {
+        let deps = mock_dependencies();
+        let recipients = vec![
+            WeightedRecipient::new(Recipient::from_string("alice"), Uint128::new(1)),
+            WeightedRecipient::new(Recipient::from_string("bob"), Uint128::new(1)),
+        ];
+
+        let (msgs, forward_addrs) = generate_forward_msgs(
+            &deps.as_ref(),
+            &mock_env(),
+            &Asset::Cw20Token(AndrAddr::from_string("cw20")),
+            Uint128::one(),
+            &recipients,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            msgs,
+            vec![cw20_transfer(&Cw20ExecuteMsg::Transfer {
+                recipient: "alice".to_string(),
+                amount: Uint128::one(),
+            })]
+        );
+        assert_eq!(forward_addrs, vec!["alice"]);
+    }
+
+    /// Answers CW20 balance queries with `balance`
+    fn set_cw20_balance(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, balance: u128) {
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::Smart { .. } => SystemResult::Ok(ContractResult::Ok(
+                to_json_binary(&BalanceResponse {
+                    balance: Uint128::new(balance),
+                })
+                .unwrap(),
+            )),
+            _ => panic!("Unexpected query"),
+        });
+    }
+
+    #[test]
+    fn test_swap_into_cw20_forwards_with_send() {
+        let mut deps = setup();
+        let hook = to_json_binary(&"hook").unwrap();
+        let cw20 = Asset::Cw20Token(AndrAddr::from_string("cw20"));
+        let msg = ExecuteMsg::SwapAndForward {
+            to_asset: cw20.clone(),
+            recipient: None,
+            recipients: Some(vec![
+                WeightedRecipient::new(Recipient::new("vault", Some(hook.clone())), Uint128::one()),
+                WeightedRecipient::new(Recipient::from_string("alice"), Uint128::one()),
+            ]),
+            max_spread: None,
+            minimum_receive: None,
+            minimum_receive_tolerance: None,
+            max_price_impact: None,
+            operations: Some(vec![SwapOperation {
+                offer_asset_info: Asset::NativeToken("untrn".to_string()),
+                ask_asset_info: cw20,
+            }]),
+            referrer: None,
+            refund_on_failure: None,
+            deadline: None,
+        };
+        set_cw20_balance(&mut deps, 0);
+        let info = mock_info("alice", &[coin(100, "untrn")]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        set_cw20_balance(&mut deps, 100);
+        let res = complete_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![
+                cw20_transfer(&Cw20ExecuteMsg::Send {
+                    contract: "vault".to_string(),
+                    amount: Uint128::new(50),
+                    msg: hook,
+                }),
+                cw20_transfer(&Cw20ExecuteMsg::Transfer {
+                    recipient: "alice".to_string(),
+                    amount: Uint128::new(50),
+                }),
+            ]
+        );
+    }
+
+    fn consolidation_swap(from_denom: &str, consolidation_id: u64) -> ForwardReplyState {
+        ForwardReplyState {
+            recipients: vec![WeightedRecipient::new(
+                Recipient::from_string("alice"),
+                Uint128::one(),
+            )],
+            refund_addr: AndrAddr::from_string("sender"),
+            amp_ctx: None,
+            from_asset: Asset::NativeToken(from_denom.to_string()),
+            from_amount: Uint128::new(100),
+            to_asset: Asset::Cw20Token(AndrAddr::from_string("cw20")),
+            refund_on_failure: false,
+            exact_out_amount: None,
+            input_fee: Uint128::zero(),
+            output_fee: Uint128::zero(),
+            referrer: None,
+            batch_leg: None,
+            consolidation_id: Some(consolidation_id),
+        }
+    }
+
+    #[test]
+    fn test_consolidation_reply_accounting() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let consolidation_id = 1;
+
+        // 10 of the asked token is pulled along with two swaps, all queued at a balance of 0
+        CONSOLIDATIONS
+            .save(
+                deps.as_mut().storage,
+                consolidation_id,
+                &Consolidation {
+                    recipients: consolidation_swap("uosmo", consolidation_id).recipients,
+                    amp_ctx: None,
+                    to_asset: Asset::Cw20Token(AndrAddr::from_string("cw20")),
+                    minimum_receive: Some(Uint128::new(110)),
+                    pending_swaps: 2,
+                    total_amount: Uint128::new(10),
+                    received_amount: Uint128::new(10),
+                    charge_fee: true,
+                },
+            )
+            .unwrap();
+        for swap_id in [2, 3] {
+            PREV_BALANCE
+                .save(deps.as_mut().storage, swap_id, &Uint128::zero())
+                .unwrap();
+        }
+
+        // The first swap returns 40 on top of the pulled 10
+        set_cw20_balance(&mut deps, 50);
+        let resp = handle_astroport_consolidation_reply(
+            deps.as_mut(),
+            env.clone(),
+            2,
+            consolidation_id,
+            consolidation_swap("uosmo", consolidation_id),
+        )
+        .unwrap();
+        assert!(resp.messages.is_empty());
+        let consolidation = CONSOLIDATIONS
+            .load(deps.as_ref().storage, consolidation_id)
+            .unwrap();
+        assert_eq!(consolidation.total_amount, Uint128::new(50));
+        assert_eq!(consolidation.received_amount, Uint128::new(50));
+        assert_eq!(consolidation.pending_swaps, 1);
+
+        // The second swap returns 60, the last reply forwards everything
+        set_cw20_balance(&mut deps, 110);
+        let resp = handle_astroport_consolidation_reply(
+            deps.as_mut(),
+            env,
+            3,
+            consolidation_id,
+            consolidation_swap("uatom", consolidation_id),
+        )
+        .unwrap();
+        assert_eq!(
+            resp.messages,
+            vec![cw20_transfer(&Cw20ExecuteMsg::Transfer {
+                recipient: "alice".to_string(),
+                amount: Uint128::new(110),
+            })]
+        );
+        assert!(!CONSOLIDATIONS.has(deps.as_ref().storage, consolidation_id));
+        assert!(!PREV_BALANCE.has(deps.as_ref().storage, 3));
+    }
+
+    #[test]
+    fn test_consolidation_below_minimum_receive() {
+        let mut deps = mock_dependencies();
+        let consolidation_id = 1;
+        CONSOLIDATIONS
+            .save(
+                deps.as_mut().storage,
+                consolidation_id,
+                &Consolidation {
+                    recipients: consolidation_swap("uosmo", consolidation_id).recipients,
+                    amp_ctx: None,
+                    to_asset: Asset::Cw20Token(AndrAddr::from_string("cw20")),
+                    minimum_receive: Some(Uint128::new(100)),
+                    pending_swaps: 1,
+                    total_amount: Uint128::zero(),
+                    received_amount: Uint128::zero(),
+                    charge_fee: true,
+                },
+            )
+            .unwrap();
+        PREV_BALANCE
+            .save(deps.as_mut().storage, 2, &Uint128::zero())
+            .unwrap();
+
+        set_cw20_balance(&mut deps, 99);
+        assert!(handle_astroport_consolidation_reply(
+            deps.as_mut(),
+            mock_env(),
+            2,
+            consolidation_id,
+            consolidation_swap("uosmo", consolidation_id),
+        )
+        .is_err());
+    }
+
+    /// Answers Slinky price queries from `prices`, keyed by the encoded request, and leaves
+    /// every other query to the mock querier
+    struct OracleQuerier {
+        base: MockQuerier,
+        prices: HashMap<Vec<u8>, Binary>,
+    }
+
+    impl Querier for OracleQuerier {
+        fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+            match from_json(bin_request).unwrap() {
+                QueryRequest::<Empty>::Stargate { path, data } => {
+                    assert_eq!(path, SLINKY_GET_PRICE_PATH);
+                    match self.prices.get(data.as_slice()) {
+                        Some(res) => SystemResult::Ok(ContractResult::Ok(res.clone())),
+                        None => SystemResult::Err(SystemError::InvalidRequest {
+                            error: "Unknown currency pair".to_string(),
+                            request: data,
+                        }),
+                    }
+                }
+                _ => self.base.raw_query(bin_request),
+            }
+        }
+    }
+
+    type OracleDeps = OwnedDeps<MockStorage, MockApi, OracleQuerier>;
+
+    fn oracle_dependencies() -> OracleDeps {
+        OwnedDeps {
+            storage: MockStorage::default(),
+            api: MockApi::default(),
+            querier: OracleQuerier {
+                base: MockQuerier::default(),
+                prices: HashMap::new(),
+            },
+            custom_query_type: PhantomData,
+        }
+    }
+
+    fn usd_pair(base: &str) -> CurrencyPair {
+        CurrencyPair {
+            base: base.to_string(),
+            quote: "USD".to_string(),
+        }
+    }
+
+    /// Sets the oracle price of `base` in USD, with 8 decimals, as last updated at `block_height`
+    fn set_oracle_price(deps: &mut OracleDeps, base: &str, price: u128, block_height: u64) {
+        let res = json!({
+            "price": { "price": price.to_string(), "block_height": block_height.to_string() },
+            "decimals": "8",
+            "id": "1",
+        });
+        deps.querier.prices.insert(
+            encode_get_price_request(&usd_pair(base)).to_vec(),
+            Binary::from(res.to_string().as_bytes()),
+        );
+    }
+
+    fn atom() -> Asset {
+        Asset::NativeToken("uatom".to_string())
+    }
+
+    /// ATOM at $10 and USDC at $1, both with 6 decimals
+    fn setup_oracle(deps: &mut OracleDeps, action: OracleGuardAction, max_price_age: Option<u64>) {
+        let height = mock_env().block.height;
+        set_oracle_price(deps, "ATOM", 1_000_000_000, height - 5);
+        set_oracle_price(deps, "USDC", 100_000_000, height - 5);
+        for (asset, base) in [(atom(), "ATOM"), (usdc(), "USDC")] {
+            let key = asset_key(&deps.as_ref(), &asset).unwrap();
+            let oracle_pair = OraclePair {
+                currency_pair: usd_pair(base),
+                decimals: 6,
+            };
+            ORACLE_PAIRS
+                .save(deps.as_mut().storage, &key, &oracle_pair)
+                .unwrap();
+        }
+        let oracle_guard = OracleGuard {
+            max_deviation: Decimal::percent(5),
+            max_price_age,
+            action,
+        };
+        ORACLE_GUARD
+            .save(deps.as_mut().storage, &oracle_guard)
+            .unwrap();
+    }
+
+    /// Answers swap simulations through the router with `amount`
+    fn set_simulated_amount(deps: &mut OracleDeps, amount: u128) {
+        SWAP_ROUTER
+            .save(deps.as_mut().storage, &AndrAddr::from_string("router"))
+            .unwrap();
+        deps.querier.base.update_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == "router" => {
+                SystemResult::Ok(ContractResult::Ok(
+                    to_json_binary(&SimulateSwapOperationResponse {
+                        amount: Uint128::new(amount),
+                    })
+                    .unwrap(),
+                ))
+            }
+            _ => panic!("Unexpected query"),
+        });
+    }
+
+    fn guard_atom_to_usdc(
+        deps: &OracleDeps,
+        minimum_receive: Option<Uint128>,
+    ) -> Result<Option<Uint128>, ContractError> {
+        let operations = vec![SwapOperation {
+            offer_asset_info: atom(),
+            ask_asset_info: usdc(),
+        }];
+        apply_oracle_guard(
+            &deps.as_ref(),
+            &mock_env(),
+            &atom(),
+            Uint128::new(100_000),
+            &usdc(),
+            &operations,
+            minimum_receive,
+        )
+    }
+
+    #[test]
+    fn test_encode_get_price_request() {
+        let data = encode_get_price_request(&CurrencyPair {
+            base: "ATOM".to_string(),
+            quote: "USD".to_string(),
+        });
+        assert_eq!(
+            data.to_vec(),
+            vec![0x0a, 0x0b, 0x0a, 0x04, b'A', b'T', b'O', b'M', 0x12, 0x03, b'U', b'S', b'D']
+        );
+    }
+
+    #[test]
+    fn test_query_oracle_price() {
+        let mut deps = oracle_dependencies();
+        let env = mock_env();
+        set_oracle_price(&mut deps, "ATOM", 1_050_000_000, env.block.height);
+
+        let price = query_oracle_price(&deps.as_ref(), &env, None, &usd_pair("ATOM")).unwrap();
+        assert_eq!(price, Decimal::from_ratio(21u128, 2u128));
+    }
+
+    #[test]
+    fn test_oracle_price_missing() {
+        let mut deps = oracle_dependencies();
+        let env = mock_env();
+        let no_price = json!({ "decimals": "8", "id": "1" });
+        deps.querier.prices.insert(
+            encode_get_price_request(&usd_pair("ATOM")).to_vec(),
+            Binary::from(no_price.to_string().as_bytes()),
+        );
+        set_oracle_price(&mut deps, "USDC", 0, env.block.height);
+
+        for base in ["ATOM", "USDC"] {
+            let err = query_oracle_price(&deps.as_ref(), &env, None, &usd_pair(base)).unwrap_err();
+            assert_eq!(
+                err,
+                ContractError::Std(StdError::generic_err(format!(
+                    "Oracle has no price for {}/USD",
+                    base
+                )))
+            );
+        }
+        // Pairs unknown to the oracle fail the query
+        assert!(query_oracle_price(&deps.as_ref(), &env, None, &usd_pair("OSMO")).is_err());
+    }
+
+    #[test]
+    fn test_oracle_price_stale() {
+        let mut deps = oracle_dependencies();
+        let env = mock_env();
+        set_oracle_price(&mut deps, "ATOM", 1_000_000_000, env.block.height - 10);
+
+        let price = query_oracle_price(&deps.as_ref(), &env, Some(10), &usd_pair("ATOM"));
+        assert_eq!(price.unwrap(), Decimal::from_ratio(10u128, 1u128));
+
+        let err = query_oracle_price(&deps.as_ref(), &env, Some(9), &usd_pair("ATOM")).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::Std(StdError::generic_err(format!(
+                "Oracle price of ATOM/USD was last updated at height {}",
+                env.block.height - 10
+            )))
+        );
+    }
+
+    #[test]
+    fn test_oracle_guard_rejects_swaps_below_floor() {
+        let mut deps = oracle_dependencies();
+        setup_oracle(&mut deps, OracleGuardAction::Reject, Some(10));
+
+        // 100_000 uatom is worth 1_000_000 uusdc, 5% deviation puts the floor at 950_000
+        set_simulated_amount(&mut deps, 950_000);
+        let minimum_receive = guard_atom_to_usdc(&deps, Some(Uint128::new(900_000))).unwrap();
+        assert_eq!(minimum_receive, Some(Uint128::new(900_000)));
+
+        set_simulated_amount(&mut deps, 949_999);
+        let err = guard_atom_to_usdc(&deps, Some(Uint128::new(900_000))).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::Std(StdError::generic_err(
+                "Swap returns 949999 native:uusdc, below the oracle floor of 950000"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_oracle_guard_raises_minimum_receive() {
+        let mut deps = oracle_dependencies();
+        setup_oracle(&mut deps, OracleGuardAction::RaiseMinimumReceive, None);
+
+        let minimum_receive = guard_atom_to_usdc(&deps, None).unwrap();
+        assert_eq!(minimum_receive, Some(Uint128::new(950_000)));
+        let minimum_receive = guard_atom_to_usdc(&deps, Some(Uint128::new(900_000))).unwrap();
+        assert_eq!(minimum_receive, Some(Uint128::new(950_000)));
+        let minimum_receive = guard_atom_to_usdc(&deps, Some(Uint128::new(990_000))).unwrap();
+        assert_eq!(minimum_receive, Some(Uint128::new(990_000)));
+    }
+
+    #[test]
+    fn test_oracle_guard_rejects_stale_prices() {
+        let mut deps = oracle_dependencies();
+        setup_oracle(&mut deps, OracleGuardAction::RaiseMinimumReceive, Some(4));
+        assert!(guard_atom_to_usdc(&deps, None).is_err());
+    }
+
+    #[test]
+    fn test_oracle_guard_scales_decimals() {
+        let mut deps = oracle_dependencies();
+        setup_oracle(&mut deps, OracleGuardAction::RaiseMinimumReceive, None);
+        let key = asset_key(&deps.as_ref(), &usdc()).unwrap();
+        let oracle_pair = OraclePair {
+            currency_pair: usd_pair("USDC"),
+            decimals: 18,
+        };
+        ORACLE_PAIRS
+            .save(deps.as_mut().storage, &key, &oracle_pair)
+            .unwrap();
+
+        let minimum_receive = guard_atom_to_usdc(&deps, None).unwrap();
+        assert_eq!(minimum_receive, Some(Uint128::new(950_000_000_000_000_000)));
+    }
+
+    #[test]
+    fn test_oracle_guard_off() {
+        let deps = oracle_dependencies();
+        let minimum_receive = guard_atom_to_usdc(&deps, Some(Uint128::new(900_000))).unwrap();
+        assert_eq!(minimum_receive, Some(Uint128::new(900_000)));
+    }
+
+    #[test]
+    fn test_volume_window_edge_bucket() {
+        let mut deps = mock_dependencies();
+        let mut env = mock_env();
+        let sender = Addr::unchecked("sender");
+        // 24 buckets of 100 seconds
+        let volume_limit = VolumeLimit {
+            max_swap_amount: None,
+            max_sender_volume: Some(Uint128::new(60)),
+            max_asset_volume: Some(Uint128::new(100)),
+            window_seconds: 2_400,
+        };
+        VOLUME_LIMITS
+            .save(deps.as_mut().storage, "native:uusdc", &volume_limit)
+            .unwrap();
+
+        env.block.time = Timestamp::from_seconds(7_650);
+        record_swap_volume(deps.as_mut(), &env, &sender, &usdc(), Uint128::new(60)).unwrap();
+
+        // The window edge at 7_650 falls in the bucket starting at 7_600, which still counts
+        env.block.time = Timestamp::from_seconds(10_050);
+        let since = volume_limit.window_start(10_050);
+        assert_eq!(since, 7_600);
+        assert_eq!(
+            asset_volume(deps.as_ref().storage, "native:uusdc", since).unwrap(),
+            Uint128::new(60)
+        );
+        let other = Addr::unchecked("other");
+        assert!(
+            record_swap_volume(deps.as_mut(), &env, &other, &usdc(), Uint128::new(41)).is_err()
+        );
+        // Passes the asset cap, recording 1 the failed transaction would revert, but not the
+        // sender cap
+        assert!(record_swap_volume(deps.as_mut(), &env, &sender, &usdc(), Uint128::one()).is_err());
+
+        // Once the window moves past the bucket, it is pruned and no longer counts
+        env.block.time = Timestamp::from_seconds(10_100);
+        record_swap_volume(deps.as_mut(), &env, &sender, &usdc(), Uint128::new(60)).unwrap();
+        record_swap_volume(deps.as_mut(), &env, &other, &usdc(), Uint128::new(39)).unwrap();
+        assert!(!ASSET_VOLUMES.has(deps.as_ref().storage, ("native:uusdc", 7_600)));
+        assert!(!SENDER_VOLUMES.has(deps.as_ref().storage, (&sender, "native:uusdc", 7_600)));
+        let since = volume_limit.window_start(10_100);
+        assert_eq!(
+            sender_volume(deps.as_ref().storage, &sender, "native:uusdc", since).unwrap(),
+            Uint128::new(60)
+        );
+        assert_eq!(
+            asset_volume(deps.as_ref().storage, "native:uusdc", since).unwrap(),
+            Uint128::new(100)
+        );
+    }
+}
@@ -8,26 +8,68 @@ use andromeda_std::{
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    attr, ensure, Binary, Deps, DepsMut, Env, MessageInfo, Reply, Response, StdError,
+    attr, coin, ensure, Addr, Binary, Coin, Decimal, Deps, DepsMut, Empty, Env, MessageInfo, Order,
+    Reply, Response, StdError, StdResult, Timestamp, Uint128,
 };
 use cw2::set_contract_version;
+use cw20::Expiration;
+use cw_storage_plus::Bound;
 use cw_utils::one_coin;
 
 use crate::{
     osmosis::{
-        execute_swap_osmosis_msg, handle_osmosis_swap_reply, query_get_route,
-        OSMOSIS_MSG_FORWARD_ID, OSMOSIS_MSG_SWAP_ID,
+        check_osmosis_price_impact, deduct_fee, estimate_osmosis_price_impact,
+        execute_swap_exact_out_osmosis_msg, execute_swap_osmosis_msg, find_osmosis_route,
+        forward_consolidation, handle_osmosis_consolidation_reply, handle_osmosis_swap_failure,
+        handle_osmosis_swap_reply, query_get_route, record_swap_volume, refund_expired_swap,
+        resolve_osmosis_route, validate_osmosis_route, OSMOSIS_MSG_FORWARD_ID,
+        OSMOSIS_MSG_SWAP_ID_OFFSET,
+    },
+    state::{
+        asset_volume, load_max_hops, load_referral_share, load_slippage_policy,
+        load_swap_router_timelock, next_swap_id, sender_volume, Consolidation, ForwardReplyState,
+        ACCRUED_FEES, ALLOWED_ASSETS, CONSOLIDATIONS, DEFAULT_REFERRAL_SHARE, DENIED_ASSETS,
+        FEE_CONFIG, FORWARD_REPLY_STATE, LEGACY_SWAP_STATE_KEYS, MAX_HOPS, PAIR_SLIPPAGE_POLICIES,
+        PAUSES, REFERRAL_EARNINGS, REFERRAL_SHARES, ROLES, ROUTES, SLIPPAGE_POLICY, SWAP_ROUTER,
+        SWAP_ROUTER_PIN, SWAP_ROUTER_PROPOSAL, SWAP_ROUTER_TIMELOCK, TREASURY_CONFIG, TWAP_GUARD,
+        VOLUME_LIMITS,
     },
-    state::{ForwardReplyState, FORWARD_REPLY_STATE, SWAP_ROUTER},
 };
 
-use andromeda_swap_and_forward::osmosis::{
-    ExecuteMsg, InstantiateMsg, QueryMsg, Slippage, SwapRoute,
+use andromeda_swap_and_forward::{
+    common::{
+        ensure_funds_match, ensure_not_expired, expired_error, get_weighted_recipients,
+        resolve_referrer, validate_referral_share, validate_swap_router_timelock, AssetList,
+        CodePin, FeeConfig, FeeSide, Role, RolesResponse, SlippagePolicy, SwapRouterProposal,
+        SwapRouterResponse, VolumeLimit, WeightedRecipient,
+    },
+    osmosis::{
+        AccruedFeesResponse, AssetListResponse, ExecuteMsg, FeeConfigResponse, InstantiateMsg,
+        MaxHopsResponse, Pause, PauseScope, PauseStatusResponse, QueryMsg, ReferralEarning,
+        ReferralEarningsResponse, ReferralShareResponse, RouteInfo, RoutesResponse, Slippage,
+        SlippagePolicyResponse, SwapLeg, SwapRoute, TreasuryConfig, TreasuryConfigResponse,
+        TwapGuard, TwapGuardResponse, VolumeCapacityResponse,
+    },
 };
 
 const CONTRACT_NAME: &str = "crates.io:swap-and-forward-osmosis";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// Swap actions checked against ADO permissions, named after their messages
+pub const SWAP_AND_FORWARD_ACTION: &str = "SwapAndForward";
+pub const SWAP_EXACT_OUT_AND_FORWARD_ACTION: &str = "SwapExactOutAndForward";
+pub const BATCH_SWAP_AND_FORWARD_ACTION: &str = "BatchSwapAndForward";
+pub const CONSOLIDATE_AND_FORWARD_ACTION: &str = "ConsolidateAndForward";
+const SWAP_ACTIONS: [&str; 4] = [
+    SWAP_AND_FORWARD_ACTION,
+    SWAP_EXACT_OUT_AND_FORWARD_ACTION,
+    BATCH_SWAP_AND_FORWARD_ACTION,
+    CONSOLIDATE_AND_FORWARD_ACTION,
+];
+
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
@@ -55,6 +97,15 @@ pub fn instantiate(
         .unwrap_or(AndrAddr::from_string("/lib/osmosis/router"));
     swap_router.get_raw_address(&deps.as_ref())?;
     SWAP_ROUTER.save(deps.storage, &swap_router)?;
+    if let Some(timelock_seconds) = msg.swap_router_timelock {
+        validate_swap_router_timelock(timelock_seconds)?;
+        SWAP_ROUTER_TIMELOCK.save(deps.storage, &timelock_seconds)?;
+    }
+    if msg.permissioned_swaps.unwrap_or(false) {
+        for action in SWAP_ACTIONS {
+            ADOContract::default().permission_action(action, deps.storage)?;
+        }
+    }
 
     Ok(inst_resp
         .add_attribute("method", "instantiate")
@@ -78,64 +129,752 @@ pub fn execute(
     }
 }
 
-pub fn handle_execute(ctx: ExecuteContext, msg: ExecuteMsg) -> Result<Response, ContractError> {
+pub fn handle_execute(mut ctx: ExecuteContext, msg: ExecuteMsg) -> Result<Response, ContractError> {
+    if let Some((action, identifier)) = swap_permission(&ctx, &msg) {
+        ADOContract::default().is_permissioned(
+            ctx.deps.branch(),
+            ctx.env.clone(),
+            action,
+            identifier,
+        )?;
+    }
+
     match msg {
         ExecuteMsg::SwapAndForward {
             to_denom,
             recipient,
+            recipients,
+            slippage,
+            max_price_impact,
+            route,
+            referrer,
+            refund_on_failure,
+            deadline,
+        } => execute_swap_and_forward(
+            ctx,
+            to_denom,
+            recipient,
+            recipients,
             slippage,
+            max_price_impact,
             route,
-        } => execute_swap_and_forward(ctx, to_denom, recipient, slippage, route),
+            referrer,
+            refund_on_failure.unwrap_or(false),
+            deadline,
+        ),
+        ExecuteMsg::SwapExactOutAndForward {
+            to_denom,
+            to_amount,
+            recipient,
+            refund_addr,
+            slippage_percentage,
+            max_price_impact,
+            route,
+            refund_on_failure,
+            deadline,
+        } => execute_swap_exact_out_and_forward(
+            ctx,
+            to_denom,
+            to_amount,
+            recipient,
+            refund_addr,
+            slippage_percentage,
+            max_price_impact,
+            route,
+            refund_on_failure.unwrap_or(false),
+            deadline,
+        ),
+        ExecuteMsg::BatchSwapAndForward { legs } => execute_batch_swap_and_forward(ctx, legs),
+        ExecuteMsg::ConsolidateAndForward {
+            to_denom,
+            recipient,
+            slippage_percentage,
+            window_seconds,
+            minimum_receive,
+            deadline,
+        } => execute_consolidate_and_forward(
+            ctx,
+            to_denom,
+            recipient,
+            slippage_percentage.map(|slippage_percentage| Slippage::Twap {
+                window_seconds,
+                slippage_percentage,
+            }),
+            minimum_receive,
+            deadline,
+        ),
+        ExecuteMsg::ConvertFees {
+            denoms,
+            slippage_percentage,
+            window_seconds,
+            minimum_receive,
+        } => execute_convert_fees(
+            ctx,
+            denoms,
+            slippage_percentage.map(|slippage_percentage| Slippage::Twap {
+                window_seconds,
+                slippage_percentage,
+            }),
+            minimum_receive,
+        ),
         ExecuteMsg::UpdateSwapRouter { swap_router } => {
-            execute_update_swap_router(ctx, swap_router)
+            execute_propose_swap_router(ctx, swap_router, None)
+        }
+        ExecuteMsg::ProposeSwapRouter {
+            swap_router,
+            code_pin,
+        } => execute_propose_swap_router(ctx, swap_router, code_pin),
+        ExecuteMsg::AcceptSwapRouter {} => execute_accept_swap_router(ctx),
+        ExecuteMsg::CancelSwapRouter {} => execute_cancel_swap_router(ctx),
+        ExecuteMsg::SetSwapRouterTimelock { timelock_seconds } => {
+            execute_set_swap_router_timelock(ctx, timelock_seconds)
         }
+        ExecuteMsg::Pause { scope } => execute_pause(ctx, scope),
+        ExecuteMsg::Unpause { scope } => execute_unpause(ctx, scope),
+        ExecuteMsg::GrantRole { role, address } => execute_grant_role(ctx, role, address),
+        ExecuteMsg::RevokeRole { role, address } => execute_revoke_role(ctx, role, address),
+        ExecuteMsg::SetFeeConfig { fee_config } => execute_set_fee_config(ctx, fee_config),
+        ExecuteMsg::SetTreasuryConfig { treasury_config } => {
+            execute_set_treasury_config(ctx, treasury_config)
+        }
+        ExecuteMsg::SetSlippagePolicy { slippage_policy } => {
+            execute_set_slippage_policy(ctx, slippage_policy)
+        }
+        ExecuteMsg::SetPairSlippagePolicy {
+            from_denom,
+            to_denom,
+            slippage_policy,
+        } => execute_set_pair_slippage_policy(ctx, from_denom, to_denom, slippage_policy),
+        ExecuteMsg::SetTwapGuard { twap_guard } => execute_set_twap_guard(ctx, twap_guard),
+        ExecuteMsg::UpdateAssetList { list, add, remove } => {
+            execute_update_asset_list(ctx, list, add, remove)
+        }
+        ExecuteMsg::SetVolumeLimit {
+            denom,
+            volume_limit,
+        } => execute_set_volume_limit(ctx, denom, volume_limit),
+        ExecuteMsg::SetReferralShare {
+            referrer,
+            share_bps,
+        } => execute_set_referral_share(ctx, referrer, share_bps),
+        ExecuteMsg::UpdateDefaultReferralShare { share_bps } => {
+            execute_update_default_referral_share(ctx, share_bps)
+        }
+        ExecuteMsg::UpdateMaxHops { max_hops } => execute_update_max_hops(ctx, max_hops),
+        ExecuteMsg::SetRoute {
+            from_denom,
+            to_denom,
+            route,
+        } => execute_set_route(ctx, from_denom, to_denom, route),
+        ExecuteMsg::RemoveRoute {
+            from_denom,
+            to_denom,
+        } => execute_remove_route(ctx, from_denom, to_denom),
         _ => ADOContract::default().execute(ctx, msg),
     }
 }
 
+/// The permissioned action a swap message falls under and the address it is checked against
+fn swap_permission(ctx: &ExecuteContext, msg: &ExecuteMsg) -> Option<(&'static str, String)> {
+    let action = match msg {
+        ExecuteMsg::SwapAndForward { .. } => SWAP_AND_FORWARD_ACTION,
+        ExecuteMsg::SwapExactOutAndForward { .. } => SWAP_EXACT_OUT_AND_FORWARD_ACTION,
+        ExecuteMsg::BatchSwapAndForward { .. } => BATCH_SWAP_AND_FORWARD_ACTION,
+        ExecuteMsg::ConsolidateAndForward { .. } => CONSOLIDATE_AND_FORWARD_ACTION,
+        _ => return None,
+    };
+    Some((action, swap_origin(ctx).to_string()))
+}
+
+/// The address a swap is made by, packets relayed through the kernel count as sent by their origin
+/// The kernel vouches for the origin, which may be an address on another chain.
+fn swap_origin(ctx: &ExecuteContext) -> Addr {
+    match &ctx.amp_ctx {
+        Some(pkt) => Addr::unchecked(pkt.ctx.get_origin()),
+        None => ctx.info.sender.clone(),
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn execute_swap_and_forward(
-    ctx: ExecuteContext,
+    mut ctx: ExecuteContext,
+    to_denom: String,
+    recipient: Option<Recipient>,
+    recipients: Option<Vec<WeightedRecipient>>,
+    slippage: Option<Slippage>,
+    max_price_impact: Option<Decimal>,
+    route: Option<Vec<SwapRoute>>,
+    referrer: Option<AndrAddr>,
+    refund_on_failure: bool,
+    deadline: Option<Expiration>,
+) -> Result<Response, ContractError> {
+    let fund = one_coin(&ctx.info).map_err(|_| ContractError::InvalidAsset {
+        asset: "Invalid or missing coin".to_string(),
+    })?;
+
+    let origin = swap_origin(&ctx);
+    if let Some(deadline) = deadline.filter(|deadline| deadline.is_expired(&ctx.env.block)) {
+        // Funds relayed through AMP are refunded, direct callers get the error
+        ensure!(ctx.amp_ctx.is_some(), expired_error(&deadline));
+        return refund_expired_swap(
+            &ctx.deps.as_ref(),
+            &ctx.env,
+            &deadline,
+            fund,
+            &AndrAddr::from_string(&origin),
+            ctx.amp_ctx.map(|pkt| pkt.ctx),
+        );
+    }
+    let from_denom = fund.denom;
+    record_swap_volume(
+        ctx.deps.branch(),
+        &ctx.env,
+        &origin,
+        &from_denom,
+        fund.amount,
+    )?;
+    let sender = AndrAddr::from_string(&origin);
+    let recipients = get_weighted_recipients(recipient, recipients, sender.clone())?;
+    let referrer = resolve_referrer(&ctx.deps.as_ref(), referrer, origin.as_str())?;
+    let (from_amount, input_fee) = deduct_fee(ctx.deps.storage, FeeSide::Input, fund.amount)?;
+    let (route, price_impact) = check_osmosis_price_impact(
+        ctx.deps.as_ref(),
+        &from_denom,
+        from_amount,
+        &to_denom,
+        route,
+        max_price_impact,
+    )?;
+
+    let swap_msg = execute_swap_osmosis_msg(
+        ctx,
+        from_denom.clone(),
+        from_amount,
+        input_fee,
+        to_denom.clone(),
+        recipients.clone(),
+        sender,
+        slippage,
+        route,
+        refund_on_failure,
+        referrer,
+        None,
+        None,
+    )?;
+
+    Ok(Response::default()
+        .add_submessage(swap_msg)
+        .add_attributes(vec![
+            attr("from_denom", from_denom),
+            attr("from_amount", from_amount),
+            attr("fee_amount", input_fee),
+            attr("to_denom", to_denom),
+        ])
+        .add_attributes(price_impact.map(|impact| attr("price_impact", impact.to_string())))
+        .add_attributes(
+            recipients
+                .iter()
+                .map(|r| attr("recipient", r.recipient.get_addr())),
+        ))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_swap_exact_out_and_forward(
+    mut ctx: ExecuteContext,
     to_denom: String,
+    to_amount: Uint128,
     recipient: Option<Recipient>,
-    slippage: Slippage,
+    refund_addr: Option<AndrAddr>,
+    slippage_percentage: Option<Decimal>,
+    max_price_impact: Option<Decimal>,
     route: Option<Vec<SwapRoute>>,
+    refund_on_failure: bool,
+    deadline: Option<Expiration>,
 ) -> Result<Response, ContractError> {
+    ensure_not_expired(deadline.as_ref(), &ctx.env.block)?;
     let fund = one_coin(&ctx.info).map_err(|_| ContractError::InvalidAsset {
         asset: "Invalid or missing coin".to_string(),
     })?;
 
     let from_denom = fund.denom;
-    let sender = AndrAddr::from_string(&ctx.info.sender);
+    let origin = swap_origin(&ctx);
+    record_swap_volume(
+        ctx.deps.branch(),
+        &ctx.env,
+        &origin,
+        &from_denom,
+        fund.amount,
+    )?;
+    let sender = AndrAddr::from_string(&origin);
     let recipient = match recipient {
         None => Recipient::new(sender.clone(), None),
         Some(recipient) => recipient,
     };
+    let refund_addr = refund_addr.unwrap_or(sender);
 
-    let swap_msg = execute_swap_osmosis_msg(
+    let (swap_msg, price_impact) = execute_swap_exact_out_osmosis_msg(
         ctx,
         from_denom.clone(),
         fund.amount,
         to_denom.clone(),
+        to_amount,
         recipient.clone(),
-        sender,
-        slippage,
+        refund_addr.clone(),
+        slippage_percentage,
+        max_price_impact,
         route,
+        refund_on_failure,
     )?;
 
     Ok(Response::default()
         .add_submessage(swap_msg)
         .add_attributes(vec![
             attr("from_denom", from_denom),
-            attr("from_amount", fund.amount),
+            attr("max_from_amount", fund.amount),
             attr("to_denom", to_denom),
+            attr("to_amount", to_amount),
             attr("recipient", recipient.get_addr()),
-        ]))
+            attr("refund_addr", refund_addr),
+        ])
+        .add_attributes(price_impact.map(|impact| attr("price_impact", impact.to_string()))))
 }
 
-fn execute_update_swap_router(
+fn execute_batch_swap_and_forward(
+    mut ctx: ExecuteContext,
+    legs: Vec<SwapLeg>,
+) -> Result<Response, ContractError> {
+    ensure!(
+        !legs.is_empty(),
+        ContractError::Std(StdError::generic_err("Batch must contain at least one leg"))
+    );
+    let offered: Vec<Coin> = legs.iter().map(|leg| leg.from_coin.clone()).collect();
+    ensure_funds_match(&ctx.info.funds, &offered)?;
+    // Swap replies measure the output by balance difference, which an offered denom would skew
+    ensure!(
+        legs.iter()
+            .all(|leg| !offered.iter().any(|coin| leg.to_denom == coin.denom)),
+        ContractError::Std(StdError::generic_err(
+            "A leg cannot swap into a denom offered by the batch"
+        ))
+    );
+
+    let origin = swap_origin(&ctx);
+    let sender = AndrAddr::from_string(&origin);
+    let mut resp = Response::default().add_attributes(vec![
+        attr("action", "batch_swap_and_forward"),
+        attr("legs", legs.len().to_string()),
+    ]);
+    for (batch_leg, leg) in legs.into_iter().enumerate() {
+        ensure_not_expired(leg.deadline.as_ref(), &ctx.env.block)?;
+        let recipients = get_weighted_recipients(leg.recipient, None, sender.clone())?;
+        record_swap_volume(
+            ctx.deps.branch(),
+            &ctx.env,
+            &origin,
+            &leg.from_coin.denom,
+            leg.from_coin.amount,
+        )?;
+        let leg_ctx = ExecuteContext {
+            deps: ctx.deps.branch(),
+            info: ctx.info.clone(),
+            env: ctx.env.clone(),
+            amp_ctx: ctx.amp_ctx.clone(),
+        };
+        let (from_amount, input_fee) =
+            deduct_fee(leg_ctx.deps.storage, FeeSide::Input, leg.from_coin.amount)?;
+        let (route, _) = check_osmosis_price_impact(
+            leg_ctx.deps.as_ref(),
+            &leg.from_coin.denom,
+            from_amount,
+            &leg.to_denom,
+            leg.route,
+            leg.max_price_impact,
+        )?;
+        // Failed legs are refunded by default so the remaining legs still go through
+        let swap_msg = execute_swap_osmosis_msg(
+            leg_ctx,
+            leg.from_coin.denom,
+            from_amount,
+            input_fee,
+            leg.to_denom,
+            recipients,
+            sender.clone(),
+            leg.slippage,
+            route,
+            leg.refund_on_failure.unwrap_or(true),
+            None,
+            Some(batch_leg as u32),
+            None,
+        )?;
+        resp = resp.add_submessage(swap_msg);
+    }
+    Ok(resp)
+}
+
+fn execute_consolidate_and_forward(
+    mut ctx: ExecuteContext,
+    to_denom: String,
+    recipient: Option<Recipient>,
+    slippage: Option<Slippage>,
+    minimum_receive: Option<Uint128>,
+    deadline: Option<Expiration>,
+) -> Result<Response, ContractError> {
+    ensure_not_expired(deadline.as_ref(), &ctx.env.block)?;
+    let origin = swap_origin(&ctx);
+    let sender = AndrAddr::from_string(&origin);
+    let recipients = get_weighted_recipients(recipient, None, sender.clone())?;
+
+    let funds: Vec<Coin> = ctx
+        .info
+        .funds
+        .iter()
+        .filter(|fund| !fund.amount.is_zero())
+        .cloned()
+        .collect();
+    ensure!(
+        !funds.is_empty(),
+        ContractError::InvalidFunds {
+            msg: "No inputs to consolidate".to_string(),
+        }
+    );
+
+    let mut consolidation = Consolidation {
+        recipients,
+        amp_ctx: ctx.amp_ctx.clone().map(|pkt| pkt.ctx),
+        to_denom: to_denom.clone(),
+        minimum_receive,
+        pending_swaps: 0,
+        total_amount: Uint128::zero(),
+        received_amount: Uint128::zero(),
+        charge_fee: true,
+    };
+    let mut swap_inputs = vec![];
+    for fund in funds {
+        // Coins already in the asked denom are forwarded as they are
+        if fund.denom == to_denom {
+            consolidation.total_amount = consolidation.total_amount.checked_add(fund.amount)?;
+            continue;
+        }
+        record_swap_volume(
+            ctx.deps.branch(),
+            &ctx.env,
+            &origin,
+            &fund.denom,
+            fund.amount,
+        )?;
+        swap_inputs.push(fund);
+    }
+
+    consolidate(ctx, consolidation, swap_inputs, sender, slippage)
+}
+
+/// Swaps every input into the asked denom of `consolidation`,
+/// the combined amount is forwarded once the last swap replies.
+fn consolidate(
+    mut ctx: ExecuteContext,
+    mut consolidation: Consolidation,
+    inputs: Vec<Coin>,
+    refund_addr: AndrAddr,
+    slippage: Option<Slippage>,
+) -> Result<Response, ContractError> {
+    let consolidation_id = next_swap_id(ctx.deps.storage)?;
+    let to_denom = consolidation.to_denom.clone();
+    let mut swap_msgs = vec![];
+    for input in inputs {
+        let leg_ctx = ExecuteContext {
+            deps: ctx.deps.branch(),
+            info: ctx.info.clone(),
+            env: ctx.env.clone(),
+            amp_ctx: ctx.amp_ctx.clone(),
+        };
+        let swap_msg = execute_swap_osmosis_msg(
+            leg_ctx,
+            input.denom,
+            input.amount,
+            // The fee is taken once from the consolidated amount
+            Uint128::zero(),
+            to_denom.clone(),
+            consolidation.recipients.clone(),
+            refund_addr.clone(),
+            slippage.clone(),
+            None,
+            false,
+            None,
+            None,
+            Some(consolidation_id),
+        )?;
+        swap_msgs.push(swap_msg);
+        consolidation.pending_swaps += 1;
+    }
+
+    let resp = Response::default()
+        .add_submessages(swap_msgs)
+        .add_attributes(vec![
+            attr("action", "consolidate"),
+            attr("consolidation_id", consolidation_id.to_string()),
+            attr("to_denom", to_denom),
+            attr("pending_swaps", consolidation.pending_swaps.to_string()),
+        ]);
+    if consolidation.pending_swaps > 0 {
+        CONSOLIDATIONS.save(ctx.deps.storage, consolidation_id, &consolidation)?;
+        return Ok(resp);
+    }
+
+    // Nothing to swap, every coin is already in the asked denom
+    let forward_resp = forward_consolidation(ctx.deps, &ctx.env, consolidation_id, consolidation)?;
+    Ok(resp
+        .add_submessages(forward_resp.messages)
+        .add_attributes(forward_resp.attributes))
+}
+
+fn execute_convert_fees(
+    ctx: ExecuteContext,
+    denoms: Option<Vec<String>>,
+    slippage: Option<Slippage>,
+    minimum_receive: Option<Uint128>,
+) -> Result<Response, ContractError> {
+    ensure_has_role(ctx.deps.as_ref(), &ctx.info.sender, Role::Keeper)?;
+    let treasury_config = TREASURY_CONFIG
+        .may_load(ctx.deps.storage)?
+        .ok_or_else(|| ContractError::Std(StdError::generic_err("No treasury is set")))?;
+
+    let denoms = match denoms {
+        Some(denoms) => denoms,
+        None => ACCRUED_FEES
+            .keys(ctx.deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<String>>>()?,
+    };
+
+    let mut consolidation = Consolidation {
+        recipients: vec![WeightedRecipient::new(
+            Recipient::new(treasury_config.recipient.clone(), None),
+            Uint128::one(),
+        )],
+        amp_ctx: None,
+        to_denom: treasury_config.denom.clone(),
+        minimum_receive,
+        pending_swaps: 0,
+        total_amount: Uint128::zero(),
+        received_amount: Uint128::zero(),
+        charge_fee: false,
+    };
+    let mut inputs = vec![];
+    for denom in denoms {
+        let Some(amount) = ACCRUED_FEES.may_load(ctx.deps.storage, &denom)? else {
+            continue;
+        };
+        // Converted fees are released before the swaps record their previous balance
+        ACCRUED_FEES.remove(ctx.deps.storage, &denom);
+        if denom == treasury_config.denom {
+            consolidation.total_amount = consolidation.total_amount.checked_add(amount)?;
+        } else {
+            inputs.push(coin(amount.u128(), denom));
+        }
+    }
+    ensure!(
+        !inputs.is_empty() || !consolidation.total_amount.is_zero(),
+        ContractError::Std(StdError::generic_err("No accrued fees to convert"))
+    );
+
+    let consolidate_resp = consolidate(
+        ctx,
+        consolidation,
+        inputs,
+        treasury_config.recipient,
+        slippage,
+    )?;
+    Ok(Response::default()
+        .add_attribute("action", "convert_fees")
+        .add_submessages(consolidate_resp.messages)
+        .add_attributes(consolidate_resp.attributes))
+}
+
+fn execute_propose_swap_router(
     ctx: ExecuteContext,
     swap_router: AndrAddr,
+    code_pin: Option<CodePin>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, env, .. } = ctx;
+
+    let swap_router_addr = swap_router.get_raw_address(&deps.as_ref())?;
+    if let Some(code_pin) = &code_pin {
+        code_pin.verify(&deps.as_ref(), &swap_router_addr)?;
+    }
+    let timelock_seconds = load_swap_router_timelock(deps.storage)?;
+    // `plus_seconds` panics on overflow
+    let executable_at = timelock_seconds
+        .checked_mul(1_000_000_000)
+        .and_then(|nanos| env.block.time.nanos().checked_add(nanos))
+        .map(Timestamp::from_nanos)
+        .ok_or_else(|| {
+            ContractError::Std(StdError::generic_err(format!(
+                "A swap router timelock of {} seconds is out of range",
+                timelock_seconds
+            )))
+        })?;
+
+    // A new proposal replaces the pending one and restarts the timelock
+    SWAP_ROUTER_PROPOSAL.save(
+        deps.storage,
+        &SwapRouterProposal {
+            swap_router: swap_router.clone(),
+            code_pin: code_pin.clone(),
+            proposed_by: sender,
+            executable_at,
+        },
+    )?;
+    Ok(Response::new()
+        .add_attributes(vec![
+            attr("action", "propose-swap-router"),
+            attr("swap_router", swap_router),
+            attr("executable_at", executable_at.to_string()),
+        ])
+        .add_attributes(code_pin.map(|code_pin| attr("code_pin", code_pin.to_string()))))
+}
+
+fn execute_accept_swap_router(ctx: ExecuteContext) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, env, .. } = ctx;
+
+    let Some(proposal) = SWAP_ROUTER_PROPOSAL.may_load(deps.storage)? else {
+        return Err(ContractError::Std(StdError::generic_err(
+            "No swap router is proposed",
+        )));
+    };
+    ensure!(
+        env.block.time >= proposal.executable_at,
+        ContractError::Std(StdError::generic_err(format!(
+            "The proposed swap router can be accepted from {}",
+            proposal.executable_at
+        )))
+    );
+    // The router may have been migrated to other code since it was proposed
+    let swap_router_addr = proposal.swap_router.get_raw_address(&deps.as_ref())?;
+    if let Some(code_pin) = &proposal.code_pin {
+        code_pin.verify(&deps.as_ref(), &swap_router_addr)?;
+    }
+    let previous_swap_router = SWAP_ROUTER.load(deps.storage)?;
+
+    SWAP_ROUTER.save(deps.storage, &proposal.swap_router)?;
+    match &proposal.code_pin {
+        Some(code_pin) => SWAP_ROUTER_PIN.save(deps.storage, code_pin)?,
+        None => SWAP_ROUTER_PIN.remove(deps.storage),
+    }
+    SWAP_ROUTER_PROPOSAL.remove(deps.storage);
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "accept-swap-router"),
+        attr("previous_swap_router", previous_swap_router),
+        attr("swap_router", proposal.swap_router),
+    ]))
+}
+
+fn execute_cancel_swap_router(ctx: ExecuteContext) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let Some(proposal) = SWAP_ROUTER_PROPOSAL.may_load(deps.storage)? else {
+        return Err(ContractError::Std(StdError::generic_err(
+            "No swap router is proposed",
+        )));
+    };
+    SWAP_ROUTER_PROPOSAL.remove(deps.storage);
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "cancel-swap-router"),
+        attr("swap_router", proposal.swap_router),
+    ]))
+}
+
+fn execute_set_swap_router_timelock(
+    ctx: ExecuteContext,
+    timelock_seconds: u64,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    // Lowering the timelock would let a single key skip it
+    let current_timelock = load_swap_router_timelock(deps.storage)?;
+    ensure!(
+        timelock_seconds >= current_timelock,
+        ContractError::Std(StdError::generic_err(format!(
+            "The swap router timelock can only be raised, it is {} seconds",
+            current_timelock
+        )))
+    );
+    validate_swap_router_timelock(timelock_seconds)?;
+
+    SWAP_ROUTER_TIMELOCK.save(deps.storage, &timelock_seconds)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-swap-router-timelock"),
+        attr("timelock_seconds", timelock_seconds.to_string()),
+    ]))
+}
+
+fn execute_pause(ctx: ExecuteContext, scope: PauseScope) -> Result<Response, ContractError> {
+    let ExecuteContext {
+        deps, info, env, ..
+    } = ctx;
+    ensure_has_role(deps.as_ref(), &info.sender, Role::Pauser)?;
+
+    let key = scope.key();
+    ensure!(
+        !PAUSES.has(deps.storage, &key),
+        ContractError::Std(StdError::generic_err(format!(
+            "{} are already paused",
+            scope
+        )))
+    );
+    PAUSES.save(
+        deps.storage,
+        &key,
+        &Pause {
+            scope: scope.clone(),
+            paused_by: info.sender.clone(),
+            paused_at: env.block.time,
+        },
+    )?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "pause"),
+        attr("scope", scope.to_string()),
+        attr("paused_by", info.sender),
+    ]))
+}
+
+fn execute_unpause(ctx: ExecuteContext, scope: PauseScope) -> Result<Response, ContractError> {
+    let ExecuteContext { deps, info, .. } = ctx;
+    ensure_has_role(deps.as_ref(), &info.sender, Role::Pauser)?;
+
+    let key = scope.key();
+    ensure!(
+        PAUSES.has(deps.storage, &key),
+        ContractError::Std(StdError::generic_err(format!("{} are not paused", scope)))
+    );
+    PAUSES.remove(deps.storage, &key);
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "unpause"),
+        attr("scope", scope.to_string()),
+        attr("unpaused_by", info.sender),
+    ]))
+}
+
+/// Owners and operators hold every role
+fn ensure_has_role(deps: Deps, sender: &Addr, role: Role) -> Result<(), ContractError> {
+    ensure!(
+        ROLES.has(deps.storage, (role.as_str(), sender))
+            || ADOContract::default().is_owner_or_operator(deps.storage, sender.as_str())?,
+        ContractError::Unauthorized {}
+    );
+    Ok(())
+}
+
+fn execute_grant_role(
+    ctx: ExecuteContext,
+    role: Role,
+    address: AndrAddr,
 ) -> Result<Response, ContractError> {
     let sender = ctx.info.sender;
     ensure!(
@@ -144,47 +883,611 @@ fn execute_update_swap_router(
     );
     let ExecuteContext { deps, .. } = ctx;
 
-    swap_router.get_raw_address(&deps.as_ref())?;
-    let previous_swap_router = SWAP_ROUTER.load(deps.storage)?;
+    let address = address.get_raw_address(&deps.as_ref())?;
+    ROLES.save(deps.storage, (role.as_str(), &address), &Empty {})?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "grant-role"),
+        attr("role", role.to_string()),
+        attr("address", address),
+    ]))
+}
 
-    SWAP_ROUTER.save(deps.storage, &swap_router)?;
+fn execute_revoke_role(
+    ctx: ExecuteContext,
+    role: Role,
+    address: AndrAddr,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure!(
+        ADOContract::default().is_owner_or_operator(ctx.deps.storage, sender.as_ref())?,
+        ContractError::Unauthorized {}
+    );
+    let ExecuteContext { deps, .. } = ctx;
+
+    let address = address.get_raw_address(&deps.as_ref())?;
+    ensure!(
+        ROLES.has(deps.storage, (role.as_str(), &address)),
+        ContractError::Std(StdError::generic_err(format!(
+            "{} does not hold the {} role",
+            address, role
+        )))
+    );
+    ROLES.remove(deps.storage, (role.as_str(), &address));
     Ok(Response::new().add_attributes(vec![
-        attr("action", "update-swap-router"),
-        attr("previous_swap_router", previous_swap_router),
-        attr("swap_router", swap_router),
+        attr("action", "revoke-role"),
+        attr("role", role.to_string()),
+        attr("address", address),
+    ]))
+}
+
+fn execute_set_fee_config(
+    ctx: ExecuteContext,
+    fee_config: Option<FeeConfig>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::FeeAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let Some(fee_config) = fee_config else {
+        FEE_CONFIG.remove(deps.storage);
+        return Ok(Response::new().add_attribute("action", "remove-fee-config"));
+    };
+    fee_config.validate(&deps.as_ref())?;
+
+    FEE_CONFIG.save(deps.storage, &fee_config)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-fee-config"),
+        attr("fee_bps", fee_config.fee_bps.to_string()),
+        attr("fee_recipient", fee_config.fee_recipient),
+    ]))
+}
+
+fn execute_set_treasury_config(
+    ctx: ExecuteContext,
+    treasury_config: Option<TreasuryConfig>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::FeeAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    // Fees accrued so far stay in the contract and can still be converted once a treasury is set
+    let Some(treasury_config) = treasury_config else {
+        TREASURY_CONFIG.remove(deps.storage);
+        return Ok(Response::new().add_attribute("action", "remove-treasury-config"));
+    };
+    treasury_config.recipient.get_raw_address(&deps.as_ref())?;
+
+    TREASURY_CONFIG.save(deps.storage, &treasury_config)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-treasury-config"),
+        attr("treasury_denom", &treasury_config.denom),
+        attr("treasury_recipient", treasury_config.recipient),
+    ]))
+}
+
+fn execute_set_slippage_policy(
+    ctx: ExecuteContext,
+    slippage_policy: Option<SlippagePolicy>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let Some(slippage_policy) = slippage_policy else {
+        SLIPPAGE_POLICY.remove(deps.storage);
+        return Ok(Response::new().add_attribute("action", "remove-slippage-policy"));
+    };
+    slippage_policy.validate()?;
+
+    SLIPPAGE_POLICY.save(deps.storage, &slippage_policy)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-slippage-policy"),
+        attr(
+            "default_slippage",
+            slippage_policy.default_slippage.to_string(),
+        ),
+        attr("max_slippage", slippage_policy.max_slippage.to_string()),
+    ]))
+}
+
+fn execute_set_pair_slippage_policy(
+    ctx: ExecuteContext,
+    from_denom: String,
+    to_denom: String,
+    slippage_policy: Option<SlippagePolicy>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let pair_key = (from_denom.clone(), to_denom.clone());
+    let action = match slippage_policy {
+        Some(slippage_policy) => {
+            slippage_policy.validate()?;
+            PAIR_SLIPPAGE_POLICIES.save(deps.storage, pair_key, &slippage_policy)?;
+            "set-pair-slippage-policy"
+        }
+        None => {
+            PAIR_SLIPPAGE_POLICIES.remove(deps.storage, pair_key);
+            "remove-pair-slippage-policy"
+        }
+    };
+    Ok(Response::new().add_attributes(vec![
+        attr("action", action),
+        attr("from_denom", from_denom),
+        attr("to_denom", to_denom),
+    ]))
+}
+
+fn execute_set_twap_guard(
+    ctx: ExecuteContext,
+    twap_guard: Option<TwapGuard>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let Some(twap_guard) = twap_guard else {
+        TWAP_GUARD.remove(deps.storage);
+        return Ok(Response::new().add_attribute("action", "remove-twap-guard"));
+    };
+    twap_guard.validate()?;
+
+    TWAP_GUARD.save(deps.storage, &twap_guard)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-twap-guard"),
+        attr("window_seconds", twap_guard.window_seconds.to_string()),
+        attr("max_deviation", twap_guard.max_deviation.to_string()),
     ]))
 }
+
+fn execute_update_asset_list(
+    ctx: ExecuteContext,
+    list: AssetList,
+    add: Vec<String>,
+    remove: Vec<String>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let asset_list = match list {
+        AssetList::Allow => ALLOWED_ASSETS,
+        AssetList::Deny => DENIED_ASSETS,
+    };
+    for denom in add.iter() {
+        asset_list.save(deps.storage, denom, &Empty {})?;
+    }
+    for denom in remove.iter() {
+        asset_list.remove(deps.storage, denom);
+    }
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "update-asset-list"),
+        attr("list", list.to_string()),
+        attr("added", add.len().to_string()),
+        attr("removed", remove.len().to_string()),
+    ]))
+}
+
+fn execute_set_volume_limit(
+    ctx: ExecuteContext,
+    denom: String,
+    volume_limit: Option<VolumeLimit>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let Some(volume_limit) = volume_limit else {
+        VOLUME_LIMITS.remove(deps.storage, &denom);
+        return Ok(Response::new().add_attributes(vec![
+            attr("action", "remove-volume-limit"),
+            attr("denom", denom),
+        ]));
+    };
+    volume_limit.validate()?;
+
+    VOLUME_LIMITS.save(deps.storage, &denom, &volume_limit)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-volume-limit"),
+        attr("denom", denom),
+        attr("window_seconds", volume_limit.window_seconds.to_string()),
+    ]))
+}
+
+fn execute_set_referral_share(
+    ctx: ExecuteContext,
+    referrer: AndrAddr,
+    share_bps: Option<u16>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::FeeAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let referrer = referrer.get_raw_address(&deps.as_ref())?;
+    let Some(share_bps) = share_bps else {
+        REFERRAL_SHARES.remove(deps.storage, &referrer);
+        return Ok(Response::new().add_attributes(vec![
+            attr("action", "remove-referral-share"),
+            attr("referrer", referrer),
+        ]));
+    };
+    validate_referral_share(share_bps)?;
+
+    REFERRAL_SHARES.save(deps.storage, &referrer, &share_bps)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-referral-share"),
+        attr("referrer", referrer),
+        attr("share_bps", share_bps.to_string()),
+    ]))
+}
+
+fn execute_update_default_referral_share(
+    ctx: ExecuteContext,
+    share_bps: u16,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::FeeAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    validate_referral_share(share_bps)?;
+
+    DEFAULT_REFERRAL_SHARE.save(deps.storage, &share_bps)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "update-default-referral-share"),
+        attr("share_bps", share_bps.to_string()),
+    ]))
+}
+
+fn execute_update_max_hops(ctx: ExecuteContext, max_hops: u32) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    ensure!(
+        max_hops > 0,
+        ContractError::Std(StdError::generic_err("Max hops must be at least 1"))
+    );
+    let previous_max_hops = load_max_hops(deps.storage)?;
+
+    MAX_HOPS.save(deps.storage, &max_hops)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "update-max-hops"),
+        attr("previous_max_hops", previous_max_hops.to_string()),
+        attr("max_hops", max_hops.to_string()),
+    ]))
+}
+
+fn execute_set_route(
+    ctx: ExecuteContext,
+    from_denom: String,
+    to_denom: String,
+    route: Vec<SwapRoute>,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    ensure!(from_denom != to_denom, ContractError::DuplicateTokens {});
+    validate_osmosis_route(deps.as_ref(), &from_denom, &to_denom, &route)?;
+
+    ROUTES.save(deps.storage, (from_denom.clone(), to_denom.clone()), &route)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "set-route"),
+        attr("from_denom", from_denom),
+        attr("to_denom", to_denom),
+        attr("hops", route.len().to_string()),
+    ]))
+}
+
+fn execute_remove_route(
+    ctx: ExecuteContext,
+    from_denom: String,
+    to_denom: String,
+) -> Result<Response, ContractError> {
+    let sender = ctx.info.sender;
+    ensure_has_role(ctx.deps.as_ref(), &sender, Role::RouterAdmin)?;
+    let ExecuteContext { deps, .. } = ctx;
+
+    let route_key = (from_denom.clone(), to_denom.clone());
+    ensure!(
+        ROUTES.has(deps.storage, route_key.clone()),
+        ContractError::Std(StdError::generic_err(format!(
+            "No route registered from {} to {}",
+            from_denom, to_denom
+        )))
+    );
+    ROUTES.remove(deps.storage, route_key);
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "remove-route"),
+        attr("from_denom", from_denom),
+        attr("to_denom", to_denom),
+    ]))
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
     match msg {
         QueryMsg::GetRoute {
             from_denom,
             to_denom,
         } => encode_binary(&query_get_route(deps, from_denom, to_denom)?),
+        QueryMsg::SimulatePriceImpact {
+            from_denom,
+            to_denom,
+            amount,
+            route,
+        } => {
+            let route = resolve_osmosis_route(deps, &from_denom, &to_denom, route)?;
+            encode_binary(&estimate_osmosis_price_impact(
+                deps,
+                &from_denom,
+                amount,
+                &route,
+            )?)
+        }
+        QueryMsg::PauseStatus { start_after, limit } => {
+            encode_binary(&query_pause_status(deps, start_after, limit)?)
+        }
+        QueryMsg::Roles {
+            role,
+            start_after,
+            limit,
+        } => encode_binary(&query_roles(deps, role, start_after, limit)?),
+        QueryMsg::SwapRouter {} => encode_binary(&SwapRouterResponse {
+            swap_router: SWAP_ROUTER.load(deps.storage)?,
+            code_pin: SWAP_ROUTER_PIN.may_load(deps.storage)?,
+            timelock_seconds: load_swap_router_timelock(deps.storage)?,
+            proposal: SWAP_ROUTER_PROPOSAL.may_load(deps.storage)?,
+        }),
+        QueryMsg::FeeConfig {} => encode_binary(&FeeConfigResponse {
+            fee_config: FEE_CONFIG.may_load(deps.storage)?,
+        }),
+        QueryMsg::TreasuryConfig {} => encode_binary(&TreasuryConfigResponse {
+            treasury_config: TREASURY_CONFIG.may_load(deps.storage)?,
+        }),
+        QueryMsg::AccruedFees { start_after, limit } => {
+            encode_binary(&query_accrued_fees(deps, start_after, limit)?)
+        }
+        QueryMsg::SlippagePolicy {} => encode_binary(&SlippagePolicyResponse {
+            slippage_policy: SLIPPAGE_POLICY.may_load(deps.storage)?,
+        }),
+        QueryMsg::PairSlippagePolicy {
+            from_denom,
+            to_denom,
+        } => encode_binary(&SlippagePolicyResponse {
+            slippage_policy: load_slippage_policy(deps.storage, (from_denom, to_denom))?,
+        }),
+        QueryMsg::TwapGuard {} => encode_binary(&TwapGuardResponse {
+            twap_guard: TWAP_GUARD.may_load(deps.storage)?,
+        }),
+        QueryMsg::AssetList {
+            list,
+            start_after,
+            limit,
+        } => encode_binary(&query_asset_list(deps, list, start_after, limit)?),
+        QueryMsg::VolumeCapacity { address, denom } => {
+            encode_binary(&query_volume_capacity(deps, env, address, denom)?)
+        }
+        QueryMsg::ReferralShare { referrer } => encode_binary(&ReferralShareResponse {
+            share_bps: load_referral_share(deps.storage, &referrer.get_raw_address(&deps)?)?,
+        }),
+        QueryMsg::ReferralEarnings {
+            referrer,
+            start_after,
+            limit,
+        } => encode_binary(&query_referral_earnings(
+            deps,
+            referrer,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::MaxHops {} => encode_binary(&MaxHopsResponse {
+            max_hops: load_max_hops(deps.storage)?,
+        }),
+        QueryMsg::Routes { start_after, limit } => {
+            encode_binary(&query_routes(deps, start_after, limit)?)
+        }
+        QueryMsg::FindRoute {
+            from_denom,
+            to_denom,
+            amount,
+        } => encode_binary(&find_osmosis_route(deps, &from_denom, &to_denom, amount)?),
     }
 }
 
+fn query_routes(
+    deps: Deps,
+    start_after: Option<(String, String)>,
+    limit: Option<u32>,
+) -> Result<RoutesResponse, ContractError> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let routes = ROUTES
+        .range(
+            deps.storage,
+            start_after.map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| {
+            let ((from_denom, to_denom), route) = item?;
+            Ok(RouteInfo {
+                from_denom,
+                to_denom,
+                route,
+            })
+        })
+        .collect::<Result<Vec<RouteInfo>, ContractError>>()?;
+    Ok(RoutesResponse { routes })
+}
+
+fn query_pause_status(
+    deps: Deps,
+    start_after: Option<PauseScope>,
+    limit: Option<u32>,
+) -> Result<PauseStatusResponse, ContractError> {
+    let start = start_after.map(|scope| scope.key());
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let pauses = PAUSES
+        .range(
+            deps.storage,
+            start.as_deref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| item.map(|(_, pause)| pause))
+        .collect::<StdResult<Vec<Pause>>>()?;
+    Ok(PauseStatusResponse { pauses })
+}
+
+fn query_roles(
+    deps: Deps,
+    role: Role,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> Result<RolesResponse, ContractError> {
+    let start = start_after
+        .map(|address| deps.api.addr_validate(&address))
+        .transpose()?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let addresses = ROLES
+        .prefix(role.as_str())
+        .keys(
+            deps.storage,
+            start.as_ref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .collect::<StdResult<Vec<Addr>>>()?;
+    Ok(RolesResponse { role, addresses })
+}
+
+fn query_asset_list(
+    deps: Deps,
+    list: AssetList,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> Result<AssetListResponse, ContractError> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let asset_list = match list {
+        AssetList::Allow => ALLOWED_ASSETS,
+        AssetList::Deny => DENIED_ASSETS,
+    };
+    let denoms = asset_list
+        .keys(
+            deps.storage,
+            start_after.as_deref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .collect::<StdResult<Vec<String>>>()?;
+    Ok(AssetListResponse { denoms })
+}
+
+fn query_volume_capacity(
+    deps: Deps,
+    env: Env,
+    address: AndrAddr,
+    denom: String,
+) -> Result<VolumeCapacityResponse, ContractError> {
+    let address = address.get_raw_address(&deps)?;
+    let Some(volume_limit) = VOLUME_LIMITS.may_load(deps.storage, &denom)? else {
+        return Ok(VolumeCapacityResponse {
+            volume_limit: None,
+            sender_remaining: None,
+            asset_remaining: None,
+        });
+    };
+
+    let since = volume_limit.window_start(env.block.time.seconds());
+    let sender_remaining = volume_limit
+        .max_sender_volume
+        .map(|max_volume| -> StdResult<Uint128> {
+            Ok(max_volume.saturating_sub(sender_volume(deps.storage, &address, &denom, since)?))
+        })
+        .transpose()?;
+    let asset_remaining = volume_limit
+        .max_asset_volume
+        .map(|max_volume| -> StdResult<Uint128> {
+            Ok(max_volume.saturating_sub(asset_volume(deps.storage, &denom, since)?))
+        })
+        .transpose()?;
+    Ok(VolumeCapacityResponse {
+        volume_limit: Some(volume_limit),
+        sender_remaining,
+        asset_remaining,
+    })
+}
+
+fn query_accrued_fees(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> Result<AccruedFeesResponse, ContractError> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let fees = ACCRUED_FEES
+        .range(
+            deps.storage,
+            start_after.as_deref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| {
+            let (denom, amount) = item?;
+            Ok(coin(amount.u128(), denom))
+        })
+        .collect::<StdResult<Vec<Coin>>>()?;
+    Ok(AccruedFeesResponse { fees })
+}
+
+fn query_referral_earnings(
+    deps: Deps,
+    referrer: AndrAddr,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> Result<ReferralEarningsResponse, ContractError> {
+    let referrer = referrer.get_raw_address(&deps)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let earnings = REFERRAL_EARNINGS
+        .prefix(&referrer)
+        .range(
+            deps.storage,
+            start_after.as_deref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| {
+            let (denom, amount) = item?;
+            Ok(ReferralEarning { denom, amount })
+        })
+        .collect::<Result<Vec<ReferralEarning>, ContractError>>()?;
+    Ok(ReferralEarningsResponse { earnings })
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn migrate(deps: DepsMut, _env: Env, _msg: MigrateMsg) -> Result<Response, ContractError> {
+    // Swap state lives in maps keyed by swap id now, the old single items are left over at most
+    for key in LEGACY_SWAP_STATE_KEYS {
+        deps.storage.remove(key);
+    }
     ADOContract::default().migrate(deps, CONTRACT_NAME, CONTRACT_VERSION)
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
     match msg.id {
-        OSMOSIS_MSG_SWAP_ID => {
-            let state: ForwardReplyState = FORWARD_REPLY_STATE.load(deps.storage)?;
-            FORWARD_REPLY_STATE.remove(deps.storage);
-
-            if msg.result.is_err() {
-                Err(ContractError::Std(StdError::generic_err(format!(
-                    "Osmosis swap failed with error: {:?}",
-                    msg.result.unwrap_err()
-                ))))
-            } else {
-                handle_osmosis_swap_reply(deps, env, msg, state)
-            }
-        }
         OSMOSIS_MSG_FORWARD_ID => {
             if msg.result.is_err() {
                 return Err(ContractError::Std(StdError::generic_err(format!(
@@ -195,6 +1498,26 @@ pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractEr
             Ok(Response::default()
                 .add_attributes(vec![attr("action", "message_forwarded_success")]))
         }
+        id if id >= OSMOSIS_MSG_SWAP_ID_OFFSET => {
+            let swap_id = id - OSMOSIS_MSG_SWAP_ID_OFFSET;
+            let state: ForwardReplyState = FORWARD_REPLY_STATE.load(deps.storage, swap_id)?;
+            FORWARD_REPLY_STATE.remove(deps.storage, swap_id);
+
+            if msg.result.is_err() {
+                let error = msg.result.unwrap_err();
+                if state.refund_on_failure {
+                    return handle_osmosis_swap_failure(deps, env, error, swap_id, state);
+                }
+                Err(ContractError::Std(StdError::generic_err(format!(
+                    "Osmosis swap failed with error: {:?}",
+                    error
+                ))))
+            } else if let Some(consolidation_id) = state.consolidation_id {
+                handle_osmosis_consolidation_reply(deps, env, swap_id, consolidation_id, state)
+            } else {
+                handle_osmosis_swap_reply(deps, env, msg, swap_id, state)
+            }
+        }
         _ => Err(ContractError::Std(StdError::GenericErr {
             msg: "Invalid Reply ID".to_string(),
         })),
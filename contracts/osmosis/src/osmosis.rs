@@ -1,48 +1,96 @@
+use std::{collections::HashMap, str::FromStr};
+
 use andromeda_std::{
     ado_contract::ADOContract,
     amp::{
-        messages::{AMPMsg, AMPPkt},
+        messages::{AMPCtx, AMPMsg, AMPPkt},
         AndrAddr, Recipient,
     },
     common::context::ExecuteContext,
     error::ContractError,
 };
 use cosmwasm_std::{
-    attr, coin, ensure, to_json_binary, Coin, Deps, DepsMut, Env, Reply, Response, StdError,
-    SubMsg, Uint128, WasmMsg,
+    attr, coin, ensure, to_json_binary, Addr, BankMsg, Binary, Coin, Decimal, Deps, DepsMut, Env,
+    Event, Order, Reply, Response, StdError, StdResult, Storage, SubMsg, Uint128, WasmMsg,
+};
+use cw20::Expiration;
+use cw_storage_plus::Bound;
+use osmosis_std::{
+    shim::{Any, Timestamp},
+    types::{
+        cosmos::base::{
+            query::v1beta1::{PageRequest, PageResponse},
+            v1beta1::Coin as OsmosisCoin,
+        },
+        osmosis::{
+            concentratedliquidity::v1beta1::{
+                ConcentratedliquidityQuerier, Pool as ConcentratedPool,
+            },
+            gamm::{
+                poolmodels::stableswap::v1beta1::Pool as StableswapPool,
+                v1beta1::{GammQuerier, Pool as BalancerPool},
+            },
+            poolmanager::v1beta1::{MsgSwapExactAmountOut, PoolmanagerQuerier, SwapAmountOutRoute},
+            twap::v1beta1::TwapQuerier,
+        },
+    },
 };
 use swaprouter::msg::{ExecuteMsg as OsmosisExecuteMsg, QueryMsg as OsmosisQueryMsg};
 
-use crate::state::{ForwardReplyState, FORWARD_REPLY_STATE, PREV_BALANCE, SWAP_ROUTER};
+use crate::state::{
+    asset_volume, load_max_hops, load_referral_share, load_slippage_policy, load_swap_router,
+    next_swap_id, sender_volume, Consolidation, ForwardReplyState, ACCRUED_FEES, ALLOWED_ASSETS,
+    ASSET_VOLUMES, CONSOLIDATIONS, DENIED_ASSETS, FEE_CONFIG, FORWARD_REPLY_STATE, PAUSES,
+    PREV_BALANCE, REFERRAL_EARNINGS, ROUTES, SENDER_VOLUMES, SWAP_ROUTER, TREASURY_CONFIG,
+    TWAP_GUARD, VOLUME_LIMITS,
+};
 
-use andromeda_swap_and_forward::osmosis::{GetRouteResponse, Slippage, SwapRoute};
+use andromeda_swap_and_forward::{
+    common::{
+        ensure_price_impact, find_paths, paused_error, price_impact, price_impact_probe,
+        recipient_shares, referral_amount, FeeSide, WeightedRecipient,
+    },
+    osmosis::{
+        FindRouteResponse, GetRouteResponse, PauseScope, PriceImpactResponse, Slippage, SwapRoute,
+    },
+};
 
-pub const OSMOSIS_MSG_SWAP_ID: u64 = 1;
 pub const OSMOSIS_MSG_FORWARD_ID: u64 = 2;
+/// Swap submessages reply with `OSMOSIS_MSG_SWAP_ID_OFFSET + swap_id`
+pub const OSMOSIS_MSG_SWAP_ID_OFFSET: u64 = 1_000;
+
+/// Longest route tried by route discovery, further capped by the configured max hops
+pub const MAX_ROUTE_HOPS: usize = 3;
+/// Candidate routes estimated by route discovery
+pub const MAX_ROUTE_CANDIDATES: usize = 10;
+/// Pools route discovery reads at most, keeping `FindRoute` within the query gas limit
+pub const MAX_ROUTE_POOLS: usize = 1_000;
+const POOLS_PAGE_LIMIT: u64 = 100;
+/// Twap slippage swaps run with when neither the caller nor a slippage policy gives one, 1%
+pub const DEFAULT_SLIPPAGE_PERCENTAGE: Decimal = Decimal::raw(10_000_000_000_000_000);
 
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn execute_swap_osmosis_msg(
     ctx: ExecuteContext,
     from_denom: String,
-    from_amount: Uint128,
+    from_amount: Uint128, // amount swapped, without the input fee
+    input_fee: Uint128,   // input fee held back until the swap succeeds
     to_denom: String,
-    recipient: Recipient,  // receiver where the swapped token goes to
-    refund_addr: AndrAddr, // refund address
-    slippage: Slippage,
+    recipients: Vec<WeightedRecipient>, // receivers where the swapped token goes to
+    refund_addr: AndrAddr,              // refund address
+    slippage: Option<Slippage>,
     route: Option<Vec<SwapRoute>>,
+    refund_on_failure: bool,
+    referrer: Option<Addr>,
+    batch_leg: Option<u32>,
+    consolidation_id: Option<u64>,
 ) -> Result<SubMsg, ContractError> {
     let ExecuteContext { deps, env, .. } = ctx;
 
     // Prepare offer and ask asset
     ensure!(from_denom != to_denom, ContractError::DuplicateTokens {});
-
-    // Prepare swap operations
-    ensure!(
-        FORWARD_REPLY_STATE
-            .may_load(deps.as_ref().storage)?
-            .is_none(),
-        ContractError::Unauthorized {}
-    );
+    ensure_denom_allowed(deps.as_ref(), &from_denom)?;
+    ensure_denom_allowed(deps.as_ref(), &to_denom)?;
 
     let amp_ctx = if let Some(pkt) = ctx.amp_ctx.clone() {
         Some(pkt.ctx)
@@ -51,29 +99,46 @@ pub(crate) fn execute_swap_osmosis_msg(
     };
 
     // Generate route for the `OsmosisExecuteMsg::Swap` message
-    let route = route.map(|route| route.iter().map(|v| v.clone().into()).collect());
+    let route = resolve_osmosis_route(deps.as_ref(), &from_denom, &to_denom, route)?;
+    ensure_not_paused(deps.as_ref(), &from_denom, &to_denom, &route)?;
+    check_twap_deviation(deps.as_ref(), &env, &from_denom, &route)?;
+    let slippage = resolve_slippage(
+        deps.as_ref(),
+        &from_denom,
+        from_amount,
+        &to_denom,
+        &route,
+        slippage,
+    )?;
+    let route = Some(route.iter().map(|v| v.clone().into()).collect());
 
-    let prev_balance = deps
-        .querier
-        .query_balance(env.contract.address.to_string(), &to_denom)?
-        .amount;
+    let prev_balance = query_balance(&deps.as_ref(), &env, &to_denom)?;
 
+    let swap_id = next_swap_id(deps.storage)?;
     FORWARD_REPLY_STATE.save(
         deps.storage,
+        swap_id,
         &ForwardReplyState {
-            recipient,
+            recipients,
             refund_addr,
             amp_ctx,
             from_denom: from_denom.clone(),
+            from_amount,
+            offered_amount: from_amount.checked_add(input_fee)?,
+            input_fee,
             to_denom: to_denom.clone(),
+            refund_on_failure,
+            prev_from_balance: None,
+            output_fee: Uint128::zero(),
+            referrer,
+            batch_leg,
+            consolidation_id,
         },
     )?;
 
-    PREV_BALANCE.save(deps.storage, &prev_balance)?;
+    PREV_BALANCE.save(deps.storage, swap_id, &prev_balance)?;
 
-    let swap_router = SWAP_ROUTER
-        .load(deps.storage)?
-        .get_raw_address(&deps.as_ref())?;
+    let swap_router = load_swap_router(&deps.as_ref())?;
     let swap_msg = OsmosisExecuteMsg::Swap {
         input_coin: coin(from_amount.u128(), from_denom.clone()),
         output_denom: to_denom,
@@ -86,22 +151,143 @@ pub(crate) fn execute_swap_osmosis_msg(
         funds: vec![coin(from_amount.u128(), from_denom)],
     };
 
-    Ok(SubMsg::reply_always(msg, OSMOSIS_MSG_SWAP_ID))
+    Ok(SubMsg::reply_always(
+        msg,
+        OSMOSIS_MSG_SWAP_ID_OFFSET + swap_id,
+    ))
+}
+
+/// Builds a poolmanager swap delivering exactly `to_amount` of `to_denom` for at most
+/// `from_amount` of `from_denom`. The unused input is refunded in the reply.
+/// The fee is paid on top: a reserve for an input fee is held back from the swap and settled
+/// in the reply, an output fee is asked from the swap along with `to_amount`.
+/// Returns the swap along with its price impact when `max_price_impact` is given.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn execute_swap_exact_out_osmosis_msg(
+    ctx: ExecuteContext,
+    from_denom: String,
+    from_amount: Uint128,
+    to_denom: String,
+    to_amount: Uint128,
+    recipient: Recipient,
+    refund_addr: AndrAddr,
+    slippage_percentage: Option<Decimal>,
+    max_price_impact: Option<Decimal>,
+    route: Option<Vec<SwapRoute>>,
+    refund_on_failure: bool,
+) -> Result<(SubMsg, Option<Decimal>), ContractError> {
+    let ExecuteContext { deps, env, .. } = ctx;
+
+    ensure!(from_denom != to_denom, ContractError::DuplicateTokens {});
+    ensure_denom_allowed(deps.as_ref(), &from_denom)?;
+    ensure_denom_allowed(deps.as_ref(), &to_denom)?;
+    let slippage_percentage =
+        resolve_exact_out_slippage(deps.storage, &from_denom, &to_denom, slippage_percentage)?;
+
+    let (fee_reserve, output_fee) = match FEE_CONFIG.may_load(deps.storage)? {
+        Some(fee_config) if fee_config.fee_side == FeeSide::Output => {
+            (Uint128::zero(), fee_config.fee_amount(to_amount))
+        }
+        Some(fee_config) => (fee_config.fee_amount(from_amount), Uint128::zero()),
+        None => (Uint128::zero(), Uint128::zero()),
+    };
+    let token_out_amount = to_amount.checked_add(output_fee)?;
+    let mut token_in_max_amount = from_amount.checked_sub(fee_reserve)?;
+
+    let route = resolve_osmosis_route(deps.as_ref(), &from_denom, &to_denom, route)?;
+    ensure_not_paused(deps.as_ref(), &from_denom, &to_denom, &route)?;
+    check_twap_deviation(deps.as_ref(), &env, &from_denom, &route)?;
+    let routes = generate_swap_amount_out_routes(&from_denom, route.clone());
+
+    let mut price_impact = None;
+    if slippage_percentage.is_some() || max_price_impact.is_some() {
+        let token_in_amount =
+            estimate_osmosis_swap_exact_out(deps.as_ref(), &routes, &to_denom, token_out_amount)?;
+        // The slippage bounds the input against the estimate
+        if let Some(slippage_percentage) = slippage_percentage {
+            token_in_max_amount =
+                token_in_max_amount.min(token_in_amount * (Decimal::one() + slippage_percentage));
+        }
+        if let Some(max_price_impact) = max_price_impact {
+            let impact =
+                estimate_osmosis_price_impact(deps.as_ref(), &from_denom, token_in_amount, &route)?
+                    .price_impact;
+            ensure_price_impact(impact, max_price_impact)?;
+            price_impact = Some(impact);
+        }
+    }
+
+    let amp_ctx = ctx.amp_ctx.map(|pkt| pkt.ctx);
+
+    let prev_balance = query_balance(&deps.as_ref(), &env, &to_denom)?;
+    // The offered coin is already part of the contract balance
+    let prev_from_balance =
+        query_balance(&deps.as_ref(), &env, &from_denom)?.checked_sub(from_amount)?;
+
+    let swap_id = next_swap_id(deps.storage)?;
+    FORWARD_REPLY_STATE.save(
+        deps.storage,
+        swap_id,
+        &ForwardReplyState {
+            recipients: vec![WeightedRecipient::new(recipient, Uint128::one())],
+            refund_addr,
+            amp_ctx,
+            from_denom,
+            from_amount: token_in_max_amount,
+            offered_amount: from_amount,
+            input_fee: fee_reserve,
+            to_denom: to_denom.clone(),
+            refund_on_failure,
+            prev_from_balance: Some(prev_from_balance),
+            output_fee,
+            referrer: None,
+            batch_leg: None,
+            consolidation_id: None,
+        },
+    )?;
+    PREV_BALANCE.save(deps.storage, swap_id, &prev_balance)?;
+
+    let swap_msg = MsgSwapExactAmountOut {
+        sender: env.contract.address.to_string(),
+        routes,
+        token_in_max_amount: token_in_max_amount.to_string(),
+        token_out: Some(OsmosisCoin {
+            denom: to_denom,
+            amount: token_out_amount.to_string(),
+        }),
+    };
+
+    Ok((
+        SubMsg::reply_always(swap_msg, OSMOSIS_MSG_SWAP_ID_OFFSET + swap_id),
+        price_impact,
+    ))
+}
+
+/// Converts an exact-input route into its exact-output form, where each hop names the denom it takes in
+fn generate_swap_amount_out_routes(
+    from_denom: &str,
+    route: Vec<SwapRoute>,
+) -> Vec<SwapAmountOutRoute> {
+    let token_in_denoms = std::iter::once(from_denom.to_string())
+        .chain(route.iter().map(|hop| hop.token_out_denom.clone()));
+    route
+        .iter()
+        .zip(token_in_denoms)
+        .map(|(hop, token_in_denom)| SwapAmountOutRoute {
+            pool_id: hop.pool_id,
+            token_in_denom,
+        })
+        .collect()
 }
 
 pub fn handle_osmosis_swap_reply(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: Env,
     msg: Reply,
+    swap_id: u64,
     state: ForwardReplyState,
 ) -> Result<Response, ContractError> {
-    let balance = deps
-        .querier
-        .query_balance(env.contract.address.to_string(), &state.to_denom)?
-        .amount;
-    let prev_balance = PREV_BALANCE.load(deps.storage)?;
-    let return_amount = balance.checked_sub(prev_balance)?;
-    PREV_BALANCE.remove(deps.storage);
+    let return_amount = take_swap_output(deps.branch(), &env, &state.to_denom, swap_id)?;
 
     if return_amount.is_zero() {
         return Err(ContractError::Std(StdError::generic_err(format!(
@@ -112,45 +298,497 @@ pub fn handle_osmosis_swap_reply(
 
     let mut resp = Response::default();
 
-    let funds = vec![Coin {
-        denom: state.to_denom.to_string(),
-        amount: return_amount,
-    }];
+    // Input fees are held back until the swap succeeds
+    let mut input_fee = state.input_fee;
+    // Exact-output swaps hand the unused input back
+    if let Some(prev_from_balance) = state.prev_from_balance {
+        let from_balance = query_balance(&deps.as_ref(), &env, &state.from_denom)?;
+        let unused_amount = from_balance.checked_sub(prev_from_balance)?;
+        // The fee reserve is settled against the amount the swap actually spent
+        if !input_fee.is_zero() {
+            let spent_amount = state.offered_amount.checked_sub(unused_amount)?;
+            input_fee = FEE_CONFIG
+                .load(deps.storage)?
+                .fee_amount(spent_amount)
+                .min(input_fee);
+        }
+        let unused_amount = unused_amount.checked_sub(input_fee)?;
+        if !unused_amount.is_zero() {
+            let refund_msg = generate_refund_msg(
+                &deps.as_ref(),
+                &env,
+                coin(unused_amount.u128(), state.from_denom.clone()),
+                &state.refund_addr,
+                state.amp_ctx.clone(),
+            )?;
+            resp = resp
+                .add_submessage(refund_msg)
+                .add_attribute("refund_amount", unused_amount);
+        }
+    }
 
-    let mut pkt = if let Some(amp_ctx) = state.amp_ctx.clone() {
-        AMPPkt::new(amp_ctx.get_origin(), amp_ctx.get_previous_sender(), vec![])
+    let (return_amount, output_fee) = match state.prev_from_balance {
+        // Exact-output swaps asked for their output fee on top of the exact amount
+        Some(_) => (
+            return_amount.checked_sub(state.output_fee)?,
+            state.output_fee,
+        ),
+        None => deduct_fee(deps.storage, FeeSide::Output, return_amount)?,
+    };
+    let (fee_denom, fee_amount) = if input_fee.is_zero() {
+        (&state.to_denom, output_fee)
     } else {
-        AMPPkt::new(
-            env.contract.address.clone(),
-            env.contract.address.clone(),
-            vec![],
-        )
+        (&state.from_denom, input_fee)
     };
+    if !fee_amount.is_zero() {
+        let fee_resp = pay_fee(
+            deps.branch(),
+            &env,
+            coin(fee_amount.u128(), fee_denom),
+            state.referrer.as_ref(),
+        )?;
+        resp = resp
+            .add_submessages(fee_resp.messages)
+            .add_attributes(fee_resp.attributes);
+    }
 
-    let Recipient { address, msg, .. } = state.recipient;
-    let msg = AMPMsg::new(
-        address.clone(),
-        msg.unwrap_or_default(),
-        Some(funds.clone()),
-    );
-
-    pkt = pkt.add_message(msg);
+    let (transfer_msg, forward_addrs) = generate_forward_msg(
+        &deps.as_ref(),
+        &env,
+        &state.to_denom,
+        return_amount,
+        state.recipients,
+        state.amp_ctx,
+    )?;
     let kernel_address = ADOContract::default().get_kernel_address(deps.storage)?;
 
-    let transfer_msg =
-        pkt.to_sub_msg(kernel_address.clone(), Some(funds), OSMOSIS_MSG_FORWARD_ID)?;
-
     resp = resp.add_submessage(transfer_msg).add_attributes(vec![
         attr("action", "swap_and_forward"),
         attr("dex", "osmosis"),
+        attr("swap_id", swap_id.to_string()),
         attr("to_denom", state.to_denom.to_string()),
         attr("to_amount", return_amount),
-        attr("forward_addr", address.to_string()),
         attr("kernel_address", kernel_address),
     ]);
+    if let Some(batch_leg) = state.batch_leg {
+        resp = resp.add_attribute("batch_leg", batch_leg.to_string());
+    }
+    resp = resp.add_attributes(
+        forward_addrs
+            .into_iter()
+            .map(|addr| attr("forward_addr", addr)),
+    );
+    Ok(resp)
+}
+
+/// Books the output of one consolidation swap and forwards the combined amount after the last one.
+pub fn handle_osmosis_consolidation_reply(
+    mut deps: DepsMut,
+    env: Env,
+    swap_id: u64,
+    consolidation_id: u64,
+    state: ForwardReplyState,
+) -> Result<Response, ContractError> {
+    let swap_output = take_swap_output(deps.branch(), &env, &state.to_denom, swap_id)?;
+
+    let mut consolidation = CONSOLIDATIONS.load(deps.storage, consolidation_id)?;
+    // The output of the earlier swaps is still held by the contract
+    let return_amount = swap_output.checked_sub(consolidation.received_amount)?;
+    consolidation.received_amount = consolidation.received_amount.checked_add(return_amount)?;
+    consolidation.total_amount = consolidation.total_amount.checked_add(return_amount)?;
+    consolidation.pending_swaps -= 1;
+
+    let resp = Response::default().add_attributes(vec![
+        attr("action", "consolidate_swap"),
+        attr("dex", "osmosis"),
+        attr("swap_id", swap_id.to_string()),
+        attr("consolidation_id", consolidation_id.to_string()),
+        attr("from_denom", state.from_denom),
+        attr("to_amount", return_amount),
+    ]);
+    if consolidation.pending_swaps > 0 {
+        CONSOLIDATIONS.save(deps.storage, consolidation_id, &consolidation)?;
+        return Ok(resp);
+    }
+
+    CONSOLIDATIONS.remove(deps.storage, consolidation_id);
+    let forward_resp = forward_consolidation(deps, &env, consolidation_id, consolidation)?;
+    Ok(resp
+        .add_submessages(forward_resp.messages)
+        .add_attributes(forward_resp.attributes))
+}
+
+/// Forwards the combined amount of a consolidation to its recipients.
+pub(crate) fn forward_consolidation(
+    mut deps: DepsMut,
+    env: &Env,
+    consolidation_id: u64,
+    consolidation: Consolidation,
+) -> Result<Response, ContractError> {
+    let Consolidation {
+        recipients,
+        amp_ctx,
+        to_denom,
+        minimum_receive,
+        total_amount,
+        charge_fee,
+        ..
+    } = consolidation;
+    ensure!(
+        !total_amount.is_zero(),
+        ContractError::Std(StdError::generic_err("Nothing to forward"))
+    );
+
+    let mut resp = Response::default();
+    // Consolidations pay the fee on the combined amount
+    let fee_amount = match FEE_CONFIG.may_load(deps.storage)? {
+        Some(fee_config) if charge_fee => fee_config.fee_amount(total_amount),
+        _ => Uint128::zero(),
+    };
+    if !fee_amount.is_zero() {
+        let fee_msg = collect_fee(deps.branch(), env, coin(fee_amount.u128(), &to_denom))?;
+        resp = resp.add_submessages(fee_msg).add_attributes(vec![
+            attr("fee_denom", &to_denom),
+            attr("fee_amount", fee_amount),
+        ]);
+    }
+    let total_amount = total_amount.checked_sub(fee_amount)?;
+    if let Some(minimum_receive) = minimum_receive {
+        ensure!(
+            total_amount >= minimum_receive,
+            ContractError::Std(StdError::generic_err(format!(
+                "Consolidated {} {}, expected at least {}",
+                total_amount, to_denom, minimum_receive
+            )))
+        );
+    }
+
+    let (transfer_msg, forward_addrs) = generate_forward_msg(
+        &deps.as_ref(),
+        env,
+        &to_denom,
+        total_amount,
+        recipients,
+        amp_ctx,
+    )?;
+    Ok(resp
+        .add_submessage(transfer_msg)
+        .add_attributes(vec![
+            attr("action", "consolidate_and_forward"),
+            attr("dex", "osmosis"),
+            attr("consolidation_id", consolidation_id.to_string()),
+            attr("to_denom", to_denom),
+            attr("to_amount", total_amount),
+        ])
+        .add_attributes(
+            forward_addrs
+                .into_iter()
+                .map(|addr| attr("forward_addr", addr)),
+        ))
+}
+
+/// Splits `amount` of `to_denom` between the recipients by weight in a single AMP packet.
+/// Returns the packet message along with the addresses that received a share.
+pub(crate) fn generate_forward_msg(
+    deps: &Deps,
+    env: &Env,
+    to_denom: &str,
+    amount: Uint128,
+    recipients: Vec<WeightedRecipient>,
+    amp_ctx: Option<AMPCtx>,
+) -> Result<(SubMsg, Vec<String>), ContractError> {
+    let mut pkt = new_amp_pkt(env, amp_ctx);
+
+    // A single packet carries one message per recipient
+    let mut forward_addrs = vec![];
+    for (Recipient { address, msg, .. }, share) in recipient_shares(amount, &recipients)? {
+        forward_addrs.push(address.to_string());
+        let msg = AMPMsg::new(
+            address.clone(),
+            msg.clone().unwrap_or_default(),
+            Some(vec![coin(share.u128(), to_denom)]),
+        );
+        pkt = pkt.add_message(msg);
+    }
+
+    let funds = vec![coin(amount.u128(), to_denom)];
+    let kernel_address = ADOContract::default().get_kernel_address(deps.storage)?;
+    let transfer_msg = pkt.to_sub_msg(kernel_address, Some(funds), OSMOSIS_MSG_FORWARD_ID)?;
+    Ok((transfer_msg, forward_addrs))
+}
+
+pub fn handle_osmosis_swap_failure(
+    deps: DepsMut,
+    env: Env,
+    error: String,
+    swap_id: u64,
+    state: ForwardReplyState,
+) -> Result<Response, ContractError> {
+    PREV_BALANCE.remove(deps.storage, swap_id);
+
+    // The whole offered amount goes back, the held back input fee included
+    let refund_amount = state.offered_amount;
+    let refund_msg = generate_refund_msg(
+        &deps.as_ref(),
+        &env,
+        coin(refund_amount.u128(), state.from_denom.clone()),
+        &state.refund_addr,
+        state.amp_ctx,
+    )?;
+
+    let mut event = Event::new("swap_failed_refunded").add_attributes(vec![
+        attr("dex", "osmosis"),
+        attr("swap_id", swap_id.to_string()),
+        attr("from_denom", state.from_denom),
+        attr("refund_amount", refund_amount),
+        attr("refund_addr", state.refund_addr),
+        attr("error", error),
+    ]);
+    if let Some(batch_leg) = state.batch_leg {
+        event = event.add_attribute("batch_leg", batch_leg.to_string());
+    }
+
+    Ok(Response::default()
+        .add_submessage(refund_msg)
+        .add_event(event))
+}
+
+/// Applies the slippage policy of the pair to the requested slippage.
+/// A minimum output amount is checked against the estimated output, it may not allow
+/// more slippage than the policy does.
+/// Without a policy, swaps giving no slippage fall back to `DEFAULT_SLIPPAGE_PERCENTAGE`.
+pub(crate) fn resolve_slippage(
+    deps: Deps,
+    from_denom: &str,
+    from_amount: Uint128,
+    to_denom: &str,
+    route: &[SwapRoute],
+    slippage: Option<Slippage>,
+) -> Result<Slippage, ContractError> {
+    let pair_key = (from_denom.to_string(), to_denom.to_string());
+    match (slippage, load_slippage_policy(deps.storage, pair_key)?) {
+        (
+            Some(Slippage::Twap {
+                window_seconds,
+                slippage_percentage,
+            }),
+            Some(slippage_policy),
+        ) => Ok(Slippage::Twap {
+            window_seconds,
+            slippage_percentage: slippage_policy.resolve(Some(slippage_percentage))?,
+        }),
+        (Some(Slippage::MinOutputAmount(min_output)), Some(slippage_policy)) => {
+            let estimate = estimate_osmosis_swap(deps, from_denom, from_amount, route)?;
+            let floor = estimate * (Decimal::one() - slippage_policy.max_slippage);
+            ensure!(
+                min_output >= floor,
+                ContractError::Std(StdError::generic_err(format!(
+                    "Minimum output {} is below {}, the least the max slippage {} allows",
+                    min_output, floor, slippage_policy.max_slippage
+                )))
+            );
+            Ok(Slippage::MinOutputAmount(min_output))
+        }
+        (Some(slippage), None) => Ok(slippage),
+        (None, Some(slippage_policy)) => Ok(Slippage::Twap {
+            window_seconds: None,
+            slippage_percentage: slippage_policy.resolve(None)?,
+        }),
+        (None, None) => Ok(Slippage::Twap {
+            window_seconds: None,
+            slippage_percentage: DEFAULT_SLIPPAGE_PERCENTAGE,
+        }),
+    }
+}
+
+/// Applies the slippage policy of the pair to the slippage an exact-output swap allows on its input
+pub(crate) fn resolve_exact_out_slippage(
+    storage: &dyn Storage,
+    from_denom: &str,
+    to_denom: &str,
+    slippage_percentage: Option<Decimal>,
+) -> Result<Option<Decimal>, ContractError> {
+    let pair_key = (from_denom.to_string(), to_denom.to_string());
+    match load_slippage_policy(storage, pair_key)? {
+        Some(slippage_policy) => Ok(Some(slippage_policy.resolve(slippage_percentage)?)),
+        None => Ok(slippage_percentage),
+    }
+}
+
+/// Splits the fee off `amount` when the fee config takes it on `side`.
+/// Returns the amount left along with the fee.
+pub(crate) fn deduct_fee(
+    storage: &dyn Storage,
+    side: FeeSide,
+    amount: Uint128,
+) -> Result<(Uint128, Uint128), ContractError> {
+    let fee = match FEE_CONFIG.may_load(storage)? {
+        Some(fee_config) if fee_config.fee_side == side => fee_config.fee_amount(amount),
+        _ => Uint128::zero(),
+    };
+    Ok((amount.checked_sub(fee)?, fee))
+}
+
+/// Pays the fee taken on a swap, handing the referrer their share of it
+pub(crate) fn pay_fee(
+    mut deps: DepsMut,
+    env: &Env,
+    fee: Coin,
+    referrer: Option<&Addr>,
+) -> Result<Response, ContractError> {
+    let mut resp = Response::default().add_attributes(vec![
+        attr("fee_denom", &fee.denom),
+        attr("fee_amount", fee.amount),
+    ]);
+    let mut protocol_amount = fee.amount;
+    if let Some(referrer) = referrer {
+        let share_bps = load_referral_share(deps.storage, referrer)?;
+        let referral_amount = referral_amount(fee.amount, share_bps);
+        if !referral_amount.is_zero() {
+            REFERRAL_EARNINGS.update(
+                deps.storage,
+                (referrer, &fee.denom),
+                |earned| -> StdResult<Uint128> {
+                    Ok(earned.unwrap_or_default().checked_add(referral_amount)?)
+                },
+            )?;
+            let referral_msg = generate_refund_msg(
+                &deps.as_ref(),
+                env,
+                coin(referral_amount.u128(), &fee.denom),
+                &AndrAddr::from_string(referrer),
+                None,
+            )?;
+            resp = resp.add_submessage(referral_msg).add_attributes(vec![
+                attr("referrer", referrer),
+                attr("referral_amount", referral_amount),
+            ]);
+            protocol_amount = protocol_amount.checked_sub(referral_amount)?;
+        }
+    }
+    if !protocol_amount.is_zero() {
+        resp = resp.add_submessages(collect_fee(
+            deps.branch(),
+            env,
+            coin(protocol_amount.u128(), fee.denom),
+        )?);
+    }
     Ok(resp)
 }
 
+/// Collects `funds` as protocol fee. Fees accrue in the contract while a treasury
+/// is set, otherwise they are paid to the fee recipient right away.
+pub(crate) fn collect_fee(
+    deps: DepsMut,
+    env: &Env,
+    funds: Coin,
+) -> Result<Option<SubMsg>, ContractError> {
+    if TREASURY_CONFIG.may_load(deps.storage)?.is_some() {
+        ACCRUED_FEES.update(
+            deps.storage,
+            &funds.denom,
+            |accrued| -> StdResult<Uint128> {
+                Ok(accrued.unwrap_or_default().checked_add(funds.amount)?)
+            },
+        )?;
+        return Ok(None);
+    }
+    let fee_recipient = FEE_CONFIG.load(deps.storage)?.fee_recipient;
+    let fee_msg = generate_refund_msg(&deps.as_ref(), env, funds, &fee_recipient, None)?;
+    Ok(Some(fee_msg))
+}
+
+/// The balance of `denom` gained since swap `swap_id` was queued, clearing its recorded balance.
+/// Accrued fees are left out, so fees booked by earlier replies do not count as output.
+pub(crate) fn take_swap_output(
+    deps: DepsMut,
+    env: &Env,
+    denom: &str,
+    swap_id: u64,
+) -> Result<Uint128, ContractError> {
+    let balance = query_balance(&deps.as_ref(), env, denom)?;
+    let prev_balance = PREV_BALANCE.load(deps.storage, swap_id)?;
+    PREV_BALANCE.remove(deps.storage, swap_id);
+    Ok(balance.checked_sub(prev_balance)?)
+}
+
+/// Balance of `denom` held by the contract, without the accrued fees
+pub(crate) fn query_balance(deps: &Deps, env: &Env, denom: &str) -> Result<Uint128, ContractError> {
+    let balance = deps
+        .querier
+        .query_balance(env.contract.address.to_string(), denom)?
+        .amount;
+    // Accrued fees are held for the treasury and left out of swap accounting
+    let accrued = ACCRUED_FEES
+        .may_load(deps.storage, denom)?
+        .unwrap_or_default();
+    Ok(balance.checked_sub(accrued)?)
+}
+
+/// The packet forwards and refunds are relayed in, continuing the AMP context of the swap
+/// when it arrived through one
+pub(crate) fn new_amp_pkt(env: &Env, amp_ctx: Option<AMPCtx>) -> AMPPkt {
+    match amp_ctx {
+        Some(amp_ctx) => AMPPkt::new(amp_ctx.get_origin(), amp_ctx.get_previous_sender(), vec![]),
+        None => AMPPkt::new(
+            env.contract.address.clone(),
+            env.contract.address.clone(),
+            vec![],
+        ),
+    }
+}
+
+/// Sends `funds` back to `refund_addr`.
+/// Refunds are routed through the kernel when the swap was triggered by an AMP packet.
+pub(crate) fn generate_refund_msg(
+    deps: &Deps,
+    env: &Env,
+    funds: Coin,
+    refund_addr: &AndrAddr,
+    amp_ctx: Option<AMPCtx>,
+) -> Result<SubMsg, ContractError> {
+    let funds = vec![funds];
+    let msg = match amp_ctx {
+        Some(amp_ctx) => {
+            let pkt = new_amp_pkt(env, Some(amp_ctx)).add_message(AMPMsg::new(
+                refund_addr.clone(),
+                Default::default(),
+                Some(funds.clone()),
+            ));
+            let kernel_address = ADOContract::default().get_kernel_address(deps.storage)?;
+            pkt.to_sub_msg(kernel_address, Some(funds), OSMOSIS_MSG_FORWARD_ID)?
+        }
+        None => SubMsg::new(BankMsg::Send {
+            to_address: refund_addr.get_raw_address(deps)?.to_string(),
+            amount: funds,
+        }),
+    };
+    Ok(msg)
+}
+
+/// Refunds a swap that arrived after its deadline instead of executing it
+pub(crate) fn refund_expired_swap(
+    deps: &Deps,
+    env: &Env,
+    deadline: &Expiration,
+    funds: Coin,
+    refund_addr: &AndrAddr,
+    amp_ctx: Option<AMPCtx>,
+) -> Result<Response, ContractError> {
+    let attrs = vec![
+        attr("action", "refund-expired-swap"),
+        attr("deadline", deadline.to_string()),
+        attr("from_denom", funds.denom.clone()),
+        attr("refund_amount", funds.amount),
+        attr("refund_addr", refund_addr.to_string()),
+    ];
+    let refund_msg = generate_refund_msg(deps, env, funds, refund_addr, amp_ctx)?;
+
+    Ok(Response::default()
+        .add_submessage(refund_msg)
+        .add_attributes(attrs))
+}
+
 pub fn query_get_route(
     deps: Deps,
     from_denom: String,
@@ -183,3 +821,472 @@ pub fn query_get_route(
         })
     }
 }
+
+/// Picks the route of a swap: the given one first, then the registered route,
+/// then the route of the swap router.
+/// Routes are only discovered through the `FindRoute` query.
+pub(crate) fn resolve_osmosis_route(
+    deps: Deps,
+    from_denom: &str,
+    to_denom: &str,
+    route: Option<Vec<SwapRoute>>,
+) -> Result<Vec<SwapRoute>, ContractError> {
+    let route_key = (from_denom.to_string(), to_denom.to_string());
+    let route = match route {
+        Some(route) => route,
+        None => match ROUTES.may_load(deps.storage, route_key)? {
+            Some(route) => route,
+            None => query_get_route(deps, from_denom.to_string(), to_denom.to_string())?.pool_route,
+        },
+    };
+    validate_osmosis_route(deps, from_denom, to_denom, &route)?;
+    Ok(route)
+}
+
+/// Rejects the swap when its price impact exceeds `max_price_impact`.
+/// Returns the route to swap through along with the price impact.
+pub(crate) fn check_osmosis_price_impact(
+    deps: Deps,
+    from_denom: &str,
+    from_amount: Uint128,
+    to_denom: &str,
+    route: Option<Vec<SwapRoute>>,
+    max_price_impact: Option<Decimal>,
+) -> Result<(Option<Vec<SwapRoute>>, Option<Decimal>), ContractError> {
+    let Some(max_price_impact) = max_price_impact else {
+        return Ok((route, None));
+    };
+    let route = resolve_osmosis_route(deps, from_denom, to_denom, route)?;
+    let price_impact =
+        estimate_osmosis_price_impact(deps, from_denom, from_amount, &route)?.price_impact;
+    ensure_price_impact(price_impact, max_price_impact)?;
+    Ok((Some(route), Some(price_impact)))
+}
+
+/// Compares the estimated output of `amount` against a small probe through the same route
+pub(crate) fn estimate_osmosis_price_impact(
+    deps: Deps,
+    from_denom: &str,
+    amount: Uint128,
+    route: &[SwapRoute],
+) -> Result<PriceImpactResponse, ContractError> {
+    let return_amount = estimate_osmosis_swap(deps, from_denom, amount, route)?;
+    let probe_amount = price_impact_probe(amount);
+    let probe_return_amount = estimate_osmosis_swap(deps, from_denom, probe_amount, route)?;
+    Ok(PriceImpactResponse {
+        return_amount,
+        price_impact: price_impact(amount, return_amount, probe_amount, probe_return_amount)?,
+    })
+}
+
+/// Estimates the output of swapping `amount` of `from_denom` through `route`
+fn estimate_osmosis_swap(
+    deps: Deps,
+    from_denom: &str,
+    amount: Uint128,
+    route: &[SwapRoute],
+) -> Result<Uint128, ContractError> {
+    let estimate = PoolmanagerQuerier::new(&deps.querier).estimate_swap_exact_amount_in(
+        route[0].pool_id,
+        format!("{}{}", amount, from_denom),
+        route.iter().map(|hop| hop.clone().into()).collect(),
+    )?;
+    Ok(Uint128::from_str(&estimate.token_out_amount)?)
+}
+
+/// Estimates the input a swap through `routes` takes to return `amount` of `to_denom`
+fn estimate_osmosis_swap_exact_out(
+    deps: Deps,
+    routes: &[SwapAmountOutRoute],
+    to_denom: &str,
+    amount: Uint128,
+) -> Result<Uint128, ContractError> {
+    let estimate = PoolmanagerQuerier::new(&deps.querier).estimate_swap_exact_amount_out(
+        routes[0].pool_id,
+        routes.to_vec(),
+        format!("{}{}", amount, to_denom),
+    )?;
+    Ok(Uint128::from_str(&estimate.token_in_amount)?)
+}
+
+/// Fails when `denom` is on the deny list, or the allow list holds denoms but not this one
+pub(crate) fn ensure_denom_allowed(deps: Deps, denom: &str) -> Result<(), ContractError> {
+    ensure!(
+        !DENIED_ASSETS.has(deps.storage, denom),
+        ContractError::Std(StdError::generic_err(format!(
+            "{} is on the deny list",
+            denom
+        )))
+    );
+    let allow_all = ALLOWED_ASSETS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .next()
+        .is_none();
+    ensure!(
+        allow_all || ALLOWED_ASSETS.has(deps.storage, denom),
+        ContractError::Std(StdError::generic_err(format!(
+            "{} is not on the allow list",
+            denom
+        )))
+    );
+    Ok(())
+}
+
+/// Enforces the volume limit of `denom` on a swap of `amount` offered by `sender`
+/// and records the amount in the current bucket of the rolling window.
+pub(crate) fn record_swap_volume(
+    deps: DepsMut,
+    env: &Env,
+    sender: &Addr,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    let key = denom;
+    let Some(volume_limit) = VOLUME_LIMITS.may_load(deps.storage, key)? else {
+        return Ok(());
+    };
+    if let Some(max_swap_amount) = volume_limit.max_swap_amount {
+        ensure!(
+            amount <= max_swap_amount,
+            ContractError::Std(StdError::generic_err(format!(
+                "Swap of {} {} exceeds the max swap amount of {}",
+                amount, denom, max_swap_amount
+            )))
+        );
+    }
+
+    let now = env.block.time.seconds();
+    let since = volume_limit.window_start(now);
+    let bucket = volume_limit.bucket_start(now);
+    if let Some(max_asset_volume) = volume_limit.max_asset_volume {
+        let expired = ASSET_VOLUMES
+            .prefix(key)
+            .keys(
+                deps.storage,
+                None,
+                Some(Bound::exclusive(since)),
+                Order::Ascending,
+            )
+            .collect::<StdResult<Vec<u64>>>()?;
+        for start in expired {
+            ASSET_VOLUMES.remove(deps.storage, (key, start));
+        }
+        let volume = asset_volume(deps.storage, key, since)?.checked_add(amount)?;
+        ensure!(
+            volume <= max_asset_volume,
+            ContractError::Std(StdError::generic_err(format!(
+                "Swaps of {} would exceed the volume cap of {} within {} seconds",
+                denom, max_asset_volume, volume_limit.window_seconds
+            )))
+        );
+        ASSET_VOLUMES.update(
+            deps.storage,
+            (key, bucket),
+            |volume| -> StdResult<Uint128> { Ok(volume.unwrap_or_default().checked_add(amount)?) },
+        )?;
+    }
+    if let Some(max_sender_volume) = volume_limit.max_sender_volume {
+        let expired = SENDER_VOLUMES
+            .prefix((sender, key))
+            .keys(
+                deps.storage,
+                None,
+                Some(Bound::exclusive(since)),
+                Order::Ascending,
+            )
+            .collect::<StdResult<Vec<u64>>>()?;
+        for start in expired {
+            SENDER_VOLUMES.remove(deps.storage, (sender, key, start));
+        }
+        let volume = sender_volume(deps.storage, sender, key, since)?.checked_add(amount)?;
+        ensure!(
+            volume <= max_sender_volume,
+            ContractError::Std(StdError::generic_err(format!(
+                "Swaps of {} by {} would exceed the volume cap of {} within {} seconds",
+                denom, sender, max_sender_volume, volume_limit.window_seconds
+            )))
+        );
+        SENDER_VOLUMES.update(
+            deps.storage,
+            (sender, key, bucket),
+            |volume| -> StdResult<Uint128> { Ok(volume.unwrap_or_default().checked_add(amount)?) },
+        )?;
+    }
+    Ok(())
+}
+
+/// Fails when a swap from `from_denom` to `to_denom` through `route` falls within a paused scope
+pub(crate) fn ensure_not_paused(
+    deps: Deps,
+    from_denom: &str,
+    to_denom: &str,
+    route: &[SwapRoute],
+) -> Result<(), ContractError> {
+    let denoms = std::iter::once(from_denom.to_string())
+        .chain(route.iter().map(|hop| hop.token_out_denom.clone()));
+    let scopes = [
+        PauseScope::Global,
+        PauseScope::Pair {
+            from_denom: from_denom.to_string(),
+            to_denom: to_denom.to_string(),
+        },
+    ]
+    .into_iter()
+    .chain(denoms.map(|denom| PauseScope::Denom { denom }));
+    for scope in scopes {
+        if let Some(pause) = PAUSES.may_load(deps.storage, &scope.key())? {
+            return Err(paused_error(&pause.scope, &pause.paused_by));
+        }
+    }
+    Ok(())
+}
+
+/// Refuses the swap when the spot price of a pool along `route` deviates from its
+/// arithmetic twap by more than the twap guard allows.
+pub(crate) fn check_twap_deviation(
+    deps: Deps,
+    env: &Env,
+    from_denom: &str,
+    route: &[SwapRoute],
+) -> Result<(), ContractError> {
+    let Some(twap_guard) = TWAP_GUARD.may_load(deps.storage)? else {
+        return Ok(());
+    };
+    let start_seconds = env
+        .block
+        .time
+        .seconds()
+        .checked_sub(twap_guard.window_seconds)
+        .ok_or_else(|| {
+            ContractError::Std(StdError::generic_err(format!(
+                "Twap window of {} seconds starts before the first block",
+                twap_guard.window_seconds
+            )))
+        })?;
+    let start_time = Timestamp {
+        seconds: start_seconds as i64,
+        nanos: env.block.time.subsec_nanos() as i32,
+    };
+
+    let mut token_in_denom = from_denom;
+    for swap_route in route {
+        // Prices are quoted in the offered denom per unit of the asked denom
+        let twap = TwapQuerier::new(&deps.querier)
+            .arithmetic_twap_to_now(
+                swap_route.pool_id,
+                swap_route.token_out_denom.clone(),
+                token_in_denom.to_string(),
+                Some(start_time.clone()),
+            )?
+            .arithmetic_twap;
+        let spot_price = PoolmanagerQuerier::new(&deps.querier)
+            .spot_price(
+                swap_route.pool_id,
+                swap_route.token_out_denom.clone(),
+                token_in_denom.to_string(),
+            )?
+            .spot_price;
+        let twap = Decimal::from_str(&twap)?;
+        let spot_price = Decimal::from_str(&spot_price)?;
+        ensure!(
+            !twap.is_zero(),
+            ContractError::Std(StdError::generic_err(format!(
+                "Pool {} has no twap",
+                swap_route.pool_id
+            )))
+        );
+
+        let deviation = twap.abs_diff(spot_price).checked_div(twap).map_err(|_| {
+            ContractError::Std(StdError::generic_err(format!(
+                "Spot price deviation of pool {} is out of range",
+                swap_route.pool_id
+            )))
+        })?;
+        ensure!(
+            deviation <= twap_guard.max_deviation,
+            ContractError::Std(StdError::generic_err(format!(
+                "Spot price of pool {} deviates {} from its twap, above the max deviation {}",
+                swap_route.pool_id, deviation, twap_guard.max_deviation
+            )))
+        );
+        token_in_denom = &swap_route.token_out_denom;
+    }
+    Ok(())
+}
+
+/// Ensures `route` takes at most the configured max hops and ends with `to_denom`,
+/// where no hop swaps a denom into itself.
+pub(crate) fn validate_osmosis_route(
+    deps: Deps,
+    from_denom: &str,
+    to_denom: &str,
+    route: &[SwapRoute],
+) -> Result<(), ContractError> {
+    ensure!(
+        !route.is_empty(),
+        ContractError::Std(StdError::generic_err("Swap route cannot be empty"))
+    );
+    let max_hops = load_max_hops(deps.storage)?;
+    ensure!(
+        route.len() <= max_hops as usize,
+        ContractError::Std(StdError::generic_err(format!(
+            "Swap route takes {} hops, at most {} are allowed",
+            route.len(),
+            max_hops
+        )))
+    );
+
+    let mut token_in_denom = from_denom;
+    for (hop, swap_route) in route.iter().enumerate() {
+        ensure!(
+            swap_route.token_out_denom != token_in_denom,
+            ContractError::Std(StdError::generic_err(format!(
+                "Swap route hop {} through pool {} swaps {} into itself",
+                hop, swap_route.pool_id, token_in_denom
+            )))
+        );
+        token_in_denom = &swap_route.token_out_denom;
+    }
+    ensure!(
+        token_in_denom == to_denom,
+        ContractError::Std(StdError::generic_err(format!(
+            "Swap route ends with {} instead of {}",
+            token_in_denom, to_denom
+        )))
+    );
+    Ok(())
+}
+
+/// Walks the pools for routes of up to `MAX_ROUTE_HOPS` hops from `from_denom` to `to_denom`
+/// and picks the one with the highest estimated output for `amount`.
+/// Only the first `MAX_ROUTE_POOLS` pools are searched.
+pub fn find_osmosis_route(
+    deps: Deps,
+    from_denom: &str,
+    to_denom: &str,
+    amount: Uint128,
+) -> Result<FindRouteResponse, ContractError> {
+    ensure!(from_denom != to_denom, ContractError::DuplicateTokens {});
+    let querier = PoolmanagerQuerier::new(&deps.querier);
+
+    let mut graph: HashMap<String, Vec<SwapRoute>> = HashMap::new();
+    for (pool_id, denoms) in query_route_pools(deps)?
+        .into_iter()
+        .filter_map(decode_pool_denoms)
+    {
+        for offer in denoms.iter() {
+            let hops = graph.entry(offer.clone()).or_default();
+            for ask in denoms.iter().filter(|ask| *ask != offer) {
+                hops.push(SwapRoute {
+                    pool_id,
+                    token_out_denom: ask.clone(),
+                });
+            }
+        }
+    }
+
+    let mut best: Option<(Vec<SwapRoute>, Uint128)> = None;
+    let paths = find_paths(
+        &graph,
+        |hop| &hop.token_out_denom,
+        &from_denom.to_string(),
+        &to_denom.to_string(),
+        MAX_ROUTE_HOPS.min(load_max_hops(deps.storage)? as usize),
+        MAX_ROUTE_CANDIDATES,
+    );
+    for route in paths {
+        // Routes through pools that cannot take the amount fail to estimate and are skipped
+        let Ok(estimate) = querier.estimate_swap_exact_amount_in(
+            route[0].pool_id,
+            format!("{}{}", amount, from_denom),
+            route.iter().map(|hop| hop.clone().into()).collect(),
+        ) else {
+            continue;
+        };
+        let Ok(token_out_amount) = Uint128::from_str(&estimate.token_out_amount) else {
+            continue;
+        };
+        let is_better = match &best {
+            Some((_, best_amount)) => token_out_amount > *best_amount,
+            None => true,
+        };
+        if is_better {
+            best = Some((route, token_out_amount));
+        }
+    }
+
+    let (route, amount) = best.ok_or_else(|| {
+        ContractError::Std(StdError::generic_err(format!(
+            "No route found from {} to {}",
+            from_denom, to_denom
+        )))
+    })?;
+    Ok(FindRouteResponse { route, amount })
+}
+
+/// Lists the gamm and concentrated liquidity pools page by page, up to `MAX_ROUTE_POOLS` pools
+fn query_route_pools(deps: Deps) -> Result<Vec<Any>, ContractError> {
+    let mut pools = vec![];
+    let gamm = GammQuerier::new(&deps.querier);
+    query_pool_pages(&mut pools, |pagination| {
+        let res = gamm.pools(Some(pagination))?;
+        Ok((res.pools, res.pagination))
+    })?;
+    let concentrated = ConcentratedliquidityQuerier::new(&deps.querier);
+    query_pool_pages(&mut pools, |pagination| {
+        let res = concentrated.pools(Some(pagination))?;
+        Ok((res.pools, res.pagination))
+    })?;
+    Ok(pools)
+}
+
+/// Adds the pools of a paginated pool query to `pools` until its last page
+/// or until `MAX_ROUTE_POOLS` pools are read
+pub(crate) fn query_pool_pages(
+    pools: &mut Vec<Any>,
+    mut query_page: impl FnMut(PageRequest) -> StdResult<(Vec<Any>, Option<PageResponse>)>,
+) -> StdResult<()> {
+    let mut key = vec![];
+    while pools.len() < MAX_ROUTE_POOLS {
+        let (page, pagination) = query_page(PageRequest {
+            key,
+            offset: 0,
+            limit: POOLS_PAGE_LIMIT.min((MAX_ROUTE_POOLS - pools.len()) as u64),
+            count_total: false,
+            reverse: false,
+        })?;
+        pools.extend(page);
+        match pagination {
+            Some(pagination) if !pagination.next_key.is_empty() => key = pagination.next_key,
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+/// Reads the id and denoms of the pool types route discovery can swap through
+fn decode_pool_denoms(pool: Any) -> Option<(u64, Vec<String>)> {
+    let value = Binary::from(pool.value);
+    if pool.type_url == BalancerPool::TYPE_URL {
+        let pool = BalancerPool::try_from(value).ok()?;
+        let denoms = pool
+            .pool_assets
+            .into_iter()
+            .filter_map(|asset| asset.token.map(|token| token.denom))
+            .collect();
+        Some((pool.id, denoms))
+    } else if pool.type_url == StableswapPool::TYPE_URL {
+        let pool = StableswapPool::try_from(value).ok()?;
+        let denoms = pool
+            .pool_liquidity
+            .into_iter()
+            .map(|coin| coin.denom)
+            .collect();
+        Some((pool.id, denoms))
+    } else if pool.type_url == ConcentratedPool::TYPE_URL {
+        let pool = ConcentratedPool::try_from(value).ok()?;
+        Some((pool.id, vec![pool.token0, pool.token1]))
+    } else {
+        None
+    }
+}
@@ -1,25 +1,230 @@
-use andromeda_std::amp::{messages::AMPCtx, AndrAddr, Recipient};
-use cosmwasm_std::Uint128;
-use cw_storage_plus::Item;
+use andromeda_std::{
+    amp::{messages::AMPCtx, AndrAddr},
+    error::ContractError,
+};
+use andromeda_swap_and_forward::{
+    common::{
+        CodePin, FeeConfig, SlippagePolicy, SwapRouterProposal, VolumeLimit, WeightedRecipient,
+        DEFAULT_SWAP_ROUTER_TIMELOCK,
+    },
+    osmosis::{Pause, SwapRoute, TreasuryConfig, TwapGuard},
+};
+use cosmwasm_std::{Addr, Deps, Empty, Order, StdResult, Storage, Uint128};
+use cw_storage_plus::{Bound, Item, Map};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
 pub struct ForwardReplyState {
-    /// Recipient
-    pub recipient: Recipient,
+    /// Forward recipients, the swapped token is split between them by weight
+    pub recipients: Vec<WeightedRecipient>,
     /// Refund Address
     pub refund_addr: AndrAddr,
     /// Amp ctx to be used for ibc communication
     pub amp_ctx: Option<AMPCtx>,
     /// Offered denom to the osmosis
     pub from_denom: String,
+    /// Amount swapped, the most the swap may take for exact-output swaps
+    pub from_amount: Uint128,
+    /// Amount of the offered coin held for the swap, the input fee included.
+    /// Handed back in full when the swap fails
+    pub offered_amount: Uint128,
     /// Asked denom returning from the osmosis
     pub to_denom: String,
+    /// Whether the offered coin is refunded when the swap fails
+    pub refund_on_failure: bool,
+    /// Balance of the offered denom without the offered amount, set for exact-output swaps
+    /// so the unused input can be refunded
+    pub prev_from_balance: Option<Uint128>,
+    /// Input side fee held back from the offered amount, paid out once the swap succeeds
+    pub input_fee: Uint128,
+    /// Output side fee asked from an exact-output swap on top of the exact amount
+    pub output_fee: Uint128,
+    /// Referrer paid a share of the protocol fee
+    pub referrer: Option<Addr>,
+    /// Index of the leg within a `BatchSwapAndForward`
+    pub batch_leg: Option<u32>,
+    /// Consolidation the swap belongs to, its output is forwarded together with the other inputs
+    pub consolidation_id: Option<u64>,
 }
 
-pub const FORWARD_REPLY_STATE: Item<ForwardReplyState> = Item::new("forward_reply_state");
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Consolidation {
+    /// Forward recipients of the combined amount
+    pub recipients: Vec<WeightedRecipient>,
+    /// Amp ctx to be used for ibc communication
+    pub amp_ctx: Option<AMPCtx>,
+    /// Asked denom every input is swapped into
+    pub to_denom: String,
+    /// Minimum combined amount to forward
+    pub minimum_receive: Option<Uint128>,
+    /// Number of swaps still waiting for their reply
+    pub pending_swaps: u32,
+    /// Combined amount collected so far
+    pub total_amount: Uint128,
+    /// Amount received after the swaps were queued, which the remaining swap replies must discount
+    pub received_amount: Uint128,
+    /// Whether the protocol fee is taken from the combined amount
+    pub charge_fee: bool,
+}
+
+/// Pending swaps keyed by swap id
+pub const FORWARD_REPLY_STATE: Map<u64, ForwardReplyState> = Map::new("forward_reply_states");
+
+/// Pending consolidations keyed by consolidation id
+pub const CONSOLIDATIONS: Map<u64, Consolidation> = Map::new("consolidations");
+
+/// Registered routes keyed by the offered and asked denoms
+pub const ROUTES: Map<(String, String), Vec<SwapRoute>> = Map::new("routes");
+
+/// Protocol fee, no fee is taken while unset
+pub const FEE_CONFIG: Item<FeeConfig> = Item::new("fee_config");
+
+/// Treasury fees accrue for, fees are paid straight to the fee recipient while unset
+pub const TREASURY_CONFIG: Item<TreasuryConfig> = Item::new("treasury_config");
+
+/// Fees accrued in the contract and not converted yet, keyed by denom
+pub const ACCRUED_FEES: Map<&str, Uint128> = Map::new("accrued_fees");
+
+/// Slippage policy of every pair without its own, slippage is left to the caller while unset
+pub const SLIPPAGE_POLICY: Item<SlippagePolicy> = Item::new("slippage_policy");
+
+/// Slippage policy overrides keyed by the offered and asked denoms
+pub const PAIR_SLIPPAGE_POLICIES: Map<(String, String), SlippagePolicy> =
+    Map::new("pair_slippage_policies");
+
+/// Active pauses keyed by `PauseScope::key`
+pub const PAUSES: Map<&str, Pause> = Map::new("pauses");
+
+/// Granted roles keyed by role and address
+pub const ROLES: Map<(&str, &Addr), Empty> = Map::new("roles");
+
+/// Guard refusing swaps through pools whose spot price strays from their twap, off while unset
+pub const TWAP_GUARD: Item<TwapGuard> = Item::new("twap_guard");
+
+/// Assets that may be offered or asked for, keyed by denom. Any asset may while it is empty
+pub const ALLOWED_ASSETS: Map<&str, Empty> = Map::new("allowed_assets");
+
+/// Assets that may not be offered or asked for, keyed by denom
+pub const DENIED_ASSETS: Map<&str, Empty> = Map::new("denied_assets");
+
+/// Volume limits keyed by the denom of the offered asset
+pub const VOLUME_LIMITS: Map<&str, VolumeLimit> = Map::new("volume_limits");
+
+/// Offered volume keyed by denom and the block time its bucket starts at
+pub const ASSET_VOLUMES: Map<(&str, u64), Uint128> = Map::new("asset_volumes");
+
+/// Offered volume keyed by sender, denom and the block time its bucket starts at
+pub const SENDER_VOLUMES: Map<(&Addr, &str, u64), Uint128> = Map::new("sender_volumes");
+
+/// Share of the protocol fee paid to each referrer, in basis points of the fee
+pub const REFERRAL_SHARES: Map<&Addr, u16> = Map::new("referral_shares");
+
+/// Share of the protocol fee paid to referrers without their own share, none until configured
+pub const DEFAULT_REFERRAL_SHARE: Item<u16> = Item::new("default_referral_share");
+
+/// Cumulative referral earnings keyed by referrer and denom
+pub const REFERRAL_EARNINGS: Map<(&Addr, &str), Uint128> = Map::new("referral_earnings");
+
+/// Most hops a swap path may take, `DEFAULT_MAX_HOPS` until configured
+pub const MAX_HOPS: Item<u32> = Item::new("max_hops");
+
+pub const DEFAULT_MAX_HOPS: u32 = 4;
+
+/// The last swap id handed out, swap ids increase monotonically
+pub const SWAP_ID: Item<u64> = Item::new("swap_id");
 
 pub const SWAP_ROUTER: Item<AndrAddr> = Item::new("swap_router");
 
-pub const PREV_BALANCE: Item<Uint128> = Item::new("prev_balance");
+/// Code the swap router must run, checked on every swap
+pub const SWAP_ROUTER_PIN: Item<CodePin> = Item::new("swap_router_pin");
+
+/// The swap router waiting for its timelock to pass
+pub const SWAP_ROUTER_PROPOSAL: Item<SwapRouterProposal> = Item::new("swap_router_proposal");
+
+/// Seconds a proposed swap router waits, `DEFAULT_SWAP_ROUTER_TIMELOCK` until configured
+pub const SWAP_ROUTER_TIMELOCK: Item<u64> = Item::new("swap_router_timelock");
+
+/// Balance of the asked asset before the swap, keyed by swap id
+pub const PREV_BALANCE: Map<u64, Uint128> = Map::new("prev_balances");
+
+/// Keys of the swap state items used before swaps were keyed by swap id, cleared on migration
+pub const LEGACY_SWAP_STATE_KEYS: [&[u8]; 2] = [b"forward_reply_state", b"prev_balance"];
+
+pub fn next_swap_id(storage: &mut dyn Storage) -> StdResult<u64> {
+    let swap_id = SWAP_ID.may_load(storage)?.unwrap_or_default() + 1;
+    SWAP_ID.save(storage, &swap_id)?;
+    Ok(swap_id)
+}
+
+pub fn load_max_hops(storage: &dyn Storage) -> StdResult<u32> {
+    Ok(MAX_HOPS.may_load(storage)?.unwrap_or(DEFAULT_MAX_HOPS))
+}
+
+/// The raw swap router address, checked against its code pin when one is set
+pub fn load_swap_router(deps: &Deps) -> Result<Addr, ContractError> {
+    let swap_router = SWAP_ROUTER.load(deps.storage)?.get_raw_address(deps)?;
+    if let Some(code_pin) = SWAP_ROUTER_PIN.may_load(deps.storage)? {
+        code_pin.verify(deps, &swap_router)?;
+    }
+    Ok(swap_router)
+}
+
+pub fn load_swap_router_timelock(storage: &dyn Storage) -> StdResult<u64> {
+    Ok(SWAP_ROUTER_TIMELOCK
+        .may_load(storage)?
+        .unwrap_or(DEFAULT_SWAP_ROUTER_TIMELOCK))
+}
+
+pub fn load_referral_share(storage: &dyn Storage, referrer: &Addr) -> StdResult<u16> {
+    match REFERRAL_SHARES.may_load(storage, referrer)? {
+        Some(share_bps) => Ok(share_bps),
+        None => Ok(DEFAULT_REFERRAL_SHARE
+            .may_load(storage)?
+            .unwrap_or_default()),
+    }
+}
+
+/// The slippage policy of a pair, its override first and then the contract wide policy
+pub fn load_slippage_policy(
+    storage: &dyn Storage,
+    pair_key: (String, String),
+) -> StdResult<Option<SlippagePolicy>> {
+    match PAIR_SLIPPAGE_POLICIES.may_load(storage, pair_key)? {
+        Some(slippage_policy) => Ok(Some(slippage_policy)),
+        None => SLIPPAGE_POLICY.may_load(storage),
+    }
+}
+
+/// Volume offered of `denom` in the buckets starting at or after `since`
+pub fn asset_volume(storage: &dyn Storage, denom: &str, since: u64) -> StdResult<Uint128> {
+    ASSET_VOLUMES
+        .prefix(denom)
+        .range(
+            storage,
+            Some(Bound::inclusive(since)),
+            None,
+            Order::Ascending,
+        )
+        .map(|item| item.map(|(_, amount)| amount))
+        .sum()
+}
+
+/// Volume `sender` offered of `denom` in the buckets starting at or after `since`
+pub fn sender_volume(
+    storage: &dyn Storage,
+    sender: &Addr,
+    denom: &str,
+    since: u64,
+) -> StdResult<Uint128> {
+    SENDER_VOLUMES
+        .prefix((sender, denom))
+        .range(
+            storage,
+            Some(Bound::inclusive(since)),
+            None,
+            Order::Ascending,
+        )
+        .map(|item| item.map(|(_, amount)| amount))
+        .sum()
+}
@@ -1 +1,2 @@
 mod tests;
+mod unit_tests;
@@ -50,6 +50,8 @@ mod test {
             swap_router: Some(AndrAddr::from_string(
                 "osmo19upgmw22nyg9qc8prw6s8ncljkfjdq62xylxhtretm9uzzvj45sq46ggv9".to_string(),
             )),
+            swap_router_timelock: None,
+            permissioned_swaps: None,
         };
         let swap_and_forward_component = AppComponent::new(
             swap_and_forward_component_name,
@@ -162,13 +164,18 @@ mod test {
         );
         swap_and_forward_contract
             .swap_and_forward(
-                slippage,
                 atom_denom.clone(),
+                None,
+                None,
                 Some(forward_addr),
+                None,
+                None,
+                None,
                 Some(vec![SwapRoute {
                     pool_id: 94,
                     token_out_denom: atom_denom.to_string(),
                 }]),
+                Some(slippage),
                 &[coin(1000000, denom)],
             )
             .unwrap();
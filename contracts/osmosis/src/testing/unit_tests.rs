@@ -0,0 +1,1442 @@
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, marker::PhantomData};
+
+    use andromeda_std::{
+        ado_base::permissioning::Permission,
+        ado_contract::ADOContract,
+        amp::{messages::AMPPkt, AndrAddr, Recipient},
+        common::context::ExecuteContext,
+        error::ContractError,
+    };
+    use andromeda_swap_and_forward::{
+        common::{
+            expired_error, AssetList, CodePin, FeeConfig, FeeSide, Role, SlippagePolicy,
+            SwapRouterResponse, VolumeLimit, WeightedRecipient, DEFAULT_SWAP_ROUTER_TIMELOCK,
+            MAX_SWAP_ROUTER_TIMELOCK,
+        },
+        osmosis::{
+            AssetListResponse, ExecuteMsg, InstantiateMsg, PauseScope, PauseStatusResponse,
+            QueryMsg, RouteInfo, RoutesResponse, Slippage, SwapRoute, TreasuryConfig, TwapGuard,
+            MAX_TWAP_WINDOW_SECONDS,
+        },
+    };
+    use cosmwasm_std::{
+        coin, from_json,
+        testing::{
+            mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
+            MOCK_CONTRACT_ADDR,
+        },
+        to_json_binary, Addr, BankMsg, Binary, Coin, ContractInfoResponse, ContractResult,
+        CosmosMsg, Decimal, DepsMut, Empty, OwnedDeps, Querier, QuerierResult, QueryRequest, Reply,
+        Response, StdError, SubMsg, SubMsgResponse, SubMsgResult, SystemError, SystemResult,
+        Timestamp, Uint128, WasmMsg, WasmQuery,
+    };
+    use cw20::Expiration;
+    use osmosis_std::{
+        shim::Any,
+        types::{
+            cosmos::base::query::v1beta1::PageResponse,
+            osmosis::{
+                poolmanager::v1beta1::{EstimateSwapExactAmountOutResponse, SpotPriceResponse},
+                twap::v1beta1::ArithmeticTwapToNowResponse,
+            },
+        },
+    };
+    use serde::Serialize;
+    use swaprouter::msg::ExecuteMsg as OsmosisExecuteMsg;
+
+    use crate::{
+        contract::{execute, handle_execute, instantiate, query, reply, SWAP_AND_FORWARD_ACTION},
+        osmosis::{
+            handle_osmosis_consolidation_reply, query_pool_pages, record_swap_volume,
+            resolve_slippage, take_swap_output, DEFAULT_SLIPPAGE_PERCENTAGE, MAX_ROUTE_POOLS,
+        },
+        state::{
+            asset_volume, load_swap_router, sender_volume, Consolidation, ForwardReplyState,
+            ACCRUED_FEES, ASSET_VOLUMES, CONSOLIDATIONS, PAIR_SLIPPAGE_POLICIES, PREV_BALANCE,
+            SENDER_VOLUMES, SLIPPAGE_POLICY, VOLUME_LIMITS,
+        },
+    };
+
+    const OWNER: &str = "owner";
+    const KERNEL: &str = "kernel";
+
+    /// Answers the osmosis stargate queries from `responses`, keyed by the query path,
+    /// and leaves every other query to the mock querier
+    struct PoolQuerier {
+        base: MockQuerier,
+        responses: HashMap<String, Binary>,
+    }
+
+    impl PoolQuerier {
+        fn set_response(&mut self, path: &str, res: &impl Serialize) {
+            self.responses
+                .insert(path.to_string(), to_json_binary(res).unwrap());
+        }
+    }
+
+    impl Querier for PoolQuerier {
+        fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+            match from_json(bin_request).unwrap() {
+                QueryRequest::<Empty>::Stargate { path, data } => match self.responses.get(&path) {
+                    Some(res) => SystemResult::Ok(ContractResult::Ok(res.clone())),
+                    None => SystemResult::Err(SystemError::InvalidRequest {
+                        error: format!("Unexpected query {}", path),
+                        request: data,
+                    }),
+                },
+                _ => self.base.raw_query(bin_request),
+            }
+        }
+    }
+
+    type TestDeps = OwnedDeps<MockStorage, MockApi, PoolQuerier>;
+
+    const ESTIMATE_SWAP_EXACT_AMOUNT_OUT_PATH: &str =
+        "/osmosis.poolmanager.v1beta1.Query/EstimateSwapExactAmountOut";
+    const SPOT_PRICE_PATH: &str = "/osmosis.poolmanager.v1beta1.Query/SpotPrice";
+    const ARITHMETIC_TWAP_TO_NOW_PATH: &str = "/osmosis.twap.v1beta1.Query/ArithmeticTwapToNow";
+
+    fn setup() -> TestDeps {
+        setup_with(false)
+    }
+
+    fn setup_with(permissioned_swaps: bool) -> TestDeps {
+        let mut deps = OwnedDeps {
+            storage: MockStorage::default(),
+            api: MockApi::default(),
+            querier: PoolQuerier {
+                base: MockQuerier::default(),
+                responses: HashMap::new(),
+            },
+            custom_query_type: PhantomData,
+        };
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            InstantiateMsg {
+                kernel_address: KERNEL.to_string(),
+                owner: None,
+                swap_router: Some(AndrAddr::from_string("router")),
+                swap_router_timelock: None,
+                permissioned_swaps: Some(permissioned_swaps),
+            },
+        )
+        .unwrap();
+        deps
+    }
+
+    /// Executes `msg` as relayed by the kernel in a packet sent by `origin`
+    fn amp_execute(
+        deps: DepsMut,
+        origin: &str,
+        funds: &[Coin],
+        msg: ExecuteMsg,
+    ) -> Result<Response, ContractError> {
+        let mut ctx = ExecuteContext::new(deps, mock_info(KERNEL, funds), mock_env());
+        ctx.amp_ctx = Some(AMPPkt::new(origin, origin, vec![]));
+        handle_execute(ctx, msg)
+    }
+
+    fn swap_to_usdc(refund_on_failure: bool) -> ExecuteMsg {
+        ExecuteMsg::SwapAndForward {
+            to_denom: "uusdc".to_string(),
+            recipient: None,
+            recipients: None,
+            slippage: None,
+            max_price_impact: None,
+            route: Some(vec![SwapRoute {
+                pool_id: 1,
+                token_out_denom: "uusdc".to_string(),
+            }]),
+            referrer: None,
+            refund_on_failure: Some(refund_on_failure),
+            deadline: None,
+        }
+    }
+
+    /// Replies to the swap submessage `index` of `res` with an error
+    fn fail_swap(deps: DepsMut, res: &Response, index: usize) -> Result<Response, ContractError> {
+        reply(
+            deps,
+            mock_env(),
+            Reply {
+                id: res.messages[index].id,
+                result: SubMsgResult::Err("pool drained".to_string()),
+            },
+        )
+    }
+
+    /// Replies to the swap submessage `index` of `res` as succeeded
+    fn complete_swap(
+        deps: DepsMut,
+        res: &Response,
+        index: usize,
+    ) -> Result<Response, ContractError> {
+        reply(
+            deps,
+            mock_env(),
+            Reply {
+                id: res.messages[index].id,
+                result: SubMsgResult::Ok(SubMsgResponse {
+                    events: vec![],
+                    data: None,
+                }),
+            },
+        )
+    }
+
+    fn attr_value(res: &Response, key: &str) -> String {
+        res.attributes
+            .iter()
+            .find(|attr| attr.key == key)
+            .map(|attr| attr.value.clone())
+            .unwrap()
+    }
+
+    fn event_attr(res: &Response, key: &str) -> String {
+        res.events
+            .iter()
+            .flat_map(|event| event.attributes.iter())
+            .find(|attr| attr.key == key)
+            .map(|attr| attr.value.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_concurrent_swaps() {
+        let mut deps = setup();
+        let info = mock_info("alice", &[coin(100, "uosmo")]);
+        let alice = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        let info = mock_info("bob", &[coin(50, "uosmo")]);
+        let bob = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        assert_ne!(alice.messages[0].id, bob.messages[0].id);
+
+        // Replies come back in any order, each forwarding the output of its own swap
+        deps.querier
+            .base
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(40, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &bob, 0).unwrap();
+        assert_eq!(attr_value(&res, "to_amount"), "40");
+        assert_eq!(attr_value(&res, "forward_addr"), "bob");
+
+        // Bob's output has left by the time Alice's swap replies
+        deps.querier
+            .base
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(80, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &alice, 0).unwrap();
+        assert_eq!(attr_value(&res, "to_amount"), "80");
+        assert_eq!(attr_value(&res, "forward_addr"), "alice");
+
+        // A swap replies once
+        assert!(complete_swap(deps.as_mut(), &bob, 0).is_err());
+    }
+
+    /// Charges a 1% fee on `fee_side`, paid to the treasury
+    fn set_fee(deps: &mut TestDeps, fee_side: FeeSide) {
+        let msg = ExecuteMsg::SetFeeConfig {
+            fee_config: Some(FeeConfig {
+                fee_bps: 100,
+                fee_recipient: AndrAddr::from_string("treasury"),
+                fee_side,
+            }),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+    }
+
+    fn bank_send(to_address: &str, amount: Coin) -> SubMsg {
+        SubMsg::new(BankMsg::Send {
+            to_address: to_address.to_string(),
+            amount: vec![amount],
+        })
+    }
+
+    /// The funds the last message of `res` forwards through the kernel
+    fn forwarded_funds(res: &Response) -> Vec<Coin> {
+        let CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr,
+            funds,
+            ..
+        }) = &res.messages.last().unwrap().msg
+        else {
+            panic!("Not a forward");
+        };
+        assert_eq!(contract_addr, KERNEL);
+        funds.clone()
+    }
+
+    /// The coin the swap submessage `index` of `res` offers to the swap router
+    fn swap_input(res: &Response, index: usize) -> Coin {
+        let CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) = &res.messages[index].msg else {
+            panic!("Not a swap router message");
+        };
+        match from_json(msg).unwrap() {
+            OsmosisExecuteMsg::Swap { input_coin, .. } => input_coin,
+            _ => panic!("Not a swap"),
+        }
+    }
+
+    #[test]
+    fn test_input_fee_paid_on_swap() {
+        let mut deps = setup();
+        set_fee(&mut deps, FeeSide::Input);
+        let info = mock_info("alice", &[coin(1000, "uosmo")]);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        assert_eq!(attr_value(&res, "from_amount"), "990");
+        assert_eq!(attr_value(&res, "fee_amount"), "10");
+        assert_eq!(swap_input(&res, 0), coin(990, "uosmo"));
+
+        // The fee held back is paid once the swap succeeds, the whole output is forwarded
+        deps.querier
+            .base
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(500, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(attr_value(&res, "fee_denom"), "uosmo");
+        assert_eq!(attr_value(&res, "fee_amount"), "10");
+        assert_eq!(attr_value(&res, "to_amount"), "500");
+        assert_eq!(res.messages[0], bank_send("treasury", coin(10, "uosmo")));
+        assert_eq!(forwarded_funds(&res), vec![coin(500, "uusdc")]);
+        assert_eq!(attr_value(&res, "forward_addr"), "alice");
+    }
+
+    #[test]
+    fn test_output_fee_paid_on_swap() {
+        let mut deps = setup();
+        set_fee(&mut deps, FeeSide::Output);
+        let info = mock_info("alice", &[coin(1000, "uosmo")]);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        assert_eq!(attr_value(&res, "from_amount"), "1000");
+        assert_eq!(attr_value(&res, "fee_amount"), "0");
+        assert_eq!(swap_input(&res, 0), coin(1000, "uosmo"));
+
+        deps.querier
+            .base
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(500, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(attr_value(&res, "fee_denom"), "uusdc");
+        assert_eq!(attr_value(&res, "fee_amount"), "5");
+        assert_eq!(attr_value(&res, "to_amount"), "495");
+        assert_eq!(res.messages[0], bank_send("treasury", coin(5, "uusdc")));
+        assert_eq!(forwarded_funds(&res), vec![coin(495, "uusdc")]);
+    }
+
+    #[test]
+    fn test_fees_accrue_for_treasury() {
+        let mut deps = setup();
+        set_fee(&mut deps, FeeSide::Output);
+        let msg = ExecuteMsg::SetTreasuryConfig {
+            treasury_config: Some(TreasuryConfig {
+                denom: "uosmo".to_string(),
+                recipient: AndrAddr::from_string("treasury"),
+            }),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+
+        let info = mock_info("alice", &[coin(1000, "uosmo")]);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        deps.querier
+            .base
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(500, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(attr_value(&res, "fee_amount"), "5");
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(forwarded_funds(&res), vec![coin(495, "uusdc")]);
+        assert_eq!(
+            ACCRUED_FEES.load(deps.as_ref().storage, "uusdc").unwrap(),
+            Uint128::new(5)
+        );
+
+        // The fees held in the contract do not count as output of later swaps
+        deps.querier
+            .base
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(5, "uusdc")]);
+        let info = mock_info("bob", &[coin(1000, "uosmo")]);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        deps.querier
+            .base
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(305, "uusdc")]);
+        let res = complete_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(attr_value(&res, "to_amount"), "297");
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(forwarded_funds(&res), vec![coin(297, "uusdc")]);
+        assert_eq!(
+            ACCRUED_FEES.load(deps.as_ref().storage, "uusdc").unwrap(),
+            Uint128::new(8)
+        );
+    }
+
+    fn convert_fees() -> ExecuteMsg {
+        ExecuteMsg::ConvertFees {
+            denoms: None,
+            slippage_percentage: None,
+            window_seconds: None,
+            minimum_receive: None,
+        }
+    }
+
+    #[test]
+    fn test_convert_fees_restricted_to_keepers() {
+        let mut deps = setup();
+        set_fee(&mut deps, FeeSide::Output);
+        let msg = ExecuteMsg::SetTreasuryConfig {
+            treasury_config: Some(TreasuryConfig {
+                denom: "uusdc".to_string(),
+                recipient: AndrAddr::from_string("treasury"),
+            }),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        ACCRUED_FEES
+            .save(deps.as_mut().storage, "uusdc", &Uint128::new(10))
+            .unwrap();
+
+        let info = mock_info("bot", &[]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, convert_fees()).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+
+        // Granted keepers convert fees next to the owner
+        let msg = ExecuteMsg::GrantRole {
+            role: Role::Keeper,
+            address: AndrAddr::from_string("bot"),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        for sender in ["bot", OWNER] {
+            ACCRUED_FEES
+                .save(deps.as_mut().storage, "uusdc", &Uint128::new(10))
+                .unwrap();
+            let info = mock_info(sender, &[]);
+            assert!(execute(deps.as_mut(), mock_env(), info, convert_fees()).is_ok());
+        }
+
+        // Other roles do not convert fees
+        let msg = ExecuteMsg::GrantRole {
+            role: Role::FeeAdmin,
+            address: AndrAddr::from_string("admin"),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        let info = mock_info("admin", &[]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, convert_fees()).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+    }
+
+    fn set_prices(deps: &mut TestDeps, twap: &str, spot_price: &str) {
+        deps.querier.set_response(
+            ARITHMETIC_TWAP_TO_NOW_PATH,
+            &ArithmeticTwapToNowResponse {
+                arithmetic_twap: twap.to_string(),
+            },
+        );
+        deps.querier.set_response(
+            SPOT_PRICE_PATH,
+            &SpotPriceResponse {
+                spot_price: spot_price.to_string(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_twap_guard() {
+        let mut deps = setup();
+        let set_guard = |window_seconds| ExecuteMsg::SetTwapGuard {
+            twap_guard: Some(TwapGuard {
+                window_seconds,
+                max_deviation: Decimal::percent(5),
+            }),
+        };
+        // Osmosis keeps twap records for 48 hours
+        let info = mock_info(OWNER, &[]);
+        let msg = set_guard(MAX_TWAP_WINDOW_SECONDS + 1);
+        assert!(execute(deps.as_mut(), mock_env(), info, msg).is_err());
+        let info = mock_info(OWNER, &[]);
+        execute(deps.as_mut(), mock_env(), info, set_guard(3_600)).unwrap();
+
+        set_prices(&mut deps, "2", "2.1");
+        let info = mock_info("alice", &[coin(100, "uosmo")]);
+        assert!(execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).is_ok());
+
+        set_prices(&mut deps, "2", "1.8");
+        let info = mock_info("alice", &[coin(100, "uosmo")]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Spot price of pool 1 deviates 0.1 from its twap, above the max deviation 0.05"
+            ))
+        );
+
+        set_prices(&mut deps, "0", "2");
+        let info = mock_info("alice", &[coin(100, "uosmo")]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap_err(),
+            ContractError::Std(StdError::generic_err("Pool 1 has no twap"))
+        );
+
+        // The window cannot start before the first block
+        set_prices(&mut deps, "2", "2");
+        let mut env = mock_env();
+        env.block.time = Timestamp::from_seconds(600);
+        let info = mock_info("alice", &[coin(100, "uosmo")]);
+        assert_eq!(
+            execute(deps.as_mut(), env, info, swap_to_usdc(false)).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Twap window of 3600 seconds starts before the first block"
+            ))
+        );
+    }
+
+    /// Sets up an exact-output swap of at most 1000 uosmo into 100 uusdc with a 1% input fee,
+    /// where the swap is estimated to take 100 uosmo
+    fn swap_exact_out(deps: &mut TestDeps) -> Response {
+        set_fee(deps, FeeSide::Input);
+        deps.querier.set_response(
+            ESTIMATE_SWAP_EXACT_AMOUNT_OUT_PATH,
+            &EstimateSwapExactAmountOutResponse {
+                token_in_amount: "100".to_string(),
+            },
+        );
+        let funds = vec![coin(1000, "uosmo")];
+        deps.querier
+            .base
+            .update_balance(MOCK_CONTRACT_ADDR, funds.clone());
+        let msg = ExecuteMsg::SwapExactOutAndForward {
+            to_denom: "uusdc".to_string(),
+            to_amount: Uint128::new(100),
+            recipient: None,
+            refund_addr: None,
+            slippage_percentage: Some(Decimal::percent(10)),
+            max_price_impact: None,
+            route: Some(vec![SwapRoute {
+                pool_id: 1,
+                token_out_denom: "uusdc".to_string(),
+            }]),
+            refund_on_failure: Some(true),
+            deadline: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("alice", &funds), msg).unwrap()
+    }
+
+    #[test]
+    fn test_swap_exact_out_refunds_clamped_input() {
+        let mut deps = setup();
+        let res = swap_exact_out(&mut deps);
+
+        // The slippage caps the swap input at 110, well below the 990 offered besides the fee reserve
+        deps.querier.base.update_balance(
+            MOCK_CONTRACT_ADDR,
+            vec![coin(900, "uosmo"), coin(100, "uusdc")],
+        );
+        let res = complete_swap(deps.as_mut(), &res, 0).unwrap();
+
+        // The swap spent 100, the fee is settled on it and the rest of the offer goes back
+        assert_eq!(attr_value(&res, "refund_amount"), "899");
+        assert_eq!(attr_value(&res, "fee_amount"), "1");
+        assert_eq!(attr_value(&res, "to_amount"), "100");
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: vec![coin(899, "uosmo")],
+            })
+        );
+    }
+
+    #[test]
+    fn test_failed_swap_exact_out_refunds_whole_offer() {
+        let mut deps = setup();
+        let res = swap_exact_out(&mut deps);
+
+        let res = fail_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(event_attr(&res, "refund_amount"), "1000");
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: vec![coin(1000, "uosmo")],
+            })]
+        );
+    }
+
+    #[test]
+    fn test_failed_swap_refunds_sender() {
+        let mut deps = setup();
+        let funds = vec![coin(100, "uosmo")];
+        let info = mock_info("alice", &funds);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(true)).unwrap();
+
+        let res = fail_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(event_attr(&res, "refund_addr"), "alice");
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: funds,
+            })]
+        );
+    }
+
+    #[test]
+    fn test_failed_amp_swap_refunds_origin() {
+        let mut deps = setup();
+        let funds = vec![coin(100, "uosmo")];
+        let res = amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(true)).unwrap();
+
+        // The refund is relayed back through the kernel to the origin of the packet
+        let res = fail_swap(deps.as_mut(), &res, 0).unwrap();
+        assert_eq!(event_attr(&res, "refund_addr"), "alice");
+        assert!(matches!(
+            &res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, funds: sent, .. })
+                if contract_addr == KERNEL && *sent == funds
+        ));
+    }
+
+    #[test]
+    fn test_expired_amp_swap_refunds_origin() {
+        let mut deps = setup();
+        let funds = vec![coin(100, "uosmo")];
+        let deadline = Expiration::AtHeight(1);
+        let mut msg = swap_to_usdc(false);
+        if let ExecuteMsg::SwapAndForward {
+            deadline: expiry, ..
+        } = &mut msg
+        {
+            *expiry = Some(deadline);
+        }
+
+        let info = mock_info("alice", &funds);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err(),
+            expired_error(&deadline)
+        );
+
+        // Packets are refunded to their origin rather than the kernel relaying them
+        let res = amp_execute(deps.as_mut(), "alice", &funds, msg).unwrap();
+        assert_eq!(attr_value(&res, "action"), "refund-expired-swap");
+        assert_eq!(attr_value(&res, "refund_addr"), "alice");
+        assert!(matches!(
+            &res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, funds: sent, .. })
+                if contract_addr == KERNEL && *sent == funds
+        ));
+    }
+
+    #[test]
+    fn test_failed_swap_without_refund_reverts() {
+        let mut deps = setup();
+        let funds = [coin(100, "uosmo")];
+        let res = amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(false)).unwrap();
+        assert!(fail_swap(deps.as_mut(), &res, 0).is_err());
+    }
+
+    #[test]
+    fn test_sender_volume_follows_swap_origin() {
+        let mut deps = setup();
+        let msg = ExecuteMsg::SetVolumeLimit {
+            denom: "uosmo".to_string(),
+            volume_limit: Some(VolumeLimit {
+                max_swap_amount: None,
+                max_sender_volume: Some(Uint128::new(100)),
+                max_asset_volume: None,
+                window_seconds: 3_600,
+            }),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        let funds = [coin(60, "uosmo")];
+
+        // Packets count against their origins rather than the kernel relaying them
+        assert!(amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(false)).is_ok());
+        assert!(amp_execute(deps.as_mut(), "bob", &funds, swap_to_usdc(false)).is_ok());
+        let volume = |deps: &OwnedDeps<_, _, _>, sender: &str| {
+            sender_volume(deps.as_ref().storage, &Addr::unchecked(sender), "uosmo", 0).unwrap()
+        };
+        assert_eq!(volume(&deps, "alice"), Uint128::new(60));
+        assert_eq!(volume(&deps, "bob"), Uint128::new(60));
+        assert!(volume(&deps, KERNEL).is_zero());
+
+        // Direct swaps share the cap of the same address
+        let info = mock_info("alice", &funds);
+        assert!(execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).is_err());
+        assert!(amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(false)).is_err());
+        let info = mock_info("carol", &funds);
+        assert!(execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).is_ok());
+    }
+
+    #[test]
+    fn test_swaps_open_by_default() {
+        let mut deps = setup();
+        let funds = [coin(100, "uosmo")];
+        assert!(amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(false)).is_ok());
+        let info = mock_info("alice", &funds);
+        assert!(execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).is_ok());
+    }
+
+    #[test]
+    fn test_permissioned_swaps_check_origin() {
+        let mut deps = setup_with(true);
+        ADOContract::set_permission(
+            deps.as_mut().storage,
+            SWAP_AND_FORWARD_ACTION,
+            "alice",
+            Permission::Whitelisted(None),
+        )
+        .unwrap();
+        let funds = [coin(100, "uosmo")];
+
+        // Packets are checked against their origin rather than the kernel relaying them
+        assert!(amp_execute(deps.as_mut(), "alice", &funds, swap_to_usdc(false)).is_ok());
+        assert_eq!(
+            amp_execute(deps.as_mut(), "mallory", &funds, swap_to_usdc(false)).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+
+        // Direct calls are checked against their sender
+        let info = mock_info("alice", &funds);
+        assert!(execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).is_ok());
+        for sender in ["mallory", KERNEL] {
+            let info = mock_info(sender, &funds);
+            assert_eq!(
+                execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap_err(),
+                ContractError::Unauthorized {}
+            );
+        }
+    }
+
+    fn usdc_route() -> Vec<SwapRoute> {
+        vec![
+            SwapRoute {
+                pool_id: 3,
+                token_out_denom: "uatom".to_string(),
+            },
+            SwapRoute {
+                pool_id: 4,
+                token_out_denom: "uusdc".to_string(),
+            },
+        ]
+    }
+
+    /// The pools the swap submessage `index` of `res` routes through
+    fn routed_pools(res: &Response, index: usize) -> Vec<u64> {
+        let CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) = &res.messages[index].msg else {
+            panic!("Not a swap router message");
+        };
+        match from_json(msg).unwrap() {
+            OsmosisExecuteMsg::Swap { route, .. } => {
+                route.unwrap().iter().map(|hop| hop.pool_id).collect()
+            }
+            _ => panic!("Not a swap"),
+        }
+    }
+
+    fn query_routes(deps: &TestDeps) -> Vec<RouteInfo> {
+        let msg = QueryMsg::Routes {
+            start_after: None,
+            limit: None,
+        };
+        let res: RoutesResponse =
+            from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        res.routes
+    }
+
+    #[test]
+    fn test_route_registry() {
+        let mut deps = setup();
+        let set_route = ExecuteMsg::SetRoute {
+            from_denom: "uosmo".to_string(),
+            to_denom: "uusdc".to_string(),
+            route: usdc_route(),
+        };
+        let remove_route = ExecuteMsg::RemoveRoute {
+            from_denom: "uosmo".to_string(),
+            to_denom: "uusdc".to_string(),
+        };
+
+        // Only router admins manage routes
+        let info = mock_info("alice", &[]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, set_route.clone()).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+        let info = mock_info(OWNER, &[]);
+        execute(deps.as_mut(), mock_env(), info, set_route).unwrap();
+        assert_eq!(
+            query_routes(&deps),
+            vec![RouteInfo {
+                from_denom: "uosmo".to_string(),
+                to_denom: "uusdc".to_string(),
+                route: usdc_route(),
+            }]
+        );
+
+        // Swaps without a route of their own take the registered one
+        let mut unrouted = swap_to_usdc(false);
+        if let ExecuteMsg::SwapAndForward { route, .. } = &mut unrouted {
+            *route = None;
+        }
+        let info = mock_info("alice", &[coin(100, "uosmo")]);
+        let res = execute(deps.as_mut(), mock_env(), info, unrouted.clone()).unwrap();
+        assert_eq!(routed_pools(&res, 0), vec![3, 4]);
+        let info = mock_info("alice", &[coin(100, "uosmo")]);
+        let res = execute(deps.as_mut(), mock_env(), info, swap_to_usdc(false)).unwrap();
+        assert_eq!(routed_pools(&res, 0), vec![1]);
+
+        let info = mock_info("alice", &[]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, remove_route.clone()).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+        let info = mock_info(OWNER, &[]);
+        execute(deps.as_mut(), mock_env(), info, remove_route.clone()).unwrap();
+        assert!(query_routes(&deps).is_empty());
+        let info = mock_info(OWNER, &[]);
+        assert!(execute(deps.as_mut(), mock_env(), info, remove_route).is_err());
+
+        // Without a registered route the swap router is asked for one
+        let info = mock_info("alice", &[coin(100, "uosmo")]);
+        assert!(execute(deps.as_mut(), mock_env(), info, unrouted).is_err());
+    }
+
+    fn hop(pool_id: u64, token_out_denom: &str) -> SwapRoute {
+        SwapRoute {
+            pool_id,
+            token_out_denom: token_out_denom.to_string(),
+        }
+    }
+
+    /// Swaps 100 uosmo into uusdc through `route`
+    fn swap_through(deps: &mut TestDeps, route: Vec<SwapRoute>) -> Result<Response, ContractError> {
+        let mut msg = swap_to_usdc(false);
+        if let ExecuteMsg::SwapAndForward {
+            route: swap_route, ..
+        } = &mut msg
+        {
+            *swap_route = Some(route);
+        }
+        let info = mock_info("alice", &[coin(100, "uosmo")]);
+        execute(deps.as_mut(), mock_env(), info, msg)
+    }
+
+    #[test]
+    fn test_swap_route_hops() {
+        let mut deps = setup();
+        let info = mock_info(OWNER, &[]);
+        let msg = ExecuteMsg::UpdateMaxHops { max_hops: 2 };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        assert_eq!(
+            swap_through(&mut deps, vec![]).unwrap_err(),
+            ContractError::Std(StdError::generic_err("Swap route cannot be empty"))
+        );
+        assert_eq!(
+            swap_through(&mut deps, vec![hop(1, "uosmo"), hop(2, "uusdc")]).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Swap route hop 0 through pool 1 swaps uosmo into itself"
+            ))
+        );
+        assert_eq!(
+            swap_through(&mut deps, vec![hop(1, "uatom"), hop(2, "uatom")]).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Swap route hop 1 through pool 2 swaps uatom into itself"
+            ))
+        );
+        assert_eq!(
+            swap_through(&mut deps, vec![hop(1, "uatom")]).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Swap route ends with uatom instead of uusdc"
+            ))
+        );
+        let three_hops = vec![hop(1, "uatom"), hop(2, "uion"), hop(3, "uusdc")];
+        assert_eq!(
+            swap_through(&mut deps, three_hops).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Swap route takes 3 hops, at most 2 are allowed"
+            ))
+        );
+        let res = swap_through(&mut deps, vec![hop(1, "uatom"), hop(2, "uusdc")]).unwrap();
+        assert_eq!(routed_pools(&res, 0), vec![1, 2]);
+
+        // Registered routes are held to the same rules
+        let info = mock_info(OWNER, &[]);
+        let msg = ExecuteMsg::SetRoute {
+            from_denom: "uosmo".to_string(),
+            to_denom: "uusdc".to_string(),
+            route: vec![hop(1, "uatom")],
+        };
+        assert!(execute(deps.as_mut(), mock_env(), info, msg).is_err());
+    }
+
+    fn set_pause(
+        deps: &mut TestDeps,
+        sender: &str,
+        scope: PauseScope,
+        paused: bool,
+    ) -> Result<Response, ContractError> {
+        let msg = if paused {
+            ExecuteMsg::Pause { scope }
+        } else {
+            ExecuteMsg::Unpause { scope }
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(sender, &[]), msg)
+    }
+
+    fn paused_by_bot(scope: &str) -> ContractError {
+        ContractError::Std(StdError::generic_err(format!(
+            "Paused: {} are paused by bot",
+            scope
+        )))
+    }
+
+    #[test]
+    fn test_pause_scopes() {
+        let mut deps = setup();
+        let direct = vec![hop(1, "uusdc")];
+        let through_atom = vec![hop(1, "uatom"), hop(2, "uusdc")];
+
+        // Only pausers pause
+        assert_eq!(
+            set_pause(&mut deps, "bot", PauseScope::Global, true).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+        let msg = ExecuteMsg::GrantRole {
+            role: Role::Pauser,
+            address: AndrAddr::from_string("bot"),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+
+        // A global pause stops every swap
+        set_pause(&mut deps, "bot", PauseScope::Global, true).unwrap();
+        assert!(set_pause(&mut deps, "bot", PauseScope::Global, true).is_err());
+        let msg = QueryMsg::PauseStatus {
+            start_after: None,
+            limit: None,
+        };
+        let res: PauseStatusResponse =
+            from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.pauses.len(), 1);
+        assert_eq!(res.pauses[0].paused_by, Addr::unchecked("bot"));
+        for route in [direct.clone(), through_atom.clone()] {
+            assert_eq!(
+                swap_through(&mut deps, route).unwrap_err(),
+                paused_by_bot("all swaps")
+            );
+        }
+        set_pause(&mut deps, "bot", PauseScope::Global, false).unwrap();
+        assert!(set_pause(&mut deps, "bot", PauseScope::Global, false).is_err());
+        assert!(swap_through(&mut deps, direct.clone()).is_ok());
+
+        // A denom pause stops swaps offering, asking for or passing through the denom
+        let atom = PauseScope::Denom {
+            denom: "uatom".to_string(),
+        };
+        set_pause(&mut deps, "bot", atom.clone(), true).unwrap();
+        assert_eq!(
+            swap_through(&mut deps, through_atom.clone()).unwrap_err(),
+            paused_by_bot("swaps through uatom")
+        );
+        assert!(swap_through(&mut deps, direct.clone()).is_ok());
+        set_pause(&mut deps, "bot", atom, false).unwrap();
+        let usdc = PauseScope::Denom {
+            denom: "uusdc".to_string(),
+        };
+        set_pause(&mut deps, "bot", usdc.clone(), true).unwrap();
+        assert_eq!(
+            swap_through(&mut deps, direct.clone()).unwrap_err(),
+            paused_by_bot("swaps through uusdc")
+        );
+        set_pause(&mut deps, "bot", usdc, false).unwrap();
+
+        // A pair pause only stops swaps in its direction
+        let pair = PauseScope::Pair {
+            from_denom: "uosmo".to_string(),
+            to_denom: "uusdc".to_string(),
+        };
+        set_pause(&mut deps, "bot", pair.clone(), true).unwrap();
+        for route in [direct.clone(), through_atom] {
+            assert_eq!(
+                swap_through(&mut deps, route).unwrap_err(),
+                paused_by_bot("swaps from uosmo to uusdc")
+            );
+        }
+        let mut reverse = swap_to_usdc(false);
+        if let ExecuteMsg::SwapAndForward {
+            to_denom, route, ..
+        } = &mut reverse
+        {
+            *to_denom = "uosmo".to_string();
+            *route = Some(vec![hop(1, "uosmo")]);
+        }
+        let info = mock_info("alice", &[coin(100, "uusdc")]);
+        assert!(execute(deps.as_mut(), mock_env(), info, reverse).is_ok());
+        set_pause(&mut deps, "bot", pair, false).unwrap();
+        assert!(swap_through(&mut deps, direct).is_ok());
+    }
+
+    fn set_router_code(deps: &mut TestDeps, code_id: u64) {
+        deps.querier.base.update_wasm(move |query| match query {
+            WasmQuery::ContractInfo { .. } => SystemResult::Ok(ContractResult::Ok(
+                to_json_binary(&ContractInfoResponse::new(code_id, "creator")).unwrap(),
+            )),
+            _ => panic!("Unexpected query"),
+        });
+    }
+
+    fn execute_at(
+        deps: &mut TestDeps,
+        seconds: u64,
+        msg: ExecuteMsg,
+    ) -> Result<Response, ContractError> {
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(seconds);
+        execute(deps.as_mut(), env, mock_info(OWNER, &[]), msg)
+    }
+
+    #[test]
+    fn test_swap_router_timelock() {
+        let mut deps = setup();
+        set_router_code(&mut deps, 7);
+        let propose = ExecuteMsg::ProposeSwapRouter {
+            swap_router: AndrAddr::from_string("router2"),
+            code_pin: Some(CodePin::CodeId(7)),
+        };
+        let info = mock_info("alice", &[]);
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), info, propose.clone()).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+        execute_at(&mut deps, 0, propose).unwrap();
+
+        // The proposal waits out the timelock, swaps keep using the current router
+        let timelock = DEFAULT_SWAP_ROUTER_TIMELOCK;
+        assert!(execute_at(&mut deps, timelock - 1, ExecuteMsg::AcceptSwapRouter {}).is_err());
+        assert_eq!(
+            load_swap_router(&deps.as_ref()).unwrap(),
+            Addr::unchecked("router")
+        );
+        execute_at(&mut deps, timelock, ExecuteMsg::AcceptSwapRouter {}).unwrap();
+        assert_eq!(
+            load_swap_router(&deps.as_ref()).unwrap(),
+            Addr::unchecked("router2")
+        );
+        let res = swap_usdc(&mut deps).unwrap();
+        assert!(matches!(
+            &res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, .. }) if contract_addr == "router2"
+        ));
+
+        // Swaps stop once the router is migrated off the pinned code
+        set_router_code(&mut deps, 8);
+        let err = ContractError::Std(StdError::generic_err(
+            "Swap router router2 runs code id 8, which does not match the pinned code id 7",
+        ));
+        assert_eq!(load_swap_router(&deps.as_ref()).unwrap_err(), err);
+        assert_eq!(swap_usdc(&mut deps).unwrap_err(), err);
+
+        // The timelock can only be raised, up to its maximum
+        let set_timelock =
+            |timelock_seconds| ExecuteMsg::SetSwapRouterTimelock { timelock_seconds };
+        assert!(execute_at(&mut deps, 0, set_timelock(timelock - 1)).is_err());
+        assert_eq!(
+            execute_at(&mut deps, 0, set_timelock(MAX_SWAP_ROUTER_TIMELOCK + 1)).unwrap_err(),
+            ContractError::Std(StdError::generic_err(
+                "Swap router timelock cannot exceed 2592000 seconds"
+            ))
+        );
+        execute_at(&mut deps, 0, set_timelock(MAX_SWAP_ROUTER_TIMELOCK)).unwrap();
+        let res: SwapRouterResponse =
+            from_json(query(deps.as_ref(), mock_env(), QueryMsg::SwapRouter {}).unwrap()).unwrap();
+        assert_eq!(res.timelock_seconds, MAX_SWAP_ROUTER_TIMELOCK);
+        assert_eq!(res.code_pin, Some(CodePin::CodeId(7)));
+        assert_eq!(res.proposal, None);
+
+        let msg = InstantiateMsg {
+            kernel_address: KERNEL.to_string(),
+            owner: None,
+            swap_router: None,
+            swap_router_timelock: Some(MAX_SWAP_ROUTER_TIMELOCK + 1),
+            permissioned_swaps: None,
+        };
+        let mut deps = mock_dependencies();
+        assert!(instantiate(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).is_err());
+    }
+
+    fn update_list(
+        deps: &mut TestDeps,
+        list: AssetList,
+        add: &[&str],
+        remove: &[&str],
+    ) -> Result<Response, ContractError> {
+        let msg = ExecuteMsg::UpdateAssetList {
+            list,
+            add: add.iter().map(|denom| denom.to_string()).collect(),
+            remove: remove.iter().map(|denom| denom.to_string()).collect(),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg)
+    }
+
+    fn swap_usdc(deps: &mut TestDeps) -> Result<Response, ContractError> {
+        swap_through(deps, vec![hop(1, "uusdc")])
+    }
+
+    #[test]
+    fn test_asset_lists() {
+        let mut deps = setup();
+        let msg = ExecuteMsg::UpdateAssetList {
+            list: AssetList::Deny,
+            add: vec!["uusdc".to_string()],
+            remove: vec![],
+        };
+        assert_eq!(
+            execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err(),
+            ContractError::Unauthorized {}
+        );
+
+        // Denied denoms can neither be offered nor asked for
+        update_list(&mut deps, AssetList::Deny, &["uusdc"], &[]).unwrap();
+        assert_eq!(
+            swap_usdc(&mut deps).unwrap_err(),
+            ContractError::Std(StdError::generic_err("uusdc is on the deny list"))
+        );
+        update_list(&mut deps, AssetList::Deny, &["uosmo"], &["uusdc"]).unwrap();
+        assert_eq!(
+            swap_usdc(&mut deps).unwrap_err(),
+            ContractError::Std(StdError::generic_err("uosmo is on the deny list"))
+        );
+        update_list(&mut deps, AssetList::Deny, &[], &["uosmo"]).unwrap();
+        assert!(swap_usdc(&mut deps).is_ok());
+
+        // Once a denom is allowed, every other denom is refused
+        update_list(&mut deps, AssetList::Allow, &["uosmo"], &[]).unwrap();
+        assert_eq!(
+            swap_usdc(&mut deps).unwrap_err(),
+            ContractError::Std(StdError::generic_err("uusdc is not on the allow list"))
+        );
+        update_list(&mut deps, AssetList::Allow, &["uusdc"], &[]).unwrap();
+        assert!(swap_usdc(&mut deps).is_ok());
+        let msg = QueryMsg::AssetList {
+            list: AssetList::Allow,
+            start_after: None,
+            limit: None,
+        };
+        let res: AssetListResponse =
+            from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.denoms, vec!["uosmo", "uusdc"]);
+
+        // The deny list wins over the allow list
+        update_list(&mut deps, AssetList::Deny, &["uusdc"], &[]).unwrap();
+        assert_eq!(
+            swap_usdc(&mut deps).unwrap_err(),
+            ContractError::Std(StdError::generic_err("uusdc is on the deny list"))
+        );
+    }
+
+    #[test]
+    fn test_batch_legs_into_same_denom() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+
+        // Both legs are queued before either swap runs
+        PREV_BALANCE
+            .save(deps.as_mut().storage, 1, &Uint128::zero())
+            .unwrap();
+        PREV_BALANCE
+            .save(deps.as_mut().storage, 2, &Uint128::zero())
+            .unwrap();
+
+        // The first leg returns 100, its reply forwards 99 and accrues a fee of 1
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(100, "uusdc")]);
+        let output = take_swap_output(deps.as_mut(), &env, "uusdc", 1).unwrap();
+        assert_eq!(output, Uint128::new(100));
+        ACCRUED_FEES
+            .save(deps.as_mut().storage, "uusdc", &Uint128::one())
+            .unwrap();
+
+        // The second leg only gets its own output, not the fee left by the first one
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(201, "uusdc")]);
+        let output = take_swap_output(deps.as_mut(), &env, "uusdc", 2).unwrap();
+        assert_eq!(output, Uint128::new(200));
+
+        for swap_id in [1, 2] {
+            assert!(!PREV_BALANCE.has(deps.as_ref().storage, swap_id));
+        }
+    }
+
+    #[test]
+    fn test_swap_output_below_recorded_balance() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        PREV_BALANCE
+            .save(deps.as_mut().storage, 1, &Uint128::new(50))
+            .unwrap();
+        deps.querier
+            .update_balance(MOCK_CONTRACT_ADDR, vec![coin(40, "uusdc")]);
+        assert!(take_swap_output(deps.as_mut(), &env, "uusdc", 1).is_err());
+    }
+
+    fn consolidation_swap(from_denom: &str, consolidation_id: u64) -> ForwardReplyState {
+        ForwardReplyState {
+            recipients: vec![WeightedRecipient::new(
+                Recipient::from_string("alice"),
+                Uint128::one(),
+            )],
+            refund_addr: AndrAddr::from_string("sender"),
+            amp_ctx: None,
+            from_denom: from_denom.to_string(),
+            from_amount: Uint128::new(100),
+            offered_amount: Uint128::new(100),
+            to_denom: "uusdc".to_string(),
+            refund_on_failure: false,
+            prev_from_balance: None,
+            input_fee: Uint128::zero(),
+            output_fee: Uint128::zero(),
+            referrer: None,
+            batch_leg: None,
+            consolidation_id: Some(consolidation_id),
+        }
+    }
+
+    #[test]
+    fn test_consolidation_reply_accounting() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let consolidation_id = 1;
+
+        // 10 uusdc was sent along with three swaps, all queued at a balance of 10
+        CONSOLIDATIONS
+            .save(
+                deps.as_mut().storage,
+                consolidation_id,
+                &Consolidation {
+                    recipients: consolidation_swap("uosmo", consolidation_id).recipients,
+                    amp_ctx: None,
+                    to_denom: "uusdc".to_string(),
+                    minimum_receive: None,
+                    pending_swaps: 3,
+                    total_amount: Uint128::new(10),
+                    received_amount: Uint128::zero(),
+                    charge_fee: true,
+                },
+            )
+            .unwrap();
+        for swap_id in [2, 3, 4] {
+            PREV_BALANCE
+                .save(deps.as_mut().storage, swap_id, &Uint128::new(10))
+                .unwrap();
+        }
+
+        // Each reply only books its own swap, the earlier output is still held by the contract
+        for (swap_id, balance, total_amount) in [(2, 50, 50), (3, 110, 110)] {
+            deps.querier
+                .update_balance(MOCK_CONTRACT_ADDR, vec![coin(balance, "uusdc")]);
+            let resp = handle_osmosis_consolidation_reply(
+                deps.as_mut(),
+                env.clone(),
+                swap_id,
+                consolidation_id,
+                consolidation_swap("uosmo", consolidation_id),
+            )
+            .unwrap();
+            assert!(resp.messages.is_empty());
+
+            let consolidation = CONSOLIDATIONS
+                .load(deps.as_ref().storage, consolidation_id)
+                .unwrap();
+            assert_eq!(consolidation.total_amount, Uint128::new(total_amount));
+            assert_eq!(
+                consolidation.received_amount,
+                Uint128::new(total_amount - 10)
+            );
+            assert!(!PREV_BALANCE.has(deps.as_ref().storage, swap_id));
+        }
+        let consolidation = CONSOLIDATIONS
+            .load(deps.as_ref().storage, consolidation_id)
+            .unwrap();
+        assert_eq!(consolidation.pending_swaps, 1);
+    }
+
+    fn twap(slippage_percentage: Decimal) -> Slippage {
+        Slippage::Twap {
+            window_seconds: None,
+            slippage_percentage,
+        }
+    }
+
+    #[test]
+    fn test_resolve_slippage_without_policy() {
+        let deps = mock_dependencies();
+        let resolve = |slippage| {
+            resolve_slippage(
+                deps.as_ref(),
+                "uosmo",
+                Uint128::new(100),
+                "uusdc",
+                &[],
+                slippage,
+            )
+        };
+        assert_eq!(resolve(None).unwrap(), twap(DEFAULT_SLIPPAGE_PERCENTAGE));
+        assert_eq!(DEFAULT_SLIPPAGE_PERCENTAGE, Decimal::percent(1));
+        // Any slippage goes through while no policy bounds it
+        assert_eq!(
+            resolve(Some(twap(Decimal::percent(50)))).unwrap(),
+            twap(Decimal::percent(50))
+        );
+        assert_eq!(
+            resolve(Some(Slippage::MinOutputAmount(Uint128::one()))).unwrap(),
+            Slippage::MinOutputAmount(Uint128::one())
+        );
+    }
+
+    #[test]
+    fn test_resolve_slippage_with_policy() {
+        let mut deps = mock_dependencies();
+        SLIPPAGE_POLICY
+            .save(
+                deps.as_mut().storage,
+                &SlippagePolicy {
+                    default_slippage: Decimal::percent(1),
+                    max_slippage: Decimal::percent(3),
+                },
+            )
+            .unwrap();
+        PAIR_SLIPPAGE_POLICIES
+            .save(
+                deps.as_mut().storage,
+                ("uatom".to_string(), "uusdc".to_string()),
+                &SlippagePolicy {
+                    default_slippage: Decimal::percent(2),
+                    max_slippage: Decimal::percent(5),
+                },
+            )
+            .unwrap();
+        let resolve = |from_denom, slippage| {
+            resolve_slippage(
+                deps.as_ref(),
+                from_denom,
+                Uint128::new(100),
+                "uusdc",
+                &[],
+                slippage,
+            )
+        };
+
+        assert_eq!(resolve("uosmo", None).unwrap(), twap(Decimal::percent(1)));
+        assert_eq!(
+            resolve("uosmo", Some(twap(Decimal::percent(3)))).unwrap(),
+            twap(Decimal::percent(3))
+        );
+        assert!(resolve("uosmo", Some(twap(Decimal::percent(4)))).is_err());
+
+        // The pair override takes the place of the global policy
+        assert_eq!(resolve("uatom", None).unwrap(), twap(Decimal::percent(2)));
+        assert_eq!(
+            resolve("uatom", Some(twap(Decimal::percent(4)))).unwrap(),
+            twap(Decimal::percent(4))
+        );
+        assert!(resolve("uatom", Some(twap(Decimal::percent(6)))).is_err());
+    }
+
+    #[test]
+    fn test_volume_window_edge_bucket() {
+        let mut deps = mock_dependencies();
+        let mut env = mock_env();
+        let sender = Addr::unchecked("sender");
+        // 24 buckets of 100 seconds
+        let volume_limit = VolumeLimit {
+            max_swap_amount: None,
+            max_sender_volume: Some(Uint128::new(60)),
+            max_asset_volume: Some(Uint128::new(100)),
+            window_seconds: 2_400,
+        };
+        VOLUME_LIMITS
+            .save(deps.as_mut().storage, "uusdc", &volume_limit)
+            .unwrap();
+
+        env.block.time = Timestamp::from_seconds(7_650);
+        record_swap_volume(deps.as_mut(), &env, &sender, "uusdc", Uint128::new(60)).unwrap();
+
+        // The window edge at 7_650 falls in the bucket starting at 7_600, which still counts
+        env.block.time = Timestamp::from_seconds(10_050);
+        let since = volume_limit.window_start(10_050);
+        assert_eq!(since, 7_600);
+        assert_eq!(
+            asset_volume(deps.as_ref().storage, "uusdc", since).unwrap(),
+            Uint128::new(60)
+        );
+        let other = Addr::unchecked("other");
+        assert!(
+            record_swap_volume(deps.as_mut(), &env, &other, "uusdc", Uint128::new(41)).is_err()
+        );
+        // Passes the asset cap, recording 1 the failed transaction would revert, but not the
+        // sender cap
+        assert!(record_swap_volume(deps.as_mut(), &env, &sender, "uusdc", Uint128::one()).is_err());
+
+        // Once the window moves past the bucket, it is pruned and no longer counts
+        env.block.time = Timestamp::from_seconds(10_100);
+        record_swap_volume(deps.as_mut(), &env, &sender, "uusdc", Uint128::new(60)).unwrap();
+        record_swap_volume(deps.as_mut(), &env, &other, "uusdc", Uint128::new(39)).unwrap();
+        assert!(!ASSET_VOLUMES.has(deps.as_ref().storage, ("uusdc", 7_600)));
+        assert!(!SENDER_VOLUMES.has(deps.as_ref().storage, (&sender, "uusdc", 7_600)));
+        let since = volume_limit.window_start(10_100);
+        assert_eq!(
+            sender_volume(deps.as_ref().storage, &sender, "uusdc", since).unwrap(),
+            Uint128::new(60)
+        );
+        assert_eq!(
+            asset_volume(deps.as_ref().storage, "uusdc", since).unwrap(),
+            Uint128::new(100)
+        );
+    }
+
+    fn pool_page(first_id: u64, len: u64) -> Vec<Any> {
+        (first_id..first_id + len)
+            .map(|id| Any {
+                type_url: "pool".to_string(),
+                value: id.to_be_bytes().to_vec(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pool_pages_follow_next_key() {
+        let mut pools = vec![];
+        query_pool_pages(&mut pools, |pagination| {
+            // Pages are keyed by the id of their first pool, the third page is the last one
+            let first_id = match pagination.key.as_slice() {
+                [] => 0,
+                key => u64::from_be_bytes(key.try_into().unwrap()),
+            };
+            let next_key = match first_id {
+                0 | 100 => (first_id + pagination.limit).to_be_bytes().to_vec(),
+                _ => vec![],
+            };
+            Ok((
+                pool_page(first_id, pagination.limit.min(10)),
+                Some(PageResponse { next_key, total: 0 }),
+            ))
+        })
+        .unwrap();
+        assert_eq!(pools.len(), 30);
+    }
+
+    #[test]
+    fn test_pool_pages_stop_at_max_pools() {
+        let mut pools = pool_page(0, 950);
+        let mut queried = 0;
+        query_pool_pages(&mut pools, |pagination| {
+            queried += 1;
+            let next_key = vec![1];
+            Ok((
+                pool_page(0, pagination.limit),
+                Some(PageResponse { next_key, total: 0 }),
+            ))
+        })
+        .unwrap();
+        assert_eq!(pools.len(), MAX_ROUTE_POOLS);
+        assert_eq!(queried, 1);
+    }
+}
@@ -2,15 +2,27 @@ use andromeda_std::{
     amp::{AndrAddr, Recipient},
     andr_exec, andr_instantiate,
     common::denom::Asset,
+    error::ContractError,
 };
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::{Decimal, Uint128};
-use cw20::Cw20ReceiveMsg;
+use cosmwasm_std::{ensure, Addr, Coin, Decimal, StdError, Timestamp, Uint128};
+use cw20::{Cw20ReceiveMsg, Expiration};
+
+use crate::common::{
+    AssetList, CodePin, FeeConfig, Role, RolesResponse, SlippagePolicy, SwapRouterResponse,
+    VolumeLimit, WeightedRecipient,
+};
 
 #[andr_instantiate]
 #[cw_serde]
 pub struct InstantiateMsg {
     pub swap_router: Option<AndrAddr>,
+    /// Seconds a proposed swap router waits before it can be accepted,
+    /// `DEFAULT_SWAP_ROUTER_TIMELOCK` when omitted
+    pub swap_router_timelock: Option<u64>,
+    /// Permission the swap actions, so only addresses granted a permission through
+    /// `PermissionAction` can swap. Open to everyone when omitted
+    pub permissioned_swaps: Option<bool>,
 }
 
 #[andr_exec]
@@ -26,15 +38,160 @@ pub enum ExecuteMsg {
         to_asset: Asset,
         /// The recipient where the swapped token is supposed to be sent
         recipient: Option<Recipient>,
+        /// Split the swapped token between several recipients by weight, exclusive with `recipient`
+        recipients: Option<Vec<WeightedRecipient>>,
         /// The max spread. Equals to slippage tolerance / 100
         max_spread: Option<Decimal>,
         /// The minimum amount of tokens to receive from swap operation
         minimum_receive: Option<Uint128>,
+        /// Derive `minimum_receive` from a simulation of the whole path, allowing this much loss.
+        /// Exclusive with `minimum_receive`
+        minimum_receive_tolerance: Option<Decimal>,
+        /// Reject the swap when its price impact exceeds this
+        max_price_impact: Option<Decimal>,
+        /// The swap operations that is supposed to be taken
+        operations: Option<Vec<SwapOperation>>,
+        /// The integrator that referred the swap, paid a share of the protocol fee. Cannot be the sender
+        referrer: Option<AndrAddr>,
+        /// Refund the offered asset to the refund address instead of reverting when the swap fails
+        refund_on_failure: Option<bool>,
+        /// Reject the swap once this passes. Swaps arriving through AMP are refunded instead
+        deadline: Option<Expiration>,
+    },
+    /// Swap native token into an exact amount of another asset using astroport.
+    /// The fee is charged on top of the swap and the unused input is refunded.
+    #[cfg_attr(not(target_arch = "wasm32"), cw_orch(payable))]
+    SwapExactOutAndForward {
+        /// The asset swap to be swapped to
+        to_asset: Asset,
+        /// The exact amount of `to_asset` to be forwarded
+        to_amount: Uint128,
+        /// The recipient where the swapped token is supposed to be sent
+        recipient: Option<Recipient>,
+        /// The address receiving the unused input, defaults to the sender or the origin of an AMP packet
+        refund_addr: Option<AndrAddr>,
+        /// The max spread. Equals to slippage tolerance / 100
+        max_spread: Option<Decimal>,
+        /// Reject the swap when its price impact exceeds this
+        max_price_impact: Option<Decimal>,
         /// The swap operations that is supposed to be taken
         operations: Option<Vec<SwapOperation>>,
+        /// Refund the offered asset to the refund address instead of reverting when the swap fails
+        refund_on_failure: Option<bool>,
+        /// Reject the swap once this passes
+        deadline: Option<Expiration>,
+    },
+    /// Swap several native coins in one message, each leg is swapped and forwarded on its own.
+    /// A failed leg refunds its coin to the sender without reverting the other legs,
+    /// unless it turns `refund_on_failure` off.
+    #[cfg_attr(not(target_arch = "wasm32"), cw_orch(payable))]
+    BatchSwapAndForward { legs: Vec<SwapLeg> },
+    /// Swap every sent coin and the given CW20 allowances into one asset,
+    /// then forward the combined amount once the last swap replies
+    #[cfg_attr(not(target_arch = "wasm32"), cw_orch(payable))]
+    ConsolidateAndForward {
+        /// The asset every input is swapped into
+        to_asset: Asset,
+        /// The recipient where the combined token is supposed to be sent
+        recipient: Option<Recipient>,
+        /// CW20 tokens pulled from the sender's allowances to this contract
+        cw20_inputs: Option<Vec<Cw20Input>>,
+        /// The max spread applied to each swap. Equals to slippage tolerance / 100
+        max_spread: Option<Decimal>,
+        /// The minimum combined amount of tokens to forward
+        minimum_receive: Option<Uint128>,
+        /// Reject the swap once this passes
+        deadline: Option<Expiration>,
     },
-    /// Update swap router
+    /// Swap the accrued fees into the treasury asset and forward them to the treasury recipient.
+    /// Restricted to the owner, operators and keepers.
+    ConvertFees {
+        /// Only convert these assets, every accrued asset when omitted
+        assets: Option<Vec<Asset>>,
+        /// The max spread applied to each swap. Equals to slippage tolerance / 100
+        max_spread: Option<Decimal>,
+        /// The minimum amount of the treasury asset to forward
+        minimum_receive: Option<Uint128>,
+    },
+    /// Update swap router. Kept for existing callers, it proposes the router without a code pin
+    /// and still has to be accepted once the timelock passes.
     UpdateSwapRouter { swap_router: AndrAddr },
+    /// Propose a new swap router, which can be accepted once the timelock passes.
+    /// The code pin is checked now, on acceptance and on every swap.
+    ProposeSwapRouter {
+        swap_router: AndrAddr,
+        code_pin: Option<CodePin>,
+    },
+    /// Switch to the proposed swap router once its timelock passed
+    AcceptSwapRouter {},
+    /// Drop the proposed swap router
+    CancelSwapRouter {},
+    /// Raise the seconds a proposed swap router waits before it can be accepted.
+    /// The timelock cannot be lowered.
+    SetSwapRouterTimelock { timelock_seconds: u64 },
+    /// Block swaps within `scope`. Restricted to the owner, operators and pausers.
+    Pause { scope: PauseScope },
+    /// Lift the pause on `scope`. Restricted to the owner, operators and pausers.
+    Unpause { scope: PauseScope },
+    /// Grant `role` to `address`
+    GrantRole { role: Role, address: AndrAddr },
+    /// Revoke `role` from `address`
+    RevokeRole { role: Role, address: AndrAddr },
+    /// Set the protocol fee, `None` turns the fee off
+    SetFeeConfig { fee_config: Option<FeeConfig> },
+    /// Set the treasury fees accrue for, `None` pays fees straight to the fee recipient again
+    SetTreasuryConfig {
+        treasury_config: Option<TreasuryConfig>,
+    },
+    /// Set the slippage policy applied to every pair without its own, `None` removes it
+    SetSlippagePolicy {
+        slippage_policy: Option<SlippagePolicy>,
+    },
+    /// Set the slippage policy of swaps from `from_asset` to `to_asset`, `None` removes the override
+    SetPairSlippagePolicy {
+        from_asset: Asset,
+        to_asset: Asset,
+        slippage_policy: Option<SlippagePolicy>,
+    },
+    /// Set the guard checking swaps against oracle prices, `None` turns the guard off
+    SetOracleGuard { oracle_guard: Option<OracleGuard> },
+    /// Map `asset` to the oracle currency pair pricing it, `None` removes the mapping.
+    /// Swaps are only checked against the oracle when both of their assets are mapped.
+    SetOraclePair {
+        asset: Asset,
+        oracle_pair: Option<OraclePair>,
+    },
+    /// Add assets to and remove assets from the allow or deny list
+    UpdateAssetList {
+        list: AssetList,
+        add: Vec<Asset>,
+        remove: Vec<Asset>,
+    },
+    /// Add and remove the code ids CW20 hooks are accepted from.
+    /// Any CW20 is accepted while none are pinned.
+    UpdateCw20CodeIds { add: Vec<u64>, remove: Vec<u64> },
+    /// Set the volume limit of swaps offering `asset`, `None` lifts the limit
+    SetVolumeLimit {
+        asset: Asset,
+        volume_limit: Option<VolumeLimit>,
+    },
+    /// Set the share of the protocol fee paid to `referrer`, `None` falls back to the default share
+    SetReferralShare {
+        referrer: AndrAddr,
+        share_bps: Option<u16>,
+    },
+    /// Update the share of the protocol fee paid to referrers without their own share
+    UpdateDefaultReferralShare { share_bps: u16 },
+    /// Update the most hops a swap path may take
+    UpdateMaxHops { max_hops: u32 },
+    /// Register the route used when a swap from `from_asset` to `to_asset` gives no operations
+    SetRoute {
+        from_asset: Asset,
+        to_asset: Asset,
+        operations: Vec<SwapOperation>,
+    },
+    /// Remove the registered route from `from_asset` to `to_asset`
+    RemoveRoute { from_asset: Asset, to_asset: Asset },
 }
 
 #[cw_serde]
@@ -44,12 +201,45 @@ pub enum Cw20HookMsg {
         to_asset: Asset,
         /// The recipient where the swapped token is supposed to be sent
         recipient: Option<Recipient>,
+        /// Split the swapped token between several recipients by weight, exclusive with `recipient`
+        recipients: Option<Vec<WeightedRecipient>>,
         /// The max spread. Equals to slippage tolerance / 100
         max_spread: Option<Decimal>,
         /// The minimum amount of tokens to receive from swap operation
         minimum_receive: Option<Uint128>,
+        /// Derive `minimum_receive` from a simulation of the whole path, allowing this much loss.
+        /// Exclusive with `minimum_receive`
+        minimum_receive_tolerance: Option<Decimal>,
+        /// Reject the swap when its price impact exceeds this
+        max_price_impact: Option<Decimal>,
         /// The swap operations that is supposed to be taken
         operations: Option<Vec<SwapOperation>>,
+        /// The integrator that referred the swap, paid a share of the protocol fee. Cannot be the sender
+        referrer: Option<AndrAddr>,
+        /// Refund the offered asset to the refund address instead of reverting when the swap fails
+        refund_on_failure: Option<bool>,
+        /// Reject the swap once this passes
+        deadline: Option<Expiration>,
+    },
+    SwapExactOutAndForward {
+        /// The asset swap to be swapped to
+        to_asset: Asset,
+        /// The exact amount of `to_asset` to be forwarded
+        to_amount: Uint128,
+        /// The recipient where the swapped token is supposed to be sent
+        recipient: Option<Recipient>,
+        /// The address receiving the unused input, defaults to the sender or the origin of an AMP packet
+        refund_addr: Option<AndrAddr>,
+        /// The max spread. Equals to slippage tolerance / 100
+        max_spread: Option<Decimal>,
+        /// Reject the swap when its price impact exceeds this
+        max_price_impact: Option<Decimal>,
+        /// The swap operations that is supposed to be taken
+        operations: Option<Vec<SwapOperation>>,
+        /// Refund the offered asset to the refund address instead of reverting when the swap fails
+        refund_on_failure: Option<bool>,
+        /// Reject the swap once this passes
+        deadline: Option<Expiration>,
     },
 }
 #[cw_serde]
@@ -63,6 +253,129 @@ pub enum QueryMsg {
         /// The swap operation to perform
         operations: Vec<SwapOperation>,
     },
+    /// How much swapping `amount` of `from_asset` moves the price against the marginal price
+    #[returns(PriceImpactResponse)]
+    SimulatePriceImpact {
+        from_asset: Asset,
+        to_asset: Asset,
+        amount: Uint128,
+        /// The swap operations to simulate, resolved like a swap when omitted
+        operations: Option<Vec<SwapOperation>>,
+    },
+    /// List the addresses `role` is granted to
+    #[returns(RolesResponse)]
+    Roles {
+        role: Role,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// The swap router, its code pin and the pending proposal
+    #[returns(SwapRouterResponse)]
+    SwapRouter {},
+    /// The protocol fee taken on swaps
+    #[returns(FeeConfigResponse)]
+    FeeConfig {},
+    /// The treasury fees accrue for
+    #[returns(TreasuryConfigResponse)]
+    TreasuryConfig {},
+    /// The fees accrued in the contract and not converted yet
+    #[returns(AccruedFeesResponse)]
+    AccruedFees {
+        start_after: Option<Asset>,
+        limit: Option<u32>,
+    },
+    /// The slippage policy applied to every pair without its own
+    #[returns(SlippagePolicyResponse)]
+    SlippagePolicy {},
+    /// The slippage policy applied to swaps from `from_asset` to `to_asset`
+    #[returns(SlippagePolicyResponse)]
+    PairSlippagePolicy { from_asset: Asset, to_asset: Asset },
+    /// The scopes swaps are paused in
+    #[returns(PauseStatusResponse)]
+    PauseStatus {
+        start_after: Option<PauseScope>,
+        limit: Option<u32>,
+    },
+    /// The guard checking swaps against oracle prices
+    #[returns(OracleGuardResponse)]
+    OracleGuard {},
+    /// The oracle currency pair pricing `asset`
+    #[returns(OraclePairResponse)]
+    OraclePair { asset: Asset },
+    /// List the assets on the allow or deny list
+    #[returns(AssetListResponse)]
+    AssetList {
+        list: AssetList,
+        start_after: Option<Asset>,
+        limit: Option<u32>,
+    },
+    /// List the code ids CW20 hooks are accepted from
+    #[returns(Cw20CodeIdsResponse)]
+    Cw20CodeIds {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// The volume limit of `asset` and what `address` may still offer of it
+    #[returns(VolumeCapacityResponse)]
+    VolumeCapacity { address: AndrAddr, asset: Asset },
+    /// The share of the protocol fee paid to `referrer`
+    #[returns(ReferralShareResponse)]
+    ReferralShare { referrer: AndrAddr },
+    /// What `referrer` has earned so far, per asset
+    #[returns(ReferralEarningsResponse)]
+    ReferralEarnings {
+        referrer: AndrAddr,
+        start_after: Option<Asset>,
+        limit: Option<u32>,
+    },
+    /// The most hops a swap path may take
+    #[returns(MaxHopsResponse)]
+    MaxHops {},
+    /// List the registered routes
+    #[returns(RoutesResponse)]
+    Routes {
+        start_after: Option<(Asset, Asset)>,
+        limit: Option<u32>,
+    },
+    /// Find the path returning the most `to_asset` for `amount` of `from_asset`.
+    /// Swaps never search for paths, pass the result as `operations` or register it as a route.
+    #[returns(FindRouteResponse)]
+    FindRoute {
+        from_asset: Asset,
+        to_asset: Asset,
+        amount: Uint128,
+    },
+}
+
+#[cw_serde]
+pub struct SwapLeg {
+    /// The coin offered by this leg, taken from the sent funds
+    pub from_coin: Coin,
+    /// The asset swap to be swapped to
+    pub to_asset: Asset,
+    /// The recipient where the swapped token is supposed to be sent
+    pub recipient: Option<Recipient>,
+    /// The max spread. Equals to slippage tolerance / 100
+    pub max_spread: Option<Decimal>,
+    /// The minimum amount of tokens to receive from swap operation
+    pub minimum_receive: Option<Uint128>,
+    /// The swap operations that is supposed to be taken
+    pub operations: Option<Vec<SwapOperation>>,
+    /// Reject the leg when its price impact exceeds this
+    pub max_price_impact: Option<Decimal>,
+    /// Refund the leg's coin when its swap fails, defaults to true.
+    /// A leg setting it to false reverts the whole batch on failure
+    pub refund_on_failure: Option<bool>,
+    /// Reject the leg once this passes
+    pub deadline: Option<Expiration>,
+}
+
+#[cw_serde]
+pub struct Cw20Input {
+    /// The CW20 token contract
+    pub token: AndrAddr,
+    /// The amount pulled from the sender's allowance
+    pub amount: Uint128,
 }
 
 #[cw_serde]
@@ -78,3 +391,221 @@ pub struct SimulateSwapOperationResponse {
     /// The expected amount of tokens being received from swap operation
     pub amount: Uint128,
 }
+
+#[cw_serde]
+pub struct FindRouteResponse {
+    /// The swap operations of the best path
+    pub operations: Vec<SwapOperation>,
+    /// The expected amount of tokens being received through the path
+    pub amount: Uint128,
+}
+
+#[cw_serde]
+pub struct RouteInfo {
+    pub from_asset: Asset,
+    pub to_asset: Asset,
+    pub operations: Vec<SwapOperation>,
+}
+
+#[cw_serde]
+pub struct PriceImpactResponse {
+    /// The expected amount of tokens being received
+    pub return_amount: Uint128,
+    /// The relative shortfall of the execution price against the marginal price
+    pub price_impact: Decimal,
+}
+
+#[cw_serde]
+pub struct FeeConfigResponse {
+    pub fee_config: Option<FeeConfig>,
+}
+
+#[cw_serde]
+pub struct TreasuryConfig {
+    /// The asset accrued fees are converted into
+    pub asset: Asset,
+    /// The address receiving the converted fees
+    pub recipient: AndrAddr,
+}
+
+#[cw_serde]
+pub struct TreasuryConfigResponse {
+    pub treasury_config: Option<TreasuryConfig>,
+}
+
+#[cw_serde]
+pub struct AccruedFee {
+    pub asset: Asset,
+    pub amount: Uint128,
+}
+
+#[cw_serde]
+pub struct AccruedFeesResponse {
+    pub fees: Vec<AccruedFee>,
+}
+
+#[cw_serde]
+pub enum PauseScope {
+    /// Every swap
+    Global,
+    /// Swaps offering, asking for or passing through `asset`
+    Asset { asset: Asset },
+    /// Swaps from `from_asset` to `to_asset`
+    Pair { from_asset: Asset, to_asset: Asset },
+}
+
+impl std::fmt::Display for PauseScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PauseScope::Global => write!(f, "all swaps"),
+            PauseScope::Asset { asset } => write!(f, "swaps through {}", asset),
+            PauseScope::Pair {
+                from_asset,
+                to_asset,
+            } => write!(f, "swaps from {} to {}", from_asset, to_asset),
+        }
+    }
+}
+
+#[cw_serde]
+pub struct Pause {
+    pub scope: PauseScope,
+    /// The address that paused the scope
+    pub paused_by: Addr,
+    pub paused_at: Timestamp,
+}
+
+#[cw_serde]
+pub struct PauseStatusResponse {
+    pub pauses: Vec<Pause>,
+}
+
+#[cw_serde]
+pub enum OracleGuardAction {
+    /// Reject swaps simulated to return less than the oracle floor
+    Reject,
+    /// Raise `minimum_receive` to the oracle floor, leaving the router to enforce it
+    RaiseMinimumReceive,
+}
+
+#[cw_serde]
+pub struct OracleGuard {
+    /// Largest shortfall of the swap output against the oracle-implied output
+    pub max_deviation: Decimal,
+    /// Largest number of blocks since an oracle price was last updated, older prices are rejected
+    pub max_price_age: Option<u64>,
+    /// What happens to swaps falling short of the oracle floor
+    pub action: OracleGuardAction,
+}
+
+impl OracleGuard {
+    pub fn validate(&self) -> Result<(), ContractError> {
+        ensure!(
+            self.max_deviation < Decimal::one(),
+            ContractError::Std(StdError::generic_err(
+                "Max oracle deviation must be below 100%"
+            ))
+        );
+        Ok(())
+    }
+}
+
+#[cw_serde]
+pub struct CurrencyPair {
+    pub base: String,
+    pub quote: String,
+}
+
+impl std::fmt::Display for CurrencyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.base, self.quote)
+    }
+}
+
+#[cw_serde]
+pub struct OraclePair {
+    /// The oracle currency pair quoting the asset
+    pub currency_pair: CurrencyPair,
+    /// Decimals of the asset, used to turn raw amounts into oracle units
+    pub decimals: u8,
+}
+
+impl OraclePair {
+    pub fn validate(&self) -> Result<(), ContractError> {
+        ensure!(
+            !self.currency_pair.base.is_empty() && !self.currency_pair.quote.is_empty(),
+            ContractError::Std(StdError::generic_err("Currency pair cannot be empty"))
+        );
+        ensure!(
+            self.decimals <= MAX_ORACLE_DECIMALS,
+            ContractError::Std(StdError::generic_err(format!(
+                "Asset decimals cannot exceed {}",
+                MAX_ORACLE_DECIMALS
+            )))
+        );
+        Ok(())
+    }
+}
+
+/// Most decimals an asset mapped to an oracle currency pair may have
+pub const MAX_ORACLE_DECIMALS: u8 = 18;
+
+#[cw_serde]
+pub struct OracleGuardResponse {
+    pub oracle_guard: Option<OracleGuard>,
+}
+
+#[cw_serde]
+pub struct OraclePairResponse {
+    pub oracle_pair: Option<OraclePair>,
+}
+
+#[cw_serde]
+pub struct AssetListResponse {
+    pub assets: Vec<Asset>,
+}
+
+#[cw_serde]
+pub struct Cw20CodeIdsResponse {
+    pub code_ids: Vec<u64>,
+}
+
+#[cw_serde]
+pub struct VolumeCapacityResponse {
+    pub volume_limit: Option<VolumeLimit>,
+    /// What the address may still offer within the window, `None` when uncapped
+    pub sender_remaining: Option<Uint128>,
+    /// What every sender together may still offer within the window, `None` when uncapped
+    pub asset_remaining: Option<Uint128>,
+}
+
+#[cw_serde]
+pub struct SlippagePolicyResponse {
+    pub slippage_policy: Option<SlippagePolicy>,
+}
+
+#[cw_serde]
+pub struct ReferralShareResponse {
+    pub share_bps: u16,
+}
+
+#[cw_serde]
+pub struct ReferralEarning {
+    pub asset: Asset,
+    pub amount: Uint128,
+}
+
+#[cw_serde]
+pub struct ReferralEarningsResponse {
+    pub earnings: Vec<ReferralEarning>,
+}
+
+#[cw_serde]
+pub struct MaxHopsResponse {
+    pub max_hops: u32,
+}
+
+#[cw_serde]
+pub struct RoutesResponse {
+    pub routes: Vec<RouteInfo>,
+}
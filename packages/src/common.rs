@@ -0,0 +1,529 @@
+use andromeda_std::{
+    amp::{AndrAddr, Recipient},
+    error::ContractError,
+};
+use cosmwasm_schema::cw_serde;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    hash::Hash,
+};
+
+use cosmwasm_std::{
+    ensure, Addr, BlockInfo, Coin, Decimal, Deps, HexBinary, StdError, Timestamp, Uint128,
+};
+use cw20::Expiration;
+
+#[cw_serde]
+pub struct WeightedRecipient {
+    /// The recipient receiving a share of the swapped token
+    pub recipient: Recipient,
+    /// The weight of the recipient's share
+    pub weight: Uint128,
+}
+
+impl WeightedRecipient {
+    pub fn new(recipient: Recipient, weight: Uint128) -> Self {
+        Self { recipient, weight }
+    }
+}
+
+/// Highest fee the owner can set, in basis points
+pub const MAX_FEE_BPS: u16 = 1_000;
+
+#[cw_serde]
+pub enum FeeSide {
+    /// The fee is taken from the offered amount before the swap
+    Input,
+    /// The fee is taken from the swapped amount before it is forwarded
+    Output,
+}
+
+#[cw_serde]
+pub struct FeeConfig {
+    /// The fee in basis points
+    pub fee_bps: u16,
+    /// The address receiving the fee
+    pub fee_recipient: AndrAddr,
+    /// Whether the fee is taken on the input or output side of the swap
+    pub fee_side: FeeSide,
+}
+
+impl FeeConfig {
+    pub fn validate(&self, deps: &Deps) -> Result<(), ContractError> {
+        ensure!(
+            self.fee_bps <= MAX_FEE_BPS,
+            ContractError::Std(StdError::generic_err(format!(
+                "Fee cannot exceed {} bps",
+                MAX_FEE_BPS
+            )))
+        );
+        self.fee_recipient.get_raw_address(deps)?;
+        Ok(())
+    }
+
+    /// The fee charged on `amount`, rounded down
+    pub fn fee_amount(&self, amount: Uint128) -> Uint128 {
+        amount.multiply_ratio(self.fee_bps, 10_000u128)
+    }
+}
+
+#[cw_serde]
+pub struct SlippagePolicy {
+    /// Slippage applied when the caller gives none
+    pub default_slippage: Decimal,
+    /// Highest slippage a caller may ask for
+    pub max_slippage: Decimal,
+}
+
+impl SlippagePolicy {
+    pub fn validate(&self) -> Result<(), ContractError> {
+        ensure!(
+            self.max_slippage < Decimal::one(),
+            ContractError::Std(StdError::generic_err("Max slippage must be below 100%"))
+        );
+        ensure!(
+            self.default_slippage <= self.max_slippage,
+            ContractError::Std(StdError::generic_err(
+                "Default slippage cannot exceed the max slippage"
+            ))
+        );
+        Ok(())
+    }
+
+    /// The slippage a swap runs with, the default one when the caller gives none
+    pub fn resolve(&self, slippage: Option<Decimal>) -> Result<Decimal, ContractError> {
+        let slippage = slippage.unwrap_or(self.default_slippage);
+        ensure!(
+            slippage <= self.max_slippage,
+            ContractError::Std(StdError::generic_err(format!(
+                "Slippage {} exceeds the max slippage {}",
+                slippage, self.max_slippage
+            )))
+        );
+        Ok(slippage)
+    }
+}
+
+/// The marginal price is sampled with this fraction of the requested amount
+pub const PRICE_IMPACT_PROBE_DIVISOR: u128 = 1_000;
+
+/// The amount simulated to sample the marginal price of a swap of `amount`
+pub fn price_impact_probe(amount: Uint128) -> Uint128 {
+    amount
+        .multiply_ratio(1u128, PRICE_IMPACT_PROBE_DIVISOR)
+        .max(Uint128::one())
+}
+
+/// How much worse the execution price of swapping `amount` into `return_amount` is
+/// than the marginal price sampled by swapping `probe_amount` into `probe_return_amount`
+pub fn price_impact(
+    amount: Uint128,
+    return_amount: Uint128,
+    probe_amount: Uint128,
+    probe_return_amount: Uint128,
+) -> Result<Decimal, ContractError> {
+    ensure!(
+        !amount.is_zero() && !probe_amount.is_zero(),
+        ContractError::Std(StdError::generic_err(
+            "Cannot estimate the price impact of swapping nothing"
+        ))
+    );
+    ensure!(
+        !probe_return_amount.is_zero(),
+        ContractError::Std(StdError::generic_err(
+            "Amount is too small to estimate the price impact"
+        ))
+    );
+    let execution_price = Decimal::from_ratio(return_amount, amount);
+    let marginal_price = Decimal::from_ratio(probe_return_amount, probe_amount);
+    if execution_price >= marginal_price {
+        return Ok(Decimal::zero());
+    }
+    Ok((marginal_price - execution_price) / marginal_price)
+}
+
+pub fn ensure_price_impact(
+    price_impact: Decimal,
+    max_price_impact: Decimal,
+) -> Result<(), ContractError> {
+    ensure!(
+        price_impact <= max_price_impact,
+        ContractError::Std(StdError::generic_err(format!(
+            "Price impact {} exceeds the max price impact {}",
+            price_impact, max_price_impact
+        )))
+    );
+    Ok(())
+}
+
+#[cw_serde]
+pub enum AssetList {
+    /// Once it holds an asset, only listed assets may be offered or asked for
+    Allow,
+    /// Listed assets may not be offered or asked for
+    Deny,
+}
+
+impl std::fmt::Display for AssetList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssetList::Allow => write!(f, "allow"),
+            AssetList::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// Administrative permissions that can be granted on top of the owner and operators,
+/// who hold every role
+#[cw_serde]
+pub enum Role {
+    /// Changes the swap router, edits the route registry and sets the swap guards:
+    /// slippage policies, price guards, asset lists, cw20 code ids and volume limits
+    RouterAdmin,
+    /// Sets the protocol fee, the treasury and referral shares
+    FeeAdmin,
+    /// Pauses and unpauses swaps
+    Pauser,
+    /// Converts accrued fees
+    Keeper,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::RouterAdmin => "router_admin",
+            Role::FeeAdmin => "fee_admin",
+            Role::Pauser => "pauser",
+            Role::Keeper => "keeper",
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cw_serde]
+pub struct RolesResponse {
+    pub role: Role,
+    pub addresses: Vec<Addr>,
+}
+
+/// Number of buckets the rolling volume window is tracked in
+pub const VOLUME_WINDOW_BUCKETS: u64 = 24;
+
+#[cw_serde]
+pub struct VolumeLimit {
+    /// Largest amount a single swap may offer
+    pub max_swap_amount: Option<Uint128>,
+    /// Most a single sender may offer within the window
+    pub max_sender_volume: Option<Uint128>,
+    /// Most every sender together may offer within the window
+    pub max_asset_volume: Option<Uint128>,
+    /// Length of the rolling window in seconds
+    pub window_seconds: u64,
+}
+
+impl VolumeLimit {
+    pub fn validate(&self) -> Result<(), ContractError> {
+        ensure!(
+            self.window_seconds > 0,
+            ContractError::Std(StdError::generic_err("Volume window cannot be zero"))
+        );
+        Ok(())
+    }
+
+    /// Block time the bucket holding `now` starts at
+    pub fn bucket_start(&self, now: u64) -> u64 {
+        let bucket_seconds = (self.window_seconds / VOLUME_WINDOW_BUCKETS).max(1);
+        now - now % bucket_seconds
+    }
+
+    /// Buckets starting at or after this block time count towards the window ending at `now`.
+    /// The window edge falls inside the first of them, which counts in full.
+    pub fn window_start(&self, now: u64) -> u64 {
+        self.bucket_start(now.saturating_sub(self.window_seconds))
+    }
+}
+
+/// The error swaps arriving after their deadline fail with
+pub fn expired_error(deadline: &Expiration) -> ContractError {
+    ContractError::Std(StdError::generic_err(format!(
+        "Expired: the swap deadline {} has passed",
+        deadline
+    )))
+}
+
+/// Fails once `deadline` has passed
+pub fn ensure_not_expired(
+    deadline: Option<&Expiration>,
+    block: &BlockInfo,
+) -> Result<(), ContractError> {
+    match deadline {
+        Some(deadline) if deadline.is_expired(block) => Err(expired_error(deadline)),
+        _ => Ok(()),
+    }
+}
+
+/// The error swaps within a paused scope fail with
+pub fn paused_error(scope: &impl Display, paused_by: &Addr) -> ContractError {
+    ContractError::Std(StdError::generic_err(format!(
+        "Paused: {} are paused by {}",
+        scope, paused_by
+    )))
+}
+
+/// Seconds a proposed swap router waits before it can be accepted, unless configured
+pub const DEFAULT_SWAP_ROUTER_TIMELOCK: u64 = 86_400;
+
+/// Longest timelock a swap router can be given, as it can only be raised
+pub const MAX_SWAP_ROUTER_TIMELOCK: u64 = 2_592_000;
+
+pub fn validate_swap_router_timelock(timelock_seconds: u64) -> Result<(), ContractError> {
+    ensure!(
+        timelock_seconds <= MAX_SWAP_ROUTER_TIMELOCK,
+        ContractError::Std(StdError::generic_err(format!(
+            "Swap router timelock cannot exceed {} seconds",
+            MAX_SWAP_ROUTER_TIMELOCK
+        )))
+    );
+    Ok(())
+}
+
+/// The code the swap router contract must run
+#[cw_serde]
+pub enum CodePin {
+    CodeId(u64),
+    /// Checksum of the wasm code, as reported by the code info query
+    Checksum(HexBinary),
+}
+
+impl CodePin {
+    /// Checks the code `contract` runs against the pin
+    pub fn verify(&self, deps: &Deps, contract: &Addr) -> Result<(), ContractError> {
+        let code_id = deps.querier.query_wasm_contract_info(contract)?.code_id;
+        let matches = match self {
+            CodePin::CodeId(pinned) => code_id == *pinned,
+            CodePin::Checksum(pinned) => {
+                deps.querier.query_wasm_code_info(code_id)?.checksum == *pinned
+            }
+        };
+        ensure!(
+            matches,
+            ContractError::Std(StdError::generic_err(format!(
+                "Swap router {} runs code id {}, which does not match the pinned {}",
+                contract, code_id, self
+            )))
+        );
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for CodePin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodePin::CodeId(code_id) => write!(f, "code id {}", code_id),
+            CodePin::Checksum(checksum) => write!(f, "checksum {}", checksum),
+        }
+    }
+}
+
+#[cw_serde]
+pub struct SwapRouterProposal {
+    pub swap_router: AndrAddr,
+    pub code_pin: Option<CodePin>,
+    pub proposed_by: Addr,
+    /// Block time the proposal can be accepted from
+    pub executable_at: Timestamp,
+}
+
+#[cw_serde]
+pub struct SwapRouterResponse {
+    pub swap_router: AndrAddr,
+    pub code_pin: Option<CodePin>,
+    /// Seconds a proposed swap router waits before it can be accepted
+    pub timelock_seconds: u64,
+    pub proposal: Option<SwapRouterProposal>,
+}
+
+/// Share of the fee a referrer can receive at most, in basis points of the fee
+pub const MAX_REFERRAL_SHARE_BPS: u16 = 10_000;
+
+pub fn validate_referral_share(share_bps: u16) -> Result<(), ContractError> {
+    ensure!(
+        share_bps <= MAX_REFERRAL_SHARE_BPS,
+        ContractError::Std(StdError::generic_err(format!(
+            "Referral share cannot exceed {} bps",
+            MAX_REFERRAL_SHARE_BPS
+        )))
+    );
+    Ok(())
+}
+
+/// The referrer's part of `fee_amount`, rounded down
+pub fn referral_amount(fee_amount: Uint128, share_bps: u16) -> Uint128 {
+    fee_amount.multiply_ratio(share_bps, MAX_REFERRAL_SHARE_BPS)
+}
+
+/// Resolves the referrer of a swap made by `swapper`, who cannot refer their own swap
+pub fn resolve_referrer(
+    deps: &Deps,
+    referrer: Option<AndrAddr>,
+    swapper: &str,
+) -> Result<Option<Addr>, ContractError> {
+    let Some(referrer) = referrer else {
+        return Ok(None);
+    };
+    let referrer = referrer.get_raw_address(deps)?;
+    ensure!(
+        referrer != swapper,
+        ContractError::Std(StdError::generic_err("A swap cannot refer itself"))
+    );
+    Ok(Some(referrer))
+}
+
+/// Resolves the forward recipients of a swap, defaulting to `default_addr` when none are given.
+pub fn get_weighted_recipients(
+    recipient: Option<Recipient>,
+    recipients: Option<Vec<WeightedRecipient>>,
+    default_addr: AndrAddr,
+) -> Result<Vec<WeightedRecipient>, ContractError> {
+    let recipients = match (recipient, recipients) {
+        (Some(_), Some(_)) => {
+            return Err(ContractError::Std(StdError::generic_err(
+                "Only one of recipient and recipients can be set",
+            )))
+        }
+        (Some(recipient), None) => vec![WeightedRecipient::new(recipient, Uint128::one())],
+        (None, Some(recipients)) => recipients,
+        (None, None) => vec![WeightedRecipient::new(
+            Recipient::new(default_addr, None),
+            Uint128::one(),
+        )],
+    };
+    ensure!(
+        !recipients.is_empty(),
+        ContractError::Std(StdError::generic_err("Recipients cannot be empty"))
+    );
+    ensure!(
+        recipients.iter().all(|r| !r.weight.is_zero()),
+        ContractError::Std(StdError::generic_err("Recipient weight cannot be zero"))
+    );
+    Ok(recipients)
+}
+
+/// Splits `amount` proportionally to `weights`.
+/// The rounding remainder is handed out one unit at a time from the first entry,
+/// so the shares always add up to `amount`.
+pub fn split_amount(amount: Uint128, weights: &[Uint128]) -> Result<Vec<Uint128>, ContractError> {
+    let total_weight = weights
+        .iter()
+        .try_fold(Uint128::zero(), |acc, weight| acc.checked_add(*weight))?;
+    ensure!(
+        !total_weight.is_zero(),
+        ContractError::Std(StdError::generic_err("Total weight cannot be zero"))
+    );
+
+    let mut shares: Vec<Uint128> = weights
+        .iter()
+        .map(|weight| amount.multiply_ratio(*weight, total_weight))
+        .collect();
+    let distributed = shares
+        .iter()
+        .try_fold(Uint128::zero(), |acc, share| acc.checked_add(*share))?;
+    let mut remainder = amount.checked_sub(distributed)?;
+    for share in shares.iter_mut() {
+        if remainder.is_zero() {
+            break;
+        }
+        *share += Uint128::one();
+        remainder -= Uint128::one();
+    }
+    Ok(shares)
+}
+
+/// Each recipient's share of `amount` by weight.
+/// Recipients whose share rounds down to zero are left out.
+pub fn recipient_shares(
+    amount: Uint128,
+    recipients: &[WeightedRecipient],
+) -> Result<Vec<(&Recipient, Uint128)>, ContractError> {
+    let weights: Vec<Uint128> = recipients.iter().map(|r| r.weight).collect();
+    let shares = split_amount(amount, &weights)?;
+    Ok(recipients
+        .iter()
+        .map(|r| &r.recipient)
+        .zip(shares)
+        .filter(|(_, share)| !share.is_zero())
+        .collect())
+}
+
+/// Ensures the sent `funds` add up exactly to the coins offered by the batch legs.
+pub fn ensure_funds_match(funds: &[Coin], offered: &[Coin]) -> Result<(), ContractError> {
+    fn totals(coins: &[Coin]) -> Result<BTreeMap<&str, Uint128>, ContractError> {
+        let mut totals: BTreeMap<&str, Uint128> = BTreeMap::new();
+        for coin in coins.iter().filter(|coin| !coin.amount.is_zero()) {
+            let total = totals.entry(coin.denom.as_str()).or_default();
+            *total = total.checked_add(coin.amount)?;
+        }
+        Ok(totals)
+    }
+    ensure!(
+        totals(funds)? == totals(offered)?,
+        ContractError::InvalidFunds {
+            msg: "Sent funds do not match the coins offered by the legs".to_string(),
+        }
+    );
+    Ok(())
+}
+
+/// Partial paths kept per depth while searching, bounding the search on dense graphs
+pub(crate) const MAX_SEARCH_FRONTIER: usize = 1_000;
+
+/// Lists paths from `from` to `to` over the adjacency list `graph`, shortest first, where
+/// `hop_node` gives the node a hop reaches. Paths never revisit a node and are at most
+/// `max_hops` long.
+/// The partial paths kept per depth are capped by the hops in `graph`, within `MAX_SEARCH_FRONTIER`.
+pub fn find_paths<N, H>(
+    graph: &HashMap<N, Vec<H>>,
+    hop_node: impl Fn(&H) -> &N,
+    from: &N,
+    to: &N,
+    max_hops: usize,
+    max_paths: usize,
+) -> Vec<Vec<H>>
+where
+    N: Eq + Hash,
+    H: Clone,
+{
+    let max_frontier = MAX_SEARCH_FRONTIER.min(graph.values().map(Vec::len).sum());
+    let mut paths = vec![];
+    let mut frontier: Vec<Vec<H>> = vec![vec![]];
+    for _ in 0..max_hops {
+        let mut next = vec![];
+        for path in frontier {
+            let last = path.last().map_or(from, &hop_node);
+            for hop in graph.get(last).into_iter().flatten() {
+                let node = hop_node(hop);
+                if node == from || path.iter().any(|visited| hop_node(visited) == node) {
+                    continue;
+                }
+                let mut extended = path.clone();
+                extended.push(hop.clone());
+                if node == to {
+                    paths.push(extended);
+                    if paths.len() >= max_paths {
+                        return paths;
+                    }
+                } else if next.len() < max_frontier {
+                    next.push(extended);
+                }
+            }
+        }
+        frontier = next;
+    }
+    paths
+}
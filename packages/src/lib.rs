@@ -1,2 +1,6 @@
 pub mod astroport;
+pub mod common;
 pub mod osmosis;
+
+#[cfg(test)]
+mod testing;
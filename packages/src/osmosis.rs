@@ -1,16 +1,29 @@
 use andromeda_std::{
     amp::{AndrAddr, Recipient},
     andr_exec, andr_instantiate,
+    error::ContractError,
 };
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::{Decimal, Uint128};
+use cosmwasm_std::{ensure, Addr, Coin, Decimal, StdError, Timestamp, Uint128};
+use cw20::Expiration;
 use osmosis_std::types::osmosis::poolmanager::v1beta1::SwapAmountInRoute;
 use swaprouter::Slippage as OsmosisSlippage;
 
+use crate::common::{
+    AssetList, CodePin, FeeConfig, Role, RolesResponse, SlippagePolicy, SwapRouterResponse,
+    VolumeLimit, WeightedRecipient,
+};
+
 #[andr_instantiate]
 #[cw_serde]
 pub struct InstantiateMsg {
     pub swap_router: Option<AndrAddr>,
+    /// Seconds a proposed swap router waits before it can be accepted,
+    /// `DEFAULT_SWAP_ROUTER_TIMELOCK` when omitted
+    pub swap_router_timelock: Option<u64>,
+    /// Permission the swap actions, so only addresses granted a permission through
+    /// `PermissionAction` can swap. Open to everyone when omitted
+    pub permissioned_swaps: Option<bool>,
 }
 
 #[andr_exec]
@@ -24,14 +37,156 @@ pub enum ExecuteMsg {
         to_denom: String,
         /// The recipient where the swapped token is supposed to be sent
         recipient: Option<Recipient>,
-        /// The slippage
-        slippage: Slippage,
+        /// Split the swapped token between several recipients by weight, exclusive with `recipient`
+        recipients: Option<Vec<WeightedRecipient>>,
+        /// The slippage, the slippage policy default applies when omitted.
+        /// Without a policy, a 1% twap slippage applies
+        slippage: Option<Slippage>,
+        /// Reject the swap when its price impact exceeds this
+        max_price_impact: Option<Decimal>,
         /// The swap operations that is supposed to be taken
         route: Option<Vec<SwapRoute>>,
+        /// The integrator that referred the swap, paid a share of the protocol fee. Cannot be the sender
+        referrer: Option<AndrAddr>,
+        /// Refund the offered coin to the sender instead of reverting when the swap fails
+        refund_on_failure: Option<bool>,
+        /// Reject the swap once this passes. Swaps arriving through AMP are refunded instead
+        deadline: Option<Expiration>,
+    },
+    /// Swap native token into an exact amount of another denom using osmosis.
+    /// The fee is charged on top of the swap and the unused input is refunded.
+    #[cfg_attr(not(target_arch = "wasm32"), cw_orch(payable))]
+    SwapExactOutAndForward {
+        /// The denom to be swapped to
+        to_denom: String,
+        /// The exact amount of `to_denom` to be forwarded
+        to_amount: Uint128,
+        /// The recipient where the swapped token is supposed to be sent
+        recipient: Option<Recipient>,
+        /// The address receiving the unused input, defaults to the sender or the origin of an AMP packet
+        refund_addr: Option<AndrAddr>,
+        /// The slippage allowed on the estimated input, the slippage policy default applies when omitted
+        slippage_percentage: Option<Decimal>,
+        /// Reject the swap when its price impact exceeds this
+        max_price_impact: Option<Decimal>,
+        /// The pools to swap through, taken from the swap router when omitted
+        route: Option<Vec<SwapRoute>>,
+        /// Refund the offered coin to the sender instead of reverting when the swap fails
+        refund_on_failure: Option<bool>,
+        /// Reject the swap once this passes
+        deadline: Option<Expiration>,
+    },
+    /// Swap several coins in one message, each leg is swapped and forwarded on its own.
+    /// A failed leg refunds its coin to the sender without reverting the other legs,
+    /// unless it turns `refund_on_failure` off.
+    #[cfg_attr(not(target_arch = "wasm32"), cw_orch(payable))]
+    BatchSwapAndForward { legs: Vec<SwapLeg> },
+    /// Swap every sent coin into one denom,
+    /// then forward the combined amount once the last swap replies
+    #[cfg_attr(not(target_arch = "wasm32"), cw_orch(payable))]
+    ConsolidateAndForward {
+        /// The denom every coin is swapped into
+        to_denom: String,
+        /// The recipient where the combined token is supposed to be sent
+        recipient: Option<Recipient>,
+        /// The twap slippage applied to each swap, the slippage policy default applies when omitted.
+        /// Without a policy, a 1% twap slippage applies
+        slippage_percentage: Option<Decimal>,
+        /// The twap window in seconds, only used along with `slippage_percentage`
+        window_seconds: Option<u64>,
+        /// The minimum combined amount of tokens to forward
+        minimum_receive: Option<Uint128>,
+        /// Reject the swap once this passes
+        deadline: Option<Expiration>,
     },
 
-    /// Update swap router
+    /// Swap the accrued fees into the treasury denom and forward them to the treasury recipient.
+    /// Restricted to the owner, operators and keepers.
+    ConvertFees {
+        /// Only convert these denoms, every accrued denom when omitted
+        denoms: Option<Vec<String>>,
+        /// The twap slippage applied to each swap, the slippage policy default applies when omitted.
+        /// Without a policy, a 1% twap slippage applies
+        slippage_percentage: Option<Decimal>,
+        /// The twap window in seconds, only used along with `slippage_percentage`
+        window_seconds: Option<u64>,
+        /// The minimum amount of the treasury denom to forward
+        minimum_receive: Option<Uint128>,
+    },
+    /// Update swap router. Kept for existing callers, it proposes the router without a code pin
+    /// and still has to be accepted once the timelock passes.
     UpdateSwapRouter { swap_router: AndrAddr },
+    /// Propose a new swap router, which can be accepted once the timelock passes.
+    /// The code pin is checked now, on acceptance and on every swap.
+    ProposeSwapRouter {
+        swap_router: AndrAddr,
+        code_pin: Option<CodePin>,
+    },
+    /// Switch to the proposed swap router once its timelock passed
+    AcceptSwapRouter {},
+    /// Drop the proposed swap router
+    CancelSwapRouter {},
+    /// Raise the seconds a proposed swap router waits before it can be accepted.
+    /// The timelock cannot be lowered.
+    SetSwapRouterTimelock { timelock_seconds: u64 },
+    /// Block swaps within `scope`. Restricted to the owner, operators and pausers.
+    Pause { scope: PauseScope },
+    /// Lift the pause on `scope`. Restricted to the owner, operators and pausers.
+    Unpause { scope: PauseScope },
+    /// Grant `role` to `address`
+    GrantRole { role: Role, address: AndrAddr },
+    /// Revoke `role` from `address`
+    RevokeRole { role: Role, address: AndrAddr },
+    /// Set the protocol fee, `None` turns the fee off
+    SetFeeConfig { fee_config: Option<FeeConfig> },
+    /// Set the treasury fees accrue for, `None` pays fees straight to the fee recipient again
+    SetTreasuryConfig {
+        treasury_config: Option<TreasuryConfig>,
+    },
+    /// Set the slippage policy applied to every pair without its own, `None` removes it
+    SetSlippagePolicy {
+        slippage_policy: Option<SlippagePolicy>,
+    },
+    /// Set the slippage policy of swaps from `from_denom` to `to_denom`, `None` removes the override
+    SetPairSlippagePolicy {
+        from_denom: String,
+        to_denom: String,
+        slippage_policy: Option<SlippagePolicy>,
+    },
+    /// Set the guard refusing swaps through pools whose spot price strays from their twap,
+    /// `None` turns the guard off
+    SetTwapGuard { twap_guard: Option<TwapGuard> },
+    /// Add denoms to and remove denoms from the allow or deny list
+    UpdateAssetList {
+        list: AssetList,
+        add: Vec<String>,
+        remove: Vec<String>,
+    },
+    /// Set the volume limit of swaps offering `denom`, `None` lifts the limit
+    SetVolumeLimit {
+        denom: String,
+        volume_limit: Option<VolumeLimit>,
+    },
+    /// Set the share of the protocol fee paid to `referrer`, `None` falls back to the default share
+    SetReferralShare {
+        referrer: AndrAddr,
+        share_bps: Option<u16>,
+    },
+    /// Update the share of the protocol fee paid to referrers without their own share
+    UpdateDefaultReferralShare { share_bps: u16 },
+    /// Update the most hops a swap path may take
+    UpdateMaxHops { max_hops: u32 },
+    /// Register the route used when a swap from `from_denom` to `to_denom` gives no route
+    SetRoute {
+        from_denom: String,
+        to_denom: String,
+        route: Vec<SwapRoute>,
+    },
+    /// Remove the registered route from `from_denom` to `to_denom`
+    RemoveRoute {
+        from_denom: String,
+        to_denom: String,
+    },
 }
 
 #[cw_serde]
@@ -43,6 +198,92 @@ pub enum QueryMsg {
         from_denom: String,
         to_denom: String,
     },
+    /// How much swapping `amount` of `from_denom` moves the price against the marginal price
+    #[returns(PriceImpactResponse)]
+    SimulatePriceImpact {
+        from_denom: String,
+        to_denom: String,
+        amount: Uint128,
+        /// The route to estimate, resolved like a swap when omitted
+        route: Option<Vec<SwapRoute>>,
+    },
+    /// The scopes swaps are paused in
+    #[returns(PauseStatusResponse)]
+    PauseStatus {
+        start_after: Option<PauseScope>,
+        limit: Option<u32>,
+    },
+    /// List the addresses `role` is granted to
+    #[returns(RolesResponse)]
+    Roles {
+        role: Role,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// The swap router, its code pin and the pending proposal
+    #[returns(SwapRouterResponse)]
+    SwapRouter {},
+    /// The protocol fee taken on swaps
+    #[returns(FeeConfigResponse)]
+    FeeConfig {},
+    /// The treasury fees accrue for
+    #[returns(TreasuryConfigResponse)]
+    TreasuryConfig {},
+    /// The fees accrued in the contract and not converted yet
+    #[returns(AccruedFeesResponse)]
+    AccruedFees {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// The slippage policy applied to every pair without its own
+    #[returns(SlippagePolicyResponse)]
+    SlippagePolicy {},
+    /// The slippage policy applied to swaps from `from_denom` to `to_denom`
+    #[returns(SlippagePolicyResponse)]
+    PairSlippagePolicy {
+        from_denom: String,
+        to_denom: String,
+    },
+    /// The guard comparing pool spot prices with their twap
+    #[returns(TwapGuardResponse)]
+    TwapGuard {},
+    /// List the denoms on the allow or deny list
+    #[returns(AssetListResponse)]
+    AssetList {
+        list: AssetList,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// The volume limit of `denom` and what `address` may still offer of it
+    #[returns(VolumeCapacityResponse)]
+    VolumeCapacity { address: AndrAddr, denom: String },
+    /// The share of the protocol fee paid to `referrer`
+    #[returns(ReferralShareResponse)]
+    ReferralShare { referrer: AndrAddr },
+    /// What `referrer` has earned so far, per denom
+    #[returns(ReferralEarningsResponse)]
+    ReferralEarnings {
+        referrer: AndrAddr,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// The most hops a swap path may take
+    #[returns(MaxHopsResponse)]
+    MaxHops {},
+    /// List the registered routes
+    #[returns(RoutesResponse)]
+    Routes {
+        start_after: Option<(String, String)>,
+        limit: Option<u32>,
+    },
+    /// Find the route returning the most `to_denom` for `amount` of `from_denom`.
+    /// Swaps never search for routes, pass the result as `route` or register it with `SetRoute`.
+    #[returns(FindRouteResponse)]
+    FindRoute {
+        from_denom: String,
+        to_denom: String,
+        amount: Uint128,
+    },
 }
 
 #[cw_serde]
@@ -69,6 +310,28 @@ impl From<Slippage> for OsmosisSlippage {
     }
 }
 
+#[cw_serde]
+pub struct SwapLeg {
+    /// The coin offered by this leg, taken from the sent funds
+    pub from_coin: Coin,
+    /// The denom to be swapped to
+    pub to_denom: String,
+    /// The recipient where the swapped token is supposed to be sent
+    pub recipient: Option<Recipient>,
+    /// The slippage, the slippage policy default applies when omitted.
+    /// Without a policy, a 1% twap slippage applies
+    pub slippage: Option<Slippage>,
+    /// The swap operations that is supposed to be taken
+    pub route: Option<Vec<SwapRoute>>,
+    /// Reject the leg when its price impact exceeds this
+    pub max_price_impact: Option<Decimal>,
+    /// Refund the leg's coin when its swap fails, defaults to true.
+    /// A leg setting it to false reverts the whole batch on failure
+    pub refund_on_failure: Option<bool>,
+    /// Reject the leg once this passes
+    pub deadline: Option<Expiration>,
+}
+
 #[cw_serde]
 pub struct SwapRoute {
     pub pool_id: u64,
@@ -88,3 +351,185 @@ impl From<SwapRoute> for SwapAmountInRoute {
 pub struct GetRouteResponse {
     pub pool_route: Vec<SwapRoute>,
 }
+
+#[cw_serde]
+pub struct FindRouteResponse {
+    /// The pools of the best route
+    pub route: Vec<SwapRoute>,
+    /// The expected amount of tokens being received through the route
+    pub amount: Uint128,
+}
+
+#[cw_serde]
+pub struct RouteInfo {
+    pub from_denom: String,
+    pub to_denom: String,
+    pub route: Vec<SwapRoute>,
+}
+
+#[cw_serde]
+pub struct PriceImpactResponse {
+    /// The expected amount of tokens being received
+    pub return_amount: Uint128,
+    /// The relative shortfall of the execution price against the marginal price
+    pub price_impact: Decimal,
+}
+
+#[cw_serde]
+pub struct FeeConfigResponse {
+    pub fee_config: Option<FeeConfig>,
+}
+
+#[cw_serde]
+pub struct TreasuryConfig {
+    /// The denom accrued fees are converted into
+    pub denom: String,
+    /// The address receiving the converted fees
+    pub recipient: AndrAddr,
+}
+
+#[cw_serde]
+pub struct TreasuryConfigResponse {
+    pub treasury_config: Option<TreasuryConfig>,
+}
+
+#[cw_serde]
+pub struct AccruedFeesResponse {
+    pub fees: Vec<Coin>,
+}
+
+#[cw_serde]
+pub struct AssetListResponse {
+    pub denoms: Vec<String>,
+}
+
+#[cw_serde]
+pub struct VolumeCapacityResponse {
+    pub volume_limit: Option<VolumeLimit>,
+    /// What the address may still offer within the window, `None` when uncapped
+    pub sender_remaining: Option<Uint128>,
+    /// What every sender together may still offer within the window, `None` when uncapped
+    pub asset_remaining: Option<Uint128>,
+}
+
+#[cw_serde]
+pub struct SlippagePolicyResponse {
+    pub slippage_policy: Option<SlippagePolicy>,
+}
+
+#[cw_serde]
+pub enum PauseScope {
+    /// Every swap
+    Global,
+    /// Swaps offering, asking for or passing through `denom`
+    Denom { denom: String },
+    /// Swaps from `from_denom` to `to_denom`
+    Pair {
+        from_denom: String,
+        to_denom: String,
+    },
+}
+
+impl PauseScope {
+    /// Storage key of the scope
+    pub fn key(&self) -> String {
+        match self {
+            PauseScope::Global => "global".to_string(),
+            PauseScope::Denom { denom } => format!("denom:{}", denom),
+            PauseScope::Pair {
+                from_denom,
+                to_denom,
+            } => format!("pair:{}|{}", from_denom, to_denom),
+        }
+    }
+}
+
+impl std::fmt::Display for PauseScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PauseScope::Global => write!(f, "all swaps"),
+            PauseScope::Denom { denom } => write!(f, "swaps through {}", denom),
+            PauseScope::Pair {
+                from_denom,
+                to_denom,
+            } => write!(f, "swaps from {} to {}", from_denom, to_denom),
+        }
+    }
+}
+
+#[cw_serde]
+pub struct Pause {
+    pub scope: PauseScope,
+    /// The address that paused the scope
+    pub paused_by: Addr,
+    pub paused_at: Timestamp,
+}
+
+#[cw_serde]
+pub struct PauseStatusResponse {
+    pub pauses: Vec<Pause>,
+}
+
+/// Longest twap window, osmosis keeps twap records for 48 hours
+pub const MAX_TWAP_WINDOW_SECONDS: u64 = 172_800;
+
+#[cw_serde]
+pub struct TwapGuard {
+    /// The arithmetic twap is taken over this many seconds up to now
+    pub window_seconds: u64,
+    /// Largest relative deviation of the spot price from the twap a pool may show
+    pub max_deviation: Decimal,
+}
+
+impl TwapGuard {
+    pub fn validate(&self) -> Result<(), ContractError> {
+        ensure!(
+            self.window_seconds > 0,
+            ContractError::Std(StdError::generic_err("Twap window cannot be zero"))
+        );
+        ensure!(
+            self.window_seconds <= MAX_TWAP_WINDOW_SECONDS,
+            ContractError::Std(StdError::generic_err(format!(
+                "Twap window cannot exceed {} seconds",
+                MAX_TWAP_WINDOW_SECONDS
+            )))
+        );
+        ensure!(
+            !self.max_deviation.is_zero(),
+            ContractError::Std(StdError::generic_err("Max twap deviation cannot be zero"))
+        );
+        Ok(())
+    }
+}
+
+#[cw_serde]
+pub struct TwapGuardResponse {
+    pub twap_guard: Option<TwapGuard>,
+}
+
+#[cw_serde]
+pub struct ReferralShareResponse {
+    pub share_bps: u16,
+}
+
+#[cw_serde]
+pub struct ReferralEarning {
+    /// The earned denom
+    pub denom: String,
+    pub amount: Uint128,
+}
+
+#[cw_serde]
+pub struct ReferralEarningsResponse {
+    pub earnings: Vec<ReferralEarning>,
+}
+
+#[cw_serde]
+pub struct MaxHopsResponse {
+    pub max_hops: u32,
+}
+
+#[cw_serde]
+pub struct RoutesResponse {
+    pub routes: Vec<RouteInfo>,
+}
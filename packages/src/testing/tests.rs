@@ -0,0 +1,458 @@
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use andromeda_std::amp::{AndrAddr, Recipient};
+    use cosmwasm_std::{
+        coin, testing::mock_dependencies, to_json_binary, Addr, CodeInfoResponse,
+        ContractInfoResponse, ContractResult, Decimal, HexBinary, SystemResult, Uint128, WasmQuery,
+    };
+
+    use crate::common::{
+        ensure_funds_match, ensure_price_impact, find_paths, get_weighted_recipients, price_impact,
+        price_impact_probe, recipient_shares, resolve_referrer, split_amount,
+        validate_swap_router_timelock, CodePin, SlippagePolicy, VolumeLimit, WeightedRecipient,
+        MAX_SEARCH_FRONTIER, MAX_SWAP_ROUTER_TIMELOCK,
+    };
+    use crate::osmosis::{TwapGuard, MAX_TWAP_WINDOW_SECONDS};
+
+    fn weights(weights: &[u128]) -> Vec<Uint128> {
+        weights.iter().map(|weight| Uint128::new(*weight)).collect()
+    }
+
+    fn weighted(recipients: &[(&str, u128)]) -> Vec<WeightedRecipient> {
+        recipients
+            .iter()
+            .map(|(addr, weight)| {
+                WeightedRecipient::new(Recipient::from_string(*addr), Uint128::new(*weight))
+            })
+            .collect()
+    }
+
+    /// Links both ends of every edge, hops carry the pool joining them and the node reached
+    fn pool_graph(edges: &[(u64, u32, u32)]) -> HashMap<u32, Vec<(u64, u32)>> {
+        let mut graph: HashMap<u32, Vec<(u64, u32)>> = HashMap::new();
+        for (pool, a, b) in edges {
+            graph.entry(*a).or_default().push((*pool, *b));
+            graph.entry(*b).or_default().push((*pool, *a));
+        }
+        graph
+    }
+
+    #[test]
+    fn test_find_paths_shortest_first() {
+        let graph = pool_graph(&[(1, 0, 3), (2, 0, 1), (3, 1, 2), (4, 2, 3)]);
+        let paths = find_paths(&graph, |(_, node)| node, &0, &3, 3, 10);
+        assert_eq!(
+            paths,
+            vec![vec![(1, 3)], vec![(2, 1), (3, 2), (4, 3)]],
+            "the direct pool comes before the longer path"
+        );
+    }
+
+    #[test]
+    fn test_find_paths_max_hops() {
+        let graph = pool_graph(&[(1, 0, 1), (2, 1, 2), (3, 2, 3)]);
+        assert!(find_paths(&graph, |(_, node)| node, &0, &3, 2, 10).is_empty());
+        assert_eq!(
+            find_paths(&graph, |(_, node)| node, &0, &3, 3, 10),
+            vec![vec![(1, 1), (2, 2), (3, 3)]]
+        );
+    }
+
+    #[test]
+    fn test_find_paths_parallel_pools() {
+        let graph = pool_graph(&[(1, 0, 1), (2, 0, 1)]);
+        assert_eq!(
+            find_paths(&graph, |(_, node)| node, &0, &1, 1, 10),
+            vec![vec![(1, 1)], vec![(2, 1)]]
+        );
+    }
+
+    #[test]
+    fn test_find_paths_no_revisit() {
+        // 0 - 1 - 2 - 0 is a cycle, the path back through 0 is never taken
+        let graph = pool_graph(&[(1, 0, 1), (2, 1, 2), (3, 2, 0), (4, 2, 3)]);
+        let paths = find_paths(&graph, |(_, node)| node, &0, &3, 4, 10);
+        assert_eq!(
+            paths,
+            vec![vec![(3, 2), (4, 3)], vec![(1, 1), (2, 2), (4, 3)]]
+        );
+        for path in paths {
+            assert!(path.iter().all(|(_, node)| *node != 0));
+        }
+    }
+
+    #[test]
+    fn test_find_paths_max_paths() {
+        let edges: Vec<(u64, u32, u32)> = (1..=5).map(|pool| (pool, 0, 1)).collect();
+        let graph = pool_graph(&edges);
+        assert_eq!(find_paths(&graph, |(_, node)| node, &0, &1, 1, 3).len(), 3);
+    }
+
+    #[test]
+    fn test_find_paths_plain_adjacency() {
+        let graph: HashMap<u32, Vec<u32>> =
+            HashMap::from([(0, vec![1, 2]), (1, vec![3]), (2, vec![3])]);
+        assert_eq!(
+            find_paths(&graph, |node| node, &0, &3, 2, 10),
+            vec![vec![1, 3], vec![2, 3]]
+        );
+        assert!(find_paths(&graph, |node| node, &0, &4, 2, 10).is_empty());
+    }
+
+    #[test]
+    fn test_find_paths_frontier_cap() {
+        // Every middle node leads to the target, but only the first `MAX_SEARCH_FRONTIER`
+        // partial paths are kept for the next hop
+        let middle = MAX_SEARCH_FRONTIER as u32 + 500;
+        let to = u32::MAX;
+        let mut graph: HashMap<u32, Vec<u32>> = HashMap::new();
+        graph.insert(0, (1..=middle).collect());
+        for node in 1..=middle {
+            graph.insert(node, vec![to]);
+        }
+        let paths = find_paths(&graph, |node| node, &0, &to, 2, usize::MAX);
+        assert_eq!(paths.len(), MAX_SEARCH_FRONTIER);
+        assert_eq!(paths[0], vec![1, to]);
+    }
+
+    #[test]
+    fn test_ensure_funds_match() {
+        let funds = vec![coin(100, "uosmo"), coin(50, "uatom")];
+        // Legs may offer the same denom several times and in any order
+        let offered = vec![coin(50, "uatom"), coin(60, "uosmo"), coin(40, "uosmo")];
+        ensure_funds_match(&funds, &offered).unwrap();
+    }
+
+    #[test]
+    fn test_ensure_funds_match_ignores_zero_coins() {
+        let funds = vec![coin(100, "uosmo"), coin(0, "uatom")];
+        let offered = vec![coin(100, "uosmo"), coin(0, "uusdc")];
+        ensure_funds_match(&funds, &offered).unwrap();
+    }
+
+    #[test]
+    fn test_ensure_funds_match_mismatch() {
+        let funds = vec![coin(100, "uosmo")];
+        // Offering less leaves funds in the contract, offering more spends funds it does not own
+        for offered in [
+            vec![coin(99, "uosmo")],
+            vec![coin(101, "uosmo")],
+            vec![coin(100, "uosmo"), coin(1, "uatom")],
+            vec![coin(100, "uatom")],
+            vec![],
+        ] {
+            assert!(ensure_funds_match(&funds, &offered).is_err());
+        }
+    }
+
+    #[test]
+    fn test_split_amount() {
+        assert_eq!(
+            split_amount(Uint128::new(100), &weights(&[1, 3])).unwrap(),
+            weights(&[25, 75])
+        );
+        assert_eq!(
+            split_amount(Uint128::new(100), &weights(&[7])).unwrap(),
+            weights(&[100])
+        );
+    }
+
+    #[test]
+    fn test_split_amount_remainder() {
+        // 100 / 3 leaves 1 over, it goes to the first entry
+        assert_eq!(
+            split_amount(Uint128::new(100), &weights(&[1, 1, 1])).unwrap(),
+            weights(&[34, 33, 33])
+        );
+        // 11 splits into 1, 3 and 5 rounded down, the 2 left over go one by one from the first
+        assert_eq!(
+            split_amount(Uint128::new(11), &weights(&[1, 2, 3])).unwrap(),
+            weights(&[2, 4, 5])
+        );
+        for amount in [0u128, 1, 2, 99, 1_000_001] {
+            let shares = split_amount(Uint128::new(amount), &weights(&[3, 5, 11])).unwrap();
+            assert_eq!(shares.iter().sum::<Uint128>(), Uint128::new(amount));
+        }
+    }
+
+    #[test]
+    fn test_split_amount_zero_weights() {
+        assert!(split_amount(Uint128::new(100), &weights(&[0, 0])).is_err());
+        assert!(split_amount(Uint128::new(100), &[]).is_err());
+        // A zero weight next to others gets nothing
+        assert_eq!(
+            split_amount(Uint128::new(100), &weights(&[0, 1])).unwrap(),
+            weights(&[0, 100])
+        );
+    }
+
+    #[test]
+    fn test_recipient_shares() {
+        let recipients = weighted(&[("alice", 1), ("bob", 2), ("carol", 1)]);
+        let shares: Vec<(String, Uint128)> = recipient_shares(Uint128::new(101), &recipients)
+            .unwrap()
+            .into_iter()
+            .map(|(recipient, share)| (recipient.get_addr(), share))
+            .collect();
+        assert_eq!(
+            shares,
+            vec![
+                ("alice".to_string(), Uint128::new(26)),
+                ("bob".to_string(), Uint128::new(50)),
+                ("carol".to_string(), Uint128::new(25)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recipient_shares_skips_empty_shares() {
+        let recipients = weighted(&[("alice", 1), ("bob", 1), ("carol", 1)]);
+        let shares: Vec<String> = recipient_shares(Uint128::new(2), &recipients)
+            .unwrap()
+            .into_iter()
+            .map(|(recipient, _)| recipient.get_addr())
+            .collect();
+        assert_eq!(shares, vec!["alice".to_string(), "bob".to_string()]);
+    }
+
+    #[test]
+    fn test_get_weighted_recipients() {
+        let sender = AndrAddr::from_string("sender");
+        let default = get_weighted_recipients(None, None, sender.clone()).unwrap();
+        assert_eq!(default, weighted(&[("sender", 1)]));
+
+        let single =
+            get_weighted_recipients(Some(Recipient::from_string("alice")), None, sender.clone())
+                .unwrap();
+        assert_eq!(single, weighted(&[("alice", 1)]));
+
+        let recipients = weighted(&[("alice", 1), ("bob", 3)]);
+        assert_eq!(
+            get_weighted_recipients(None, Some(recipients.clone()), sender.clone()).unwrap(),
+            recipients
+        );
+
+        assert!(get_weighted_recipients(
+            Some(Recipient::from_string("alice")),
+            Some(recipients),
+            sender.clone()
+        )
+        .is_err());
+        assert!(get_weighted_recipients(None, Some(vec![]), sender.clone()).is_err());
+        assert!(
+            get_weighted_recipients(None, Some(weighted(&[("alice", 0), ("bob", 1)])), sender)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_resolve_referrer() {
+        let deps = mock_dependencies();
+        assert_eq!(
+            resolve_referrer(&deps.as_ref(), None, "swapper").unwrap(),
+            None
+        );
+        assert_eq!(
+            resolve_referrer(
+                &deps.as_ref(),
+                Some(AndrAddr::from_string("integrator")),
+                "swapper"
+            )
+            .unwrap(),
+            Some(Addr::unchecked("integrator"))
+        );
+        // Swappers cannot collect the referral share of their own fee
+        assert!(resolve_referrer(
+            &deps.as_ref(),
+            Some(AndrAddr::from_string("swapper")),
+            "swapper"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_slippage_policy_resolve() {
+        let slippage_policy = SlippagePolicy {
+            default_slippage: Decimal::percent(1),
+            max_slippage: Decimal::percent(3),
+        };
+        assert_eq!(slippage_policy.resolve(None).unwrap(), Decimal::percent(1));
+        assert_eq!(
+            slippage_policy.resolve(Some(Decimal::zero())).unwrap(),
+            Decimal::zero()
+        );
+        // The max slippage itself is allowed, anything above it is not
+        assert_eq!(
+            slippage_policy.resolve(Some(Decimal::percent(3))).unwrap(),
+            Decimal::percent(3)
+        );
+        assert!(slippage_policy
+            .resolve(Some(Decimal::permille(31)))
+            .is_err());
+    }
+
+    #[test]
+    fn test_slippage_policy_validate() {
+        let policy = |default_slippage, max_slippage| SlippagePolicy {
+            default_slippage: Decimal::percent(default_slippage),
+            max_slippage: Decimal::percent(max_slippage),
+        };
+        policy(1, 3).validate().unwrap();
+        policy(3, 3).validate().unwrap();
+        assert!(policy(4, 3).validate().is_err());
+        assert!(policy(1, 100).validate().is_err());
+    }
+
+    #[test]
+    fn test_twap_guard_validate() {
+        let guard = |window_seconds, max_deviation| TwapGuard {
+            window_seconds,
+            max_deviation: Decimal::percent(max_deviation),
+        };
+        guard(1, 5).validate().unwrap();
+        guard(MAX_TWAP_WINDOW_SECONDS, 5).validate().unwrap();
+        assert!(guard(0, 5).validate().is_err());
+        assert!(guard(MAX_TWAP_WINDOW_SECONDS + 1, 5).validate().is_err());
+        assert!(guard(3_600, 0).validate().is_err());
+    }
+
+    #[test]
+    fn test_price_impact_probe() {
+        assert_eq!(
+            price_impact_probe(Uint128::new(1_000_000)),
+            Uint128::new(1_000)
+        );
+        // Small amounts still probe with a single unit
+        assert_eq!(price_impact_probe(Uint128::new(999)), Uint128::one());
+        assert_eq!(price_impact_probe(Uint128::zero()), Uint128::one());
+    }
+
+    #[test]
+    fn test_price_impact() {
+        // The probe gets 2 per unit, the full amount only 1.9
+        assert_eq!(
+            price_impact(
+                Uint128::new(1_000_000),
+                Uint128::new(1_900_000),
+                Uint128::new(1_000),
+                Uint128::new(2_000)
+            )
+            .unwrap(),
+            Decimal::percent(5)
+        );
+        // Same price, no impact
+        assert_eq!(
+            price_impact(
+                Uint128::new(1_000_000),
+                Uint128::new(2_000_000),
+                Uint128::new(1_000),
+                Uint128::new(2_000)
+            )
+            .unwrap(),
+            Decimal::zero()
+        );
+        // A better execution price than the probe's rounds to no impact
+        assert_eq!(
+            price_impact(
+                Uint128::new(1_000_000),
+                Uint128::new(2_100_000),
+                Uint128::new(1_000),
+                Uint128::new(2_000)
+            )
+            .unwrap(),
+            Decimal::zero()
+        );
+    }
+
+    #[test]
+    fn test_price_impact_empty_probe() {
+        assert!(price_impact(
+            Uint128::new(100),
+            Uint128::new(50),
+            Uint128::one(),
+            Uint128::zero()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_price_impact_zero_amount() {
+        assert!(price_impact(
+            Uint128::zero(),
+            Uint128::zero(),
+            Uint128::one(),
+            Uint128::new(2)
+        )
+        .is_err());
+        assert!(price_impact(
+            Uint128::new(100),
+            Uint128::new(50),
+            Uint128::zero(),
+            Uint128::new(2)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_ensure_price_impact() {
+        ensure_price_impact(Decimal::percent(1), Decimal::percent(2)).unwrap();
+        ensure_price_impact(Decimal::percent(2), Decimal::percent(2)).unwrap();
+        assert!(ensure_price_impact(Decimal::permille(21), Decimal::percent(2)).is_err());
+    }
+
+    #[test]
+    fn test_volume_window_bounds() {
+        // 24 buckets of 100 seconds
+        let volume_limit = VolumeLimit {
+            max_swap_amount: None,
+            max_sender_volume: None,
+            max_asset_volume: None,
+            window_seconds: 2_400,
+        };
+        assert_eq!(volume_limit.bucket_start(10_000), 10_000);
+        assert_eq!(volume_limit.bucket_start(10_099), 10_000);
+
+        // The window edge at 7_650 falls in the bucket starting at 7_600, which still counts
+        assert_eq!(volume_limit.window_start(10_050), 7_600);
+        assert_eq!(volume_limit.window_start(10_100), 7_700);
+        assert_eq!(volume_limit.window_start(1_000), 0);
+    }
+
+    #[test]
+    fn test_validate_swap_router_timelock() {
+        validate_swap_router_timelock(0).unwrap();
+        validate_swap_router_timelock(MAX_SWAP_ROUTER_TIMELOCK).unwrap();
+        assert!(validate_swap_router_timelock(MAX_SWAP_ROUTER_TIMELOCK + 1).is_err());
+    }
+
+    #[test]
+    fn test_code_pin_verify() {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::ContractInfo { .. } => SystemResult::Ok(ContractResult::Ok(
+                to_json_binary(&ContractInfoResponse::new(7, "creator")).unwrap(),
+            )),
+            WasmQuery::CodeInfo { code_id } => SystemResult::Ok(ContractResult::Ok(
+                to_json_binary(&CodeInfoResponse::new(
+                    *code_id,
+                    "creator",
+                    HexBinary::from(vec![7; 32]),
+                ))
+                .unwrap(),
+            )),
+            _ => panic!("Unexpected query"),
+        });
+        let router = Addr::unchecked("router");
+
+        CodePin::CodeId(7).verify(&deps.as_ref(), &router).unwrap();
+        assert!(CodePin::CodeId(8).verify(&deps.as_ref(), &router).is_err());
+        CodePin::Checksum(HexBinary::from(vec![7; 32]))
+            .verify(&deps.as_ref(), &router)
+            .unwrap();
+        assert!(CodePin::Checksum(HexBinary::from(vec![8; 32]))
+            .verify(&deps.as_ref(), &router)
+            .is_err());
+    }
+}